@@ -169,12 +169,30 @@ pub struct DomainResponse {
 pub struct Answer {
     pub username: String,
     pub password: String,
+    /// The gateway's half of the post-quantum key exchange, if the client's [`Offer`] contained one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_exchange: Option<KeyExchange>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Offer {
     pub username: String,
     pub password: String,
+    /// The client's half of a post-quantum key exchange from which both sides derive the WireGuard preshared key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_exchange: Option<KeyExchange>,
+}
+
+/// One half of a hybrid X25519 + ML-KEM-768 key exchange.
+///
+/// The portal only passes these through, the resulting key is only known to the client and the gateway.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyExchange {
+    /// An ephemeral X25519 public key.
+    pub x25519: Key,
+    /// An ML-KEM-768 encapsulation key in an [`Offer`] or the ciphertext encapsulated to it in an [`Answer`].
+    #[serde(with = "key::base64_bytes")]
+    pub ml_kem: Vec<u8>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -354,7 +372,7 @@ mod tests {
 
     use itertools::Itertools;
 
-    use super::{ResourceDescription, ResourceDescriptionDns, ResourceId};
+    use super::{Key, KeyExchange, Offer, ResourceDescription, ResourceDescriptionDns, ResourceId};
 
     fn fake_resource(name: &str, uuid: &str) -> ResourceDescription {
         ResourceDescription::Dns(ResourceDescriptionDns {
@@ -413,4 +431,32 @@ mod tests {
             expected
         );
     }
+
+    #[test]
+    fn offer_without_key_exchange_deserializes() {
+        let offer: Offer = serde_json::from_str(r#"{"username":"foo","password":"bar"}"#).unwrap();
+
+        assert_eq!(offer.key_exchange, None);
+    }
+
+    #[test]
+    fn key_exchange_roundtrips_as_base64() {
+        let offer = Offer {
+            username: "foo".to_owned(),
+            password: "bar".to_owned(),
+            key_exchange: Some(KeyExchange {
+                x25519: Key([1; 32]),
+                ml_kem: vec![2; 1184],
+            }),
+        };
+
+        let json = serde_json::to_value(&offer).unwrap();
+        assert_eq!(
+            json["key_exchange"]["x25519"],
+            "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+        );
+
+        let deserialized: Offer = serde_json::from_value(json).unwrap();
+        assert_eq!(deserialized.key_exchange, offer.key_exchange);
+    }
 }
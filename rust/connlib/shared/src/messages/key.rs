@@ -89,6 +89,27 @@ impl SerializableSecret for Key {}
 
 pub type SecretKey = Secret<Key>;
 
+/// (De)serializes arbitrary bytes as a base64 string, like [`Key`] but without a fixed length.
+pub(super) mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        STANDARD.decode(s).map_err(de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use boringtun::x25519::{PublicKey, StaticSecret};
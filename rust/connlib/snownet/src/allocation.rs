@@ -6,6 +6,7 @@ use crate::{
 };
 use ::backoff::backoff::Backoff;
 use bytecodec::{DecodeExt as _, EncodeExt as _};
+use firezone_relay::MobilityTicket;
 use rand::random;
 use std::{
     collections::{HashMap, VecDeque},
@@ -15,8 +16,10 @@ use std::{
 use str0m::{net::Protocol, Candidate};
 use stun_codec::{
     rfc5389::{
-        attributes::{ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress},
-        errors::{StaleNonce, Unauthorized},
+        attributes::{
+            AlternateServer, ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
+        },
+        errors::{StaleNonce, TryAlternate, Unauthorized},
     },
     rfc5766::{
         attributes::{
@@ -31,12 +34,26 @@ use tracing::{field, Span};
 
 const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// How often we follow an ALTERNATE-SERVER redirect before giving up, protects against redirect loops.
+const MAX_REDIRECTS: usize = 3;
+
+/// After how long we try an unreachable relay again, doubled after every failed attempt up to [`MAX_UNREACHABLE_RETRY_INTERVAL`].
+const UNREACHABLE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_UNREACHABLE_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 10);
+
 /// Represents a TURN allocation that refreshes itself.
 ///
 /// Allocations have a lifetime and need to be continuously refreshed to stay active.
 #[derive(Debug)]
 pub struct Allocation {
     server: SocketAddr,
+    /// The server we are currently talking to.
+    ///
+    /// Same as `server` unless it redirected us to an alternate server, see <https://www.rfc-editor.org/rfc/rfc8489#section-10>.
+    active_server: SocketAddr,
+    /// The number of redirects we followed since our last successful allocation.
+    num_redirects: usize,
+    transport: RelayTransport,
 
     /// If present, the last address the relay observed for us.
     last_srflx_candidate: Option<Candidate>,
@@ -47,6 +64,16 @@ pub struct Allocation {
 
     /// When we received the allocation and how long it is valid.
     allocation_lifetime: Option<(Instant, Duration)>,
+    /// If present, allows us to keep the allocation when our address changes, see <https://www.rfc-editor.org/rfc/rfc8016>.
+    mobility_ticket: Option<MobilityTicket>,
+    /// Whether the relay stopped responding, i.e. we exhausted all retransmissions of a request.
+    unreachable: bool,
+    /// When we try to make a new allocation on an unreachable relay.
+    retry_unreachable_at: Option<Instant>,
+    /// How long we wait until the next attempt if the relay remains unreachable.
+    unreachable_retry_interval: Duration,
+    /// Whether the relay ever answered one of our requests.
+    received_response: bool,
 
     buffered_transmits: VecDeque<Transmit<'static>>,
     events: VecDeque<CandidateEvent>,
@@ -65,6 +92,20 @@ pub struct Allocation {
     nonce: Option<Nonce>,
 }
 
+/// The transport we use to talk to a TURN server.
+///
+/// The transport only concerns the connection between us and the relay.
+/// Data is always relayed to peers via UDP.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RelayTransport {
+    #[default]
+    Udp,
+    /// TCP or TLS: Both are stream-oriented and thus look the same to us.
+    ///
+    /// The IO layer is responsible for splitting the stream into individual messages using [`framing`](crate::framing).
+    Tcp,
+}
+
 /// A socket that has been allocated on a TURN server.
 ///
 /// Note that any combination of IP versions is possible here.
@@ -90,6 +131,7 @@ impl Socket {
 impl Allocation {
     pub fn new(
         server: SocketAddr,
+        transport: RelayTransport,
         username: Username,
         password: String,
         realm: Realm,
@@ -97,6 +139,9 @@ impl Allocation {
     ) -> Self {
         let mut allocation = Self {
             server,
+            active_server: server,
+            num_redirects: 0,
+            transport,
             last_srflx_candidate: Default::default(),
             ip4_allocation: Default::default(),
             ip6_allocation: Default::default(),
@@ -108,6 +153,11 @@ impl Allocation {
             realm,
             nonce: Default::default(),
             allocation_lifetime: Default::default(),
+            mobility_ticket: Default::default(),
+            unreachable: false,
+            retry_unreachable_at: None,
+            unreachable_retry_interval: UNREACHABLE_RETRY_INTERVAL,
+            received_response: false,
             channel_bindings: Default::default(),
             last_now: now,
             buffered_channel_bindings: RingBuffer::new(100),
@@ -139,6 +189,13 @@ impl Allocation {
         self.realm = realm;
         self.password = password.to_owned();
 
+        if self.unreachable {
+            tracing::debug!("Giving unreachable relay another chance");
+
+            self.retry_unreachable(now);
+            return;
+        }
+
         if !self.has_allocation() && self.allocate_in_flight() {
             tracing::debug!("Not refreshing allocation because we are already making one");
             return;
@@ -147,13 +204,47 @@ impl Allocation {
         if self.is_suspended() {
             tracing::debug!("Attempting to make a new allocation");
 
+            // Give the original server another chance, it may no longer be overloaded.
+            self.active_server = self.server;
+            self.num_redirects = 0;
             self.authenticate_and_queue(make_allocate_request());
             return;
         }
 
         tracing::debug!("Refreshing allocation");
 
-        self.authenticate_and_queue(make_refresh_request());
+        self.authenticate_and_queue(make_refresh_request(self.mobility_ticket.clone()));
+    }
+
+    /// Handles a change of our local network, e.g. switching from Wi-Fi to cellular.
+    ///
+    /// The relay observed us under a different address, so our server-reflexive candidate is stale.
+    /// We refresh right away: With a [`MobilityTicket`], the relay moves the allocation and its channels to our new address.
+    /// Otherwise, the refresh fails and we make a new allocation.
+    pub fn handle_local_network_change(&mut self, now: Instant) {
+        self.update_now(now);
+
+        if let Some(candidate) = self.last_srflx_candidate.take() {
+            self.events.push_back(CandidateEvent::Invalid(candidate));
+        }
+
+        // In-flight requests are retransmitted from our new address anyway.
+        if self.allocate_in_flight() || self.refresh_in_flight() {
+            return;
+        }
+
+        if !self.has_allocation() {
+            tracing::debug!("Attempting to make a new allocation after network change");
+
+            self.active_server = self.server;
+            self.num_redirects = 0;
+            self.authenticate_and_queue(make_allocate_request());
+            return;
+        }
+
+        tracing::debug!("Refreshing allocation after network change");
+
+        self.authenticate_and_queue(make_refresh_request(self.mobility_ticket.clone()));
     }
 
     #[tracing::instrument(level = "debug", skip_all, fields(id, method, class, rtt))]
@@ -166,7 +257,7 @@ impl Allocation {
     ) -> bool {
         self.update_now(now);
 
-        if from != self.server {
+        if from != self.active_server {
             return false;
         }
 
@@ -186,6 +277,8 @@ impl Allocation {
         };
 
         self.backoff.reset();
+        self.received_response = true;
+        self.unreachable_retry_interval = UNREACHABLE_RETRY_INTERVAL;
 
         let rtt = now.duration_since(sent_at);
         Span::current().record("rtt", field::debug(rtt));
@@ -226,6 +319,16 @@ impl Allocation {
                 return true;
             }
 
+            if error.code() == TryAlternate::CODEPOINT && message.method() == ALLOCATE {
+                self.follow_redirect(
+                    message
+                        .get_attribute::<AlternateServer>()
+                        .map(|a| a.address()),
+                );
+
+                return true;
+            }
+
             match message.method() {
                 ALLOCATE => {
                     self.buffered_channel_bindings.clear();
@@ -283,9 +386,13 @@ impl Allocation {
                     return true;
                 };
 
-                let maybe_srflx_candidate = message
-                    .attributes()
-                    .find_map(|addr| srflx_candidate(local, addr));
+                // The mapped address of a TCP connection is useless for hole-punching UDP.
+                let maybe_srflx_candidate = match self.transport {
+                    RelayTransport::Udp => message
+                        .attributes()
+                        .find_map(|addr| srflx_candidate(local, addr)),
+                    RelayTransport::Tcp => None,
+                };
                 let maybe_ip4_relay_candidate = message
                     .attributes()
                     .find_map(relay_candidate(|s| s.is_ipv4()));
@@ -299,6 +406,8 @@ impl Allocation {
                 }
 
                 self.allocation_lifetime = Some((now, lifetime));
+                self.mobility_ticket = message.get_attribute::<MobilityTicket>().cloned();
+                self.num_redirects = 0;
                 update_candidate(
                     maybe_srflx_candidate,
                     &mut self.last_srflx_candidate,
@@ -339,6 +448,10 @@ impl Allocation {
 
                 self.allocation_lifetime = Some((now, lifetime.lifetime()));
 
+                if let Some(ticket) = message.get_attribute::<MobilityTicket>() {
+                    self.mobility_ticket = Some(ticket.clone());
+                }
+
                 tracing::info!(
                     srflx = ?self.last_srflx_candidate,
                     relay_ip4 = ?self.ip4_allocation,
@@ -378,7 +491,7 @@ impl Allocation {
         packet: &'p [u8],
         now: Instant,
     ) -> Option<(SocketAddr, &'p [u8], Socket)> {
-        if from != self.server {
+        if from != self.active_server {
             return None;
         }
 
@@ -401,6 +514,12 @@ impl Allocation {
     pub fn handle_timeout(&mut self, now: Instant) {
         self.update_now(now);
 
+        if self.retry_unreachable_at.is_some_and(|at| now >= at) {
+            tracing::info!("Retrying unreachable relay");
+
+            self.retry_unreachable(now);
+        }
+
         if self
             .allocation_expires_at()
             .is_some_and(|expires_at| now >= expires_at)
@@ -424,13 +543,22 @@ impl Allocation {
 
             tracing::debug!(id = ?request.transaction_id(), method = %request.method(), "Request timed out after {backoff:?}, re-sending");
 
-            self.authenticate_and_queue(request);
+            if !self.authenticate_and_queue(request) {
+                tracing::info!(retry_in = ?self.unreachable_retry_interval, "Relay is unreachable");
+
+                self.invalidate_allocation();
+                self.unreachable = true;
+                self.retry_unreachable_at = Some(now + self.unreachable_retry_interval);
+                self.unreachable_retry_interval =
+                    (self.unreachable_retry_interval * 2).min(MAX_UNREACHABLE_RETRY_INTERVAL);
+            }
         }
 
         if let Some(refresh_at) = self.refresh_allocation_at() {
             if (now >= refresh_at) && !self.refresh_in_flight() {
                 tracing::debug!("Allocation is due for a refresh");
-                let queued = self.authenticate_and_queue(make_refresh_request());
+                let queued =
+                    self.authenticate_and_queue(make_refresh_request(self.mobility_ticket.clone()));
 
                 // If we fail to queue the refresh message because we've exceeded our backoff, give
                 if !queued {
@@ -476,11 +604,16 @@ impl Allocation {
             earliest_timeout = earliest(earliest_timeout, Some(*sent_at + *backoff));
         }
 
-        earliest_timeout
+        earliest(earliest_timeout, self.retry_unreachable_at)
     }
 
     #[tracing::instrument(level = "debug", skip(self, now), fields(relay = %self.server))]
     pub fn bind_channel(&mut self, peer: SocketAddr, now: Instant) {
+        if self.unreachable {
+            tracing::debug!("Relay is unreachable");
+            return;
+        }
+
         if self.is_suspended() {
             tracing::debug!("Allocation is suspended");
             return;
@@ -544,6 +677,42 @@ impl Allocation {
         Some(channel_data)
     }
 
+    /// The server we are currently talking to.
+    ///
+    /// Differs from the server we were created with if it redirected us to an alternate server.
+    /// All messages for this allocation, including channel data, must be sent here.
+    pub fn active_server(&self) -> SocketAddr {
+        self.active_server
+    }
+
+    /// Re-attempts our allocation on the alternate server the relay redirected us to.
+    ///
+    /// Nonces are specific to a server, thus we start authenticating from scratch.
+    fn follow_redirect(&mut self, alternate_server: Option<SocketAddr>) {
+        let Some(alternate_server) = alternate_server else {
+            tracing::warn!("Relay asked us to try an alternate server but did not say which one");
+            return;
+        };
+
+        // For TCP, the IO layer would have to connect to the alternate server first.
+        if self.transport != RelayTransport::Udp {
+            tracing::warn!(%alternate_server, "Cannot follow redirect over TCP");
+            return;
+        }
+
+        if self.num_redirects >= MAX_REDIRECTS {
+            tracing::warn!(%alternate_server, "Too many redirects, giving up");
+            return;
+        }
+
+        tracing::info!(%alternate_server, "Relay is overloaded, following redirect");
+
+        self.num_redirects += 1;
+        self.active_server = alternate_server;
+        self.nonce = None;
+        self.authenticate_and_queue(make_allocate_request());
+    }
+
     fn refresh_allocation_at(&self) -> Option<Instant> {
         let (received_at, lifetime) = self.allocation_lifetime?;
 
@@ -569,9 +738,53 @@ impl Allocation {
 
         self.channel_bindings.clear();
         self.allocation_lifetime = None;
+        self.mobility_ticket = None;
         self.sent_requests.clear();
     }
 
+    /// Whether the relay stopped responding to our requests.
+    ///
+    /// Connections should be moved to other relays.
+    /// We try to make a new allocation with an increasing back-off or once [`Allocation::refresh`] is called.
+    pub fn is_unreachable(&self) -> bool {
+        self.unreachable
+    }
+
+    /// Whether we should retry this allocation via TCP.
+    ///
+    /// That is the case if the relay never answered us via UDP, e.g. because our network blocks outbound UDP.
+    pub fn can_fall_back_to_tcp(&self) -> bool {
+        self.unreachable && !self.received_response && self.transport == RelayTransport::Udp
+    }
+
+    /// Starts over with a new allocation via TCP, see [`Allocation::can_fall_back_to_tcp`].
+    pub fn fall_back_to_tcp(&mut self, now: Instant) {
+        self.update_now(now);
+
+        if let Some(candidate) = self.last_srflx_candidate.take() {
+            self.events.push_back(CandidateEvent::Invalid(candidate));
+        }
+
+        self.transport = RelayTransport::Tcp;
+        self.retry_unreachable(now);
+    }
+
+    /// Starts over with a new allocation after the relay was unreachable.
+    fn retry_unreachable(&mut self, now: Instant) {
+        self.unreachable = false;
+        self.retry_unreachable_at = None;
+        self.active_server = self.server;
+        self.num_redirects = 0;
+        self.nonce = None;
+        self.backoff = backoff::new(now, REQUEST_TIMEOUT);
+        self.authenticate_and_queue(make_allocate_request());
+    }
+
+    /// Whether the relay allocated at least one socket for us.
+    pub fn has_relay_candidate(&self) -> bool {
+        self.ip4_allocation.is_some() || self.ip6_allocation.is_some()
+    }
+
     /// Checks whether the given socket is part of this allocation.
     pub fn has_socket(&self, socket: SocketAddr) -> bool {
         let is_ip4 = self.ip4_socket().is_some_and(|s| s.address() == socket);
@@ -711,7 +924,7 @@ impl Allocation {
             .insert(id, (authenticated_message.clone(), self.last_now, backoff));
         self.buffered_transmits.push_back(Transmit {
             src: None,
-            dst: self.server,
+            dst: self.active_server,
             payload: encode(authenticated_message).into(),
         });
 
@@ -764,11 +977,13 @@ fn make_allocate_request() -> Message<Attribute> {
     message.add_attribute(AdditionalAddressFamily::new(
         stun_codec::rfc8656::attributes::AddressFamily::V6,
     ));
+    // An empty ticket signals that we support mobility.
+    message.add_attribute(MobilityTicket::new(Vec::new()));
 
     message
 }
 
-fn make_refresh_request() -> Message<Attribute> {
+fn make_refresh_request(mobility_ticket: Option<MobilityTicket>) -> Message<Attribute> {
     let mut message = Message::new(MessageClass::Request, REFRESH, TransactionId::new(random()));
 
     message.add_attribute(RequestedTransport::new(17));
@@ -776,6 +991,11 @@ fn make_refresh_request() -> Message<Attribute> {
         stun_codec::rfc8656::attributes::AddressFamily::V6,
     ));
 
+    // Allows the relay to find our allocation in case our address changed.
+    if let Some(mobility_ticket) = mobility_ticket {
+        message.add_attribute(mobility_ticket);
+    }
+
     message
 }
 
@@ -856,7 +1076,9 @@ stun_codec::define_attribute_enums!(
         XorRelayAddress,
         XorPeerAddress,
         ChannelNumber,
-        Lifetime
+        Lifetime,
+        AlternateServer,
+        MobilityTicket
     ]
 );
 
@@ -1065,6 +1287,7 @@ mod tests {
     const PEER2_IP6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 20000);
 
     const RELAY: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3478);
+    const ALTERNATE_RELAY: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3479);
     const RELAY_ADDR_IP4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9999);
     const RELAY_ADDR_IP6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9999);
 
@@ -1338,10 +1561,8 @@ mod tests {
 
         let mut expected_backoffs = VecDeque::from(backoff::steps(start));
 
-        loop {
-            let Some(timeout) = allocation.poll_timeout() else {
-                break;
-            };
+        while !allocation.is_unreachable() {
+            let timeout = allocation.poll_timeout().unwrap();
 
             assert_eq!(expected_backoffs.pop_front().unwrap(), timeout);
 
@@ -1354,6 +1575,96 @@ mod tests {
         assert!(expected_backoffs.is_empty())
     }
 
+    #[test]
+    fn retries_unreachable_relay_with_increasing_backoff() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test(start);
+
+        let mut gave_up_at = make_unreachable(&mut allocation);
+        let mut retry_interval = UNREACHABLE_RETRY_INTERVAL;
+
+        for _ in 0..3 {
+            let retry_at = allocation.poll_timeout().unwrap();
+            assert_eq!(retry_at, gave_up_at + retry_interval);
+
+            allocation.handle_timeout(retry_at);
+
+            assert!(!allocation.is_unreachable());
+            assert_eq!(allocation.next_message().unwrap().method(), ALLOCATE);
+
+            gave_up_at = make_unreachable(&mut allocation);
+            retry_interval *= 2;
+        }
+    }
+
+    #[test]
+    fn retries_unreachable_relay_on_refresh() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test(start);
+
+        let now = make_unreachable(&mut allocation);
+        allocation.refresh(
+            Username::new("foobar".to_owned()).unwrap(),
+            "baz",
+            Realm::new("firezone".to_owned()).unwrap(),
+            now,
+        );
+
+        assert!(!allocation.is_unreachable());
+        assert_eq!(allocation.next_message().unwrap().method(), ALLOCATE);
+    }
+
+    #[test]
+    fn is_unreachable_after_refresh_is_not_answered() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test(start).with_allocate_response(&[RELAY_ADDR_IP4]);
+        assert!(allocation.has_relay_candidate());
+
+        let refresh_at = allocation.refresh_allocation_at().unwrap();
+        allocation.handle_timeout(refresh_at);
+
+        make_unreachable(&mut allocation);
+
+        assert!(!allocation.has_relay_candidate());
+        assert_eq!(
+            iter::from_fn(|| allocation.poll_event()).last(),
+            Some(CandidateEvent::Invalid(
+                Candidate::relayed(RELAY_ADDR_IP4, Protocol::Udp).unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_tcp_if_relay_never_answers_via_udp() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test(start);
+
+        assert!(!allocation.can_fall_back_to_tcp());
+
+        let now = make_unreachable(&mut allocation);
+        assert!(allocation.can_fall_back_to_tcp());
+
+        allocation.fall_back_to_tcp(now);
+
+        assert!(!allocation.is_unreachable());
+        assert_eq!(allocation.retry_unreachable_at, None);
+        assert_eq!(allocation.transport, RelayTransport::Tcp);
+        assert_eq!(allocation.next_message().unwrap().method(), ALLOCATE);
+    }
+
+    #[test]
+    fn does_not_fall_back_to_tcp_if_relay_answered_via_udp() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test(start).with_allocate_response(&[RELAY_ADDR_IP4]);
+
+        let refresh_at = allocation.refresh_allocation_at().unwrap();
+        allocation.handle_timeout(refresh_at);
+
+        make_unreachable(&mut allocation);
+
+        assert!(!allocation.can_fall_back_to_tcp());
+    }
+
     #[test]
     fn given_no_ip6_allocation_does_not_attempt_to_bind_channel_to_ip6_address() {
         let mut allocation =
@@ -1497,6 +1808,28 @@ mod tests {
         assert_eq!(next_event, None);
     }
 
+    #[test]
+    fn does_not_return_srflx_candidate_over_tcp() {
+        let mut allocation = Allocation::for_test(Instant::now());
+        allocation.transport = RelayTransport::Tcp;
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(
+            &allocate_response(&allocate, &[RELAY_ADDR_IP4]),
+            Instant::now(),
+        );
+
+        let next_event = allocation.poll_event();
+        assert_eq!(
+            next_event,
+            Some(CandidateEvent::New(
+                Candidate::relayed(RELAY_ADDR_IP4, Protocol::Udp).unwrap()
+            ))
+        );
+        let next_event = allocation.poll_event();
+        assert_eq!(next_event, None);
+    }
+
     #[test]
     fn calling_refresh_with_same_credentials_will_trigger_refresh() {
         let mut allocation = Allocation::for_test(Instant::now());
@@ -1516,6 +1849,65 @@ mod tests {
         assert!(lifetime.is_none() || lifetime.is_some_and(|l| l.lifetime() != Duration::ZERO));
     }
 
+    #[test]
+    fn refresh_presents_mobility_ticket_from_allocate_response() {
+        let mut allocation = Allocation::for_test(Instant::now());
+
+        let allocate = allocation.next_message().unwrap();
+        assert_eq!(
+            allocate.get_attribute::<MobilityTicket>(),
+            Some(&MobilityTicket::new(Vec::new()))
+        );
+
+        let mut response = decode(&allocate_response(&allocate, &[RELAY_ADDR_IP4]))
+            .unwrap()
+            .unwrap();
+        response.add_attribute(MobilityTicket::new(vec![1, 2, 3, 4]));
+        allocation.handle_test_input(&encode(response), Instant::now());
+
+        allocation.refresh_with_same_credentials();
+
+        let refresh = allocation.next_message().unwrap();
+        assert_eq!(
+            refresh.get_attribute::<MobilityTicket>(),
+            Some(&MobilityTicket::new(vec![1, 2, 3, 4]))
+        );
+    }
+
+    #[test]
+    fn local_network_change_invalidates_srflx_candidate_and_refreshes() {
+        let mut allocation = Allocation::for_test(Instant::now());
+
+        let allocate = allocation.next_message().unwrap();
+        let mut response = decode(&allocate_response(&allocate, &[RELAY_ADDR_IP4]))
+            .unwrap()
+            .unwrap();
+        response.add_attribute(MobilityTicket::new(vec![1, 2, 3, 4]));
+        allocation.handle_test_input(&encode(response), Instant::now());
+        let _ = iter::from_fn(|| allocation.poll_event()).collect::<Vec<_>>(); // Drain events.
+
+        allocation.handle_local_network_change(Instant::now());
+
+        assert_eq!(
+            allocation.poll_event(),
+            Some(CandidateEvent::Invalid(
+                Candidate::server_reflexive(PEER1, PEER1, Protocol::Udp).unwrap()
+            ))
+        );
+        assert_eq!(
+            allocation.poll_event(),
+            None,
+            "relay candidates to stay valid"
+        );
+
+        let refresh = allocation.next_message().unwrap();
+        assert_eq!(refresh.method(), REFRESH);
+        assert_eq!(
+            refresh.get_attribute::<MobilityTicket>(),
+            Some(&MobilityTicket::new(vec![1, 2, 3, 4]))
+        );
+    }
+
     #[test]
     fn failed_refresh_will_invalidate_relay_candiates() {
         let mut allocation = Allocation::for_test(Instant::now());
@@ -1612,6 +2004,58 @@ mod tests {
         assert_eq!(allocate.method(), ALLOCATE);
     }
 
+    #[test]
+    fn follows_redirect_to_alternate_server() {
+        let mut allocation = Allocation::for_test(Instant::now());
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_test_input(&try_alternate_response(&allocate), Instant::now());
+
+        let transmit = allocation.poll_transmit().unwrap();
+        let allocate = decode(&transmit.payload).unwrap().unwrap();
+        assert_eq!(transmit.dst, ALTERNATE_RELAY);
+        assert_eq!(allocate.method(), ALLOCATE);
+
+        let handled = allocation.handle_input(
+            ALTERNATE_RELAY,
+            PEER1,
+            &allocate_response(&allocate, &[RELAY_ADDR_IP4]),
+            Instant::now(),
+        );
+
+        assert!(handled);
+        assert_eq!(allocation.active_server(), ALTERNATE_RELAY);
+        assert_eq!(allocation.ip4_socket().unwrap().server(), RELAY);
+        assert_eq!(allocation.ip4_socket().unwrap().address(), RELAY_ADDR_IP4);
+    }
+
+    #[test]
+    fn stops_following_redirects_after_max_redirects() {
+        let mut allocation = Allocation::for_test(Instant::now());
+        let mut from = RELAY;
+
+        for _ in 0..MAX_REDIRECTS {
+            let allocate = allocation.next_message().unwrap();
+            allocation.handle_input(
+                from,
+                PEER1,
+                &try_alternate_response(&allocate),
+                Instant::now(),
+            );
+            from = ALTERNATE_RELAY;
+        }
+
+        let allocate = allocation.next_message().unwrap();
+        allocation.handle_input(
+            from,
+            PEER1,
+            &try_alternate_response(&allocate),
+            Instant::now(),
+        );
+
+        assert!(allocation.next_message().is_none());
+    }
+
     #[test]
     fn allocation_is_refreshed_after_half_its_lifetime() {
         let mut allocation = Allocation::for_test(Instant::now());
@@ -1826,7 +2270,7 @@ mod tests {
             }
         }
 
-        assert!(allocation.poll_timeout().is_none());
+        assert!(allocation.is_unreachable());
         assert_eq!(
             iter::from_fn(|| allocation.poll_event()).collect::<Vec<_>>(),
             vec![
@@ -1904,6 +2348,18 @@ mod tests {
         encode(message)
     }
 
+    fn try_alternate_response(request: &Message<Attribute>) -> Vec<u8> {
+        let mut message = Message::new(
+            MessageClass::ErrorResponse,
+            request.method(),
+            request.transaction_id(),
+        );
+        message.add_attribute(ErrorCode::from(TryAlternate));
+        message.add_attribute(AlternateServer::new(ALTERNATE_RELAY));
+
+        encode(message)
+    }
+
     fn server_error(request: &Message<Attribute>) -> Vec<u8> {
         let mut message = Message::new(
             MessageClass::ErrorResponse,
@@ -1962,10 +2418,23 @@ mod tests {
         message.get_attribute::<XorPeerAddress>().unwrap().address()
     }
 
+    /// Lets all in-flight requests time out until the allocation gives up, returns when it did.
+    fn make_unreachable(allocation: &mut Allocation) -> Instant {
+        while !allocation.is_unreachable() {
+            let timeout = allocation.poll_timeout().unwrap();
+
+            while allocation.poll_transmit().is_some() {}
+            allocation.handle_timeout(timeout);
+        }
+
+        allocation.last_now
+    }
+
     impl Allocation {
         fn for_test(start: Instant) -> Allocation {
             Allocation::new(
                 RELAY,
+                RelayTransport::Udp,
                 Username::new("foobar".to_owned()).unwrap(),
                 "baz".to_owned(),
                 Realm::new("firezone".to_owned()).unwrap(),
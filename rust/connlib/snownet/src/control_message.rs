@@ -0,0 +1,185 @@
+use crate::key_exchange::{KeyExchangeAnswer, KeyExchangeOffer};
+use pnet_packet::ip::IpNextHeaderProtocol;
+use pnet_packet::ipv4::{checksum, Ipv4Packet, MutableIpv4Packet};
+use pnet_packet::Packet;
+use std::net::Ipv4Addr;
+
+/// IP protocol number reserved for experimentation, see <https://www.rfc-editor.org/rfc/rfc3692>.
+///
+/// Control messages are never forwarded to the TUN device so this only needs to be distinct from real traffic.
+const PROTOCOL: IpNextHeaderProtocol = IpNextHeaderProtocol(253);
+const IPV4_HEADER_LEN: usize = 20;
+
+const MTU_PROBE: [u8; 4] = *b"FZMP";
+const MTU_ACK: [u8; 4] = *b"FZMA";
+const REKEY_OFFER: [u8; 4] = *b"FZRO";
+const REKEY_ANSWER: [u8; 4] = *b"FZRA";
+
+/// A message we exchange with the peer through the WireGuard tunnel.
+///
+/// Every message is wrapped in a minimal IPv4 header so `boringtun` treats it like any other packet.
+/// The body starts with a 4 byte magic and a 4 byte ID, followed by message-specific data.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ControlMessage {
+    /// Probes the path MTU, see [`PathMtu`](crate::path_mtu::PathMtu).
+    MtuProbe(u32),
+    MtuAck(u32),
+    /// Rotates the preshared key, see [`Rekey`](crate::rekey::Rekey).
+    RekeyOffer {
+        id: u32,
+        offer: KeyExchangeOffer,
+    },
+    RekeyAnswer {
+        id: u32,
+        answer: KeyExchangeAnswer,
+    },
+}
+
+impl ControlMessage {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        self.encode_padded(0)
+    }
+
+    /// Encodes the message, zero-padded to at least `size` bytes.
+    ///
+    /// The IP header's total length excludes the padding which is why `boringtun` strips it again on the receiving side.
+    pub(crate) fn encode_padded(&self, size: usize) -> Vec<u8> {
+        let (magic, id, key) = match self {
+            ControlMessage::MtuProbe(id) => (MTU_PROBE, *id, None),
+            ControlMessage::MtuAck(id) => (MTU_ACK, *id, None),
+            ControlMessage::RekeyOffer { id, offer } => {
+                (REKEY_OFFER, *id, Some((&offer.x25519, &offer.ml_kem)))
+            }
+            ControlMessage::RekeyAnswer { id, answer } => {
+                (REKEY_ANSWER, *id, Some((&answer.x25519, &answer.ml_kem)))
+            }
+        };
+
+        let mut body = Vec::with_capacity(8);
+        body.extend_from_slice(&magic);
+        body.extend_from_slice(&id.to_be_bytes());
+        if let Some((x25519, ml_kem)) = key {
+            body.extend_from_slice(x25519);
+            body.extend_from_slice(ml_kem);
+        }
+
+        let len = IPV4_HEADER_LEN + body.len();
+        let mut buf = vec![0u8; len.max(size)];
+
+        let mut packet = MutableIpv4Packet::new(&mut buf).expect("buffer is large enough");
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length(len as u16);
+        packet.set_ttl(1);
+        packet.set_next_level_protocol(PROTOCOL);
+        packet.set_source(Ipv4Addr::UNSPECIFIED);
+        packet.set_destination(Ipv4Addr::UNSPECIFIED);
+        packet.set_checksum(checksum(&packet.to_immutable()));
+
+        buf[IPV4_HEADER_LEN..len].copy_from_slice(&body);
+
+        buf
+    }
+
+    /// Parses a decapsulated packet as a control message.
+    pub(crate) fn decode(packet: &[u8]) -> Option<Self> {
+        let packet = Ipv4Packet::new(packet)?;
+
+        // Real traffic never has an unspecified source or destination, only what we wrote in `encode_padded` does.
+        if packet.get_version() != 4
+            || packet.get_next_level_protocol() != PROTOCOL
+            || packet.get_source() != Ipv4Addr::UNSPECIFIED
+            || packet.get_destination() != Ipv4Addr::UNSPECIFIED
+        {
+            return None;
+        }
+
+        let payload = packet.payload();
+        let magic: [u8; 4] = payload.get(..4)?.try_into().ok()?;
+        let id = u32::from_be_bytes(payload.get(4..8)?.try_into().ok()?);
+        let rest = &payload[8..];
+
+        match magic {
+            MTU_PROBE => Some(ControlMessage::MtuProbe(id)),
+            MTU_ACK => Some(ControlMessage::MtuAck(id)),
+            REKEY_OFFER => {
+                let (x25519, ml_kem) = split_key(rest)?;
+
+                Some(ControlMessage::RekeyOffer {
+                    id,
+                    offer: KeyExchangeOffer { x25519, ml_kem },
+                })
+            }
+            REKEY_ANSWER => {
+                let (x25519, ml_kem) = split_key(rest)?;
+
+                Some(ControlMessage::RekeyAnswer {
+                    id,
+                    answer: KeyExchangeAnswer { x25519, ml_kem },
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn split_key(bytes: &[u8]) -> Option<([u8; 32], Vec<u8>)> {
+    let x25519 = bytes.get(..32)?.try_into().ok()?;
+
+    Some((x25519, bytes[32..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded_probe_roundtrips() {
+        let probe = ControlMessage::MtuProbe(42).encode_padded(1400);
+        assert_eq!(probe.len(), 1400);
+
+        // `boringtun` truncates decapsulated packets to the length in the IP header.
+        let truncated = &probe[..IPV4_HEADER_LEN + 8];
+
+        assert_eq!(
+            ControlMessage::decode(truncated),
+            Some(ControlMessage::MtuProbe(42))
+        );
+    }
+
+    #[test]
+    fn rekey_offer_roundtrips() {
+        let message = ControlMessage::RekeyOffer {
+            id: 1,
+            offer: KeyExchangeOffer {
+                x25519: [1; 32],
+                ml_kem: vec![2; 1184],
+            },
+        };
+
+        let encoded = message.encode();
+
+        assert!(encoded.len() <= 1280, "must fit into the minimum MTU");
+        assert_eq!(ControlMessage::decode(&encoded), Some(message));
+    }
+
+    #[test]
+    fn ignores_regular_traffic() {
+        let mut packet = ControlMessage::MtuAck(1).encode();
+        MutableIpv4Packet::new(&mut packet)
+            .unwrap()
+            .set_next_level_protocol(IpNextHeaderProtocol(17));
+
+        assert_eq!(ControlMessage::decode(&packet), None);
+    }
+
+    #[test]
+    fn ignores_experimental_protocol_from_real_addresses() {
+        let mut packet = ControlMessage::MtuAck(1).encode();
+        let mut ip = MutableIpv4Packet::new(&mut packet).unwrap();
+        ip.set_source(Ipv4Addr::new(100, 64, 0, 1));
+        ip.set_destination(Ipv4Addr::new(100, 64, 0, 2));
+
+        assert_eq!(ControlMessage::decode(&packet), None);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::node::Transmit;
+use std::{collections::HashMap, net::SocketAddr};
+
+/// The maximum number of segments the Linux kernel accepts in a single GSO send, see `UDP_MAX_SEGMENTS`.
+const MAX_SEGMENTS: usize = 64;
+/// The maximum size of a single UDP datagram handed to the kernel.
+const MAX_BATCH_SIZE: usize = (1 << 16) - 1;
+
+/// Groups [`Transmit`]s into batches that can be sent with a single syscall using UDP generic segmentation offload (GSO).
+///
+/// A batch consists of segments sent from the same local socket to the same destination.
+/// All segments of a batch are of equal size, except the last one which may be shorter.
+/// Encapsulated WireGuard packets of a bulk transfer typically all have the same size, allowing us to send up to 64 of them at once.
+///
+/// Platforms that don't support GSO can use a `max_segments` of 1, in which case every [`Batch`] contains a single datagram.
+#[derive(Debug)]
+pub struct GsoQueue {
+    max_segments: usize,
+    /// Batches that can still be appended to.
+    open: HashMap<(Option<SocketAddr>, SocketAddr), Batch>,
+    /// Batches that are full or ended with a shorter segment.
+    closed: Vec<Batch>,
+}
+
+/// One or more UDP datagrams that can be sent with a single syscall.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Batch {
+    /// The local interface from which this batch should be sent, see [`Transmit::src`].
+    pub src: Option<SocketAddr>,
+    /// The remote the batch should be sent to.
+    pub dst: SocketAddr,
+    /// The size of each datagram in `payload`, only the last one may be shorter.
+    pub segment_size: usize,
+    /// The datagrams of this batch, back to back.
+    pub payload: Vec<u8>,
+}
+
+impl Batch {
+    /// The number of datagrams in this batch.
+    pub fn num_segments(&self) -> usize {
+        self.payload.len().div_ceil(self.segment_size)
+    }
+
+    fn is_full(&self, max_segments: usize) -> bool {
+        self.num_segments() >= max_segments
+            || self.payload.len() + self.segment_size > MAX_BATCH_SIZE
+    }
+}
+
+impl GsoQueue {
+    pub fn new(max_segments: usize) -> Self {
+        Self {
+            max_segments: max_segments.clamp(1, MAX_SEGMENTS),
+            open: HashMap::default(),
+            closed: Vec::default(),
+        }
+    }
+
+    /// Appends the [`Transmit`] to the batch for its source and destination, starting a new one if necessary.
+    pub fn enqueue(&mut self, transmit: &Transmit<'_>) {
+        let key = (transmit.src, transmit.dst);
+        let len = transmit.payload.len();
+
+        if len == 0 {
+            return;
+        }
+
+        if let Some(batch) = self.open.get_mut(&key) {
+            if len <= batch.segment_size {
+                batch.payload.extend_from_slice(&transmit.payload);
+
+                if len < batch.segment_size || batch.is_full(self.max_segments) {
+                    let batch = self.open.remove(&key).expect("batch to exist");
+                    self.closed.push(batch);
+                }
+
+                return;
+            }
+
+            // A larger segment cannot be appended, send what we have so far.
+            let batch = self.open.remove(&key).expect("batch to exist");
+            self.closed.push(batch);
+        }
+
+        let batch = Batch {
+            src: transmit.src,
+            dst: transmit.dst,
+            segment_size: len,
+            payload: transmit.payload.to_vec(),
+        };
+
+        if batch.is_full(self.max_segments) {
+            self.closed.push(batch);
+            return;
+        }
+
+        self.open.insert(key, batch);
+    }
+
+    /// Returns all batches, leaving the queue empty.
+    ///
+    /// Batches are returned in the order they were closed, followed by those that could still have been appended to.
+    pub fn drain(&mut self) -> impl Iterator<Item = Batch> + '_ {
+        self.closed
+            .drain(..)
+            .chain(self.open.drain().map(|(_, batch)| batch))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.open.is_empty() && self.closed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const SRC: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 1000);
+    const DST1: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 2000);
+    const DST2: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)), 3000);
+
+    #[test]
+    fn equally_sized_packets_to_same_destination_are_batched() {
+        let mut queue = GsoQueue::new(MAX_SEGMENTS);
+
+        queue.enqueue(&transmit(DST1, 100));
+        queue.enqueue(&transmit(DST1, 100));
+        queue.enqueue(&transmit(DST1, 40));
+
+        let batches = queue.drain().collect::<Vec<_>>();
+
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].segment_size, 100);
+        assert_eq!(batches[0].num_segments(), 3);
+        assert_eq!(batches[0].payload.len(), 240);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn shorter_segment_closes_batch() {
+        let mut queue = GsoQueue::new(MAX_SEGMENTS);
+
+        queue.enqueue(&transmit(DST1, 100));
+        queue.enqueue(&transmit(DST1, 40));
+        queue.enqueue(&transmit(DST1, 100));
+
+        let segments = queue.drain().map(|b| b.num_segments()).collect::<Vec<_>>();
+
+        assert_eq!(segments, vec![2, 1]);
+    }
+
+    #[test]
+    fn different_destinations_are_separate_batches() {
+        let mut queue = GsoQueue::new(MAX_SEGMENTS);
+
+        queue.enqueue(&transmit(DST1, 100));
+        queue.enqueue(&transmit(DST2, 100));
+        queue.enqueue(&transmit(DST1, 100));
+
+        let mut batches = queue
+            .drain()
+            .map(|b| (b.dst, b.num_segments()))
+            .collect::<Vec<_>>();
+        batches.sort();
+
+        assert_eq!(batches, vec![(DST1, 2), (DST2, 1)]);
+    }
+
+    #[test]
+    fn respects_max_segments() {
+        let mut queue = GsoQueue::new(2);
+
+        for _ in 0..5 {
+            queue.enqueue(&transmit(DST1, 100));
+        }
+
+        let segments = queue.drain().map(|b| b.num_segments()).collect::<Vec<_>>();
+
+        assert_eq!(segments, vec![2, 2, 1]);
+    }
+
+    fn transmit(dst: SocketAddr, len: usize) -> Transmit<'static> {
+        Transmit {
+            src: Some(SRC),
+            dst,
+            payload: vec![0u8; len].into(),
+        }
+    }
+}
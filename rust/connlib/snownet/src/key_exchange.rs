@@ -0,0 +1,237 @@
+use boringtun::x25519::{PublicKey, StaticSecret};
+use hkdf::Hkdf;
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768};
+use secrecy::Secret;
+use sha2::Sha256;
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// Domain separation for the preshared keys we derive.
+const INFO: &[u8] = b"firezone wireguard psk v1";
+
+/// The client's half of a hybrid X25519 + ML-KEM-768 key exchange, sent as part of an [`Offer`](crate::Offer).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyExchangeOffer {
+    /// An ephemeral X25519 public key.
+    pub x25519: [u8; 32],
+    /// An ephemeral ML-KEM-768 encapsulation key.
+    pub ml_kem: Vec<u8>,
+}
+
+/// The server's half of a hybrid X25519 + ML-KEM-768 key exchange, sent as part of an [`Answer`](crate::Answer).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyExchangeAnswer {
+    /// An ephemeral X25519 public key.
+    pub x25519: [u8; 32],
+    /// A shared secret, encapsulated to the client's ML-KEM-768 encapsulation key.
+    pub ml_kem: Vec<u8>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub(crate) enum Error {
+    #[error("Invalid ML-KEM encapsulation key")]
+    InvalidEncapsulationKey,
+    #[error("Invalid ML-KEM ciphertext")]
+    InvalidCiphertext,
+    #[error("X25519 public key is of low order")]
+    NonContributory,
+}
+
+/// The state a client keeps between sending its [`KeyExchangeOffer`] and receiving the [`KeyExchangeAnswer`].
+///
+/// The derived preshared key only ever exists on the two peers and is bound to both of their WireGuard static keys.
+/// Combining X25519 with ML-KEM keeps it secret as long as either of them is unbroken, protecting recorded traffic against future quantum computers.
+/// The exchange itself is unauthenticated: whoever relays it and also hands out the static keys, i.e. the signalling channel, can still actively intercept it.
+pub(crate) struct Initiator {
+    x25519: StaticSecret,
+    ml_kem: DecapsulationKey,
+    offer: KeyExchangeOffer,
+}
+
+impl Initiator {
+    pub(crate) fn new() -> Self {
+        let mut rng = rand::thread_rng();
+
+        let x25519 = StaticSecret::random_from_rng(&mut rng);
+        let (ml_kem, encapsulation_key) = MlKem768::generate(&mut rng);
+
+        let offer = KeyExchangeOffer {
+            x25519: PublicKey::from(&x25519).to_bytes(),
+            ml_kem: encapsulation_key.as_bytes().to_vec(),
+        };
+
+        Self {
+            x25519,
+            ml_kem,
+            offer,
+        }
+    }
+
+    pub(crate) fn offer(&self) -> KeyExchangeOffer {
+        self.offer.clone()
+    }
+
+    /// Derives the preshared key from the server's [`KeyExchangeAnswer`].
+    ///
+    /// `local` and `remote` are the WireGuard static public keys of us and the server.
+    pub(crate) fn finish(
+        self,
+        answer: &KeyExchangeAnswer,
+        local: PublicKey,
+        remote: PublicKey,
+    ) -> Result<Secret<[u8; 32]>, Error> {
+        let x25519 = self.x25519.diffie_hellman(&PublicKey::from(answer.x25519));
+        if !x25519.was_contributory() {
+            return Err(Error::NonContributory);
+        }
+
+        let ciphertext = Ciphertext::<MlKem768>::try_from(answer.ml_kem.as_slice())
+            .map_err(|_| Error::InvalidCiphertext)?;
+        let ml_kem = self
+            .ml_kem
+            .decapsulate(&ciphertext)
+            .map_err(|_| Error::InvalidCiphertext)?;
+
+        Ok(derive_psk(
+            x25519.as_bytes(),
+            &ml_kem,
+            (&local, &remote),
+            &self.offer,
+            answer,
+        ))
+    }
+}
+
+/// Answers a client's [`KeyExchangeOffer`], returning the [`KeyExchangeAnswer`] to send back and the derived preshared key.
+///
+/// `local` and `remote` are the WireGuard static public keys of us and the client.
+pub(crate) fn respond(
+    offer: &KeyExchangeOffer,
+    local: PublicKey,
+    remote: PublicKey,
+) -> Result<(KeyExchangeAnswer, Secret<[u8; 32]>), Error> {
+    let mut rng = rand::thread_rng();
+
+    let encapsulation_key = Encoded::<EncapsulationKey>::try_from(offer.ml_kem.as_slice())
+        .map(|key| EncapsulationKey::from_bytes(&key))
+        .map_err(|_| Error::InvalidEncapsulationKey)?;
+    let (ciphertext, ml_kem) = encapsulation_key
+        .encapsulate(&mut rng)
+        .map_err(|_| Error::InvalidEncapsulationKey)?;
+
+    let secret = StaticSecret::random_from_rng(&mut rng);
+    let x25519 = secret.diffie_hellman(&PublicKey::from(offer.x25519));
+    if !x25519.was_contributory() {
+        return Err(Error::NonContributory);
+    }
+
+    let answer = KeyExchangeAnswer {
+        x25519: PublicKey::from(&secret).to_bytes(),
+        ml_kem: ciphertext.to_vec(),
+    };
+    let psk = derive_psk(
+        x25519.as_bytes(),
+        &ml_kem,
+        (&remote, &local),
+        offer,
+        &answer,
+    );
+
+    Ok((answer, psk))
+}
+
+/// Combines both shared secrets into a single preshared key, bound to the static keys of `(initiator, responder)` and the public values of the exchange.
+fn derive_psk(
+    x25519: &[u8],
+    ml_kem: &[u8],
+    (initiator, responder): (&PublicKey, &PublicKey),
+    offer: &KeyExchangeOffer,
+    answer: &KeyExchangeAnswer,
+) -> Secret<[u8; 32]> {
+    let hkdf = Hkdf::<Sha256>::new(None, &[x25519, ml_kem].concat());
+
+    let mut psk = [0u8; 32];
+    hkdf.expand_multi_info(
+        &[
+            INFO,
+            initiator.as_bytes(),
+            responder.as_bytes(),
+            &offer.x25519,
+            &offer.ml_kem,
+            &answer.x25519,
+            &answer.ml_kem,
+        ],
+        &mut psk,
+    )
+    .expect("32 bytes is a valid output length for HKDF-SHA256");
+
+    Secret::new(psk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn both_sides_derive_same_psk() {
+        let initiator = Initiator::new();
+
+        let (answer, server_psk) = respond(&initiator.offer(), server(), client()).unwrap();
+        let client_psk = initiator.finish(&answer, client(), server()).unwrap();
+
+        assert_eq!(client_psk.expose_secret(), server_psk.expose_secret());
+    }
+
+    #[test]
+    fn tampered_answer_yields_different_psk() {
+        let initiator = Initiator::new();
+
+        let (mut answer, server_psk) = respond(&initiator.offer(), server(), client()).unwrap();
+        answer.x25519 =
+            PublicKey::from(&StaticSecret::random_from_rng(rand::thread_rng())).to_bytes();
+        let client_psk = initiator.finish(&answer, client(), server()).unwrap();
+
+        assert_ne!(client_psk.expose_secret(), server_psk.expose_secret());
+    }
+
+    #[test]
+    fn psk_is_bound_to_static_keys() {
+        let initiator = Initiator::new();
+
+        let other = PublicKey::from(&StaticSecret::random_from_rng(rand::thread_rng()));
+        let (answer, server_psk) = respond(&initiator.offer(), server(), other).unwrap();
+        let client_psk = initiator.finish(&answer, client(), server()).unwrap();
+
+        assert_ne!(client_psk.expose_secret(), server_psk.expose_secret());
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let initiator = Initiator::new();
+
+        let mut offer = initiator.offer();
+        offer.ml_kem.truncate(100);
+        assert_eq!(
+            respond(&offer, server(), client()).unwrap_err(),
+            Error::InvalidEncapsulationKey
+        );
+
+        let (mut answer, _) = respond(&initiator.offer(), server(), client()).unwrap();
+        answer.ml_kem.truncate(100);
+        assert_eq!(
+            initiator.finish(&answer, client(), server()).unwrap_err(),
+            Error::InvalidCiphertext
+        );
+    }
+
+    fn client() -> PublicKey {
+        PublicKey::from(&StaticSecret::from([1; 32]))
+    }
+
+    fn server() -> PublicKey {
+        PublicKey::from(&StaticSecret::from([2; 32]))
+    }
+}
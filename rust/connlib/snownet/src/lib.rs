@@ -3,17 +3,30 @@
 mod allocation;
 mod backoff;
 mod channel_data;
+mod control_message;
+mod gso;
 mod index;
 mod ip_packet;
+mod key_exchange;
+mod nat_detection;
 mod node;
+mod nomination;
+mod path_mtu;
+mod rekey;
 mod ringbuffer;
 mod stats;
 mod stun_binding;
 mod utils;
 
+pub use allocation::RelayTransport;
+pub use firezone_relay::framing;
+pub use gso::{Batch, GsoQueue};
 pub use ip_packet::{IpPacket, MutableIpPacket};
+pub use key_exchange::{KeyExchangeAnswer, KeyExchangeOffer};
+pub use nat_detection::{FilteringBehaviour, MappingBehaviour, NatType};
 pub use node::{
     Answer, Client, ClientNode, Credentials, Error, Event, Node, Offer, Server, ServerNode,
     Transmit,
 };
-pub use stats::{ConnectionStats, NodeStats};
+pub use nomination::NominationPolicy;
+pub use stats::{ConnectionStats, NodeStats, PathType};
@@ -0,0 +1,740 @@
+//! Classification of the NAT we are behind, loosely following <https://www.rfc-editor.org/rfc/rfc5780>.
+//!
+//! We learn the mapping behaviour by comparing the addresses that different STUN servers (and alternate ports of the same server) observe us as.
+//! The filtering behaviour can only be tested with servers that support RFC 5780's `CHANGE-REQUEST` attribute, i.e. that advertise an `OTHER-ADDRESS`.
+//!
+//! `stun_codec` doesn't implement these attributes (yet).
+
+use crate::node::Transmit;
+use bytecodec::fixnum::{U32beDecoder, U32beEncoder};
+use bytecodec::{
+    ByteCount, Decode, DecodeExt, Encode, EncodeExt, Eos, SizedEncode, TryTaggedDecode,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use stun_codec::{
+    net::{SocketAddrDecoder, SocketAddrEncoder},
+    rfc5389::{attributes::ErrorCode, attributes::XorMappedAddress, methods::BINDING},
+    AttributeType, Message, MessageClass, TransactionId,
+};
+
+/// How long we wait for a response before re-sending a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// After how many unanswered attempts we give up on a probe.
+///
+/// For filtering probes, giving up is the expected outcome if the NAT drops the response.
+const MAX_PROBE_ATTEMPTS: u8 = 3;
+
+/// The behaviour of the NAT between us and the internet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatType {
+    pub mapping: MappingBehaviour,
+    /// `None` if none of the STUN servers supports testing the filtering behaviour.
+    pub filtering: Option<FilteringBehaviour>,
+}
+
+impl NatType {
+    /// Whether peers are likely able to reach us via our server-reflexive candidates.
+    ///
+    /// If not, a connection will most likely end up being relayed.
+    pub fn allows_hole_punching(&self) -> bool {
+        matches!(
+            self.mapping,
+            MappingBehaviour::NoNat | MappingBehaviour::EndpointIndependent
+        )
+    }
+}
+
+/// How the NAT maps our local socket to a public one, see <https://www.rfc-editor.org/rfc/rfc4787#section-4.1>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingBehaviour {
+    /// STUN servers observed our local address, we are not behind a NAT.
+    NoNat,
+    /// All destinations see the same public address.
+    EndpointIndependent,
+    /// Destinations with different IPs see different public addresses.
+    ///
+    /// We also report this if the NAT might be [`MappingBehaviour::AddressAndPortDependent`] but no STUN server offered an alternate port to test this.
+    AddressDependent,
+    /// Every destination sees a different public address, often called a "symmetric NAT".
+    AddressAndPortDependent,
+}
+
+/// From which remotes the NAT lets traffic through to a mapped socket, see <https://www.rfc-editor.org/rfc/rfc4787#section-5>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilteringBehaviour {
+    /// Anyone can send to our public address.
+    EndpointIndependent,
+    /// Only IPs we have sent to can send to our public address.
+    AddressDependent,
+    /// Only sockets we have sent to can send to our public address.
+    AddressAndPortDependent,
+}
+
+/// A SANS-IO state machine that probes STUN servers to classify our NAT.
+#[derive(Debug)]
+pub(crate) struct NatDetection {
+    servers: HashSet<SocketAddr>,
+    probes: HashMap<TransactionId, Probe>,
+
+    /// The address each server observed us as.
+    mappings: HashMap<SocketAddr, SocketAddr>,
+    /// Whether any server observed us as one of our local addresses.
+    observed_local_address: bool,
+
+    /// The server we test the filtering behaviour with, if any supports it.
+    filtering_server: Option<SocketAddr>,
+    /// Whether the server responded from a different IP and port.
+    responded_from_other_address: Option<bool>,
+    /// Whether the server responded from a different port.
+    responded_from_other_port: Option<bool>,
+    filtering_unsupported: bool,
+
+    result: Option<NatType>,
+
+    buffered_transmits: VecDeque<Transmit<'static>>,
+    events: VecDeque<NatType>,
+}
+
+#[derive(Debug)]
+struct Probe {
+    dst: SocketAddr,
+    kind: ProbeKind,
+    sent_at: Instant,
+    attempts: u8,
+    payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ProbeKind {
+    Mapping,
+    Filtering(ChangeRequest),
+}
+
+impl NatDetection {
+    pub(crate) fn new(servers: HashSet<SocketAddr>, now: Instant) -> Self {
+        let mut detection = Self {
+            servers: HashSet::default(),
+            probes: HashMap::default(),
+            mappings: HashMap::default(),
+            observed_local_address: false,
+            filtering_server: None,
+            responded_from_other_address: None,
+            responded_from_other_port: None,
+            filtering_unsupported: false,
+            result: None,
+            buffered_transmits: VecDeque::default(),
+            events: VecDeque::default(),
+        };
+
+        for server in &servers {
+            detection.send_probe(*server, ProbeKind::Mapping, now);
+        }
+        detection.servers = servers;
+
+        detection
+    }
+
+    pub(crate) fn servers(&self) -> HashSet<SocketAddr> {
+        self.servers.clone()
+    }
+
+    /// Handles a response to one of our probes.
+    ///
+    /// Responses to filtering probes are sent from a different address than the one we sent the probe to, hence we match on the transaction ID only.
+    pub(crate) fn handle_input(
+        &mut self,
+        from: SocketAddr,
+        local: SocketAddr,
+        packet: &[u8],
+        now: Instant,
+    ) -> bool {
+        if self.probes.is_empty() {
+            return false;
+        }
+
+        let Ok(Ok(message)) =
+            stun_codec::MessageDecoder::<Attribute>::default().decode_from_bytes(packet)
+        else {
+            return false;
+        };
+
+        let Some(probe) = self.probes.remove(&message.transaction_id()) else {
+            return false;
+        };
+
+        match (probe.kind, message.class()) {
+            (ProbeKind::Mapping, MessageClass::SuccessResponse) => {
+                let Some(mapped_address) = message.get_attribute::<XorMappedAddress>() else {
+                    tracing::debug!(%from, "STUN server replied but is missing `XOR-MAPPED-ADDRESS`");
+                    self.try_classify();
+                    return true;
+                };
+
+                let observed = mapped_address.address();
+                self.observed_local_address |= observed == local;
+                self.mappings.insert(probe.dst, observed);
+
+                let other_address = message.get_attribute::<OtherAddress>().map(|a| a.address());
+
+                if let Some(other_address) = other_address {
+                    self.probe_other_address(probe.dst, other_address, now);
+                }
+            }
+            (ProbeKind::Filtering(change), MessageClass::SuccessResponse) => {
+                if change.ip {
+                    self.responded_from_other_address = Some(true);
+                } else {
+                    self.responded_from_other_port = Some(true);
+                }
+            }
+            (ProbeKind::Filtering(_), MessageClass::ErrorResponse) => {
+                let code = message.get_attribute::<ErrorCode>().map(|e| e.code());
+                tracing::debug!(%from, ?code, "STUN server does not support `CHANGE-REQUEST`");
+
+                self.filtering_unsupported = true;
+            }
+            (_, class) => {
+                tracing::debug!(%from, %class, "Unexpected response to NAT probe");
+            }
+        }
+
+        self.try_classify();
+
+        true
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        let timed_out = self
+            .probes
+            .iter()
+            .filter(|(_, p)| p.sent_at + PROBE_TIMEOUT <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        if timed_out.is_empty() {
+            return;
+        }
+
+        for id in timed_out {
+            let mut probe = self.probes.remove(&id).expect("id to be present");
+
+            if probe.attempts < MAX_PROBE_ATTEMPTS {
+                probe.attempts += 1;
+                probe.sent_at = now;
+
+                self.buffered_transmits.push_back(Transmit {
+                    src: None,
+                    dst: probe.dst,
+                    payload: probe.payload.clone().into(),
+                });
+                self.probes.insert(id, probe);
+
+                continue;
+            }
+
+            match probe.kind {
+                ProbeKind::Mapping => {
+                    tracing::debug!(server = %probe.dst, "STUN server did not respond to NAT probe");
+                }
+                ProbeKind::Filtering(ChangeRequest { ip: true, .. }) => {
+                    self.responded_from_other_address.get_or_insert(false);
+                }
+                ProbeKind::Filtering(ChangeRequest { ip: false, .. }) => {
+                    self.responded_from_other_port.get_or_insert(false);
+                }
+            }
+        }
+
+        self.try_classify();
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.probes
+            .values()
+            .map(|p| p.sent_at + PROBE_TIMEOUT)
+            .min()
+    }
+
+    pub(crate) fn poll_transmit(&mut self) -> Option<Transmit<'static>> {
+        self.buffered_transmits.pop_front()
+    }
+
+    pub(crate) fn poll_event(&mut self) -> Option<NatType> {
+        self.events.pop_front()
+    }
+
+    /// Probes the alternate address advertised by `server` via `OTHER-ADDRESS`.
+    ///
+    /// We only do this for the first server that advertises one: Its alternate port tells us whether the mapping depends on the destination port and the server lets us test the filtering behaviour.
+    fn probe_other_address(&mut self, server: SocketAddr, other_address: SocketAddr, now: Instant) {
+        if !self.servers.contains(&server) || self.filtering_server.is_some() {
+            return;
+        }
+
+        self.filtering_server = Some(server);
+
+        self.send_probe(
+            SocketAddr::new(server.ip(), other_address.port()),
+            ProbeKind::Mapping,
+            now,
+        );
+        self.send_probe(
+            server,
+            ProbeKind::Filtering(ChangeRequest {
+                ip: true,
+                port: true,
+            }),
+            now,
+        );
+        self.send_probe(
+            server,
+            ProbeKind::Filtering(ChangeRequest {
+                ip: false,
+                port: true,
+            }),
+            now,
+        );
+    }
+
+    fn send_probe(&mut self, dst: SocketAddr, kind: ProbeKind, now: Instant) {
+        let id = TransactionId::new(rand::random());
+
+        let mut request = Message::<Attribute>::new(MessageClass::Request, BINDING, id);
+        if let ProbeKind::Filtering(change) = kind {
+            request.add_attribute(change);
+        }
+
+        let payload = stun_codec::MessageEncoder::<Attribute>::default()
+            .encode_into_bytes(request)
+            .expect("binding requests can always be encoded");
+
+        self.buffered_transmits.push_back(Transmit {
+            src: None,
+            dst,
+            payload: payload.clone().into(),
+        });
+        self.probes.insert(
+            id,
+            Probe {
+                dst,
+                kind,
+                sent_at: now,
+                attempts: 1,
+                payload,
+            },
+        );
+    }
+
+    /// Classifies our NAT once all probes are done.
+    fn try_classify(&mut self) {
+        if !self.probes.is_empty() || self.result.is_some() {
+            return;
+        }
+
+        let Some(mapping) = self.mapping_behaviour() else {
+            tracing::debug!(
+                num_responses = %self.mappings.len(),
+                "Not enough STUN servers responded to detect NAT type"
+            );
+            return;
+        };
+
+        let nat_type = NatType {
+            mapping,
+            filtering: self.filtering_behaviour(),
+        };
+
+        tracing::info!(?nat_type, "Detected NAT type");
+
+        self.result = Some(nat_type);
+        self.events.push_back(nat_type);
+    }
+
+    fn mapping_behaviour(&self) -> Option<MappingBehaviour> {
+        if self.observed_local_address {
+            return Some(MappingBehaviour::NoNat);
+        }
+
+        if self.mappings.len() < 2 {
+            return None;
+        }
+
+        let mut observed = self.mappings.values();
+        let first = observed.next().expect("at least two mappings");
+
+        if observed.all(|m| m == first) {
+            return Some(MappingBehaviour::EndpointIndependent);
+        }
+
+        let port_dependent = self.mappings.iter().any(|(server_a, mapped_a)| {
+            self.mappings.iter().any(|(server_b, mapped_b)| {
+                server_a.ip() == server_b.ip() && server_a != server_b && mapped_a != mapped_b
+            })
+        });
+
+        if port_dependent {
+            return Some(MappingBehaviour::AddressAndPortDependent);
+        }
+
+        Some(MappingBehaviour::AddressDependent)
+    }
+
+    fn filtering_behaviour(&self) -> Option<FilteringBehaviour> {
+        if self.filtering_unsupported {
+            return None;
+        }
+
+        match (
+            self.responded_from_other_address?,
+            self.responded_from_other_port?,
+        ) {
+            (true, _) => Some(FilteringBehaviour::EndpointIndependent),
+            (false, true) => Some(FilteringBehaviour::AddressDependent),
+            (false, false) => Some(FilteringBehaviour::AddressAndPortDependent),
+        }
+    }
+}
+
+/// Asks the server to send its response from a different IP and/or port, see <https://www.rfc-editor.org/rfc/rfc5780#section-7.2>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChangeRequest {
+    ip: bool,
+    port: bool,
+}
+
+impl ChangeRequest {
+    pub(crate) const CODEPOINT: u16 = 0x0003;
+
+    const CHANGE_IP: u32 = 0x04;
+    const CHANGE_PORT: u32 = 0x02;
+}
+
+impl stun_codec::Attribute for ChangeRequest {
+    type Decoder = ChangeRequestDecoder;
+    type Encoder = ChangeRequestEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ChangeRequestDecoder(U32beDecoder);
+
+impl Decode for ChangeRequestDecoder {
+    type Item = ChangeRequest;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.decode(buf, eos)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        let flags = self.0.finish_decoding()?;
+
+        Ok(ChangeRequest {
+            ip: flags & ChangeRequest::CHANGE_IP != 0,
+            port: flags & ChangeRequest::CHANGE_PORT != 0,
+        })
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl TryTaggedDecode for ChangeRequestDecoder {
+    type Tag = AttributeType;
+
+    fn try_start_decoding(&mut self, attribute_type: Self::Tag) -> bytecodec::Result<bool> {
+        Ok(attribute_type.as_u16() == ChangeRequest::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ChangeRequestEncoder(U32beEncoder);
+
+impl Encode for ChangeRequestEncoder {
+    type Item = ChangeRequest;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.encode(buf, eos)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        let mut flags = 0;
+        if item.ip {
+            flags |= ChangeRequest::CHANGE_IP;
+        }
+        if item.port {
+            flags |= ChangeRequest::CHANGE_PORT;
+        }
+
+        self.0.start_encoding(flags)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl SizedEncode for ChangeRequestEncoder {
+    fn exact_requiring_bytes(&self) -> u64 {
+        self.0.exact_requiring_bytes()
+    }
+}
+
+/// The alternate address of a STUN server supporting RFC 5780, see <https://www.rfc-editor.org/rfc/rfc5780#section-7.4>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OtherAddress(SocketAddr);
+
+impl OtherAddress {
+    pub(crate) const CODEPOINT: u16 = 0x802C;
+
+    pub(crate) fn new(address: SocketAddr) -> Self {
+        Self(address)
+    }
+
+    pub(crate) fn address(&self) -> SocketAddr {
+        self.0
+    }
+}
+
+impl stun_codec::Attribute for OtherAddress {
+    type Decoder = OtherAddressDecoder;
+    type Encoder = OtherAddressEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct OtherAddressDecoder(SocketAddrDecoder);
+
+impl Decode for OtherAddressDecoder {
+    type Item = OtherAddress;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.decode(buf, eos)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        self.0.finish_decoding().map(OtherAddress::new)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl TryTaggedDecode for OtherAddressDecoder {
+    type Tag = AttributeType;
+
+    fn try_start_decoding(&mut self, attribute_type: Self::Tag) -> bytecodec::Result<bool> {
+        Ok(attribute_type.as_u16() == OtherAddress::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct OtherAddressEncoder(SocketAddrEncoder);
+
+impl Encode for OtherAddressEncoder {
+    type Item = OtherAddress;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.encode(buf, eos)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        self.0.start_encoding(item.0)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl SizedEncode for OtherAddressEncoder {
+    fn exact_requiring_bytes(&self) -> u64 {
+        self.0.exact_requiring_bytes()
+    }
+}
+
+stun_codec::define_attribute_enums!(
+    Attribute,
+    AttributeDecoder,
+    AttributeEncoder,
+    [XorMappedAddress, ErrorCode, OtherAddress, ChangeRequest]
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const LOCAL: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)), 5000);
+    const SERVER1: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 3478);
+    const SERVER1_OTHER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 2)), 3479);
+    const SERVER2: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)), 3478);
+    const MAPPED1: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 40000);
+    const MAPPED2: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 40001);
+
+    #[test]
+    fn same_mapping_from_all_servers_is_endpoint_independent() {
+        let now = Instant::now();
+        let mut detection = NatDetection::new(HashSet::from([SERVER1, SERVER2]), now);
+
+        respond_to_all(&mut detection, now, |_| Some(MAPPED1), None);
+
+        assert_eq!(
+            detection.poll_event(),
+            Some(NatType {
+                mapping: MappingBehaviour::EndpointIndependent,
+                filtering: None
+            })
+        );
+        assert!(detection.poll_event().is_none());
+    }
+
+    #[test]
+    fn observing_local_address_means_no_nat() {
+        let now = Instant::now();
+        let mut detection = NatDetection::new(HashSet::from([SERVER1]), now);
+
+        respond_to_all(&mut detection, now, |_| Some(LOCAL), None);
+
+        assert_eq!(
+            detection.poll_event().unwrap().mapping,
+            MappingBehaviour::NoNat
+        );
+    }
+
+    #[test]
+    fn different_mapping_for_alternate_port_is_address_and_port_dependent() {
+        let now = Instant::now();
+        let mut detection = NatDetection::new(HashSet::from([SERVER1, SERVER2]), now);
+
+        respond_to_all(
+            &mut detection,
+            now,
+            |probe| match probe.kind {
+                ProbeKind::Mapping if probe.dst == SERVER1 => Some(MAPPED1),
+                ProbeKind::Mapping => Some(MAPPED2),
+                ProbeKind::Filtering(_) => None, // Dropped by the NAT.
+            },
+            Some(SERVER1_OTHER),
+        );
+        time_out_all(&mut detection, now);
+
+        assert_eq!(
+            detection.poll_event(),
+            Some(NatType {
+                mapping: MappingBehaviour::AddressAndPortDependent,
+                filtering: Some(FilteringBehaviour::AddressAndPortDependent)
+            })
+        );
+    }
+
+    #[test]
+    fn response_from_other_port_only_is_address_dependent_filtering() {
+        let now = Instant::now();
+        let mut detection = NatDetection::new(HashSet::from([SERVER1, SERVER2]), now);
+
+        respond_to_all(
+            &mut detection,
+            now,
+            |probe| match probe.kind {
+                ProbeKind::Filtering(ChangeRequest { ip: true, .. }) => None,
+                _ => Some(MAPPED1),
+            },
+            Some(SERVER1_OTHER),
+        );
+        time_out_all(&mut detection, now);
+
+        assert_eq!(
+            detection.poll_event(),
+            Some(NatType {
+                mapping: MappingBehaviour::EndpointIndependent,
+                filtering: Some(FilteringBehaviour::AddressDependent)
+            })
+        );
+    }
+
+    #[test]
+    fn single_server_without_alternate_address_is_inconclusive() {
+        let now = Instant::now();
+        let mut detection = NatDetection::new(HashSet::from([SERVER1]), now);
+
+        respond_to_all(&mut detection, now, |_| Some(MAPPED1), None);
+
+        assert!(detection.probes.is_empty());
+        assert!(detection.poll_event().is_none());
+    }
+
+    /// Answers every outstanding probe with the mapping returned by `mapped`, unless it returns `None`.
+    fn respond_to_all(
+        detection: &mut NatDetection,
+        now: Instant,
+        mapped: impl Fn(&Probe) -> Option<SocketAddr>,
+        other_address: Option<SocketAddr>,
+    ) {
+        let mut answered = HashSet::new();
+
+        while let Some(transmit) = detection.poll_transmit() {
+            let request = stun_codec::MessageDecoder::<Attribute>::default()
+                .decode_from_bytes(&transmit.payload)
+                .unwrap()
+                .unwrap();
+            let id = request.transaction_id();
+
+            if !answered.insert(id) {
+                continue;
+            }
+
+            let Some(mapped_address) = detection.probes.get(&id).and_then(&mapped) else {
+                continue;
+            };
+
+            let mut response =
+                Message::<Attribute>::new(MessageClass::SuccessResponse, BINDING, id);
+            response.add_attribute(XorMappedAddress::new(mapped_address));
+            // Only the first server supports RFC 5780.
+            if let Some(other_address) = other_address.filter(|_| transmit.dst == SERVER1) {
+                response.add_attribute(OtherAddress::new(other_address));
+            }
+            let response = stun_codec::MessageEncoder::<Attribute>::default()
+                .encode_into_bytes(response)
+                .unwrap();
+
+            assert!(detection.handle_input(transmit.dst, LOCAL, &response, now));
+        }
+    }
+
+    fn time_out_all(detection: &mut NatDetection, mut now: Instant) {
+        while let Some(timeout) = detection.poll_timeout() {
+            now = now.max(timeout);
+            detection.handle_timeout(now);
+        }
+        while detection.poll_transmit().is_some() {}
+    }
+}
@@ -19,9 +19,15 @@ use str0m::ice::{IceAgent, IceAgentEvent, IceCreds, StunMessage, StunPacket};
 use str0m::net::Protocol;
 use str0m::{Candidate, CandidateKind, IceConnectionState};
 
-use crate::allocation::{Allocation, Socket};
+use crate::allocation::{Allocation, RelayTransport, Socket};
+use crate::control_message::ControlMessage;
 use crate::index::IndexLfsr;
-use crate::stats::{ConnectionStats, NodeStats};
+use crate::key_exchange::{self, KeyExchangeAnswer, KeyExchangeOffer};
+use crate::nat_detection::{NatDetection, NatType};
+use crate::nomination::{NominationPolicy, WorkingPaths};
+use crate::path_mtu::PathMtu;
+use crate::rekey::Rekey;
+use crate::stats::{BindingRequests, ConnectionStats, NodeStats, PathType};
 use crate::stun_binding::StunBinding;
 use crate::utils::earliest;
 use crate::{IpPacket, MutableIpPacket};
@@ -43,6 +49,16 @@ const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
 
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
 
+/// We set a Wireguard keep-alive to ensure the WG session doesn't timeout on an idle connection.
+///
+/// Without such a timeout, using a tunnel after the REKEY_TIMEOUT requires handshaking a new session which delays the new application packet by 1 RTT.
+const WG_KEEP_ALIVE: Option<u16> = Some(10);
+
+/// How long we keep accepting packets for the sessions of our previous preshared key after rotating it.
+///
+/// Until the peer switched as well, it will keep using the previous key.
+const PREVIOUS_TUNNEL_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
 /// Manages a set of wireguard connections for a server.
 pub type ServerNode<TId> = Node<Server, TId>;
 /// Manages a set of wireguard connections for a client.
@@ -84,6 +100,15 @@ pub struct Node<T, TId> {
 
     bindings: HashMap<SocketAddr, StunBinding>,
     allocations: HashMap<SocketAddr, Allocation>,
+    /// The transport to use for a particular relay, defaults to [`RelayTransport::Udp`].
+    relay_transports: HashMap<SocketAddr, RelayTransport>,
+    nomination_policy: NominationPolicy,
+    /// Whether we derive the WireGuard preshared key from a post-quantum key exchange, see [`Node::set_post_quantum_psk`].
+    post_quantum_psk: bool,
+    /// After how long we rotate the preshared key of a connection, see [`Node::set_max_key_age`].
+    max_key_age: Option<Duration>,
+    nat_detection: Option<NatDetection>,
+    nat_type: Option<NatType>,
 
     connections: Connections<TId>,
     pending_events: VecDeque<Event<TId>>,
@@ -131,6 +156,12 @@ where
             buffer: Box::new([0u8; MAX_UDP_SIZE]),
             bindings: HashMap::default(),
             allocations: HashMap::default(),
+            relay_transports: HashMap::default(),
+            nomination_policy: NominationPolicy::default(),
+            post_quantum_psk: false,
+            max_key_age: None,
+            nat_detection: None,
+            nat_type: None,
             connections: Default::default(),
             stats: Default::default(),
         }
@@ -173,6 +204,183 @@ where
         Ok(())
     }
 
+    /// Re-gathers our local candidates after the local network changed, e.g. when switching from Wi-Fi to cellular.
+    ///
+    /// All host and server-reflexive candidates are invalidated on all connections and we query the STUN servers again.
+    /// Allocations are refreshed right away which moves them to our new address if the relay supports mobility.
+    ///
+    /// ICE is restarted on all established connections with new credentials, signalled via [`Event::SignalIceRestart`].
+    /// New candidates are signalled via [`Event::SignalIceCandidate`] as they are discovered, allowing ICE to nominate a new candidate pair.
+    ///
+    /// Established connections keep their WireGuard session, so packets continue to flow once ICE nominated a new pair.
+    pub fn reset_local_network(&mut self, now: Instant) {
+        tracing::info!("Local network changed, re-gathering candidates");
+
+        let stale_candidates = self
+            .host_candidates
+            .drain()
+            .chain(self.bindings.values().filter_map(|b| b.candidate()))
+            .collect::<Vec<_>>();
+
+        for (_, agent) in self.connections.agents_mut() {
+            for candidate in &stale_candidates {
+                agent.invalidate_candidate(candidate);
+            }
+        }
+
+        for (server, binding) in self.bindings.iter_mut() {
+            *binding = StunBinding::new(*server, now);
+        }
+
+        for allocation in self.allocations.values_mut() {
+            allocation.handle_local_network_change(now);
+        }
+
+        // Invalidates the allocations' server-reflexive candidates.
+        self.bindings_and_allocations_drain_events();
+
+        // Our new network is likely behind a different NAT.
+        self.nat_type = None;
+        if let Some(detection) = self.nat_detection.as_mut() {
+            *detection = NatDetection::new(detection.servers(), now);
+        }
+
+        let established = self
+            .connections
+            .established
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+
+        for id in established {
+            if let Err(e) = self.restart_ice(id, None, now) {
+                tracing::debug!(%id, "Failed to restart ICE: {e}");
+            }
+        }
+    }
+
+    /// Accepts an ICE restart of the remote, see [`Event::SignalIceRestart`].
+    ///
+    /// We restart ICE on our end as well, the returned credentials need to be signalled back to the remote which passes them to [`Node::accept_ice_restart_answer`].
+    #[tracing::instrument(level = "info", skip_all, fields(%id))]
+    pub fn accept_ice_restart(
+        &mut self,
+        id: TId,
+        credentials: Credentials,
+        now: Instant,
+    ) -> Result<Credentials, Error> {
+        tracing::info!("Remote restarted ICE");
+
+        self.restart_ice(id, Some(credentials), now)
+    }
+
+    /// Completes an ICE restart we initiated with the credentials the remote answered with.
+    #[tracing::instrument(level = "info", skip_all, fields(%id))]
+    pub fn accept_ice_restart_answer(
+        &mut self,
+        id: TId,
+        credentials: Credentials,
+        now: Instant,
+    ) -> Result<(), Error> {
+        let connection = self
+            .connections
+            .get_established_mut(&id)
+            .ok_or(Error::NotConnected)?;
+
+        connection.agent.set_remote_credentials(IceCreds {
+            ufrag: credentials.username,
+            pass: credentials.password,
+        });
+        connection.agent.handle_timeout(now);
+
+        Ok(())
+    }
+
+    /// Classifies the NAT we are behind by probing the given STUN servers.
+    ///
+    /// Once done, the result is emitted as [`Event::NatTypeDetected`] and available via [`Node::nat_type`].
+    /// The mapping behaviour can only be detected with at least two servers (or one that supports RFC 5780).
+    /// Testing the filtering behaviour requires a server that supports RFC 5780.
+    ///
+    /// Detection is repeated with the same servers on [`Node::reset_local_network`].
+    pub fn detect_nat_type(&mut self, stun_servers: HashSet<SocketAddr>, now: Instant) {
+        self.nat_type = None;
+        self.nat_detection = Some(NatDetection::new(stun_servers, now));
+    }
+
+    /// The largest IP packet we can currently send to the peer of this connection without it being dropped, if the connection is established.
+    ///
+    /// Every connection starts out with 1280 bytes, the minimum MTU of IPv6.
+    /// We then discover larger sizes by probing the path with padded keepalives, see [`Event::PathMtuChanged`].
+    /// Peers that don't acknowledge our first probes predate this and stay at 1280 bytes.
+    pub fn path_mtu(&self, id: TId) -> Option<u16> {
+        self.connections
+            .established
+            .get(&id)
+            .map(|c| c.path_mtu.mtu())
+    }
+
+    /// The [`NatType`] we detected via [`Node::detect_nat_type`], if any.
+    pub fn nat_type(&self) -> Option<NatType> {
+        self.nat_type
+    }
+
+    /// Configure which transport to use for talking to the given relay.
+    ///
+    /// Must be called before the relay is used for the first time, i.e. before a connection with this relay is created.
+    /// Using [`RelayTransport::Tcp`] allows us to reach relays from networks that block UDP.
+    /// In that case, the IO layer needs to send and receive all traffic for this relay over a TCP or TLS connection.
+    pub fn set_relay_transport(&mut self, relay: SocketAddr, transport: RelayTransport) {
+        self.relay_transports.insert(relay, transport);
+    }
+
+    /// Configure which path we send our packets on if there are several working ones, see [`NominationPolicy`].
+    ///
+    /// Applies to all existing and future connections on which we are the controlling ICE agent.
+    pub fn set_nomination_policy(&mut self, policy: NominationPolicy) {
+        self.nomination_policy = policy;
+    }
+
+    /// Configure whether new connections derive their WireGuard preshared key from a hybrid X25519 + ML-KEM-768 key exchange.
+    ///
+    /// The exchange is carried end-to-end in the [`Offer`] and [`Answer`] and bound to both WireGuard static keys, meaning a passive observer of the signalling channel never learns the resulting key.
+    /// It does not protect against an active signalling channel though, which also hands out the static keys.
+    /// If the [`Answer`] doesn't complete the exchange, the connection fails instead of falling back to [`Offer::session_key`].
+    /// Hence, only enable this if the remote is known to answer key exchanges, nodes that predate it don't.
+    ///
+    /// Off by default.
+    ///
+    /// Only relevant for nodes that create connections via [`Node::new_connection`], accepting nodes always answer a key exchange.
+    pub fn set_post_quantum_psk(&mut self, enabled: bool) {
+        self.post_quantum_psk = enabled;
+    }
+
+    /// Configure after how long we rotate the preshared key of a connection by ourselves, see [`Node::rotate_preshared_key`].
+    ///
+    /// `None` disables automatic rotation.
+    /// Applies to all existing and future connections.
+    pub fn set_max_key_age(&mut self, age: Option<Duration>) {
+        self.max_key_age = age;
+
+        for (_, connection) in self.connections.iter_established_mut() {
+            connection.rekey.set_max_key_age(age);
+        }
+    }
+
+    /// Rotate the WireGuard preshared key of an established connection without tearing it down.
+    ///
+    /// The new key is derived from a fresh hybrid X25519 + ML-KEM-768 key exchange that we perform with the peer through the tunnel.
+    /// Once we switched to it, we emit [`Event::PresharedKeyRotated`].
+    #[tracing::instrument(level = "debug", skip_all, fields(%id))]
+    pub fn rotate_preshared_key(&mut self, id: TId, now: Instant) {
+        let Some(connection) = self.connections.get_established_mut(&id) else {
+            tracing::debug!("Unknown connection");
+            return;
+        };
+
+        connection.rekey.start(now);
+    }
+
     #[tracing::instrument(level = "debug", skip_all, fields(%id))]
     pub fn add_remote_candidate(&mut self, id: TId, candidate: String, now: Instant) {
         let candidate = match Candidate::from_sdp_string(&candidate) {
@@ -251,7 +459,19 @@ where
         now: Instant,
         buffer: &'s mut [u8],
     ) -> Result<Option<(TId, MutableIpPacket<'s>)>, Error> {
-        self.add_local_as_host_candidate(local)?;
+        // For relays reached via TCP, the local address is the one of our TCP socket which is useless for hole-punching.
+        if self.relay_transports.get(&from) != Some(&RelayTransport::Tcp) {
+            self.add_local_as_host_candidate(local)?;
+        }
+
+        if self
+            .nat_detection
+            .as_mut()
+            .is_some_and(|d| d.handle_input(from, local, packet, now))
+        {
+            self.nat_detection_drain_events();
+            return Ok(None);
+        }
 
         match self.bindings_try_handle(from, local, packet, now) {
             ControlFlow::Continue(()) => {}
@@ -266,7 +486,7 @@ where
         // For our agents, it is important what the initial "destination" of the packet was.
         let destination = relayed.map(|s| s.address()).unwrap_or(local);
 
-        match self.agents_try_handle(from, destination, packet, now) {
+        match self.agents_try_handle(from, destination, relayed, packet, now) {
             ControlFlow::Continue(()) => {}
             ControlFlow::Break(Ok(())) => return Ok(None),
             ControlFlow::Break(Err(e)) => return Err(e),
@@ -287,6 +507,8 @@ where
     /// Wireguard is an IP tunnel, so we "enforce" that only IP packets are sent through it.
     /// We say "enforce" an [`IpPacket`] can be created from an (almost) arbitrary byte buffer at virtually no cost.
     /// Nevertheless, using [`IpPacket`] in our API has good documentation value.
+    ///
+    /// To send many packets with few syscalls, collect the returned [`Transmit`]s in a [`GsoQueue`](crate::GsoQueue).
     #[tracing::instrument(level = "debug", skip_all, fields(id = %connection))]
     pub fn encapsulate<'s>(
         &'s mut self,
@@ -335,7 +557,7 @@ where
 
                 Ok(Some(Transmit {
                     src: None,
-                    dst: relay,
+                    dst: allocation.active_server(),
                     payload: Cow::Borrowed(channel_data_packet),
                 }))
             }
@@ -365,6 +587,9 @@ where
         for a in self.allocations.values_mut() {
             connection_timeout = earliest(connection_timeout, a.poll_timeout());
         }
+        if let Some(d) = self.nat_detection.as_ref() {
+            connection_timeout = earliest(connection_timeout, d.poll_timeout());
+        }
 
         earliest(connection_timeout, self.next_rate_limiter_reset)
     }
@@ -392,12 +617,15 @@ where
             connection.handle_timeout(
                 id,
                 now,
+                &self.nomination_policy,
                 &mut self.allocations,
                 &mut self.pending_events,
                 &mut self.buffered_transmits,
             );
         }
 
+        self.install_rotated_keys(now);
+
         for (id, connection) in self.connections.initial.iter_mut() {
             connection.handle_timeout(id, now);
         }
@@ -410,6 +638,14 @@ where
             allocation.handle_timeout(now);
         }
 
+        self.failover_unreachable_relays(now);
+        self.fall_back_to_tcp(now);
+
+        if let Some(detection) = self.nat_detection.as_mut() {
+            detection.handle_timeout(now);
+        }
+        self.nat_detection_drain_events();
+
         let next_reset = *self.next_rate_limiter_reset.get_or_insert(now);
 
         if now >= next_reset {
@@ -439,6 +675,12 @@ where
             }
         }
 
+        if let Some(transmit) = self.nat_detection.as_mut().and_then(|d| d.poll_transmit()) {
+            self.stats.stun_bytes_to_relays += transmit.payload.len();
+
+            return Some(transmit);
+        }
+
         self.buffered_transmits.pop_front()
     }
 
@@ -456,10 +698,7 @@ where
     ) -> Connection {
         agent.handle_timeout(now);
 
-        /// We set a Wireguard keep-alive to ensure the WG session doesn't timeout on an idle connection.
-        ///
-        /// Without such a timeout, using a tunnel after the REKEY_TIMEOUT requires handshaking a new session which delays the new application packet by 1 RTT.
-        const WG_KEEP_ALIVE: Option<u16> = Some(10);
+        let index = self.index.next();
 
         Connection {
             agent,
@@ -468,15 +707,22 @@ where
                 remote,
                 Some(key),
                 WG_KEEP_ALIVE,
-                self.index.next(),
+                index,
                 Some(self.rate_limiter.clone()),
             ),
+            tunnel_index: index,
+            previous_tunnel: None,
+            remote,
+            rekey: Rekey::new(self.public_key(), remote, self.max_key_age, now),
             stun_servers: allowed_stun_servers,
             turn_servers: allowed_turn_servers,
             next_timer_update: now,
             peer_socket: None,
             possible_sockets: Default::default(),
             stats: Default::default(),
+            binding_requests: Default::default(),
+            paths: Default::default(),
+            path_mtu: PathMtu::new(now),
             buffer: Box::new([0u8; MAX_UDP_SIZE]),
             intent_sent_at,
             is_failed: false,
@@ -540,7 +786,12 @@ where
         now: Instant,
     ) -> ControlFlow<(), (SocketAddr, &'p [u8], Option<Socket>)> {
         // First, check whether the packet is from a known allocation.
-        let Some(allocation) = self.allocations.get_mut(&from) else {
+        // An allocation might have been redirected, thus we need to check the server it is actually talking to.
+        let Some(allocation) = self
+            .allocations
+            .values_mut()
+            .find(|a| a.active_server() == from)
+        else {
             return ControlFlow::Continue((from, packet, None));
         };
 
@@ -573,6 +824,7 @@ where
         &mut self,
         from: SocketAddr,
         destination: SocketAddr,
+        relayed: Option<Socket>,
         packet: &[u8],
         now: Instant,
     ) -> ControlFlow<Result<(), Error>> {
@@ -580,25 +832,55 @@ where
             return ControlFlow::Continue(());
         };
 
-        for (_, agent) in self.connections.agents_mut() {
-            if agent.accepts_message(&message) {
-                agent.handle_packet(
-                    now,
-                    StunPacket {
-                        proto: Protocol::Udp,
-                        source: from,
-                        destination,
-                        message,
+        if self
+            .connections
+            .iter_established_mut()
+            .any(|(_, conn)| conn.paths.handle_check_response(&conn.agent, packet, now))
+        {
+            return ControlFlow::Break(Ok(()));
+        }
+
+        let num_agents = self.connections.len();
+
+        let Some((id, agent)) = self
+            .connections
+            .agents_mut()
+            .find(|(_, agent)| agent.accepts_message(&message))
+        else {
+            return ControlFlow::Break(Err(Error::UnhandledStunMessage { num_agents }));
+        };
+
+        agent.handle_packet(
+            now,
+            StunPacket {
+                proto: Protocol::Udp,
+                source: from,
+                destination,
+                message,
+            },
+        );
+
+        if let Some(conn) = self.connections.get_established_mut(&id) {
+            if conn
+                .binding_requests
+                .handle_incoming(destination, from, packet, now)
+            {
+                let path = match relayed {
+                    Some(socket) => PeerSocket::Relay {
+                        relay: socket.server(),
+                        dest: from,
                     },
-                );
+                    None => PeerSocket::Direct {
+                        source: destination,
+                        dest: from,
+                    },
+                };
 
-                return ControlFlow::Break(Ok(()));
+                conn.paths.confirm(path, now);
             }
         }
 
-        ControlFlow::Break(Err(Error::UnhandledStunMessage {
-            num_agents: self.connections.len(),
-        }))
+        ControlFlow::Break(Ok(()))
     }
 
     #[must_use]
@@ -630,6 +912,7 @@ where
             );
 
             let handshake_complete_after_decapsulate = conn.wg_handshake_complete();
+            conn.update_handshake_stats(now);
 
             // I can't think of a better way to detect this ...
             if !handshake_complete_before_decapsulate && handshake_complete_after_decapsulate {
@@ -647,6 +930,157 @@ where
         }))
     }
 
+    /// Switches every connection that agreed on a new preshared key with its peer to a new tunnel using that key.
+    fn install_rotated_keys(&mut self, now: Instant) {
+        for (id, connection) in self.connections.iter_established_mut() {
+            let Some(rotated) = connection.rekey.poll_rotated_key() else {
+                continue;
+            };
+
+            let index = self.index.next();
+            let tunnel = Tunn::new(
+                self.private_key.clone(),
+                connection.remote,
+                Some(*rotated.psk.expose_secret()),
+                WG_KEEP_ALIVE,
+                index,
+                Some(self.rate_limiter.clone()),
+            );
+
+            tracing::info!(%id, "Rotated preshared key");
+
+            connection.replace_tunnel(
+                tunnel,
+                index,
+                rotated.is_initiator,
+                &mut self.allocations,
+                &mut self.buffered_transmits,
+                now,
+            );
+            self.pending_events
+                .push_back(Event::PresharedKeyRotated(id));
+        }
+    }
+
+    /// Retries allocations via TCP on relays that never answered us via UDP.
+    ///
+    /// Only if that fails as well, we fail over to other relays.
+    fn fall_back_to_tcp(&mut self, now: Instant) {
+        for (relay, allocation) in self
+            .allocations
+            .iter_mut()
+            .filter(|(_, a)| a.can_fall_back_to_tcp())
+        {
+            tracing::info!(%relay, "Relay is unreachable via UDP, falling back to TCP");
+
+            allocation.fall_back_to_tcp(now);
+
+            self.relay_transports.insert(*relay, RelayTransport::Tcp);
+            self.pending_events.push_back(Event::RelayTransportChanged {
+                relay: *relay,
+                transport: RelayTransport::Tcp,
+            });
+        }
+    }
+
+    /// Migrates connections away from relays that stopped responding.
+    ///
+    /// A connection that isn't allowed to use any other working relay is assigned one of the other relays we know about, preferably one we already have an allocation on.
+    /// Its candidates are signalled to the peer and ICE nominates a new pair, keeping the WireGuard session intact.
+    ///
+    /// We keep the allocation of the unreachable relay, it retries with a back-off and is used again by new connections once it recovers.
+    fn failover_unreachable_relays(&mut self, now: Instant) {
+        let unreachable = self
+            .allocations
+            .iter()
+            .filter(|(_, a)| a.is_unreachable())
+            .map(|(relay, _)| *relay)
+            .filter(|relay| {
+                self.connections
+                    .turn_servers()
+                    .any(|turn_servers| turn_servers.contains(relay))
+            })
+            .collect::<Vec<_>>();
+
+        for relay in unreachable {
+            let replacement = self
+                .allocations
+                .iter()
+                .filter(|(server, a)| **server != relay && !a.is_unreachable())
+                .min_by_key(|(server, a)| (!a.has_relay_candidate(), **server))
+                .map(|(server, _)| *server);
+
+            tracing::info!(%relay, ?replacement, "Relay is unreachable, failing over connections");
+
+            for (id, turn_servers, agent) in self.connections.turn_servers_and_agents_mut() {
+                if !turn_servers.contains(&relay) {
+                    continue;
+                }
+
+                let has_other_relay = turn_servers
+                    .iter()
+                    .any(|s| self.allocations.get(s).is_some_and(|a| !a.is_unreachable()));
+
+                if has_other_relay {
+                    turn_servers.remove(&relay);
+                    continue;
+                }
+
+                let Some((replacement, replacement_allocation)) =
+                    replacement.and_then(|r| Some((r, self.allocations.get_mut(&r)?)))
+                else {
+                    tracing::warn!(%id, %relay, "No relay to fail over to");
+                    continue;
+                };
+
+                tracing::info!(%id, from = %relay, to = %replacement, "Failing over to another relay");
+
+                turn_servers.remove(&relay);
+                turn_servers.insert(replacement);
+
+                for candidate in replacement_allocation.current_candidates() {
+                    add_local_candidate(id, agent, candidate, &mut self.pending_events);
+                }
+
+                for remote in agent
+                    .remote_candidates()
+                    .iter()
+                    .filter(|c| c.kind() != CandidateKind::Host)
+                {
+                    replacement_allocation.bind_channel(remote.addr(), now);
+                }
+            }
+
+            let Some(allocation) = self.allocations.get_mut(&relay) else {
+                continue;
+            };
+
+            while let Some(event) = allocation.poll_event() {
+                if let CandidateEvent::Invalid(candidate) = event {
+                    for (_, agent) in self.connections.agents_mut() {
+                        agent.invalidate_candidate(&candidate);
+                    }
+                }
+            }
+
+            for (_, connection) in self.connections.iter_established_mut() {
+                connection.paths.forget_relay(relay);
+            }
+        }
+    }
+
+    fn nat_detection_drain_events(&mut self) {
+        let Some(detection) = self.nat_detection.as_mut() else {
+            return;
+        };
+
+        while let Some(nat_type) = detection.poll_event() {
+            self.nat_type = Some(nat_type);
+            self.pending_events
+                .push_back(Event::NatTypeDetected(nat_type));
+        }
+    }
+
     fn bindings_and_allocations_drain_events(&mut self) {
         let binding_events = self.bindings.iter_mut().flat_map(|(server, binding)| {
             iter::from_fn(|| binding.poll_event().map(|e| (*server, e)))
@@ -717,6 +1151,7 @@ where
         agent.set_controlling(true);
 
         let session_key = Secret::new(random());
+        let key_exchange = self.post_quantum_psk.then(key_exchange::Initiator::new);
         let ice_creds = agent.local_credentials();
 
         let params = Offer {
@@ -725,11 +1160,13 @@ where
                 username: ice_creds.ufrag.clone(),
                 password: ice_creds.pass.clone(),
             },
+            key_exchange: key_exchange.as_ref().map(|k| k.offer()),
         };
 
         let initial_connection = InitialConnection {
             agent,
             session_key,
+            key_exchange,
             stun_servers: allowed_stun_servers,
             turn_servers: allowed_turn_servers,
             created_at: now,
@@ -752,6 +1189,8 @@ where
     }
 
     /// Accept an [`Answer`] from the remote for a connection previously created via [`Node::new_connection`].
+    ///
+    /// Emits [`Event::ConnectionFailed`] if we requested a post-quantum key exchange and the [`Answer`] doesn't complete it.
     #[tracing::instrument(level = "info", skip_all, fields(%id))]
     pub fn accept_answer(&mut self, id: TId, remote: PublicKey, answer: Answer, now: Instant) {
         let Some(initial) = self.connections.initial.remove(&id) else {
@@ -759,6 +1198,27 @@ where
             return;
         };
 
+        // Once we asked for a post-quantum key exchange, we never fall back to the session key.
+        // Otherwise, anybody who can tamper with the signalling channel could silently downgrade us.
+        let session_key = match (initial.key_exchange, answer.key_exchange) {
+            (Some(initiator), Some(answer)) => {
+                match initiator.finish(&answer, self.public_key(), remote) {
+                    Ok(psk) => psk,
+                    Err(e) => {
+                        tracing::warn!("Failed to complete post-quantum key exchange: {e}");
+                        self.pending_events.push_back(Event::ConnectionFailed(id));
+                        return;
+                    }
+                }
+            }
+            (Some(_), None) => {
+                tracing::warn!("Remote did not answer post-quantum key exchange");
+                self.pending_events.push_back(Event::ConnectionFailed(id));
+                return;
+            }
+            (None, _) => initial.session_key,
+        };
+
         let mut agent = initial.agent;
         agent.set_remote_credentials(IceCreds {
             ufrag: answer.credentials.username,
@@ -775,7 +1235,7 @@ where
         let connection = self.init_connection(
             agent,
             remote,
-            *initial.session_key.expose_secret(),
+            *session_key.expose_secret(),
             initial.stun_servers,
             initial.turn_servers,
             initial.intent_sent_at,
@@ -834,11 +1294,26 @@ where
             ufrag: offer.credentials.username,
             pass: offer.credentials.password,
         });
+
+        let (key_exchange, session_key) = match offer
+            .key_exchange
+            .as_ref()
+            .map(|offer| key_exchange::respond(offer, self.public_key(), remote))
+        {
+            Some(Ok((answer, psk))) => (Some(answer), psk),
+            Some(Err(e)) => {
+                tracing::warn!("Failed to answer post-quantum key exchange: {e}");
+                (None, offer.session_key)
+            }
+            None => (None, offer.session_key),
+        };
+
         let answer = Answer {
             credentials: Credentials {
                 username: agent.local_credentials().ufrag.clone(),
                 password: agent.local_credentials().pass.clone(),
             },
+            key_exchange,
         };
 
         self.seed_agent_with_local_candidates(
@@ -851,7 +1326,7 @@ where
         let connection = self.init_connection(
             agent,
             remote,
-            *offer.session_key.expose_secret(),
+            *session_key.expose_secret(),
             allowed_stun_servers,
             allowed_turn_servers,
             now, // Technically, this isn't fully correct because gateways don't send intents so we just use the current time.
@@ -871,6 +1346,58 @@ impl<T, TId> Node<T, TId>
 where
     TId: Eq + Hash + Copy + fmt::Display,
 {
+    /// Replaces the ICE agent of an established connection with a new one, using new credentials.
+    ///
+    /// Without `remote` credentials, we initiate the restart and signal our new credentials to the remote.
+    fn restart_ice(
+        &mut self,
+        id: TId,
+        remote: Option<Credentials>,
+        now: Instant,
+    ) -> Result<Credentials, Error> {
+        let connection = self
+            .connections
+            .get_established_mut(&id)
+            .ok_or(Error::NotConnected)?;
+        let stun_servers = connection.stun_servers.clone();
+        let turn_servers = connection.turn_servers.clone();
+
+        let mut agent = IceAgent::new();
+        agent.set_controlling(connection.agent.controlling());
+
+        let credentials = Credentials {
+            username: agent.local_credentials().ufrag.clone(),
+            password: agent.local_credentials().pass.clone(),
+        };
+
+        match remote {
+            Some(remote) => {
+                agent.set_remote_credentials(IceCreds {
+                    ufrag: remote.username,
+                    pass: remote.password,
+                });
+            }
+            None => {
+                tracing::info!(%id, "Restarting ICE");
+
+                // The remote must learn about the restart before it receives candidates for the new agent.
+                self.pending_events.push_back(Event::SignalIceRestart {
+                    connection: id,
+                    credentials: credentials.clone(),
+                });
+            }
+        }
+
+        self.seed_agent_with_local_candidates(id, &mut agent, &stun_servers, &turn_servers);
+
+        self.connections
+            .get_established_mut(&id)
+            .expect("connection to exist")
+            .restart_ice(agent, now);
+
+        Ok(credentials)
+    }
+
     fn upsert_stun_servers(&mut self, servers: &HashSet<SocketAddr>, now: Instant) {
         for server in servers {
             if !self.bindings.contains_key(server) {
@@ -902,9 +1429,15 @@ where
                 continue;
             }
 
+            let transport = self
+                .relay_transports
+                .get(server)
+                .copied()
+                .unwrap_or_default();
+
             self.allocations.insert(
                 *server,
-                Allocation::new(*server, username, password.clone(), realm, now),
+                Allocation::new(*server, transport, username, password.clone(), realm, now),
             );
 
             tracing::info!(address = %server, "Added new TURN server");
@@ -996,7 +1529,7 @@ where
     }
 
     fn stats(&self) -> impl Iterator<Item = (TId, ConnectionStats)> + '_ {
-        self.established.iter().map(move |(id, c)| (*id, c.stats))
+        self.established.iter().map(move |(id, c)| (*id, c.stats()))
     }
 
     fn agent_mut(&mut self, id: TId) -> Option<&mut IceAgent> {
@@ -1016,6 +1549,28 @@ where
         initial_agents.chain(negotiated_agents)
     }
 
+    fn turn_servers(&self) -> impl Iterator<Item = &HashSet<SocketAddr>> {
+        let initial = self.initial.values().map(|c| &c.turn_servers);
+        let established = self.established.values().map(|c| &c.turn_servers);
+
+        initial.chain(established)
+    }
+
+    fn turn_servers_and_agents_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (TId, &mut HashSet<SocketAddr>, &mut IceAgent)> {
+        let initial = self
+            .initial
+            .iter_mut()
+            .map(|(id, c)| (*id, &mut c.turn_servers, &mut c.agent));
+        let established = self
+            .established
+            .iter_mut()
+            .map(|(id, c)| (*id, &mut c.turn_servers, &mut c.agent));
+
+        initial.chain(established)
+    }
+
     fn get_established_mut(&mut self, id: &TId) -> Option<&mut Connection> {
         self.established.get_mut(id)
     }
@@ -1065,7 +1620,7 @@ fn encode_as_channel_data(
 
     Ok(Transmit {
         src: None,
-        dst: relay,
+        dst: allocation.active_server(),
         payload: Cow::Owned(payload),
     })
 }
@@ -1135,14 +1690,21 @@ fn add_local_candidate<TId>(
 
 pub struct Offer {
     /// The Wireguard session key for a connection.
+    ///
+    /// Not used if the offer contains a [`Offer::key_exchange`].
     pub session_key: Secret<[u8; 32]>,
     pub credentials: Credentials,
+    /// Our half of a post-quantum key exchange, see [`Node::set_post_quantum_psk`].
+    pub key_exchange: Option<KeyExchangeOffer>,
 }
 
 pub struct Answer {
     pub credentials: Credentials,
+    /// The remote's half of a post-quantum key exchange, if the [`Offer`] contained one.
+    pub key_exchange: Option<KeyExchangeAnswer>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Credentials {
     /// The ICE username (ufrag).
     pub username: String,
@@ -1161,10 +1723,40 @@ pub enum Event<TId> {
     },
     ConnectionEstablished(TId),
 
+    /// Signal new ICE credentials to the remote because we restarted ICE, see [`Node::reset_local_network`].
+    ///
+    /// The remote passes them to [`Node::accept_ice_restart`] and signals back the credentials it returns.
+    SignalIceRestart {
+        connection: TId,
+        credentials: Credentials,
+    },
+
     /// We failed to establish a connection.
     ///
     /// All state associated with the connection has been cleared.
     ConnectionFailed(TId),
+
+    /// We classified the NAT we are behind, see [`Node::detect_nat_type`].
+    NatTypeDetected(NatType),
+
+    /// We switched to a new preshared key for this connection, see [`Node::rotate_preshared_key`].
+    PresharedKeyRotated(TId),
+
+    /// The path MTU of a connection changed, see [`Node::path_mtu`].
+    ///
+    /// IP packets larger than `mtu` are likely to be dropped on the way to the peer and should be fragmented or clamped, e.g. by lowering the TCP MSS.
+    PathMtuChanged {
+        connection: TId,
+        mtu: u16,
+    },
+
+    /// We switched to a different transport for talking to this relay, see [`Node::set_relay_transport`].
+    ///
+    /// For [`RelayTransport::Tcp`], the IO layer needs to connect to the relay and send all [`Transmit`]s for it over that connection.
+    RelayTransportChanged {
+        relay: SocketAddr,
+        transport: RelayTransport,
+    },
 }
 
 #[derive(Debug)]
@@ -1201,6 +1793,7 @@ pub(crate) enum CandidateEvent {
 struct InitialConnection {
     agent: IceAgent,
     session_key: Secret<[u8; 32]>,
+    key_exchange: Option<key_exchange::Initiator>,
     stun_servers: HashSet<SocketAddr>,
     turn_servers: HashSet<SocketAddr>,
 
@@ -1230,6 +1823,12 @@ struct Connection {
     agent: IceAgent,
 
     tunnel: Tunn,
+    /// The index we passed to [`Tunn::new`].
+    tunnel_index: u32,
+    /// The tunnel using our previous preshared key, see [`Node::rotate_preshared_key`].
+    previous_tunnel: Option<PreviousTunnel>,
+    remote: PublicKey,
+    rekey: Rekey,
     next_timer_update: Instant,
 
     // When this is `Some`, we are connected.
@@ -1241,6 +1840,9 @@ struct Connection {
     turn_servers: HashSet<SocketAddr>,
 
     stats: ConnectionStats,
+    binding_requests: BindingRequests,
+    paths: WorkingPaths,
+    path_mtu: PathMtu,
 
     buffer: Box<[u8; MAX_UDP_SIZE]>,
     intent_sent_at: Instant,
@@ -1250,9 +1852,43 @@ struct Connection {
     signalling_completed_at: Instant,
 }
 
+struct PreviousTunnel {
+    tunnel: Tunn,
+    index: u32,
+    expires_at: Instant,
+}
+
+impl PreviousTunnel {
+    /// Whether the packet belongs to one of the sessions of this tunnel.
+    ///
+    /// Handshake responses, cookie replies and data packets carry the receiver's session index, see <https://www.wireguard.com/protocol/>.
+    /// [`boringtun`] derives the session indices of a tunnel by shifting its index by 8 bits.
+    fn is_for(&self, packet: &[u8]) -> bool {
+        let Some(receiver_index) = packet.get(4..8) else {
+            return false;
+        };
+        let receiver_index =
+            u32::from_le_bytes(receiver_index.try_into().expect("slice has 4 bytes"));
+
+        matches!(packet.first(), Some(2..=4)) && receiver_index >> 8 == self.index
+    }
+}
+
+/// Selects the tunnel of our previous preshared key if `previous` is set and we still have it.
+fn select_tunnel<'a>(
+    current: &'a mut Tunn,
+    previous_tunnel: &'a mut Option<PreviousTunnel>,
+    previous: bool,
+) -> &'a mut Tunn {
+    match previous_tunnel {
+        Some(previous_tunnel) if previous => &mut previous_tunnel.tunnel,
+        _ => current,
+    }
+}
+
 /// The socket of the peer we are connected to.
-#[derive(Debug, PartialEq, Clone, Copy)]
-enum PeerSocket {
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub(crate) enum PeerSocket {
     Direct {
         source: SocketAddr,
         dest: SocketAddr,
@@ -1270,6 +1906,17 @@ impl PeerSocket {
             PeerSocket::Relay { relay, .. } => *relay,
         }
     }
+
+    pub(crate) fn is_relayed(&self) -> bool {
+        matches!(self, PeerSocket::Relay { .. })
+    }
+
+    pub(crate) fn is_ipv6(&self) -> bool {
+        match self {
+            PeerSocket::Direct { dest, .. } => dest.is_ipv6(),
+            PeerSocket::Relay { dest, .. } => dest.is_ipv6(),
+        }
+    }
 }
 
 impl Connection {
@@ -1292,15 +1939,75 @@ impl Connection {
         self.tunnel.time_since_last_handshake().is_some()
     }
 
+    /// We can only send [`ControlMessage`]s through an established WireGuard session.
+    fn can_send_control_messages(&self) -> bool {
+        self.peer_socket.is_some() && self.wg_handshake_complete()
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            rtt: self.binding_requests.smoothed_rtt(),
+            loss: self.binding_requests.loss(),
+            ..self.stats
+        }
+    }
+
+    /// Counts a handshake whenever `boringtun` reports a more recent one than the last we saw.
+    fn update_handshake_stats(&mut self, now: Instant) {
+        /// `boringtun` measures the time since the last handshake with its own clock.
+        const TOLERANCE: Duration = Duration::from_secs(1);
+
+        let Some(handshake_at) = self
+            .tunnel
+            .time_since_last_handshake()
+            .and_then(|since| now.checked_sub(since))
+        else {
+            return;
+        };
+
+        if self
+            .stats
+            .last_handshake_at
+            .is_some_and(|last| handshake_at <= last + TOLERANCE)
+        {
+            return;
+        }
+
+        self.stats.handshakes += 1;
+        self.stats.last_handshake_at = Some(handshake_at);
+    }
+
     fn duration_since_intent(&self, now: Instant) -> Duration {
         now.duration_since(self.intent_sent_at)
     }
 
+    fn restart_ice(&mut self, mut agent: IceAgent, now: Instant) {
+        agent.handle_timeout(now);
+
+        self.agent = agent;
+        // The remote needs to signal its candidates again.
+        self.signalling_completed_at = now;
+
+        // Our checks were authenticated with the old credentials and the samples belong to the old candidate pairs.
+        self.paths = WorkingPaths::default();
+        self.binding_requests = BindingRequests::default();
+
+        // Keep sending on the current socket until the new agent nominates a pair.
+        // Without a fresh path, WireGuard activity from the peer may also replace it in the meantime.
+        if let Some(socket) = self.peer_socket {
+            tracing::debug!(
+                ?socket,
+                "Keeping remote socket until ICE nominates a new one"
+            );
+        }
+    }
+
     fn set_remote_from_wg_activity(
         &mut self,
         local: SocketAddr,
         dest: SocketAddr,
         relay_socket: Option<Socket>,
+        now: Instant,
     ) -> PeerSocket {
         let remote_socket = match relay_socket {
             Some(relay_socket) => PeerSocket::Relay {
@@ -1313,9 +2020,19 @@ impl Connection {
             },
         };
 
+        // The nomination policy picked our current path, don't let the peer's packets switch us away from it whilst it is working.
+        if let Some(current) = self.peer_socket.filter(|current| {
+            *current != remote_socket
+                && self.agent.controlling()
+                && self.paths.is_fresh(*current, now)
+        }) {
+            return current;
+        }
+
         if self.peer_socket != Some(remote_socket) {
             tracing::debug!(old = ?self.peer_socket, new = ?remote_socket, "Updating remote socket from WG activity");
             self.peer_socket = Some(remote_socket);
+            self.stats.path = Some(self.path_type_of(remote_socket));
         }
 
         remote_socket
@@ -1326,8 +2043,26 @@ impl Connection {
         let agent_timeout = self.agent.poll_timeout();
         let next_wg_timer = Some(self.next_timer_update);
         let candidate_timeout = self.candidate_timeout();
-
-        earliest(agent_timeout, earliest(next_wg_timer, candidate_timeout))
+        let control_timeout = self
+            .can_send_control_messages()
+            .then(|| earliest(self.path_mtu.poll_timeout(), self.rekey.poll_timeout()))
+            .flatten();
+        let previous_tunnel_timeout = self.previous_tunnel.as_ref().map(|p| p.expires_at);
+        let path_check_timeout = self.peer_socket.and_then(|_| self.paths.poll_timeout());
+
+        earliest(
+            agent_timeout,
+            earliest(
+                next_wg_timer,
+                earliest(
+                    candidate_timeout,
+                    earliest(
+                        control_timeout,
+                        earliest(previous_tunnel_timeout, path_check_timeout),
+                    ),
+                ),
+            ),
+        )
     }
 
     fn candidate_timeout(&self) -> Option<Instant> {
@@ -1342,6 +2077,7 @@ impl Connection {
         &mut self,
         id: TId,
         now: Instant,
+        policy: &NominationPolicy,
         allocations: &mut HashMap<SocketAddr, Allocation>,
         events: &mut VecDeque<Event<TId>>,
         transmits: &mut VecDeque<Transmit<'static>>,
@@ -1376,6 +2112,8 @@ impl Connection {
 
             let mut buf = [0u8; MAX_SCRATCH_SPACE];
 
+            self.update_handshake_stats(now);
+
             match self.tunnel.update_timers(&mut buf) {
                 TunnResult::Done => {}
                 TunnResult::Err(WireGuardError::ConnectionExpired) => {
@@ -1386,6 +2124,7 @@ impl Connection {
                     tracing::warn!(%id, ?e);
                 }
                 TunnResult::WriteToNetwork(b) => {
+                    self.stats.record_wg_sent(b.len());
                     transmits.extend(make_owned_transmit(peer_socket, b, allocations, now));
                 }
                 _ => panic!("Unexpected result from update_timers"),
@@ -1409,6 +2148,7 @@ impl Connection {
                     let candidate = self
                         .local_candidate(source)
                         .expect("to only nominate existing candidates");
+                    let path = path_type(candidate.kind());
 
                     let remote_socket = match candidate.kind() {
                         CandidateKind::Relayed => {
@@ -1440,11 +2180,16 @@ impl Connection {
                         }
                     };
 
+                    self.paths.confirm(remote_socket, now);
+                    self.binding_requests.nominate(source, destination);
+
                     if self.peer_socket != Some(remote_socket) {
                         let is_first_connection = self.peer_socket.is_none();
 
                         tracing::info!(old = ?self.peer_socket, new = ?remote_socket, duration_since_intent = ?self.duration_since_intent(now), "Updating remote socket");
                         self.peer_socket = Some(remote_socket);
+                        self.stats.path = path;
+                        self.path_mtu.reset(now);
 
                         self.invalidate_candiates();
                         self.force_handshake(allocations, transmits, now);
@@ -1458,11 +2203,71 @@ impl Connection {
             }
         }
 
+        if let Some(better) = self
+            .peer_socket
+            .filter(|_| self.agent.controlling())
+            .and_then(|current| self.paths.select(current, policy, now))
+        {
+            tracing::info!(%id, old = ?self.peer_socket, new = ?better, "Switching path as per nomination policy");
+            self.peer_socket = Some(better);
+            self.stats.path = Some(self.path_type_of(better));
+            self.path_mtu.reset(now);
+        }
+
+        while let Some((path, request)) = self
+            .peer_socket
+            .and_then(|_| self.paths.poll_check(&self.agent, now))
+        {
+            if path.is_relayed() {
+                self.stats.stun_bytes_to_peer_relayed += request.len();
+            } else {
+                self.stats.stun_bytes_to_peer_direct += request.len();
+            }
+
+            transmits.extend(make_owned_transmit(path, &request, allocations, now));
+        }
+
+        self.probe_path_mtu(allocations, transmits, now);
+
+        if let Some(mtu) = self.path_mtu.poll_event() {
+            tracing::info!(%id, %mtu, "Path MTU changed");
+
+            events.push_back(Event::PathMtuChanged {
+                connection: id,
+                mtu,
+            });
+        }
+
+        self.rekey.handle_timeout(now);
+
+        if let Some(offer) = self
+            .can_send_control_messages()
+            .then(|| self.rekey.poll_message(now))
+            .flatten()
+        {
+            tracing::debug!(%id, "Offering new preshared key");
+
+            self.send_control_message(&offer.encode(), false, allocations, transmits, now);
+        }
+
+        if self
+            .previous_tunnel
+            .as_ref()
+            .is_some_and(|p| now >= p.expires_at)
+        {
+            tracing::debug!(%id, "Discarding tunnel of previous preshared key");
+
+            self.previous_tunnel = None;
+        }
+
         while let Some(transmit) = self.agent.poll_transmit() {
             let source = transmit.source;
             let dst = transmit.destination;
             let packet = transmit.contents;
 
+            self.binding_requests
+                .handle_outgoing(source, dst, &packet, now);
+
             // Check if `str0m` wants us to send from a "remote" socket, i.e. one that we allocated with a relay.
             let allocation = allocations
                 .iter_mut()
@@ -1491,7 +2296,7 @@ impl Connection {
 
             transmits.push_back(Transmit {
                 src: None,
-                dst: *relay,
+                dst: allocation.active_server(),
                 payload: Cow::Owned(channel_data),
             });
         }
@@ -1511,6 +2316,8 @@ impl Connection {
             }
         };
 
+        self.stats.record_wg_sent(len);
+
         Ok(Some(&buffer[..len]))
     }
 
@@ -1526,7 +2333,15 @@ impl Connection {
         transmits: &mut VecDeque<Transmit<'static>>,
         now: Instant,
     ) -> ControlFlow<Result<(), Error>, MutableIpPacket<'b>> {
-        match self.tunnel.decapsulate(None, packet, buffer) {
+        self.stats.record_wg_received(packet.len());
+
+        let via_previous = self
+            .previous_tunnel
+            .as_ref()
+            .is_some_and(|p| p.is_for(packet));
+        let tunnel = select_tunnel(&mut self.tunnel, &mut self.previous_tunnel, via_previous);
+
+        match tunnel.decapsulate(None, packet, buffer) {
             TunnResult::Done => ControlFlow::Break(Ok(())),
             TunnResult::Err(e) => ControlFlow::Break(Err(Error::Decapsulate(e))),
 
@@ -1535,7 +2350,13 @@ impl Connection {
             // In our API, we parse the packets directly as an IpPacket.
             // Thus, the caller can query whatever data they'd like, not just the source IP so we don't return it in addition.
             TunnResult::WriteToTunnelV4(packet, ip) => {
-                self.set_remote_from_wg_activity(local, from, relayed);
+                self.set_remote_from_wg_activity(local, from, relayed, now);
+
+                if let Some(message) = ControlMessage::decode(packet) {
+                    self.handle_control_message(message, via_previous, allocations, transmits, now);
+
+                    return ControlFlow::Break(Ok(()));
+                }
 
                 let ipv4_packet =
                     MutableIpv4Packet::new(packet).expect("boringtun verifies validity");
@@ -1544,7 +2365,7 @@ impl Connection {
                 ControlFlow::Continue(ipv4_packet.into())
             }
             TunnResult::WriteToTunnelV6(packet, ip) => {
-                self.set_remote_from_wg_activity(local, from, relayed);
+                self.set_remote_from_wg_activity(local, from, relayed, now);
 
                 let ipv6_packet =
                     MutableIpv6Packet::new(packet).expect("boringtun verifies validity");
@@ -1558,13 +2379,16 @@ impl Connection {
             // This should be fairly rare which is why we just allocate these and return them from `poll_transmit` instead.
             // Overall, this results in a much nicer API for our caller and should not affect performance.
             TunnResult::WriteToNetwork(bytes) => {
-                let socket = self.set_remote_from_wg_activity(local, from, relayed);
+                let socket = self.set_remote_from_wg_activity(local, from, relayed, now);
 
+                self.stats.record_wg_sent(bytes.len());
                 transmits.extend(make_owned_transmit(socket, bytes, allocations, now));
 
                 while let TunnResult::WriteToNetwork(packet) =
-                    self.tunnel.decapsulate(None, &[], self.buffer.as_mut())
+                    select_tunnel(&mut self.tunnel, &mut self.previous_tunnel, via_previous)
+                        .decapsulate(None, &[], self.buffer.as_mut())
                 {
+                    self.stats.record_wg_sent(packet.len());
                     transmits.extend(make_owned_transmit(socket, packet, allocations, now));
                 }
 
@@ -1573,6 +2397,104 @@ impl Connection {
         }
     }
 
+    fn probe_path_mtu(
+        &mut self,
+        allocations: &mut HashMap<SocketAddr, Allocation>,
+        transmits: &mut VecDeque<Transmit<'static>>,
+        now: Instant,
+    ) {
+        if !self.can_send_control_messages() {
+            return;
+        }
+
+        self.path_mtu.handle_timeout(now);
+
+        let Some((probe_id, size)) = self.path_mtu.poll_probe(now) else {
+            return;
+        };
+
+        tracing::trace!(%size, "Sending MTU probe");
+
+        let probe = ControlMessage::MtuProbe(probe_id).encode_padded(usize::from(size));
+        self.send_control_message(&probe, false, allocations, transmits, now);
+    }
+
+    fn handle_control_message(
+        &mut self,
+        message: ControlMessage,
+        via_previous: bool,
+        allocations: &mut HashMap<SocketAddr, Allocation>,
+        transmits: &mut VecDeque<Transmit<'static>>,
+        now: Instant,
+    ) {
+        let reply = match message {
+            ControlMessage::MtuProbe(probe_id) => Some(ControlMessage::MtuAck(probe_id)),
+            ControlMessage::MtuAck(probe_id) => {
+                self.path_mtu.handle_ack(probe_id, now);
+                None
+            }
+            ControlMessage::RekeyOffer { id, offer } => self.rekey.handle_offer(id, offer, now),
+            ControlMessage::RekeyAnswer { id, answer } => {
+                self.rekey.handle_answer(id, answer, now);
+                None
+            }
+        };
+
+        // Reply through the tunnel the message arrived on, the peer may not have switched to our new key yet.
+        if let Some(reply) = reply {
+            self.send_control_message(&reply.encode(), via_previous, allocations, transmits, now);
+        }
+    }
+
+    /// Encapsulates an encoded [`ControlMessage`] and sends it to the peer.
+    fn send_control_message(
+        &mut self,
+        message: &[u8],
+        via_previous: bool,
+        allocations: &mut HashMap<SocketAddr, Allocation>,
+        transmits: &mut VecDeque<Transmit<'static>>,
+        now: Instant,
+    ) {
+        let Some(socket) = self.peer_socket else {
+            return;
+        };
+
+        let TunnResult::WriteToNetwork(bytes) =
+            select_tunnel(&mut self.tunnel, &mut self.previous_tunnel, via_previous)
+                .encapsulate(message, self.buffer.as_mut())
+        else {
+            return;
+        };
+
+        self.stats.record_wg_sent(bytes.len());
+        transmits.extend(make_owned_transmit(socket, bytes, allocations, now));
+    }
+
+    /// Switches to a tunnel using a new preshared key, keeping the current one around until the peer switched as well.
+    fn replace_tunnel(
+        &mut self,
+        tunnel: Tunn,
+        index: u32,
+        is_initiator: bool,
+        allocations: &mut HashMap<SocketAddr, Allocation>,
+        transmits: &mut VecDeque<Transmit<'static>>,
+        now: Instant,
+    ) {
+        let previous = std::mem::replace(&mut self.tunnel, tunnel);
+        let previous_index = std::mem::replace(&mut self.tunnel_index, index);
+
+        self.previous_tunnel = Some(PreviousTunnel {
+            tunnel: previous,
+            index: previous_index,
+            expires_at: now + PREVIOUS_TUNNEL_GRACE_PERIOD,
+        });
+
+        // The peer answered our offer and thus already switched, handshake a session for the new key right away.
+        if is_initiator && self.peer_socket.is_some() {
+            self.force_handshake(allocations, transmits, now);
+        }
+    }
+
     fn force_handshake(
         &mut self,
         allocations: &mut HashMap<SocketAddr, Allocation>,
@@ -1596,6 +2518,7 @@ impl Connection {
             .peer_socket
             .expect("cannot force handshake without socket");
 
+        self.stats.record_wg_sent(bytes.len());
         transmits.extend(make_owned_transmit(socket, bytes, allocations, now));
     }
 
@@ -1628,6 +2551,16 @@ impl Connection {
         }
     }
 
+    fn path_type_of(&self, socket: PeerSocket) -> PathType {
+        match socket {
+            PeerSocket::Relay { .. } => PathType::Relayed,
+            PeerSocket::Direct { source, .. } => self
+                .local_candidate(source)
+                .and_then(|c| path_type(c.kind()))
+                .unwrap_or(PathType::Host),
+        }
+    }
+
     fn local_candidate(&self, source: SocketAddr) -> Option<&Candidate> {
         self.agent
             .local_candidates()
@@ -1636,6 +2569,15 @@ impl Connection {
     }
 }
 
+fn path_type(kind: CandidateKind) -> Option<PathType> {
+    match kind {
+        CandidateKind::Host => Some(PathType::Host),
+        CandidateKind::ServerReflexive => Some(PathType::ServerReflexive),
+        CandidateKind::Relayed => Some(PathType::Relayed),
+        CandidateKind::PeerReflexive => None,
+    }
+}
+
 #[must_use]
 fn make_owned_transmit(
     socket: PeerSocket,
@@ -0,0 +1,442 @@
+use crate::node::PeerSocket;
+use bytecodec::{DecodeExt as _, EncodeExt as _};
+use rand::random;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use str0m::ice::IceAgent;
+use stun_codec::{
+    rfc5245::attributes::{IceControlled, IceControlling, Priority},
+    rfc5389::{
+        attributes::{Fingerprint, MessageIntegrity, Username, XorMappedAddress},
+        methods::BINDING,
+    },
+    Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId,
+};
+
+/// After how long without a successful binding request we no longer consider a path to be working.
+///
+/// Same as the consent expiry, see <https://www.rfc-editor.org/rfc/rfc7675#section-5.1>.
+const PATH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often we send our own binding request on each working path.
+///
+/// [`str0m`] only keeps checking the nominated candidate pair, the other paths would otherwise hit [`PATH_TIMEOUT`].
+const PATH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The priority we put into our binding requests.
+///
+/// It is only used if the peer learns a new peer-reflexive candidate from our request, which doesn't happen because we only check known paths.
+const PATH_CHECK_PRIORITY: u32 = (110 << 24) | (65535 << 8) | 255;
+
+/// Decides which of the working paths to a peer we send our packets on.
+///
+/// [`str0m`] nominates candidate pairs purely by ICE priority.
+/// On top of that, we track every path that answered one of our binding requests and re-evaluate periodically which one to use.
+/// This allows a connection to upgrade from a relayed to a direct path once hole-punching succeeds.
+///
+/// Only the controlling side, i.e. the client, applies the policy, the other side follows the path its WireGuard packets arrive on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NominationPolicy {
+    /// Prefer direct paths over relayed ones.
+    pub prefer_direct: bool,
+    /// Prefer IPv6 over IPv4 paths, see [`NominationPolicy::ipv6_window`].
+    pub prefer_ipv6: bool,
+    /// An IPv6 path is only preferred if it started working no later than this after the IPv4 path, similar to Happy Eyeballs (RFC 8305).
+    ///
+    /// This prevents us from switching away from a well-established IPv4 path just because IPv6 connectivity showed up much later.
+    pub ipv6_window: Duration,
+    /// How often we re-evaluate which path to use.
+    pub reevaluate_interval: Duration,
+}
+
+impl Default for NominationPolicy {
+    fn default() -> Self {
+        Self {
+            prefer_direct: true,
+            prefer_ipv6: true,
+            ipv6_window: Duration::from_secs(1),
+            reevaluate_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The paths to a peer that we know to be working.
+#[derive(Debug, Default)]
+pub(crate) struct WorkingPaths {
+    paths: HashMap<PeerSocket, Path>,
+    next_evaluation: Option<Instant>,
+    /// Our own binding requests that are waiting for a response.
+    checks_in_flight: HashMap<TransactionId, (PeerSocket, Instant)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Path {
+    /// When this path started working.
+    confirmed_at: Instant,
+    /// When this path last proved to be working.
+    last_confirmed_at: Instant,
+    /// When we send our next binding request on this path.
+    next_check_at: Instant,
+}
+
+impl WorkingPaths {
+    /// Records that `path` is working, e.g. because it answered one of our binding requests.
+    pub(crate) fn confirm(&mut self, path: PeerSocket, now: Instant) {
+        self.paths
+            .entry(path)
+            .and_modify(|p| p.last_confirmed_at = now)
+            .or_insert(Path {
+                confirmed_at: now,
+                last_confirmed_at: now,
+                next_check_at: now + PATH_CHECK_INTERVAL,
+            });
+    }
+
+    /// Whether `path` answered one of the last two binding requests we or [`str0m`] sent on it.
+    pub(crate) fn is_fresh(&self, path: PeerSocket, now: Instant) -> bool {
+        self.paths
+            .get(&path)
+            .is_some_and(|p| now.duration_since(p.last_confirmed_at) < PATH_CHECK_INTERVAL * 2)
+    }
+
+    /// Returns the next binding request we need to send to keep a path alive, together with the path to send it on.
+    pub(crate) fn poll_check(
+        &mut self,
+        agent: &IceAgent,
+        now: Instant,
+    ) -> Option<(PeerSocket, Vec<u8>)> {
+        self.checks_in_flight
+            .retain(|_, (_, sent_at)| now.duration_since(*sent_at) < PATH_TIMEOUT);
+
+        let remote_credentials = agent.remote_credentials()?;
+
+        let (socket, path) = self
+            .paths
+            .iter_mut()
+            .find(|(_, path)| path.next_check_at <= now)?;
+        path.next_check_at = now + PATH_CHECK_INTERVAL;
+
+        let transaction_id = TransactionId::new(random());
+        let mut request = Message::new(MessageClass::Request, BINDING, transaction_id);
+        request.add_attribute(Attribute::Username(
+            Username::new(format!(
+                "{}:{}",
+                remote_credentials.ufrag,
+                agent.local_credentials().ufrag
+            ))
+            .ok()?,
+        ));
+        request.add_attribute(Attribute::Priority(Priority::new(PATH_CHECK_PRIORITY)));
+        request.add_attribute(if agent.controlling() {
+            Attribute::IceControlling(IceControlling::new(random()))
+        } else {
+            Attribute::IceControlled(IceControlled::new(random()))
+        });
+        request.add_attribute(Attribute::MessageIntegrity(
+            MessageIntegrity::new_short_term_credential(&request, &remote_credentials.pass)
+                .expect("signing never fails"),
+        ));
+        request.add_attribute(Attribute::Fingerprint(
+            Fingerprint::new(&request).expect("fingerprinting never fails"),
+        ));
+
+        let request = MessageEncoder::default()
+            .encode_into_bytes(request)
+            .expect("encoding never fails");
+
+        self.checks_in_flight.insert(transaction_id, (*socket, now));
+
+        Some((*socket, request))
+    }
+
+    /// Handles a response to one of our own binding requests, returns whether the packet was one.
+    pub(crate) fn handle_check_response(
+        &mut self,
+        agent: &IceAgent,
+        packet: &[u8],
+        now: Instant,
+    ) -> bool {
+        if self.checks_in_flight.is_empty() {
+            return false;
+        }
+
+        let Ok(Ok(response)) = MessageDecoder::<Attribute>::default().decode_from_bytes(packet)
+        else {
+            return false;
+        };
+
+        let Some((path, _)) = self
+            .checks_in_flight
+            .get(&response.transaction_id())
+            .copied()
+        else {
+            return false;
+        };
+
+        let Some(remote_credentials) = agent.remote_credentials() else {
+            return false;
+        };
+
+        let authentic = response
+            .get_attribute::<MessageIntegrity>()
+            .is_some_and(|mi| {
+                mi.check_short_term_credential(&remote_credentials.pass)
+                    .is_ok()
+            });
+
+        if response.class() != MessageClass::SuccessResponse || !authentic {
+            return false;
+        }
+
+        self.checks_in_flight.remove(&response.transaction_id());
+        self.confirm(path, now);
+
+        true
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.paths.values().map(|p| p.next_check_at).min()
+    }
+
+    /// Forgets all paths via the given relay, e.g. because it stopped responding.
+    pub(crate) fn forget_relay(&mut self, relay: SocketAddr) {
+        self.paths
+            .retain(|path, _| !matches!(path, PeerSocket::Relay { relay: r, .. } if *r == relay));
+        self.checks_in_flight.retain(
+            |_, (path, _)| !matches!(path, PeerSocket::Relay { relay: r, .. } if *r == relay),
+        );
+    }
+
+    /// Selects the path we should switch to according to the given policy, if any.
+    ///
+    /// Only does something every [`NominationPolicy::reevaluate_interval`].
+    pub(crate) fn select(
+        &mut self,
+        current: PeerSocket,
+        policy: &NominationPolicy,
+        now: Instant,
+    ) -> Option<PeerSocket> {
+        let next_evaluation = *self.next_evaluation.get_or_insert(now);
+        if now < next_evaluation {
+            return None;
+        }
+        self.next_evaluation = Some(now + policy.reevaluate_interval);
+
+        self.paths
+            .retain(|_, p| now.duration_since(p.last_confirmed_at) < PATH_TIMEOUT);
+
+        let (best, _) = self.paths.iter().min_by(|(a_socket, a), (b_socket, b)| {
+            compare(policy, current, (**a_socket, **a), (**b_socket, **b))
+        })?;
+
+        (*best != current).then_some(*best)
+    }
+}
+
+/// Orders two paths, the better one first.
+fn compare(
+    policy: &NominationPolicy,
+    current: PeerSocket,
+    (a_socket, a): (PeerSocket, Path),
+    (b_socket, b): (PeerSocket, Path),
+) -> Ordering {
+    if policy.prefer_direct && a_socket.is_relayed() != b_socket.is_relayed() {
+        return a_socket.is_relayed().cmp(&b_socket.is_relayed());
+    }
+
+    if policy.prefer_ipv6 && a_socket.is_ipv6() != b_socket.is_ipv6() {
+        let (ipv6, ipv4) = if a_socket.is_ipv6() { (a, b) } else { (b, a) };
+        let ipv6_in_time = ipv6.confirmed_at <= ipv4.confirmed_at + policy.ipv6_window;
+
+        return match (a_socket.is_ipv6(), ipv6_in_time) {
+            (true, true) | (false, false) => Ordering::Less,
+            (true, false) | (false, true) => Ordering::Greater,
+        };
+    }
+
+    // Avoid switching between equally good paths.
+    (a_socket != current)
+        .cmp(&(b_socket != current))
+        .then(a.confirmed_at.cmp(&b.confirmed_at))
+}
+
+stun_codec::define_attribute_enums!(
+    Attribute,
+    AttributeDecoder,
+    AttributeEncoder,
+    [
+        Username,
+        MessageIntegrity,
+        Fingerprint,
+        XorMappedAddress,
+        Priority,
+        IceControlling,
+        IceControlled
+    ]
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use str0m::ice::IceCreds;
+
+    const DIRECT_IP4: PeerSocket = PeerSocket::Direct {
+        source: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 1),
+        dest: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 2),
+    };
+    const DIRECT_IP6: PeerSocket = PeerSocket::Direct {
+        source: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 1),
+        dest: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 2),
+    };
+    const RELAYED: PeerSocket = PeerSocket::Relay {
+        relay: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)), 3),
+        dest: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 2),
+    };
+
+    #[test]
+    fn upgrades_from_relayed_to_direct_path() {
+        let mut paths = WorkingPaths::default();
+        let policy = NominationPolicy::default();
+        let start = Instant::now();
+
+        paths.confirm(RELAYED, start);
+        assert_eq!(paths.select(RELAYED, &policy, start), None);
+
+        let later = start + Duration::from_secs(10);
+        paths.confirm(RELAYED, later);
+        paths.confirm(DIRECT_IP4, later);
+
+        assert_eq!(paths.select(RELAYED, &policy, later), Some(DIRECT_IP4));
+    }
+
+    #[test]
+    fn prefers_ipv6_only_within_window() {
+        let policy = NominationPolicy::default();
+        let start = Instant::now();
+
+        let mut paths = WorkingPaths::default();
+        paths.confirm(DIRECT_IP4, start);
+        paths.confirm(DIRECT_IP6, start + Duration::from_millis(500));
+
+        assert_eq!(
+            paths.select(DIRECT_IP4, &policy, start + Duration::from_secs(1)),
+            Some(DIRECT_IP6)
+        );
+
+        let mut paths = WorkingPaths::default();
+        paths.confirm(DIRECT_IP4, start);
+        paths.confirm(DIRECT_IP6, start + Duration::from_secs(5));
+
+        assert_eq!(
+            paths.select(DIRECT_IP4, &policy, start + Duration::from_secs(5)),
+            None
+        );
+    }
+
+    #[test]
+    fn forgets_paths_that_stopped_working() {
+        let mut paths = WorkingPaths::default();
+        let policy = NominationPolicy::default();
+        let start = Instant::now();
+
+        paths.confirm(DIRECT_IP4, start);
+        paths.confirm(RELAYED, start + PATH_TIMEOUT);
+
+        assert_eq!(
+            paths.select(DIRECT_IP4, &policy, start + PATH_TIMEOUT),
+            Some(RELAYED)
+        );
+    }
+
+    #[test]
+    fn keeps_working_paths_alive_with_own_checks() {
+        let mut paths = WorkingPaths::default();
+        let agent = agent();
+        let start = Instant::now();
+
+        paths.confirm(DIRECT_IP4, start);
+        assert!(paths.poll_check(&agent, start).is_none());
+
+        let now = start + PATH_CHECK_INTERVAL;
+        let (path, request) = paths.poll_check(&agent, now).unwrap();
+        assert_eq!(path, DIRECT_IP4);
+
+        let request = MessageDecoder::<Attribute>::default()
+            .decode_from_bytes(&request)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            request.get_attribute::<Username>().unwrap().name(),
+            format!("remote:{}", agent.local_credentials().ufrag)
+        );
+        assert!(request
+            .get_attribute::<MessageIntegrity>()
+            .unwrap()
+            .check_short_term_credential("password")
+            .is_ok());
+
+        let later = start + PATH_TIMEOUT;
+        assert!(paths.handle_check_response(&agent, &response_to(&request, "password"), later));
+        assert!(paths.is_fresh(DIRECT_IP4, later));
+        assert_eq!(
+            paths.select(DIRECT_IP4, &NominationPolicy::default(), later),
+            None
+        );
+    }
+
+    #[test]
+    fn ignores_unauthenticated_check_responses() {
+        let mut paths = WorkingPaths::default();
+        let agent = agent();
+        let start = Instant::now();
+
+        paths.confirm(DIRECT_IP4, start);
+        let (_, request) = paths
+            .poll_check(&agent, start + PATH_CHECK_INTERVAL)
+            .unwrap();
+        let request = MessageDecoder::<Attribute>::default()
+            .decode_from_bytes(&request)
+            .unwrap()
+            .unwrap();
+
+        assert!(!paths.handle_check_response(
+            &agent,
+            &response_to(&request, "wrong"),
+            start + PATH_CHECK_INTERVAL
+        ));
+    }
+
+    fn agent() -> IceAgent {
+        let mut agent = IceAgent::new();
+        agent.set_controlling(true);
+        agent.set_remote_credentials(IceCreds {
+            ufrag: "remote".to_owned(),
+            pass: "password".to_owned(),
+        });
+
+        agent
+    }
+
+    fn response_to(request: &Message<Attribute>, password: &str) -> Vec<u8> {
+        let mut response = Message::new(
+            MessageClass::SuccessResponse,
+            BINDING,
+            request.transaction_id(),
+        );
+        response.add_attribute(Attribute::XorMappedAddress(XorMappedAddress::new(
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 1),
+        )));
+        response.add_attribute(Attribute::MessageIntegrity(
+            MessageIntegrity::new_short_term_credential(&response, password).unwrap(),
+        ));
+
+        MessageEncoder::default()
+            .encode_into_bytes(response)
+            .unwrap()
+    }
+}
@@ -0,0 +1,313 @@
+use std::time::{Duration, Instant};
+
+/// The MTU we assume every path to support, same as the minimum MTU of IPv6.
+///
+/// See `BASE_PLPMTU` in <https://www.rfc-editor.org/rfc/rfc8899#section-5.1.2>.
+pub(crate) const BASE_MTU: u16 = 1280;
+/// The largest MTU we probe for.
+///
+/// Inner packets larger than this would not fit into a standard Ethernet frame once encapsulated anyway.
+pub(crate) const MAX_MTU: u16 = 1500;
+
+/// We stop the search once the gap between the largest working and smallest failing size is at most this.
+const SEARCH_GRANULARITY: u16 = 8;
+/// How long we wait for a probe to be acknowledged.
+///
+/// RFC 8899 suggests 15s but our probes are answered immediately by the peer instead of being piggybacked on application traffic.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often we send a probe of a certain size before concluding that the path doesn't support it, see `MAX_PROBES` in RFC 8899.
+const MAX_PROBES: u8 = 3;
+/// How long we wait before searching for a larger MTU again, see `PMTU_RAISE_TIMER` in RFC 8899.
+const RAISE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Packetization layer path MTU discovery for a single connection, modelled after DPLPMTUD (RFC 8899).
+///
+/// We probe the path with padded [`ControlMessage::MtuProbe`](crate::control_message::ControlMessage::MtuProbe)s sent through the WireGuard tunnel.
+/// Because the padding sits inside the encrypted payload, a probe traverses the path exactly like a data packet of the same size would, including the relay's channel-data overhead.
+/// The peer acknowledges every probe it receives, allowing us to binary search for the largest size that makes it through.
+///
+/// Peers that predate path MTU discovery don't acknowledge probes.
+/// Hence, our first probe has [`BASE_MTU`] which every path supports and if none of its attempts are acknowledged, we never probe the peer again.
+///
+/// All sizes refer to the inner IP packet, i.e. what the TUN device can hand us without it being dropped on the way.
+#[derive(Debug)]
+pub(crate) struct PathMtu {
+    mtu: u16,
+
+    /// The largest size we know to work.
+    low: u16,
+    /// The smallest size we know not to work, or one more than [`MAX_MTU`] if we don't know any.
+    high: u16,
+
+    probe: Option<Probe>,
+    next_probe_id: u32,
+    /// When to start the next search if we don't have a probe in flight.
+    next_search_at: Instant,
+
+    /// When the MTU last changed, if we haven't reported it yet.
+    changed_at: Option<Instant>,
+
+    /// Whether the peer acknowledges our probes, `None` until we know.
+    peer_acks_probes: Option<bool>,
+}
+
+#[derive(Debug)]
+struct Probe {
+    id: u32,
+    size: u16,
+    /// `None` if the probe should be (re)sent.
+    sent_at: Option<Instant>,
+    attempts: u8,
+}
+
+impl PathMtu {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            mtu: BASE_MTU,
+            low: BASE_MTU,
+            high: MAX_MTU + 1,
+            probe: None,
+            next_probe_id: 0,
+            next_search_at: now,
+            changed_at: None,
+            peer_acks_probes: None,
+        }
+    }
+
+    /// The largest inner IP packet we can currently send on this path.
+    pub(crate) fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// Starts over from [`BASE_MTU`], e.g. because we switched to a different path.
+    pub(crate) fn reset(&mut self, now: Instant) {
+        let mtu = self.mtu;
+        let next_probe_id = self.next_probe_id;
+        let peer_acks_probes = self.peer_acks_probes;
+
+        *self = Self::new(now);
+        self.next_probe_id = next_probe_id;
+        self.peer_acks_probes = peer_acks_probes;
+
+        if mtu != BASE_MTU {
+            self.changed_at = Some(now);
+        }
+    }
+
+    /// Returns the ID and size of the probe to send next, if any.
+    pub(crate) fn poll_probe(&mut self, now: Instant) -> Option<(u32, u16)> {
+        if self.peer_acks_probes == Some(false) {
+            return None;
+        }
+
+        if self.probe.is_none() {
+            if now < self.next_search_at {
+                return None;
+            }
+
+            if self.high - self.low <= SEARCH_GRANULARITY {
+                tracing::debug!(mtu = %self.mtu, "Path MTU search complete");
+
+                self.high = MAX_MTU + 1;
+                self.next_search_at = now + RAISE_INTERVAL;
+
+                return None;
+            }
+
+            self.next_probe_id = self.next_probe_id.wrapping_add(1);
+            self.probe = Some(Probe {
+                id: self.next_probe_id,
+                size: match self.peer_acks_probes {
+                    Some(_) => self.low + (self.high - self.low) / 2,
+                    None => BASE_MTU,
+                },
+                sent_at: None,
+                attempts: 0,
+            });
+        }
+
+        let probe = self.probe.as_mut()?;
+
+        if probe.sent_at.is_some() {
+            return None;
+        }
+
+        probe.sent_at = Some(now);
+        probe.attempts += 1;
+
+        Some((probe.id, probe.size))
+    }
+
+    pub(crate) fn handle_ack(&mut self, id: u32, now: Instant) {
+        if self.probe.as_ref().map(|p| p.id) != Some(id) {
+            tracing::trace!(%id, "Ignoring ack for unknown MTU probe");
+            return;
+        }
+
+        let probe = self.probe.take().expect("checked above");
+        self.low = probe.size;
+        self.peer_acks_probes = Some(true);
+
+        if probe.size > self.mtu {
+            tracing::debug!(old = %self.mtu, new = %probe.size, "Path MTU increased");
+
+            self.mtu = probe.size;
+            self.changed_at = Some(now);
+        }
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        let Some(probe) = self.probe.as_mut() else {
+            return;
+        };
+        let Some(sent_at) = probe.sent_at else {
+            return;
+        };
+
+        if now < sent_at + PROBE_TIMEOUT {
+            return;
+        }
+
+        if probe.attempts < MAX_PROBES {
+            probe.sent_at = None;
+            return;
+        }
+
+        if self.peer_acks_probes.is_none() {
+            tracing::debug!("Peer does not acknowledge MTU probes, not probing again");
+
+            self.peer_acks_probes = Some(false);
+            self.probe = None;
+            return;
+        }
+
+        tracing::debug!(size = %probe.size, "Path does not support MTU");
+
+        self.high = probe.size;
+        self.probe = None;
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        if let Some(changed_at) = self.changed_at {
+            return Some(changed_at);
+        }
+
+        if self.peer_acks_probes == Some(false) {
+            return None;
+        }
+
+        match &self.probe {
+            Some(Probe {
+                sent_at: Some(sent_at),
+                ..
+            }) => Some(*sent_at + PROBE_TIMEOUT),
+            Some(Probe { sent_at: None, .. }) => None,
+            None => Some(self.next_search_at),
+        }
+    }
+
+    /// Returns the new MTU if it changed since we last called this.
+    pub(crate) fn poll_event(&mut self) -> Option<u16> {
+        self.changed_at.take().map(|_| self.mtu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converges_on_largest_working_size() {
+        let start = Instant::now();
+        let mut mtu = PathMtu::new(start);
+
+        let now = run_search(&mut mtu, start, 1420);
+
+        assert!((1420 - SEARCH_GRANULARITY..=1420).contains(&mtu.mtu()));
+        assert_eq!(mtu.poll_event(), Some(mtu.mtu()));
+        assert_eq!(mtu.poll_timeout(), Some(now + RAISE_INTERVAL));
+    }
+
+    #[test]
+    fn stays_at_base_mtu_if_no_probe_is_acked() {
+        let start = Instant::now();
+        let mut mtu = PathMtu::new(start);
+
+        run_search(&mut mtu, start, BASE_MTU);
+
+        assert_eq!(mtu.mtu(), BASE_MTU);
+        assert_eq!(mtu.poll_event(), None);
+    }
+
+    #[test]
+    fn retransmits_lost_probe() {
+        let start = Instant::now();
+        let mut mtu = PathMtu::new(start);
+
+        let (first_id, first_size) = mtu.poll_probe(start).unwrap();
+        assert_eq!(mtu.poll_probe(start), None);
+
+        mtu.handle_timeout(start + PROBE_TIMEOUT);
+
+        assert_eq!(
+            mtu.poll_probe(start + PROBE_TIMEOUT),
+            Some((first_id, first_size))
+        );
+    }
+
+    #[test]
+    fn stops_probing_if_peer_never_acks() {
+        let start = Instant::now();
+        let mut mtu = PathMtu::new(start);
+
+        let mut now = start;
+        for _ in 0..MAX_PROBES {
+            let (_, size) = mtu.poll_probe(now).unwrap();
+            assert_eq!(size, BASE_MTU);
+
+            now += PROBE_TIMEOUT;
+            mtu.handle_timeout(now);
+        }
+
+        assert_eq!(mtu.poll_probe(now), None);
+        assert_eq!(mtu.poll_timeout(), None);
+
+        mtu.reset(now);
+        assert_eq!(mtu.poll_probe(now + RAISE_INTERVAL), None);
+    }
+
+    #[test]
+    fn reset_reports_base_mtu() {
+        let start = Instant::now();
+        let mut mtu = PathMtu::new(start);
+        let now = run_search(&mut mtu, start, MAX_MTU);
+        mtu.poll_event();
+
+        mtu.reset(now);
+
+        assert_eq!(mtu.mtu(), BASE_MTU);
+        assert_eq!(mtu.poll_event(), Some(BASE_MTU));
+        assert!(mtu.poll_probe(now).is_some());
+    }
+
+    /// Runs a search on a path that drops all packets larger than `path_mtu`.
+    fn run_search(mtu: &mut PathMtu, mut now: Instant, path_mtu: u16) -> Instant {
+        for _ in 0..100 {
+            mtu.handle_timeout(now);
+
+            if let Some((id, size)) = mtu.poll_probe(now) {
+                if size <= path_mtu {
+                    mtu.handle_ack(id, now);
+                }
+                continue;
+            }
+
+            let Some(sent_at) = mtu.probe.as_ref().and_then(|p| p.sent_at) else {
+                return now; // No probe in flight, search is complete.
+            };
+            now = sent_at + PROBE_TIMEOUT;
+        }
+
+        panic!("search did not complete")
+    }
+}
@@ -0,0 +1,365 @@
+use crate::control_message::ControlMessage;
+use crate::key_exchange::{self, KeyExchangeAnswer, KeyExchangeOffer};
+use boringtun::x25519::PublicKey;
+use secrecy::Secret;
+use std::time::{Duration, Instant};
+
+/// How long we wait for the peer to answer a rekey offer before sending it again.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often we send a rekey offer before giving up.
+const MAX_ATTEMPTS: u8 = 5;
+
+/// Rotates the WireGuard preshared key of an established connection.
+///
+/// Either side can start a rotation by sending a [`ControlMessage::RekeyOffer`] through the tunnel, carrying a fresh hybrid key exchange.
+/// The peer switches to the new key right after sending its [`ControlMessage::RekeyAnswer`], we switch once we receive it.
+/// Like the rest of the tunnel traffic, neither message is visible to the signalling channel.
+pub(crate) struct Rekey {
+    /// Our and the peer's WireGuard static public key, bound into every key we derive.
+    local: PublicKey,
+    remote: PublicKey,
+
+    /// After how long we rotate the key by ourselves, if at all.
+    max_key_age: Option<Duration>,
+    /// When we agreed on the current key.
+    key_agreed_at: Instant,
+
+    next_id: u32,
+    in_flight: Option<InFlight>,
+    /// The last offer we answered, in case our answer gets lost and the peer sends the offer again.
+    last_answer: Option<(u32, KeyExchangeAnswer)>,
+
+    rotated_key: Option<RotatedKey>,
+}
+
+struct InFlight {
+    id: u32,
+    initiator: key_exchange::Initiator,
+    started_at: Instant,
+    /// `None` if the offer should be (re)sent.
+    sent_at: Option<Instant>,
+    attempts: u8,
+}
+
+/// A key we agreed on with the peer but haven't switched to yet.
+pub(crate) struct RotatedKey {
+    pub(crate) psk: Secret<[u8; 32]>,
+    /// Whether we started the rotation, in which case the peer already switched to the new key.
+    pub(crate) is_initiator: bool,
+    agreed_at: Instant,
+}
+
+impl Rekey {
+    pub(crate) fn new(
+        local: PublicKey,
+        remote: PublicKey,
+        max_key_age: Option<Duration>,
+        now: Instant,
+    ) -> Self {
+        Self {
+            local,
+            remote,
+            max_key_age,
+            key_agreed_at: now,
+            next_id: 0,
+            in_flight: None,
+            last_answer: None,
+            rotated_key: None,
+        }
+    }
+
+    pub(crate) fn set_max_key_age(&mut self, max_key_age: Option<Duration>) {
+        self.max_key_age = max_key_age;
+    }
+
+    /// Starts rotating the key, unless we are already doing so.
+    pub(crate) fn start(&mut self, now: Instant) {
+        if self.in_flight.is_some() {
+            return;
+        }
+
+        self.next_id = self.next_id.wrapping_add(1);
+        self.in_flight = Some(InFlight {
+            id: self.next_id,
+            initiator: key_exchange::Initiator::new(),
+            started_at: now,
+            sent_at: None,
+            attempts: 0,
+        });
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        if self.in_flight.is_none()
+            && self
+                .max_key_age
+                .is_some_and(|age| now >= self.key_agreed_at + age)
+        {
+            tracing::debug!("Preshared key reached its maximum age");
+
+            self.start(now);
+        }
+
+        let Some(in_flight) = self.in_flight.as_mut() else {
+            return;
+        };
+        let Some(sent_at) = in_flight.sent_at else {
+            return;
+        };
+
+        if now < sent_at + RETRANSMIT_TIMEOUT {
+            return;
+        }
+
+        if in_flight.attempts < MAX_ATTEMPTS {
+            in_flight.sent_at = None;
+            return;
+        }
+
+        tracing::warn!("Peer did not answer rekey offer, keeping current preshared key");
+
+        self.in_flight = None;
+        self.key_agreed_at = now; // Don't try again before another full key age passed.
+    }
+
+    /// Returns the offer to send to the peer, if any.
+    pub(crate) fn poll_message(&mut self, now: Instant) -> Option<ControlMessage> {
+        let in_flight = self.in_flight.as_mut()?;
+
+        if in_flight.sent_at.is_some() {
+            return None;
+        }
+
+        in_flight.sent_at = Some(now);
+        in_flight.attempts += 1;
+
+        Some(ControlMessage::RekeyOffer {
+            id: in_flight.id,
+            offer: in_flight.initiator.offer(),
+        })
+    }
+
+    /// Answers an offer from the peer, returning the answer to send back.
+    pub(crate) fn handle_offer(
+        &mut self,
+        id: u32,
+        offer: KeyExchangeOffer,
+        now: Instant,
+    ) -> Option<ControlMessage> {
+        if let Some((answered, answer)) = &self.last_answer {
+            if *answered == id {
+                return Some(ControlMessage::RekeyAnswer {
+                    id,
+                    answer: answer.clone(),
+                });
+            }
+        }
+
+        // Both sides started a rotation at the same time, only the offer with the larger X25519 key proceeds.
+        if let Some(in_flight) = &self.in_flight {
+            if in_flight.initiator.offer().x25519 > offer.x25519 {
+                tracing::debug!("Ignoring concurrent rekey offer from peer");
+                return None;
+            }
+
+            self.in_flight = None;
+        }
+
+        let (answer, psk) = match key_exchange::respond(&offer, self.local, self.remote) {
+            Ok(answer) => answer,
+            Err(e) => {
+                tracing::warn!("Failed to answer rekey offer: {e}");
+                return None;
+            }
+        };
+
+        self.last_answer = Some((id, answer.clone()));
+        self.key_agreed_at = now;
+        self.rotated_key = Some(RotatedKey {
+            psk,
+            is_initiator: false,
+            agreed_at: now,
+        });
+
+        Some(ControlMessage::RekeyAnswer { id, answer })
+    }
+
+    pub(crate) fn handle_answer(&mut self, id: u32, answer: KeyExchangeAnswer, now: Instant) {
+        if self.in_flight.as_ref().map(|i| i.id) != Some(id) {
+            tracing::trace!(%id, "Ignoring answer for unknown rekey offer");
+            return;
+        }
+
+        let in_flight = self.in_flight.take().expect("checked above");
+
+        match in_flight.initiator.finish(&answer, self.local, self.remote) {
+            Ok(psk) => {
+                self.key_agreed_at = now;
+                self.rotated_key = Some(RotatedKey {
+                    psk,
+                    is_initiator: true,
+                    agreed_at: now,
+                });
+            }
+            Err(e) => {
+                tracing::warn!("Failed to complete rekey: {e}");
+            }
+        }
+    }
+
+    /// Returns the key we should switch to, if any.
+    pub(crate) fn poll_rotated_key(&mut self) -> Option<RotatedKey> {
+        self.rotated_key.take()
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        if let Some(rotated) = &self.rotated_key {
+            return Some(rotated.agreed_at);
+        }
+
+        match &self.in_flight {
+            Some(InFlight {
+                sent_at: Some(sent_at),
+                ..
+            }) => Some(*sent_at + RETRANSMIT_TIMEOUT),
+            Some(InFlight {
+                sent_at: None,
+                started_at,
+                ..
+            }) => Some(*started_at),
+            None => self.max_key_age.map(|age| self.key_agreed_at + age),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use boringtun::x25519::StaticSecret;
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn both_sides_agree_on_rotated_key() {
+        let now = Instant::now();
+        let mut alice = Rekey::new(alice_public_key(), bob_public_key(), None, now);
+        let mut bob = Rekey::new(bob_public_key(), alice_public_key(), None, now);
+
+        alice.start(now);
+        let (alice_key, bob_key) = exchange(&mut alice, &mut bob, now);
+
+        assert!(alice_key.is_initiator);
+        assert!(!bob_key.is_initiator);
+        assert_eq!(alice_key.psk.expose_secret(), bob_key.psk.expose_secret());
+    }
+
+    #[test]
+    fn repeats_answer_for_retransmitted_offer() {
+        let now = Instant::now();
+        let mut alice = Rekey::new(alice_public_key(), bob_public_key(), None, now);
+        let mut bob = Rekey::new(bob_public_key(), alice_public_key(), None, now);
+
+        alice.start(now);
+        let Some(ControlMessage::RekeyOffer { id, offer }) = alice.poll_message(now) else {
+            panic!("expected offer")
+        };
+
+        let first = bob.handle_offer(id, offer.clone(), now);
+        let second = bob.handle_offer(id, offer, now);
+
+        assert_eq!(first, second);
+        assert!(bob.poll_rotated_key().is_some());
+    }
+
+    #[test]
+    fn retransmits_unanswered_offer() {
+        let now = Instant::now();
+        let mut alice = Rekey::new(alice_public_key(), bob_public_key(), None, now);
+
+        alice.start(now);
+        assert!(alice.poll_message(now).is_some());
+        assert!(alice.poll_message(now).is_none());
+
+        alice.handle_timeout(now + RETRANSMIT_TIMEOUT);
+
+        assert!(alice.poll_message(now + RETRANSMIT_TIMEOUT).is_some());
+    }
+
+    #[test]
+    fn concurrent_rotations_converge_on_one_key() {
+        let now = Instant::now();
+        let mut alice = Rekey::new(alice_public_key(), bob_public_key(), None, now);
+        let mut bob = Rekey::new(bob_public_key(), alice_public_key(), None, now);
+
+        alice.start(now);
+        bob.start(now);
+        let alice_offer = alice.poll_message(now).unwrap();
+        let bob_offer = bob.poll_message(now).unwrap();
+
+        let alice_answer = deliver(&mut alice, bob_offer, now);
+        let bob_answer = deliver(&mut bob, alice_offer, now);
+
+        // Exactly one of them answers.
+        let answer = match (alice_answer, bob_answer) {
+            (Some(answer), None) => deliver(&mut bob, answer, now),
+            (None, Some(answer)) => deliver(&mut alice, answer, now),
+            other => panic!("expected exactly one answer, got {other:?}"),
+        };
+        assert!(answer.is_none());
+
+        let alice_key = alice.poll_rotated_key().unwrap();
+        let bob_key = bob.poll_rotated_key().unwrap();
+
+        assert_ne!(alice_key.is_initiator, bob_key.is_initiator);
+        assert_eq!(alice_key.psk.expose_secret(), bob_key.psk.expose_secret());
+    }
+
+    #[test]
+    fn rotates_after_max_key_age() {
+        let now = Instant::now();
+        let age = Duration::from_secs(3600);
+        let mut alice = Rekey::new(alice_public_key(), bob_public_key(), Some(age), now);
+
+        assert_eq!(alice.poll_timeout(), Some(now + age));
+        assert!(alice.poll_message(now).is_none());
+
+        alice.handle_timeout(now + age);
+
+        assert!(matches!(
+            alice.poll_message(now + age),
+            Some(ControlMessage::RekeyOffer { .. })
+        ));
+    }
+
+    fn exchange(
+        initiator: &mut Rekey,
+        responder: &mut Rekey,
+        now: Instant,
+    ) -> (RotatedKey, RotatedKey) {
+        let offer = initiator.poll_message(now).unwrap();
+        let answer = deliver(responder, offer, now).unwrap();
+        deliver(initiator, answer, now);
+
+        (
+            initiator.poll_rotated_key().unwrap(),
+            responder.poll_rotated_key().unwrap(),
+        )
+    }
+
+    fn deliver(rekey: &mut Rekey, message: ControlMessage, now: Instant) -> Option<ControlMessage> {
+        match message {
+            ControlMessage::RekeyOffer { id, offer } => rekey.handle_offer(id, offer, now),
+            ControlMessage::RekeyAnswer { id, answer } => {
+                rekey.handle_answer(id, answer, now);
+                None
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    fn alice_public_key() -> PublicKey {
+        PublicKey::from(&StaticSecret::from([1; 32]))
+    }
+
+    fn bob_public_key() -> PublicKey {
+        PublicKey::from(&StaticSecret::from([2; 32]))
+    }
+}
@@ -1,4 +1,13 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+/// After how long we consider a STUN binding request to a peer lost.
+const BINDING_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many binding requests we consider for estimating loss.
+const LOSS_WINDOW: usize = 20;
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct NodeStats {
@@ -12,6 +21,241 @@ pub struct ConnectionStats {
     pub stun_bytes_to_peer_direct: HumanBytes,
     /// How many bytes we sent as part of exchanging STUN messages to other peers via relays.
     pub stun_bytes_to_peer_relayed: HumanBytes,
+
+    /// How many bytes of WireGuard packets we sent to the peer, including handshakes and keep-alives.
+    pub wg_bytes_sent: HumanBytes,
+    /// How many WireGuard packets we sent to the peer.
+    pub wg_packets_sent: usize,
+    /// How many bytes of WireGuard packets we received from the peer, including handshakes and keep-alives.
+    pub wg_bytes_received: HumanBytes,
+    /// How many WireGuard packets we received from the peer.
+    pub wg_packets_received: usize,
+
+    /// How many WireGuard handshakes we completed with the peer.
+    pub handshakes: usize,
+    /// When we last completed a WireGuard handshake with the peer.
+    pub last_handshake_at: Option<Instant>,
+
+    /// The path we currently send our packets on, `None` until ICE nominated a candidate pair.
+    pub path: Option<PathType>,
+    /// The smoothed round-trip time of the STUN binding requests ICE sends on the nominated candidate pair to check connectivity.
+    pub rtt: Option<Duration>,
+    /// The share of recent STUN binding requests on the nominated candidate pair that went unanswered, between 0 and 1.
+    pub loss: f32,
+}
+
+impl ConnectionStats {
+    pub(crate) fn record_wg_sent(&mut self, num_bytes: usize) {
+        self.wg_bytes_sent += num_bytes;
+        self.wg_packets_sent += 1;
+    }
+
+    pub(crate) fn record_wg_received(&mut self, num_bytes: usize) {
+        self.wg_bytes_received += num_bytes;
+        self.wg_packets_received += 1;
+    }
+}
+
+/// The kind of our local candidate of the nominated candidate pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathType {
+    /// We talk to the peer directly from one of our interfaces.
+    Host,
+    /// We talk to the peer directly through a NAT.
+    ServerReflexive,
+    /// We talk to the peer via a TURN server.
+    Relayed,
+}
+
+/// Estimates round-trip time and loss from the STUN binding requests ICE sends to the peer.
+///
+/// [`str0m`] handles the binding requests and responses so we only observe them by their transaction ID.
+/// ICE checks all candidate pairs, most of which never work, thus we keep samples per pair and only report those of the nominated one.
+#[derive(Debug, Default)]
+pub(crate) struct BindingRequests {
+    pairs: HashMap<CandidatePair, PairSamples>,
+    nominated: Option<CandidatePair>,
+}
+
+/// The local and remote socket of a candidate pair.
+///
+/// For relayed candidates, the local socket is the address of our allocation.
+type CandidatePair = (SocketAddr, SocketAddr);
+
+#[derive(Debug, Default)]
+struct PairSamples {
+    in_flight: VecDeque<InFlightRequest>,
+    /// Whether our most recent binding requests were answered, newest last.
+    outcomes: VecDeque<bool>,
+    smoothed_rtt: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct InFlightRequest {
+    transaction_id: [u8; 12],
+    sent_at: Instant,
+    retransmitted: bool,
+}
+
+impl BindingRequests {
+    /// Records an outgoing packet from `local` to `remote`, ignoring anything but binding requests.
+    pub(crate) fn handle_outgoing(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        packet: &[u8],
+        now: Instant,
+    ) {
+        self.expire(now);
+
+        let Some((BINDING_REQUEST, transaction_id)) = parse_header(packet) else {
+            return;
+        };
+
+        self.pairs
+            .entry((local, remote))
+            .or_default()
+            .handle_request(transaction_id, now);
+    }
+
+    /// Records an incoming packet from `remote` to `local`, ignoring anything but successful binding responses.
+    ///
+    /// Returns whether the packet answered one of our binding requests.
+    pub(crate) fn handle_incoming(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        packet: &[u8],
+        now: Instant,
+    ) -> bool {
+        self.expire(now);
+
+        let Some((BINDING_SUCCESS_RESPONSE, transaction_id)) = parse_header(packet) else {
+            return false;
+        };
+
+        self.pairs
+            .get_mut(&(local, remote))
+            .is_some_and(|pair| pair.handle_response(transaction_id, now))
+    }
+
+    /// Sets the candidate pair ICE nominated, the only one we report RTT and loss for.
+    pub(crate) fn nominate(&mut self, local: SocketAddr, remote: SocketAddr) {
+        self.nominated = Some((local, remote));
+    }
+
+    pub(crate) fn smoothed_rtt(&self) -> Option<Duration> {
+        self.nominated_pair()?.smoothed_rtt
+    }
+
+    pub(crate) fn loss(&self) -> f32 {
+        let Some(pair) = self.nominated_pair() else {
+            return 0.0;
+        };
+
+        if pair.outcomes.is_empty() {
+            return 0.0;
+        }
+
+        let num_lost = pair.outcomes.iter().filter(|answered| !**answered).count();
+
+        num_lost as f32 / pair.outcomes.len() as f32
+    }
+
+    fn nominated_pair(&self) -> Option<&PairSamples> {
+        self.pairs.get(&self.nominated?)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        for pair in self.pairs.values_mut() {
+            pair.expire(now);
+        }
+    }
+}
+
+impl PairSamples {
+    fn handle_request(&mut self, transaction_id: [u8; 12], now: Instant) {
+        if let Some(request) = self
+            .in_flight
+            .iter_mut()
+            .find(|r| r.transaction_id == transaction_id)
+        {
+            // A response to a retransmitted request is ambiguous, see <https://www.rfc-editor.org/rfc/rfc6298#section-3>.
+            request.retransmitted = true;
+            return;
+        }
+
+        self.in_flight.push_back(InFlightRequest {
+            transaction_id,
+            sent_at: now,
+            retransmitted: false,
+        });
+    }
+
+    fn handle_response(&mut self, transaction_id: [u8; 12], now: Instant) -> bool {
+        let Some(index) = self
+            .in_flight
+            .iter()
+            .position(|r| r.transaction_id == transaction_id)
+        else {
+            return false;
+        };
+        let request = self.in_flight.remove(index).expect("index to be valid");
+
+        self.record_outcome(true);
+
+        if request.retransmitted {
+            return true;
+        }
+
+        let rtt = now.duration_since(request.sent_at);
+
+        // Same smoothing as TCP, see <https://www.rfc-editor.org/rfc/rfc6298#section-2>.
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            Some(smoothed) => (smoothed * 7 + rtt) / 8,
+            None => rtt,
+        });
+
+        true
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self
+            .in_flight
+            .front()
+            .is_some_and(|r| now.duration_since(r.sent_at) >= BINDING_REQUEST_TIMEOUT)
+        {
+            self.in_flight.pop_front();
+            self.record_outcome(false);
+        }
+    }
+
+    fn record_outcome(&mut self, answered: bool) {
+        if self.outcomes.len() == LOSS_WINDOW {
+            self.outcomes.pop_front();
+        }
+
+        self.outcomes.push_back(answered);
+    }
+}
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS_RESPONSE: u16 = 0x0101;
+
+/// Parses message type and transaction ID from the header of a STUN message, see <https://www.rfc-editor.org/rfc/rfc5389#section-6>.
+fn parse_header(packet: &[u8]) -> Option<(u16, [u8; 12])> {
+    let header = packet.get(..20)?;
+
+    let message_type = u16::from_be_bytes([header[0], header[1]]);
+    let magic_cookie = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+    if magic_cookie != 0x2112_A442 {
+        return None;
+    }
+
+    let transaction_id = header[8..20].try_into().expect("slice to be 12 bytes");
+
+    Some((message_type, transaction_id))
 }
 
 #[derive(Default, Clone, Copy)]
@@ -46,6 +290,7 @@ fn fmt_human_bytes(mut throughput: f64) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
 
     #[test]
     fn fmt_human_bytes() {
@@ -53,4 +298,117 @@ mod tests {
         assert_eq!(format!("{:?}", HumanBytes(1_000)), "1.00 kB");
         assert_eq!(format!("{:?}", HumanBytes(12_500_000)), "12.50 MB");
     }
+
+    #[test]
+    fn binding_requests_estimate_rtt_and_loss() {
+        let mut requests = nominated_requests();
+        let start = Instant::now();
+
+        requests.handle_outgoing(LOCAL, REMOTE, &stun_header(BINDING_REQUEST, 1), start);
+        requests.handle_outgoing(LOCAL, REMOTE, &stun_header(BINDING_REQUEST, 2), start);
+        requests.handle_incoming(
+            LOCAL,
+            REMOTE,
+            &stun_header(BINDING_SUCCESS_RESPONSE, 1),
+            start + Duration::from_millis(80),
+        );
+
+        assert_eq!(requests.smoothed_rtt(), Some(Duration::from_millis(80)));
+        assert_eq!(requests.loss(), 0.0);
+
+        requests.handle_outgoing(
+            LOCAL,
+            REMOTE,
+            &stun_header(BINDING_REQUEST, 3),
+            start + BINDING_REQUEST_TIMEOUT,
+        );
+        requests.handle_incoming(
+            LOCAL,
+            REMOTE,
+            &stun_header(BINDING_SUCCESS_RESPONSE, 3),
+            start + BINDING_REQUEST_TIMEOUT + Duration::from_millis(160),
+        );
+
+        assert_eq!(requests.smoothed_rtt(), Some(Duration::from_millis(90)));
+        assert_eq!(requests.loss(), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn ignores_rtt_of_retransmitted_binding_requests() {
+        let mut requests = nominated_requests();
+        let start = Instant::now();
+
+        requests.handle_outgoing(LOCAL, REMOTE, &stun_header(BINDING_REQUEST, 1), start);
+        requests.handle_outgoing(
+            LOCAL,
+            REMOTE,
+            &stun_header(BINDING_REQUEST, 1),
+            start + Duration::from_millis(500),
+        );
+        requests.handle_incoming(
+            LOCAL,
+            REMOTE,
+            &stun_header(BINDING_SUCCESS_RESPONSE, 1),
+            start + Duration::from_millis(600),
+        );
+
+        assert_eq!(requests.smoothed_rtt(), None);
+        assert_eq!(requests.loss(), 0.0);
+    }
+
+    #[test]
+    fn only_reports_nominated_candidate_pair() {
+        const DEAD_REMOTE: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3));
+
+        let mut requests = BindingRequests::default();
+        let start = Instant::now();
+
+        requests.handle_outgoing(LOCAL, DEAD_REMOTE, &stun_header(BINDING_REQUEST, 1), start);
+        requests.handle_outgoing(LOCAL, REMOTE, &stun_header(BINDING_REQUEST, 2), start);
+        requests.handle_incoming(
+            LOCAL,
+            REMOTE,
+            &stun_header(BINDING_SUCCESS_RESPONSE, 2),
+            start + Duration::from_millis(40),
+        );
+        requests.handle_incoming(
+            LOCAL,
+            DEAD_REMOTE,
+            &stun_header(BINDING_SUCCESS_RESPONSE, 2),
+            start + Duration::from_millis(50),
+        );
+
+        assert_eq!(requests.smoothed_rtt(), None, "nothing nominated yet");
+
+        requests.nominate(LOCAL, REMOTE);
+        requests.handle_outgoing(
+            LOCAL,
+            REMOTE,
+            &stun_header(BINDING_REQUEST, 3),
+            start + BINDING_REQUEST_TIMEOUT,
+        );
+
+        assert_eq!(requests.smoothed_rtt(), Some(Duration::from_millis(40)));
+        assert_eq!(requests.loss(), 0.0);
+    }
+
+    const LOCAL: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1));
+    const REMOTE: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2));
+
+    fn nominated_requests() -> BindingRequests {
+        let mut requests = BindingRequests::default();
+        requests.nominate(LOCAL, REMOTE);
+
+        requests
+    }
+
+    fn stun_header(message_type: u16, transaction_id: u8) -> Vec<u8> {
+        let mut header = Vec::with_capacity(20);
+        header.extend_from_slice(&message_type.to_be_bytes());
+        header.extend_from_slice(&0u16.to_be_bytes());
+        header.extend_from_slice(&0x2112_A442u32.to_be_bytes());
+        header.extend_from_slice(&[transaction_id; 12]);
+
+        header
+    }
 }
@@ -78,6 +78,31 @@ fn answer_after_stale_connection_does_not_panic() {
     alice.accept_answer(1, bob.public_key(), answer, now + Duration::from_secs(1));
 }
 
+#[test]
+fn answer_without_post_quantum_key_exchange_fails_connection() {
+    let (mut alice, mut bob) = alice_and_bob();
+    alice.set_post_quantum_psk(true);
+
+    let mut answer = send_offer(&mut alice, &mut bob, Instant::now());
+    answer.key_exchange = None;
+    alice.accept_answer(1, bob.public_key(), answer, Instant::now());
+
+    assert_eq!(alice.poll_event(), Some(Event::ConnectionFailed(1)));
+    assert!(!alice.is_expecting_answer(1));
+}
+
+#[test]
+fn failed_post_quantum_key_exchange_fails_connection() {
+    let (mut alice, mut bob) = alice_and_bob();
+    alice.set_post_quantum_psk(true);
+
+    let mut answer = send_offer(&mut alice, &mut bob, Instant::now());
+    answer.key_exchange.as_mut().unwrap().ml_kem.truncate(100);
+    alice.accept_answer(1, bob.public_key(), answer, Instant::now());
+
+    assert_eq!(alice.poll_event(), Some(Event::ConnectionFailed(1)));
+}
+
 #[test]
 fn only_generate_candidate_event_after_answer() {
     let local_candidate = SocketAddr::new(IpAddr::from(Ipv4Addr::LOCALHOST), 10000);
@@ -149,6 +174,55 @@ fn second_connection_with_same_relay_reuses_allocation() {
     assert!(alice.poll_transmit().is_none());
 }
 
+#[test]
+fn reset_local_network_queries_stun_servers_again() {
+    let mut alice = ClientNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()));
+
+    let _ = alice.new_connection(
+        1,
+        HashSet::from([STUN]),
+        HashSet::new(),
+        Instant::now(),
+        Instant::now(),
+    );
+
+    let transmit = alice.poll_transmit().unwrap();
+    assert_eq!(transmit.dst, STUN);
+    assert!(alice.poll_transmit().is_none());
+
+    alice.reset_local_network(Instant::now());
+
+    let transmit = alice.poll_transmit().unwrap();
+    assert_eq!(transmit.dst, STUN);
+}
+
+#[test]
+fn reset_local_network_restarts_ice_with_new_credentials() {
+    let now = Instant::now();
+
+    let (mut alice, mut bob) = alice_and_bob();
+    let answer = send_offer(&mut alice, &mut bob, now);
+    let initial_username = answer.credentials.username.clone();
+    alice.accept_answer(1, bob.public_key(), answer, now);
+
+    alice.reset_local_network(now);
+
+    let credentials = iter::from_fn(|| alice.poll_event())
+        .find_map(|e| match e {
+            Event::SignalIceRestart {
+                connection: 1,
+                credentials,
+            } => Some(credentials),
+            _ => None,
+        })
+        .unwrap();
+
+    let answer = bob.accept_ice_restart(1, credentials, now).unwrap();
+    assert_ne!(answer.username, initial_username);
+
+    alice.accept_ice_restart_answer(1, answer, now).unwrap();
+}
+
 fn alice_and_bob() -> (ClientNode<u64>, ServerNode<u64>) {
     let alice = ClientNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()));
     let bob = ServerNode::<u64>::new(StaticSecret::random_from_rng(rand::thread_rng()));
@@ -189,3 +263,4 @@ fn s(socket: &str) -> SocketAddr {
 }
 
 const RELAY: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 10000));
+const STUN: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3478));
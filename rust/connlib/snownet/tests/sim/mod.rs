@@ -0,0 +1,702 @@
+//! A simulated network for driving [`Node`]s entirely in-memory.
+//!
+//! Time is virtual and only advances when we run out of work, so tests run as fast as the CPU allows.
+//! Packets travel with a configurable latency and may be dropped with a configurable probability.
+//! Hosts either have a public address or sit behind a [`NatType`] and relaying happens via an embedded [`firezone_relay::Server`].
+//!
+//! The simulation is NOT deterministic.
+//! Everything the harness controls (loss, keys, the relay's allocations) is drawn from the seed but `str0m` and `boringtun` use OS randomness (ICE credentials, transaction IDs, ...) and `boringtun` reads the real clock for its timers.
+//! Hence, a seed does not replay a run exactly and tests must assert outcomes with enough slack rather than exact packet traces.
+
+use boringtun::x25519::StaticSecret;
+use firezone_relay::{AllocationId, ClientSocket, Command, PeerSocket, StaticCredentials};
+use pnet_packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::{checksum, MutableIpv4Packet},
+    Packet as _,
+};
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+use snownet::{ClientNode, Event, IpPacket, Node, ServerNode, Transmit};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, Instant, SystemTime},
+};
+
+pub const CONNECTION: u64 = 1;
+
+pub const RELAY: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)), 3478);
+
+const CLIENT_PUBLIC_IP: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 1);
+const CLIENT_PRIVATE: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)), 52625);
+const SERVER_PUBLIC_IP: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 2);
+const SERVER_PRIVATE: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 2, 2)), 52625);
+
+const RELAY_USERNAME: &str = "sim";
+const RELAY_PASSWORD: &str = "secret";
+
+const MAX_UDP_SIZE: usize = (1 << 16) - 1;
+
+/// How NATs map and filter traffic, see <https://www.rfc-editor.org/rfc/rfc4787>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// Endpoint-independent mapping and filtering: Anyone can send to a mapped port.
+    FullCone,
+    /// Endpoint-independent mapping but only accepts traffic from addresses we sent to.
+    PortRestrictedCone,
+    /// A new mapping per destination and only accepts traffic from that destination, defeats hole-punching.
+    Symmetric,
+}
+
+pub struct Simulation {
+    start: Instant,
+    now: Instant,
+
+    pub client: Host<snownet::Client>,
+    pub server: Host<snownet::Server>,
+    relay: Relay,
+
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    next_sequence: u64,
+
+    rng: StdRng,
+    latency: Duration,
+    loss: f64,
+    max_datagram_size: usize,
+}
+
+impl Simulation {
+    /// Creates a new simulation, `seed` drives everything the harness itself randomises.
+    pub fn new(seed: u64) -> Self {
+        let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+
+        let start = Instant::now();
+        // Each consumer gets its own stream so that e.g. drawing another key does not change which packets are lost.
+        let mut seeds = StdRng::seed_from_u64(seed);
+        let mut key_rng = StdRng::seed_from_u64(seeds.gen());
+        let relay_seed = seeds.gen();
+        let rng = StdRng::seed_from_u64(seeds.gen());
+
+        Self {
+            start,
+            now: start,
+            client: Host::new(
+                ClientNode::new(StaticSecret::random_from_rng(&mut key_rng)),
+                SocketAddr::new(IpAddr::V4(CLIENT_PUBLIC_IP), CLIENT_PRIVATE.port()),
+            ),
+            server: Host::new(
+                ServerNode::new(StaticSecret::random_from_rng(&mut key_rng)),
+                SocketAddr::new(IpAddr::V4(SERVER_PUBLIC_IP), SERVER_PRIVATE.port()),
+            ),
+            relay: Relay::new(relay_seed),
+            in_flight: BinaryHeap::new(),
+            next_sequence: 0,
+            rng,
+            latency: Duration::from_millis(10),
+            loss: 0.0,
+            max_datagram_size: usize::MAX,
+        }
+    }
+
+    /// Sets the one-way latency of every packet.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+
+        self
+    }
+
+    /// Sets the probability with which each packet is dropped.
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+
+        self
+    }
+
+    /// Drops every UDP datagram with a payload larger than `size`, e.g. because a link on the way has a small MTU.
+    pub fn with_max_datagram_size(mut self, size: usize) -> Self {
+        self.max_datagram_size = size;
+
+        self
+    }
+
+    pub fn with_client_nat(mut self, nat: NatType) -> Self {
+        self.client
+            .move_to(CLIENT_PRIVATE, Some(Nat::new(nat, CLIENT_PUBLIC_IP.into())));
+
+        self
+    }
+
+    pub fn with_server_nat(mut self, nat: NatType) -> Self {
+        self.server
+            .move_to(SERVER_PRIVATE, Some(Nat::new(nat, SERVER_PUBLIC_IP.into())));
+
+        self
+    }
+
+    /// Exchanges offer and answer between client and server, using the relay as STUN and TURN server.
+    pub fn connect(&mut self) {
+        let stun_servers = HashSet::from([RELAY]);
+        let turn_servers = HashSet::from([(
+            RELAY,
+            RELAY_USERNAME.to_owned(),
+            RELAY_PASSWORD.to_owned(),
+            "firezone".to_owned(),
+        )]);
+
+        self.client
+            .node
+            .add_local_host_candidate(self.client.local)
+            .unwrap();
+        self.server
+            .node
+            .add_local_host_candidate(self.server.local)
+            .unwrap();
+
+        let offer = self.client.node.new_connection(
+            CONNECTION,
+            stun_servers.clone(),
+            turn_servers.clone(),
+            self.now,
+            self.now,
+        );
+        let answer = self.server.node.accept_connection(
+            CONNECTION,
+            offer,
+            self.client.node.public_key(),
+            stun_servers,
+            turn_servers,
+            self.now,
+        );
+        self.client
+            .node
+            .accept_answer(CONNECTION, self.server.node.public_key(), answer, self.now);
+    }
+
+    /// Moves the client to a new network, e.g. from Wi-Fi to cellular, and lets it re-gather its candidates.
+    pub fn move_client(&mut self, local: SocketAddr, nat: Option<(NatType, IpAddr)>) {
+        self.client
+            .move_to(local, nat.map(|(nat, public_ip)| Nat::new(nat, public_ip)));
+        self.client.node.reset_local_network(self.now);
+        self.client.node.add_local_host_candidate(local).unwrap();
+    }
+
+    /// Asks the client to rotate the preshared key of [`CONNECTION`].
+    pub fn rotate_client_key(&mut self) {
+        self.client.node.rotate_preshared_key(CONNECTION, self.now);
+    }
+
+    /// Sends an IP packet with the given payload from the client to the server through the tunnel.
+    pub fn send_from_client(&mut self, payload: &[u8]) {
+        let packet = ip_packet(payload);
+        let transmit = self
+            .client
+            .node
+            .encapsulate(CONNECTION, IpPacket::new(&packet).unwrap(), self.now)
+            .ok()
+            .flatten()
+            .map(Transmit::into_owned);
+
+        if let Some(transmit) = transmit {
+            let (src, dst) = self.client.route(transmit.src, transmit.dst);
+            self.send(src, dst, transmit.payload.into_owned());
+        }
+    }
+
+    /// Sends an IP packet with the given payload from the server to the client through the tunnel.
+    pub fn send_from_server(&mut self, payload: &[u8]) {
+        let packet = ip_packet(payload);
+        let transmit = self
+            .server
+            .node
+            .encapsulate(CONNECTION, IpPacket::new(&packet).unwrap(), self.now)
+            .ok()
+            .flatten()
+            .map(Transmit::into_owned);
+
+        if let Some(transmit) = transmit {
+            let (src, dst) = self.server.route(transmit.src, transmit.dst);
+            self.send(src, dst, transmit.payload.into_owned());
+        }
+    }
+
+    /// Runs the simulation until `condition` is true or `timeout` has passed, returning whether `condition` was met.
+    pub fn run_until(
+        &mut self,
+        timeout: Duration,
+        mut condition: impl FnMut(&mut Self) -> bool,
+    ) -> bool {
+        let deadline = self.now + timeout;
+
+        loop {
+            self.step();
+
+            if condition(self) {
+                return true;
+            }
+
+            let next = self.next_deadline().unwrap_or(deadline);
+
+            if next > deadline {
+                self.now = deadline;
+                return false;
+            }
+
+            // Always make progress, even if a component wants to be woken in the past.
+            self.now = next.max(self.now + Duration::from_millis(1));
+        }
+    }
+
+    fn step(&mut self) {
+        let now = self.now;
+
+        if self.client.node.poll_timeout().is_some_and(|t| t <= now) {
+            self.client.node.handle_timeout(now);
+        }
+        if self.server.node.poll_timeout().is_some_and(|t| t <= now) {
+            self.server.node.handle_timeout(now);
+        }
+        if self
+            .relay
+            .next_wake
+            .is_some_and(|t| t <= self.system_time())
+        {
+            self.relay.next_wake = None;
+            self.relay
+                .server
+                .handle_deadline_reached(self.system_time());
+        }
+
+        loop {
+            self.signal_events();
+            self.flush_transmits();
+
+            let Some(Reverse(packet)) = self.in_flight.peek() else {
+                break;
+            };
+            if packet.at > now {
+                break;
+            }
+
+            let Reverse(packet) = self.in_flight.pop().expect("just peeked");
+            self.deliver(packet);
+        }
+    }
+
+    fn next_deadline(&mut self) -> Option<Instant> {
+        let relay_wake = self
+            .relay
+            .next_wake
+            .map(|wake| self.start + wake.duration_since(start_system_time()).unwrap_or_default());
+
+        [
+            self.client.node.poll_timeout(),
+            self.server.node.poll_timeout(),
+            relay_wake,
+            self.in_flight.peek().map(|Reverse(p)| p.at),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Forwards ICE candidates and restarts via our (instant) signalling channel and records all other events.
+    fn signal_events(&mut self) {
+        while let Some(event) = self.client.node.poll_event() {
+            match event {
+                Event::SignalIceCandidate {
+                    connection,
+                    candidate,
+                } => self
+                    .server
+                    .node
+                    .add_remote_candidate(connection, candidate, self.now),
+                Event::SignalIceRestart {
+                    connection,
+                    credentials,
+                } => {
+                    let answer = self
+                        .server
+                        .node
+                        .accept_ice_restart(connection, credentials, self.now)
+                        .unwrap();
+                    self.client
+                        .node
+                        .accept_ice_restart_answer(connection, answer, self.now)
+                        .unwrap();
+                }
+                other => self.client.events.push(other),
+            }
+        }
+
+        while let Some(event) = self.server.node.poll_event() {
+            match event {
+                Event::SignalIceCandidate {
+                    connection,
+                    candidate,
+                } => self
+                    .client
+                    .node
+                    .add_remote_candidate(connection, candidate, self.now),
+                Event::SignalIceRestart {
+                    connection,
+                    credentials,
+                } => {
+                    let answer = self
+                        .client
+                        .node
+                        .accept_ice_restart(connection, credentials, self.now)
+                        .unwrap();
+                    self.server
+                        .node
+                        .accept_ice_restart_answer(connection, answer, self.now)
+                        .unwrap();
+                }
+                other => self.server.events.push(other),
+            }
+        }
+    }
+
+    fn flush_transmits(&mut self) {
+        while let Some(transmit) = self.client.node.poll_transmit() {
+            let (src, dst) = self.client.route(transmit.src, transmit.dst);
+            self.send(src, dst, transmit.payload.into_owned());
+        }
+
+        while let Some(transmit) = self.server.node.poll_transmit() {
+            let (src, dst) = self.server.route(transmit.src, transmit.dst);
+            self.send(src, dst, transmit.payload.into_owned());
+        }
+
+        while let Some(command) = self.relay.server.next_command() {
+            match command {
+                Command::SendMessage { payload, recipient } => {
+                    self.send(Some(RELAY), recipient.into_socket(), payload);
+                }
+                Command::CreateAllocation { id, port, .. } => {
+                    self.relay.allocations.insert(port, id);
+                }
+                Command::FreeAllocation { id, .. } => {
+                    self.relay.allocations.retain(|_, a| *a != id);
+                }
+                Command::ForwardData { id, data, receiver } => {
+                    let Some(port) = self.relay.port_of(id) else {
+                        continue;
+                    };
+
+                    self.send(
+                        Some(SocketAddr::new(RELAY.ip(), port)),
+                        receiver.into_socket(),
+                        data,
+                    );
+                }
+                Command::Wake { deadline } => {
+                    self.relay.next_wake = Some(deadline);
+                }
+                Command::CreateTcpAllocation { .. }
+                | Command::ConnectToPeer { .. }
+                | Command::BindConnection { .. }
+                | Command::CloseConnection { .. } => {
+                    unreachable!("the simulation only uses UDP")
+                }
+            }
+        }
+    }
+
+    /// Puts a packet on the wire, unless it is lost.
+    ///
+    /// A `src` of `None` means the host cannot send the packet, e.g. because it asked for a local address it no longer has.
+    fn send(&mut self, src: Option<SocketAddr>, dst: SocketAddr, payload: Vec<u8>) {
+        let Some(src) = src else {
+            return;
+        };
+
+        if self.rng.gen_bool(self.loss) {
+            tracing::trace!(%src, %dst, "Dropping packet");
+            return;
+        }
+
+        if payload.len() > self.max_datagram_size {
+            tracing::trace!(%src, %dst, len = %payload.len(), "Dropping packet that exceeds MTU");
+            return;
+        }
+
+        self.in_flight.push(Reverse(InFlight {
+            at: self.now + self.latency,
+            sequence: self.next_sequence,
+            src,
+            dst,
+            payload,
+        }));
+        self.next_sequence += 1;
+    }
+
+    fn deliver(&mut self, packet: InFlight) {
+        let now = self.now;
+        let system_time = self.system_time();
+
+        if packet.dst == RELAY {
+            self.relay.server.handle_client_input(
+                &packet.payload,
+                ClientSocket::new(packet.src),
+                system_time,
+            );
+            return;
+        }
+
+        if packet.dst.ip() == RELAY.ip() {
+            if let Some(id) = self.relay.allocations.get(&packet.dst.port()) {
+                self.relay.server.handle_peer_traffic(
+                    &packet.payload,
+                    PeerSocket::new(packet.src),
+                    *id,
+                    system_time,
+                );
+            }
+            return;
+        }
+
+        if self.client.receive(&packet, now) {
+            return;
+        }
+        if self.server.receive(&packet, now) {
+            return;
+        }
+
+        tracing::trace!(src = %packet.src, dst = %packet.dst, "No route to host");
+    }
+
+    fn system_time(&self) -> SystemTime {
+        start_system_time() + self.now.duration_since(self.start)
+    }
+}
+
+/// A host running a [`Node`] on a single interface, optionally behind a NAT.
+pub struct Host<T> {
+    pub node: Node<T, u64>,
+    /// The socket of our interface.
+    local: SocketAddr,
+    nat: Option<Nat>,
+
+    /// Events other than ICE candidates, which are forwarded to the other host.
+    pub events: Vec<Event<u64>>,
+    /// The payloads of the IP packets we received through the tunnel.
+    pub received: VecDeque<Vec<u8>>,
+
+    buffer: Box<[u8; MAX_UDP_SIZE]>,
+}
+
+impl<T> Host<T> {
+    fn new(node: Node<T, u64>, local: SocketAddr) -> Self {
+        Self {
+            node,
+            local,
+            nat: None,
+            events: Vec::new(),
+            received: VecDeque::new(),
+            buffer: Box::new([0u8; MAX_UDP_SIZE]),
+        }
+    }
+
+    fn move_to(&mut self, local: SocketAddr, nat: Option<Nat>) {
+        self.local = local;
+        self.nat = nat;
+    }
+
+    /// Resolves the address a packet appears to come from on the wire.
+    fn route(
+        &mut self,
+        src: Option<SocketAddr>,
+        dst: SocketAddr,
+    ) -> (Option<SocketAddr>, SocketAddr) {
+        let src = src.unwrap_or(self.local);
+
+        if src != self.local {
+            return (None, dst);
+        }
+
+        let public = match self.nat.as_mut() {
+            Some(nat) => nat.outbound(src, dst),
+            None => src,
+        };
+
+        (Some(public), dst)
+    }
+
+    /// Hands the packet to our node if it is addressed to us, returns whether it was.
+    fn receive(&mut self, packet: &InFlight, now: Instant) -> bool {
+        let local = match self.nat.as_ref() {
+            Some(nat) if nat.public_ip == packet.dst.ip() => {
+                match nat.inbound(packet.src, packet.dst.port()) {
+                    Some(local) if local == self.local => local,
+                    _ => {
+                        tracing::trace!(src = %packet.src, dst = %packet.dst, "Dropped by NAT");
+                        return true;
+                    }
+                }
+            }
+            None if self.local == packet.dst => self.local,
+            _ => return false,
+        };
+
+        match self.node.decapsulate(
+            local,
+            packet.src,
+            &packet.payload,
+            now,
+            self.buffer.as_mut(),
+        ) {
+            Ok(Some((_, ip_packet))) => {
+                self.received.push_back(ip_packet.payload().to_vec());
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::debug!(src = %packet.src, dst = %packet.dst, "Failed to decapsulate: {e}");
+            }
+        }
+
+        true
+    }
+}
+
+struct Nat {
+    kind: NatType,
+    public_ip: IpAddr,
+    next_port: u16,
+    /// The public port for each private socket (and destination, for symmetric NATs).
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    /// For each public port, the private socket it maps to and the remotes we sent to from it.
+    bindings: HashMap<u16, (SocketAddr, HashSet<SocketAddr>)>,
+}
+
+impl Nat {
+    fn new(kind: NatType, public_ip: IpAddr) -> Self {
+        Self {
+            kind,
+            public_ip,
+            next_port: 40000,
+            mappings: HashMap::new(),
+            bindings: HashMap::new(),
+        }
+    }
+
+    fn outbound(&mut self, src: SocketAddr, dst: SocketAddr) -> SocketAddr {
+        let key = match self.kind {
+            NatType::FullCone | NatType::PortRestrictedCone => (src, None),
+            NatType::Symmetric => (src, Some(dst)),
+        };
+
+        let port = *self.mappings.entry(key).or_insert_with(|| {
+            let port = self.next_port;
+            self.next_port += 1;
+
+            port
+        });
+
+        self.bindings
+            .entry(port)
+            .or_insert_with(|| (src, HashSet::new()))
+            .1
+            .insert(dst);
+
+        SocketAddr::new(self.public_ip, port)
+    }
+
+    fn inbound(&self, from: SocketAddr, port: u16) -> Option<SocketAddr> {
+        let (private, remotes) = self.bindings.get(&port)?;
+
+        let allowed = match self.kind {
+            NatType::FullCone => true,
+            NatType::PortRestrictedCone | NatType::Symmetric => remotes.contains(&from),
+        };
+
+        allowed.then_some(*private)
+    }
+}
+
+struct Relay {
+    server: firezone_relay::Server<StdRng>,
+    allocations: HashMap<u16, AllocationId>,
+    next_wake: Option<SystemTime>,
+}
+
+impl Relay {
+    fn new(seed: u64) -> Self {
+        let IpAddr::V4(ip) = RELAY.ip() else {
+            unreachable!("relay has an IPv4 address")
+        };
+
+        let mut server = firezone_relay::Server::new(ip, StdRng::seed_from_u64(seed), 49152, 65535);
+        server.set_static_credentials(
+            format!("{RELAY_USERNAME}:{RELAY_PASSWORD}")
+                .parse::<StaticCredentials>()
+                .unwrap(),
+        );
+
+        Self {
+            server,
+            allocations: HashMap::new(),
+            next_wake: None,
+        }
+    }
+
+    fn port_of(&self, id: AllocationId) -> Option<u16> {
+        self.allocations
+            .iter()
+            .find_map(|(port, a)| (*a == id).then_some(*port))
+    }
+}
+
+struct InFlight {
+    at: Instant,
+    /// Breaks ties between packets sent at the same time, delivering them in the order they were sent.
+    sequence: u64,
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: Vec<u8>,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.sequence) == (other.at, other.sequence)
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.at, self.sequence).cmp(&(other.at, other.sequence))
+    }
+}
+
+fn start_system_time() -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+}
+
+/// An IPv4 packet from `10.0.0.1` to `10.0.0.2` with the given payload.
+fn ip_packet(payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; 20 + payload.len()];
+
+    let mut packet = MutableIpv4Packet::new(&mut buf).unwrap();
+    packet.set_version(4);
+    packet.set_header_length(5);
+    packet.set_total_length((20 + payload.len()) as u16);
+    packet.set_ttl(64);
+    packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+    packet.set_source(Ipv4Addr::new(10, 0, 0, 1));
+    packet.set_destination(Ipv4Addr::new(10, 0, 0, 2));
+    packet.set_payload(payload);
+    let checksum = checksum(&packet.to_immutable());
+    packet.set_checksum(checksum);
+
+    buf
+}
@@ -0,0 +1,268 @@
+mod sim;
+
+use sim::{NatType, Simulation, CONNECTION};
+use snownet::{ConnectionStats, Event, NominationPolicy, PathType};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+#[test]
+fn connects_directly_between_public_hosts() {
+    let mut sim = Simulation::new(0);
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), both_established));
+    assert!(ping(&mut sim));
+    assert_eq!(client_path(&sim), Some(PathType::Host));
+}
+
+#[test]
+fn hole_punches_through_port_restricted_cone_nats() {
+    let mut sim = Simulation::new(1)
+        .with_client_nat(NatType::PortRestrictedCone)
+        .with_server_nat(NatType::PortRestrictedCone);
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), both_established));
+    assert!(sim.run_until(Duration::from_secs(30), |sim| {
+        client_path(sim) == Some(PathType::ServerReflexive)
+    }));
+    assert!(ping(&mut sim));
+}
+
+#[test]
+fn falls_back_to_relay_between_symmetric_nats() {
+    let mut sim = Simulation::new(2)
+        .with_client_nat(NatType::Symmetric)
+        .with_server_nat(NatType::Symmetric);
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), both_established));
+    assert!(ping(&mut sim));
+    assert_eq!(client_path(&sim), Some(PathType::Relayed));
+}
+
+#[test]
+fn establishes_connection_despite_packet_loss() {
+    let mut sim = Simulation::new(3)
+        .with_client_nat(NatType::FullCone)
+        .with_latency(Duration::from_millis(50))
+        .with_loss(0.1);
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(20), both_established));
+}
+
+#[test]
+fn reconnects_after_client_changes_network() {
+    let mut sim = Simulation::new(4)
+        .with_client_nat(NatType::PortRestrictedCone)
+        .with_server_nat(NatType::FullCone);
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), both_established));
+    assert!(ping(&mut sim));
+
+    sim.move_client(
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 64, 0, 2)), 52625),
+        Some((
+            NatType::PortRestrictedCone,
+            IpAddr::V4(Ipv4Addr::new(198, 51, 100, 3)),
+        )),
+    );
+
+    assert!(sim.run_until(Duration::from_secs(30), ping));
+    assert!(!sim
+        .client
+        .events
+        .contains(&Event::ConnectionFailed(CONNECTION)));
+}
+
+#[test]
+fn survives_local_ip_change() {
+    let mut sim = Simulation::new(11);
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), both_established));
+    assert!(ping(&mut sim));
+
+    // The client's old address is gone for good, only an ICE restart finds the new path.
+    sim.move_client(
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 4)), 52625),
+        None,
+    );
+
+    assert!(sim.run_until(Duration::from_secs(30), ping));
+    assert_eq!(client_path(&sim), Some(PathType::Host));
+    assert!(!sim
+        .client
+        .events
+        .contains(&Event::ConnectionFailed(CONNECTION)));
+    assert!(!sim
+        .server
+        .events
+        .contains(&Event::ConnectionFailed(CONNECTION)));
+}
+
+#[test]
+fn discovers_path_mtu() {
+    /// Encapsulating a packet with WireGuard adds a 16 byte header and a 16 byte authentication tag.
+    const WG_OVERHEAD: usize = 32;
+    const MAX_DATAGRAM_SIZE: usize = 1400;
+    const MAX_MTU: u16 = (MAX_DATAGRAM_SIZE - WG_OVERHEAD) as u16;
+
+    let mut sim = Simulation::new(5).with_max_datagram_size(MAX_DATAGRAM_SIZE);
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), both_established));
+    assert!(sim.run_until(Duration::from_secs(30), |sim| {
+        sim.client.node.path_mtu(CONNECTION) > Some(MAX_MTU - 8)
+    }));
+
+    let mtu = sim.client.node.path_mtu(CONNECTION).unwrap();
+
+    assert!(mtu <= MAX_MTU);
+    assert!(sim.client.events.contains(&Event::PathMtuChanged {
+        connection: CONNECTION,
+        mtu
+    }));
+    assert!(ping(&mut sim));
+}
+
+#[test]
+fn connects_with_post_quantum_psk() {
+    let mut sim = Simulation::new(6);
+    sim.client.node.set_post_quantum_psk(true);
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), both_established));
+    assert!(ping(&mut sim));
+}
+
+#[test]
+fn rotates_preshared_key_without_reconnecting() {
+    let mut sim = Simulation::new(7);
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), both_established));
+    assert!(ping(&mut sim));
+
+    sim.rotate_client_key();
+
+    assert!(sim.run_until(Duration::from_secs(10), |sim| {
+        sim.client
+            .events
+            .contains(&Event::PresharedKeyRotated(CONNECTION))
+            && sim
+                .server
+                .events
+                .contains(&Event::PresharedKeyRotated(CONNECTION))
+    }));
+    assert!(sim.run_until(Duration::from_secs(10), ping));
+    assert!(!sim
+        .client
+        .events
+        .contains(&Event::ConnectionFailed(CONNECTION)));
+}
+
+#[test]
+fn rotates_preshared_key_after_max_age() {
+    let mut sim = Simulation::new(8);
+    sim.server
+        .node
+        .set_max_key_age(Some(Duration::from_secs(60)));
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), both_established));
+    assert!(sim.run_until(Duration::from_secs(90), |sim| {
+        sim.client
+            .events
+            .contains(&Event::PresharedKeyRotated(CONNECTION))
+    }));
+    assert!(sim.run_until(Duration::from_secs(10), ping));
+}
+
+#[test]
+fn reports_rtt_and_loss_of_nominated_candidate_pair_only() {
+    let mut sim = Simulation::new(9);
+    // An address the client doesn't actually have, checks of all pairs with it fail.
+    sim.client
+        .node
+        .add_local_host_candidate(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(10, 99, 0, 2)),
+            52625,
+        ))
+        .unwrap();
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), both_established));
+    assert!(!sim.run_until(Duration::from_secs(10), |_| false));
+
+    let stats = client_stats(&sim).unwrap();
+
+    assert_eq!(stats.path, Some(PathType::Host));
+    assert_eq!(stats.loss, 0.0);
+    assert!(stats.rtt.is_some());
+}
+
+#[test]
+fn upgrades_to_direct_path_after_path_timeout() {
+    let mut sim = Simulation::new(10)
+        .with_client_nat(NatType::PortRestrictedCone)
+        .with_server_nat(NatType::PortRestrictedCone);
+    // Re-evaluate only after the paths found during the initial connectivity checks would have timed out.
+    sim.client.node.set_nomination_policy(NominationPolicy {
+        reevaluate_interval: Duration::from_secs(35),
+        ..NominationPolicy::default()
+    });
+    sim.connect();
+
+    assert!(sim.run_until(Duration::from_secs(10), both_established));
+    assert!(sim.run_until(Duration::from_secs(60), |sim| {
+        client_path(sim) == Some(PathType::ServerReflexive)
+    }));
+    assert!(!sim.run_until(Duration::from_secs(60), |sim| {
+        client_path(sim) != Some(PathType::ServerReflexive)
+    }));
+    assert!(ping(&mut sim));
+}
+
+fn both_established(sim: &mut Simulation) -> bool {
+    sim.client
+        .events
+        .contains(&Event::ConnectionEstablished(CONNECTION))
+        && sim
+            .server
+            .events
+            .contains(&Event::ConnectionEstablished(CONNECTION))
+}
+
+/// Sends a packet in each direction and checks that both arrive within a second.
+fn ping(sim: &mut Simulation) -> bool {
+    sim.client.received.clear();
+    sim.server.received.clear();
+
+    sim.send_from_client(b"ping");
+    sim.send_from_server(b"pong");
+
+    sim.run_until(Duration::from_secs(1), |sim| {
+        sim.server.received.contains(&b"ping".to_vec())
+            && sim.client.received.contains(&b"pong".to_vec())
+    })
+}
+
+fn client_path(sim: &Simulation) -> Option<PathType> {
+    client_stats(sim)?.path
+}
+
+fn client_stats(sim: &Simulation) -> Option<ConnectionStats> {
+    let (_, stats) = sim
+        .client
+        .node
+        .stats()
+        .1
+        .find(|(id, _)| *id == CONNECTION)?;
+
+    Some(stats)
+}
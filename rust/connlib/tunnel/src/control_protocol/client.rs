@@ -3,8 +3,8 @@ use std::{collections::HashSet, net::IpAddr, time::Instant};
 use boringtun::x25519::PublicKey;
 use connlib_shared::{
     messages::{
-        Answer, ClientPayload, DomainResponse, GatewayId, Key, Offer, Relay, RequestConnection,
-        ResourceDescription, ResourceId, ReuseConnection,
+        Answer, ClientPayload, DomainResponse, GatewayId, Key, KeyExchange, Offer, Relay,
+        RequestConnection, ResourceDescription, ResourceId, ReuseConnection,
     },
     Callbacks,
 };
@@ -93,6 +93,10 @@ where
                 ice_parameters: Offer {
                     username: offer.credentials.username,
                     password: offer.credentials.password,
+                    key_exchange: offer.key_exchange.map(|k| KeyExchange {
+                        x25519: Key(k.x25519),
+                        ml_kem: k.ml_kem,
+                    }),
                 },
                 domain: awaiting_connection.domain,
             },
@@ -155,6 +159,12 @@ where
                     username: rtc_ice_params.username,
                     password: rtc_ice_params.password,
                 },
+                key_exchange: rtc_ice_params
+                    .key_exchange
+                    .map(|k| snownet::KeyExchangeAnswer {
+                        x25519: k.x25519.0,
+                        ml_kem: k.ml_kem,
+                    }),
             },
             Instant::now(),
         );
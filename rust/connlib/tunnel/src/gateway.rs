@@ -6,8 +6,8 @@ use crate::Tunnel;
 use boringtun::x25519::PublicKey;
 use chrono::{DateTime, Utc};
 use connlib_shared::messages::{
-    Answer, ClientId, ConnectionAccepted, DomainResponse, Interface as InterfaceConfig, Key, Offer,
-    Relay, ResourceId,
+    Answer, ClientId, ConnectionAccepted, DomainResponse, Interface as InterfaceConfig, Key,
+    KeyExchange, Offer, Relay, ResourceId,
 };
 use connlib_shared::{Callbacks, Dname, Error, Result};
 use ip_network::IpNetwork;
@@ -106,6 +106,10 @@ where
                     username: offer.username,
                     password: offer.password,
                 },
+                key_exchange: offer.key_exchange.map(|k| snownet::KeyExchangeOffer {
+                    x25519: k.x25519.0,
+                    ml_kem: k.ml_kem,
+                }),
             },
             client,
             stun(&relays, |addr| {
@@ -129,6 +133,10 @@ where
             ice_parameters: Answer {
                 username: answer.credentials.username,
                 password: answer.credentials.password,
+                key_exchange: answer.key_exchange.map(|k| KeyExchange {
+                    x25519: Key(k.x25519),
+                    ml_kem: k.ml_kem,
+                }),
             },
             domain_response: domain.map(|domain| DomainResponse {
                 domain,
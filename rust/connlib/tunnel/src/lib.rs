@@ -12,7 +12,7 @@ use device_channel::Device;
 use futures_util::FutureExt;
 use peer::PacketTransform;
 use peer_store::PeerStore;
-use snownet::{Node, Server};
+use snownet::{GsoQueue, Node, Server};
 use sockets::{Received, Sockets};
 use std::{
     collections::HashSet,
@@ -43,6 +43,8 @@ mod sockets;
 mod utils;
 
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
+/// The maximum number of packets we read from the device before sending them in batches.
+const MAX_PACKETS_PER_BATCH: usize = 64;
 const DNS_QUERIES_QUEUE_SIZE: usize = 100;
 
 const REALM: &str = "firezone";
@@ -103,20 +105,21 @@ where
 
         ready!(self.connections_state.sockets.poll_send_ready(cx))?; // Ensure socket is ready before we read from device.
 
-        match self.device.poll_read(&mut self.read_buf, cx)? {
-            Poll::Ready(packet) => {
-                let Some((peer_id, packet)) = self.role_state.encapsulate(packet, Instant::now())
-                else {
-                    cx.waker().wake_by_ref();
-                    return Poll::Pending;
-                };
+        for _ in 0..MAX_PACKETS_PER_BATCH {
+            let Poll::Ready(packet) = self.device.poll_read(&mut self.read_buf, cx)? else {
+                break;
+            };
 
-                self.connections_state.send(peer_id, packet.as_immutable());
+            cx.waker().wake_by_ref();
 
-                cx.waker().wake_by_ref();
-            }
-            Poll::Pending => {}
+            let Some((peer_id, packet)) = self.role_state.encapsulate(packet, Instant::now())
+            else {
+                continue;
+            };
+
+            self.connections_state.send(peer_id, packet.as_immutable());
         }
+        self.connections_state.send_batches();
 
         // After any state change, check what the new timeout is and reset it if necessary.
         if self.connections_state.poll_timeout(cx).is_ready() {
@@ -161,21 +164,21 @@ where
 
         ready!(self.connections_state.sockets.poll_send_ready(cx))?; // Ensure socket is ready before we read from device.
 
-        match self.device.poll_read(&mut self.read_buf, cx)? {
-            Poll::Ready(packet) => {
-                let Some((peer_id, packet)) = self.role_state.encapsulate(packet) else {
-                    cx.waker().wake_by_ref();
-                    return Poll::Pending;
-                };
+        for _ in 0..MAX_PACKETS_PER_BATCH {
+            let Poll::Ready(packet) = self.device.poll_read(&mut self.read_buf, cx)? else {
+                // device not ready for reading, moving on ..
+                break;
+            };
 
-                self.connections_state.send(peer_id, packet.as_immutable());
+            cx.waker().wake_by_ref();
 
-                cx.waker().wake_by_ref();
-            }
-            Poll::Pending => {
-                // device not ready for reading, moving on ..
-            }
+            let Some((peer_id, packet)) = self.role_state.encapsulate(packet) else {
+                continue;
+            };
+
+            self.connections_state.send(peer_id, packet.as_immutable());
         }
+        self.connections_state.send_batches();
 
         // After any state change, check what the new timeout is and reset it if necessary.
         if self.connections_state.poll_timeout(cx).is_ready() {
@@ -233,6 +236,8 @@ struct ConnectionState<TRole, TId> {
     timeout: Option<Pin<Box<tokio::time::Sleep>>>,
     stats_timer: tokio::time::Interval,
     sockets: Sockets,
+    /// Encapsulated packets waiting to be sent in batches.
+    gso_queue: GsoQueue,
 }
 
 impl<TRole, TId> ConnectionState<TRole, TId>
@@ -240,10 +245,14 @@ where
     TId: Eq + Hash + Copy + fmt::Display,
 {
     fn new(private_key: StaticSecret) -> Result<Self> {
+        let sockets = Sockets::new()?;
+
         Ok(ConnectionState {
+            // Not using post-quantum preshared keys (yet): gateways that predate them don't answer the key exchange, failing the connection.
             node: Node::new(private_key),
             write_buf: Box::new([0; MAX_UDP_SIZE]),
-            sockets: Sockets::new()?,
+            gso_queue: GsoQueue::new(sockets.max_gso_segments()),
+            sockets,
             stats_timer: tokio::time::interval(Duration::from_secs(60)),
             timeout: None,
         })
@@ -263,11 +272,20 @@ where
             return Ok(());
         };
 
-        self.sockets.try_send(&transmit)?;
+        self.gso_queue.enqueue(&transmit);
 
         Ok(())
     }
 
+    /// Sends all packets queued by [`ConnectionState::send`].
+    fn send_batches(&mut self) {
+        for batch in self.gso_queue.drain() {
+            if let Err(e) = self.sockets.try_send_batch(&batch) {
+                tracing::warn!(src = ?batch.src, dst = %batch.dst, num_segments = %batch.num_segments(), "Failed to send UDP packets: {e}");
+            }
+        }
+    }
+
     // TODO: passing the peer_store looks weird, we can just remove ConnectionState and move everything into Tunnel, there's no Mutexes any longer that justify this separation
     fn poll_sockets<TTransform, TResource>(
         &mut self,
@@ -352,13 +370,21 @@ where
             tracing::warn!("Failed to poll sockets for readiness: {e}");
         };
 
+        // A change of transport needs to take effect before we send the transmits that are meant for it.
+        let event = self.node.poll_event();
+        if let Some(snownet::Event::RelayTransportChanged { relay, transport }) = &event {
+            self.sockets.set_relay_transport(*relay, *transport);
+
+            cx.waker().wake_by_ref(); // There might be more events.
+        }
+
         while let Some(transmit) = self.node.poll_transmit() {
             if let Err(e) = self.sockets.try_send(&transmit) {
                 tracing::warn!(src = ?transmit.src, dst = %transmit.dst, "Failed to send UDP packet: {e}");
             }
         }
 
-        match self.node.poll_event() {
+        match event {
             Some(snownet::Event::SignalIceCandidate {
                 connection,
                 candidate,
@@ -1,20 +1,40 @@
 use bytes::Bytes;
-use core::slice;
+use futures::future::BoxFuture;
+use futures_util::FutureExt as _;
 use quinn_udp::{RecvMeta, UdpSockRef, UdpSocketState};
 use socket2::{SockAddr, Type};
 use std::{
+    collections::HashMap,
     io::{self, IoSliceMut},
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    pin::Pin,
     task::{ready, Context, Poll},
 };
-use tokio::{io::Interest, net::UdpSocket};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, Interest, ReadBuf},
+    net::{TcpSocket, TcpStream, UdpSocket},
+};
 
 use crate::{Error, Result, MAX_UDP_SIZE};
-use snownet::Transmit;
+use snownet::framing::{encode_frame, FrameDecoder};
+use snownet::{Batch, RelayTransport, Transmit};
+
+/// The number of datagrams we attempt to read with a single syscall.
+///
+/// On Linux, this uses `recvmmsg` and each datagram may consist of several segments coalesced by GRO.
+const NUM_RECV_BUFFERS: usize = 8;
+
+/// The maximum number of bytes we buffer for a [`RelayStream`] before dropping messages, just like a full UDP socket would.
+const MAX_STREAM_BUFFER: usize = 1024 * 1024;
 
 pub struct Sockets {
     socket_v4: Option<Socket<MAX_UDP_SIZE>>,
     socket_v6: Option<Socket<MAX_UDP_SIZE>>,
+
+    /// Relays we talk to via TCP instead of UDP, see [`RelayTransport::Tcp`].
+    relay_streams: HashMap<SocketAddr, RelayStream>,
+    /// The messages read from [`Sockets::relay_streams`] in the last call to [`Sockets::poll_recv_from`].
+    stream_messages: Vec<StreamMessage>,
 }
 
 impl Sockets {
@@ -44,9 +64,27 @@ impl Sockets {
         Ok(Self {
             socket_v4: socket_v4.ok(),
             socket_v6: socket_v6.ok(),
+            relay_streams: HashMap::new(),
+            stream_messages: Vec::new(),
         })
     }
 
+    /// Configures how we talk to the given relay, see [`snownet::Event::RelayTransportChanged`].
+    ///
+    /// For [`RelayTransport::Tcp`], all traffic to and from this relay goes through a TCP connection which we establish right away.
+    pub fn set_relay_transport(&mut self, relay: SocketAddr, transport: RelayTransport) {
+        match transport {
+            RelayTransport::Udp => {
+                self.relay_streams.remove(&relay);
+            }
+            RelayTransport::Tcp => {
+                self.relay_streams
+                    .entry(relay)
+                    .or_insert_with(|| RelayStream::connect(relay));
+            }
+        }
+    }
+
     pub fn can_handle(&self, addr: &SocketAddr) -> bool {
         match addr {
             SocketAddr::V4(_) => self.socket_v4.is_some(),
@@ -77,10 +115,52 @@ impl Sockets {
             ready!(socket.poll_send_ready(cx))?;
         }
 
+        // Streams buffer whatever we cannot write right away, thus we never wait for them.
+        for stream in self.relay_streams.values_mut() {
+            stream.poll_send(cx);
+        }
+
         Poll::Ready(Ok(()))
     }
 
+    /// The maximum number of segments we can send in a single [`Batch`], 1 if GSO is not supported.
+    pub fn max_gso_segments(&self) -> usize {
+        self.socket_v4
+            .iter()
+            .chain(self.socket_v6.iter())
+            .map(|s| s.state.max_gso_segments())
+            .min()
+            .unwrap_or(1)
+    }
+
+    pub fn try_send_batch(&mut self, batch: &Batch) -> Result<usize> {
+        if let Some(stream) = self.relay_streams.get_mut(&batch.dst) {
+            for segment in batch.payload.chunks(batch.segment_size) {
+                stream.send(segment);
+            }
+
+            return Ok(batch.payload.len());
+        }
+
+        match batch.dst {
+            SocketAddr::V4(_) => {
+                let socket = self.socket_v4.as_ref().ok_or(Error::NoIpv4)?;
+                Ok(socket.try_send_batch(batch)?)
+            }
+            SocketAddr::V6(_) => {
+                let socket = self.socket_v6.as_ref().ok_or(Error::NoIpv6)?;
+                Ok(socket.try_send_batch(batch)?)
+            }
+        }
+    }
+
     pub fn try_send(&mut self, transmit: &Transmit) -> Result<usize> {
+        if let Some(stream) = self.relay_streams.get_mut(&transmit.dst) {
+            stream.send(&transmit.payload);
+
+            return Ok(transmit.payload.len());
+        }
+
         match transmit.dst {
             SocketAddr::V4(_) => {
                 let socket = self.socket_v4.as_ref().ok_or(Error::NoIpv4)?;
@@ -107,6 +187,18 @@ impl Sockets {
             iter.ip6 = Some(packets?);
         }
 
+        self.stream_messages.clear();
+        for stream in self.relay_streams.values_mut() {
+            stream.poll_recv(cx, &mut self.stream_messages);
+        }
+        if !self.stream_messages.is_empty() {
+            iter.stream = Some(self.stream_messages.iter().map(|message| Received {
+                local: message.local,
+                from: message.from,
+                packet: &message.packet,
+            }));
+        }
+
         if iter.is_empty() {
             return Poll::Pending;
         }
@@ -115,28 +207,31 @@ impl Sockets {
     }
 }
 
-struct PacketIter<T4, T6> {
+struct PacketIter<T4, T6, TS> {
     ip4: Option<T4>,
     ip6: Option<T6>,
+    stream: Option<TS>,
 }
 
-impl<T4, T6> PacketIter<T4, T6> {
+impl<T4, T6, TS> PacketIter<T4, T6, TS> {
     fn new() -> Self {
         Self {
             ip4: None,
             ip6: None,
+            stream: None,
         }
     }
 
     fn is_empty(&self) -> bool {
-        self.ip4.is_none() && self.ip6.is_none()
+        self.ip4.is_none() && self.ip6.is_none() && self.stream.is_none()
     }
 }
 
-impl<'a, T4, T6> Iterator for PacketIter<T4, T6>
+impl<'a, T4, T6, TS> Iterator for PacketIter<T4, T6, TS>
 where
     T4: Iterator<Item = Received<'a>>,
     T6: Iterator<Item = Received<'a>>,
+    TS: Iterator<Item = Received<'a>>,
 {
     type Item = Received<'a>;
 
@@ -149,6 +244,10 @@ where
             return Some(packet);
         }
 
+        if let Some(packet) = self.stream.as_mut().and_then(|i| i.next()) {
+            return Some(packet);
+        }
+
         None
     }
 }
@@ -163,7 +262,9 @@ struct Socket<const N: usize> {
     state: UdpSocketState,
     port: u16,
     socket: UdpSocket,
-    buffer: Box<[u8; N]>,
+    /// [`NUM_RECV_BUFFERS`] buffers of size `N`, back to back.
+    buffer: Box<[u8]>,
+    metas: [RecvMeta; NUM_RECV_BUFFERS],
 }
 
 impl<const N: usize> Socket<N> {
@@ -175,7 +276,8 @@ impl<const N: usize> Socket<N> {
             state: UdpSocketState::new(UdpSockRef::from(&socket))?,
             port,
             socket: tokio::net::UdpSocket::from_std(socket)?,
-            buffer: Box::new([0u8; N]),
+            buffer: vec![0u8; N * NUM_RECV_BUFFERS].into_boxed_slice(),
+            metas: [RecvMeta::default(); NUM_RECV_BUFFERS],
         })
     }
 
@@ -187,7 +289,8 @@ impl<const N: usize> Socket<N> {
             state: UdpSocketState::new(UdpSockRef::from(&socket))?,
             port,
             socket: tokio::net::UdpSocket::from_std(socket)?,
-            buffer: Box::new([0u8; N]),
+            buffer: vec![0u8; N * NUM_RECV_BUFFERS].into_boxed_slice(),
+            metas: [RecvMeta::default(); NUM_RECV_BUFFERS],
         })
     }
 
@@ -201,37 +304,51 @@ impl<const N: usize> Socket<N> {
             socket,
             buffer,
             state,
+            metas,
         } = self;
-
-        let bufs = &mut [IoSliceMut::new(buffer.as_mut())];
-        let mut meta = RecvMeta::default();
+        let port = *port;
 
         loop {
             ready!(socket.poll_recv_ready(cx))?;
 
+            let mut bufs = buffer
+                .chunks_mut(N)
+                .map(IoSliceMut::new)
+                .collect::<Vec<_>>();
+
             if let Ok(len) = socket.try_io(Interest::READABLE, || {
-                state.recv((&socket).into(), bufs, slice::from_mut(&mut meta))
+                state.recv((&socket).into(), &mut bufs, metas.as_mut_slice())
             }) {
-                debug_assert_eq!(len, 1);
-
-                if meta.len == 0 {
-                    continue;
-                }
-
-                let Some(local_ip) = meta.dst_ip else {
-                    tracing::warn!("Skipping packet without local IP");
-                    continue;
-                };
-
-                let local = SocketAddr::new(local_ip, *port);
-
-                let iter = buffer[..meta.len]
-                    .chunks(meta.stride)
-                    .map(move |packet| Received {
-                        local,
-                        from: meta.addr,
-                        packet,
+                drop(bufs);
+
+                let iter = buffer
+                    .chunks(N)
+                    .zip(metas.iter())
+                    .take(len)
+                    .filter_map(move |(buffer, meta)| {
+                        if meta.len == 0 {
+                            return None;
+                        }
+
+                        let Some(local_ip) = meta.dst_ip else {
+                            tracing::warn!("Skipping packet without local IP");
+                            return None;
+                        };
+
+                        let local = SocketAddr::new(local_ip, port);
+
+                        // With GRO, a single datagram may contain several segments of `stride` bytes.
+                        let packets = buffer[..meta.len]
+                            .chunks(meta.stride)
+                            .map(move |packet| Received {
+                                local,
+                                from: meta.addr,
+                                packet,
+                            });
+
+                        Some(packets)
                     })
+                    .flatten()
                     .inspect(|r| {
                         tracing::trace!(target: "wire", from = "network", src = %r.from, dst = %r.local, num_bytes = %r.packet.len());
                     });
@@ -264,6 +381,224 @@ impl<const N: usize> Socket<N> {
             }],
         )
     }
+
+    /// Sends all segments of the [`Batch`] with a single syscall using GSO.
+    fn try_send_batch(&self, batch: &Batch) -> io::Result<usize> {
+        tracing::trace!(target: "wire", to = "network", src = ?batch.src, dst = %batch.dst, num_bytes = %batch.payload.len(), num_segments = %batch.num_segments());
+
+        let segment_size = (batch.num_segments() > 1).then_some(batch.segment_size);
+
+        self.state.send(
+            (&self.socket).into(),
+            &[quinn_udp::Transmit {
+                destination: batch.dst,
+                ecn: None,
+                contents: Bytes::copy_from_slice(&batch.payload),
+                segment_size,
+                src_ip: batch.src.map(|s| s.ip()),
+            }],
+        )
+    }
+}
+
+/// A message read from a [`RelayStream`].
+struct StreamMessage {
+    local: SocketAddr,
+    from: SocketAddr,
+    packet: Vec<u8>,
+}
+
+/// A TCP connection to a relay, see [`RelayTransport::Tcp`].
+///
+/// Messages are framed as per [`snownet::framing`].
+/// If the connection fails, we drop everything that is buffered and reconnect once we have something to send again.
+/// Retransmitting lost messages is up to `snownet`, just like with UDP.
+struct RelayStream {
+    relay: SocketAddr,
+    state: StreamState,
+    decoder: FrameDecoder,
+    /// Framed messages that we could not yet write to the stream.
+    send_buffer: Vec<u8>,
+    recv_buffer: Box<[u8]>,
+}
+
+enum StreamState {
+    Connecting(BoxFuture<'static, io::Result<TcpStream>>),
+    Connected {
+        stream: TcpStream,
+        local: SocketAddr,
+    },
+    Closed,
+}
+
+impl RelayStream {
+    fn connect(relay: SocketAddr) -> Self {
+        Self {
+            relay,
+            state: StreamState::Connecting(connect_tcp(relay).boxed()),
+            decoder: FrameDecoder::default(),
+            send_buffer: Vec::new(),
+            recv_buffer: vec![0u8; MAX_UDP_SIZE].into_boxed_slice(),
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) {
+        if self.send_buffer.len() + message.len() > MAX_STREAM_BUFFER {
+            tracing::debug!(relay = %self.relay, "Send buffer of relay stream is full, dropping message");
+            return;
+        }
+
+        tracing::trace!(target: "wire", to = "network", dst = %self.relay, num_bytes = %message.len(), "TCP");
+
+        self.send_buffer.extend_from_slice(&encode_frame(message));
+
+        if let StreamState::Closed = self.state {
+            tracing::debug!(relay = %self.relay, "Reconnecting to relay via TCP");
+
+            self.state = StreamState::Connecting(connect_tcp(self.relay).boxed());
+            return;
+        }
+
+        if let StreamState::Connected { stream, .. } = &self.state {
+            if let Err(e) = try_write_all(stream, &mut self.send_buffer) {
+                self.close(e);
+            }
+        }
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>) {
+        if let Err(e) = self.poll_write_buffered(cx) {
+            self.close(e);
+        }
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>, messages: &mut Vec<StreamMessage>) {
+        if let Err(e) = self.poll_read_messages(cx, messages) {
+            self.close(e);
+        }
+    }
+
+    fn poll_write_buffered(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let Some((stream, _)) = self.state.poll_connected(self.relay, cx)? else {
+            return Ok(());
+        };
+
+        while !self.send_buffer.is_empty() {
+            let Poll::Ready(written) = Pin::new(&mut *stream).poll_write(cx, &self.send_buffer)?
+            else {
+                break;
+            };
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+
+            self.send_buffer.drain(..written);
+        }
+
+        Ok(())
+    }
+
+    fn poll_read_messages(
+        &mut self,
+        cx: &mut Context<'_>,
+        messages: &mut Vec<StreamMessage>,
+    ) -> io::Result<()> {
+        let Some((stream, local)) = self.state.poll_connected(self.relay, cx)? else {
+            return Ok(());
+        };
+
+        loop {
+            let mut buffer = ReadBuf::new(&mut self.recv_buffer);
+
+            let Poll::Ready(()) = Pin::new(&mut *stream).poll_read(cx, &mut buffer)? else {
+                break;
+            };
+            if buffer.filled().is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            tracing::trace!(target: "wire", from = "network", src = %self.relay, dst = %local, num_bytes = %buffer.filled().len(), "TCP");
+
+            self.decoder.push(buffer.filled());
+        }
+
+        while let Some(packet) = self.decoder.next_frame()? {
+            messages.push(StreamMessage {
+                local,
+                from: self.relay,
+                packet,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self, error: io::Error) {
+        tracing::warn!(relay = %self.relay, "TCP connection to relay failed: {error}");
+
+        self.state = StreamState::Closed;
+        self.decoder = FrameDecoder::default();
+        self.send_buffer.clear();
+    }
+}
+
+impl StreamState {
+    /// Drives the connection attempt, returning the stream once we are connected.
+    fn poll_connected(
+        &mut self,
+        relay: SocketAddr,
+        cx: &mut Context<'_>,
+    ) -> io::Result<Option<(&mut TcpStream, SocketAddr)>> {
+        if let StreamState::Connecting(connect) = self {
+            let Poll::Ready(stream) = connect.poll_unpin(cx) else {
+                return Ok(None);
+            };
+            let stream = stream?;
+            let local = stream.local_addr()?;
+
+            tracing::info!(%relay, %local, "Connected to relay via TCP");
+
+            *self = StreamState::Connected { stream, local };
+        }
+
+        match self {
+            StreamState::Connected { stream, local } => Ok(Some((stream, *local))),
+            StreamState::Connecting(_) | StreamState::Closed => Ok(None),
+        }
+    }
+}
+
+async fn connect_tcp(relay: SocketAddr) -> io::Result<TcpStream> {
+    let socket = match relay {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    #[cfg(target_os = "linux")]
+    {
+        socket2::SockRef::from(&socket).set_mark(crate::FIREZONE_MARK)?;
+    }
+
+    let stream = socket.connect(relay).await?;
+    stream.set_nodelay(true)?;
+
+    Ok(stream)
+}
+
+/// Writes as much of `buffer` as the stream accepts without blocking and removes it from the buffer.
+fn try_write_all(stream: &TcpStream, buffer: &mut Vec<u8>) -> io::Result<()> {
+    while !buffer.is_empty() {
+        match stream.try_write(buffer) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                buffer.drain(..written);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
 }
 
 fn make_socket(addr: impl Into<SocketAddr>) -> Result<std::net::UdpSocket> {
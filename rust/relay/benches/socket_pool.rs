@@ -0,0 +1,193 @@
+//! Compares the throughput of the `SocketPool` against spawning one task per allocation.
+//!
+//! Run with `cargo bench --bench socket_pool`.
+//! Each scenario binds `NUM_ALLOCATIONS` sockets on localhost and measures how long it takes until all packets sent to them arrive at the "eventloop".
+//! Because this is UDP, some packets may be dropped under load; the number of received packets is reported alongside the duration.
+//! We stop waiting once no packet has arrived for `IDLE_TIMEOUT`.
+
+#[cfg(target_os = "linux")]
+fn main() {
+    linux::main();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("The socket pool is only available on Linux");
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use firezone_relay::{
+        AddressFamily, AllocationId, ChannelTable, IpStack, SocketPool, SocketPoolEvent,
+    };
+    use futures::channel::mpsc;
+    use futures::{SinkExt, StreamExt};
+    use std::future::poll_fn;
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::num::NonZeroUsize;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const NUM_ALLOCATIONS: u16 = 1000;
+    const PACKETS_PER_ALLOCATION: usize = 100;
+    const PAYLOAD_SIZE: usize = 100;
+    const FIRST_PORT: u16 = 50_000;
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[tokio::main]
+    pub(crate) async fn main() {
+        let num_workers = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+        let (received, duration) = task_per_allocation().await;
+        report("task per allocation", received, duration);
+
+        let (received, duration) = socket_pool(num_workers).await;
+        report(
+            &format!("socket pool ({num_workers} workers)"),
+            received,
+            duration,
+        );
+    }
+
+    /// The design before the [`SocketPool`]: Each allocation has its own task and socket which forwards all packets to the eventloop via a channel.
+    async fn task_per_allocation() -> (usize, Duration) {
+        let (sender, mut receiver) = mpsc::channel::<(Vec<u8>, SocketAddr, u16)>(1);
+
+        let tasks = ports()
+            .map(|port| {
+                let mut sender = sender.clone();
+
+                tokio::spawn(async move {
+                    let socket = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, port))
+                        .await
+                        .unwrap();
+                    let mut buffer = [0u8; 65536];
+
+                    loop {
+                        let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+
+                        if sender
+                            .send((buffer[..len].to_vec(), from, port))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        tokio::time::sleep(Duration::from_millis(100)).await; // Let the tasks bind their sockets.
+
+        let result = measure(async move {
+            let mut received = 0;
+            let mut last_received_at = Instant::now();
+
+            while received < total_packets() {
+                let Ok(Some((data, _, _))) =
+                    tokio::time::timeout(IDLE_TIMEOUT, receiver.next()).await
+                else {
+                    break;
+                };
+                assert_eq!(data.len(), PAYLOAD_SIZE);
+                received += 1;
+                last_received_at = Instant::now();
+            }
+
+            (received, last_received_at)
+        })
+        .await;
+
+        for task in tasks {
+            task.abort();
+            let _ = task.await; // Make sure the socket is closed before the next scenario binds the same port.
+        }
+
+        result
+    }
+
+    async fn socket_pool(num_workers: usize) -> (usize, Duration) {
+        // The main sockets are not used here, let the OS pick a port so we don't clash with a running relay.
+        let mut pool = SocketPool::new(
+            num_workers,
+            IpStack::Ip4(Ipv4Addr::LOCALHOST),
+            0,
+            ChannelTable::new(),
+        )
+        .unwrap();
+
+        for (index, port) in ports().enumerate() {
+            let id = format!("AID-{index}").parse::<AllocationId>().unwrap();
+
+            pool.bind(id, AddressFamily::V4, port).unwrap();
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await; // Let the workers register their sockets.
+
+        measure(async move {
+            let mut received = 0;
+            let mut last_received_at = Instant::now();
+
+            while received < total_packets() {
+                let Ok(Some(event)) =
+                    tokio::time::timeout(IDLE_TIMEOUT, poll_fn(|cx| pool.poll_event(cx))).await
+                else {
+                    break;
+                };
+                let SocketPoolEvent::Data(data, _, _) = event else {
+                    panic!("Unexpected event");
+                };
+                assert_eq!(data.len(), PAYLOAD_SIZE);
+                received += 1;
+                last_received_at = Instant::now();
+            }
+
+            (received, last_received_at)
+        })
+        .await
+    }
+
+    /// Sends all packets from a separate thread and measures how long it takes until `receive` got the last one.
+    async fn measure(
+        receive: impl std::future::Future<Output = (usize, Instant)>,
+    ) -> (usize, Duration) {
+        let start = Instant::now();
+
+        let sender = thread::spawn(|| {
+            let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let payload = [1u8; PAYLOAD_SIZE];
+
+            for _ in 0..PACKETS_PER_ALLOCATION {
+                for port in ports() {
+                    socket
+                        .send_to(&payload, (Ipv4Addr::LOCALHOST, port))
+                        .unwrap();
+                }
+            }
+        });
+
+        let (received, last_received_at) = receive.await;
+
+        sender.join().unwrap();
+
+        (received, last_received_at.duration_since(start))
+    }
+
+    fn report(name: &str, received: usize, duration: Duration) {
+        let packets_per_sec = received as f64 / duration.as_secs_f64();
+
+        println!(
+            "{name}: received {received}/{} packets in {duration:?} ({packets_per_sec:.0} packets/s)",
+            total_packets()
+        );
+    }
+
+    fn ports() -> impl Iterator<Item = u16> {
+        FIRST_PORT..FIRST_PORT + NUM_ALLOCATIONS
+    }
+
+    fn total_packets() -> usize {
+        NUM_ALLOCATIONS as usize * PACKETS_PER_ALLOCATION
+    }
+}
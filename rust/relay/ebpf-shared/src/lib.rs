@@ -0,0 +1,66 @@
+//! Keys and values of the maps shared between the relay and its eBPF program.
+//!
+//! All fields of the keys are stored in network byte order so the eBPF program can compare them with the bytes of a packet as they are.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+/// The well-known port of the relay's main socket.
+pub const TURN_PORT: u16 = 3478;
+
+/// A client and the number of a channel it has bound.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ClientAndChannelV4 {
+    pub ipv4_address: [u8; 4],
+    pub port: [u8; 2],
+    pub channel: [u8; 2],
+}
+
+impl ClientAndChannelV4 {
+    pub fn new(ipv4_address: [u8; 4], port: u16, channel: u16) -> Self {
+        Self {
+            ipv4_address,
+            port: port.to_be_bytes(),
+            channel: channel.to_be_bytes(),
+        }
+    }
+}
+
+/// A peer and the port of the allocation it talks to.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PortAndPeerV4 {
+    pub ipv4_address: [u8; 4],
+    pub allocation_port: [u8; 2],
+    pub peer_port: [u8; 2],
+}
+
+impl PortAndPeerV4 {
+    pub fn new(ipv4_address: [u8; 4], allocation_port: u16, peer_port: u16) -> Self {
+        Self {
+            ipv4_address,
+            allocation_port: allocation_port.to_be_bytes(),
+            peer_port: peer_port.to_be_bytes(),
+        }
+    }
+}
+
+/// The number of bytes the eBPF program relayed on a channel.
+///
+/// Unlike the keys, these are native-endian because only the eBPF program and the relay ever look at them.
+/// The eBPF program updates them with atomic adds.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrafficV4 {
+    pub to_peer: u64,
+    pub from_peer: u64,
+}
+
+// SAFETY: Both types are `repr(C)`, contain only byte arrays and thus have no padding.
+#[cfg(feature = "std")]
+unsafe impl aya::Pod for ClientAndChannelV4 {}
+#[cfg(feature = "std")]
+unsafe impl aya::Pod for PortAndPeerV4 {}
+// SAFETY: `repr(C)` and contains only `u64`s, thus has no padding.
+#[cfg(feature = "std")]
+unsafe impl aya::Pod for TrafficV4 {}
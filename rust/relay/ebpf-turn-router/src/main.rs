@@ -0,0 +1,311 @@
+//! An XDP program that relays channel data between clients and peers without involving userspace.
+//!
+//! The relay publishes all bound channels in [`CHAN_TO_UDP_44`] and [`UDP_TO_CHAN_44`].
+//! Channel data from a client on one of these channels is unwrapped and sent to the peer from the allocation's port.
+//! Data from a peer is wrapped in a channel data message and sent to the client from the TURN port.
+//! The number of bytes relayed on each channel is counted in [`TRAFFIC_44`], from where the relay picks it up for its metrics.
+//!
+//! Only IPv4 is handled here.
+//! Everything we can't handle, e.g. IPv6, IP options or STUN messages, is passed to the network stack, i.e. the relay's userspace process.
+//! Both directions send the packet back out of the interface it arrived on, assuming peers and clients are reached via the same gateway.
+
+#![no_std]
+#![no_main]
+
+use aya_ebpf::bindings::xdp_action;
+use aya_ebpf::helpers::bpf_xdp_adjust_head;
+use aya_ebpf::macros::{map, xdp};
+use aya_ebpf::maps::HashMap;
+use aya_ebpf::programs::XdpContext;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use ebpf_shared::{ClientAndChannelV4, PortAndPeerV4, TrafficV4, TURN_PORT};
+
+const MAX_CHANNELS: u32 = 0x100000;
+
+const ETH_P_IPV4: [u8; 2] = 0x0800u16.to_be_bytes();
+const IPPROTO_UDP: u8 = 17;
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+#[map]
+static CHAN_TO_UDP_44: HashMap<ClientAndChannelV4, PortAndPeerV4> =
+    HashMap::with_max_entries(MAX_CHANNELS, 0);
+
+#[map]
+static UDP_TO_CHAN_44: HashMap<PortAndPeerV4, ClientAndChannelV4> =
+    HashMap::with_max_entries(MAX_CHANNELS, 0);
+
+/// Shared by all CPUs and updated with atomic adds.
+///
+/// A per-CPU map would save us the atomics but needs `MAX_CHANNELS` entries for every CPU, which adds up to gigabytes of kernel memory on hosts with many cores.
+#[map]
+static TRAFFIC_44: HashMap<ClientAndChannelV4, TrafficV4> =
+    HashMap::with_max_entries(MAX_CHANNELS, 0);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EthHdr {
+    dst: [u8; 6],
+    src: [u8; 6],
+    ether_type: [u8; 2],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Ipv4Hdr {
+    version_ihl: u8,
+    tos: u8,
+    total_len: [u8; 2],
+    id: [u8; 2],
+    frag_off: [u8; 2],
+    ttl: u8,
+    protocol: u8,
+    check: [u8; 2],
+    src: [u8; 4],
+    dst: [u8; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UdpHdr {
+    src: [u8; 2],
+    dst: [u8; 2],
+    len: [u8; 2],
+    check: [u8; 2],
+}
+
+const HEADERS_LEN: usize =
+    mem::size_of::<EthHdr>() + mem::size_of::<Ipv4Hdr>() + mem::size_of::<UdpHdr>();
+
+#[xdp]
+pub fn handle_turn(ctx: XdpContext) -> u32 {
+    try_handle_turn(&ctx).unwrap_or(xdp_action::XDP_PASS)
+}
+
+fn try_handle_turn(ctx: &XdpContext) -> Option<u32> {
+    let eth = *ptr_at::<EthHdr>(ctx, 0)?;
+    if eth.ether_type != ETH_P_IPV4 {
+        return None;
+    }
+
+    let ip = *ptr_at::<Ipv4Hdr>(ctx, mem::size_of::<EthHdr>())?;
+    if ip.version_ihl != 0x45 || ip.protocol != IPPROTO_UDP || ip.ttl <= 1 {
+        return None; // Not IPv4, has options, not UDP or about to expire.
+    }
+    if u16::from_be_bytes(ip.frag_off) & 0x3FFF != 0 {
+        return None; // Fragmented.
+    }
+
+    let udp = *ptr_at::<UdpHdr>(ctx, mem::size_of::<EthHdr>() + mem::size_of::<Ipv4Hdr>())?;
+    let udp_len = u16::from_be_bytes(udp.len) as usize;
+
+    // The lengths in the headers are controlled by the sender, only trust them if they match the actual packet.
+    // The packet may be longer than that because of Ethernet padding.
+    let packet_len = ctx.data_end() - ctx.data();
+    if udp_len > packet_len.checked_sub(mem::size_of::<EthHdr>() + mem::size_of::<Ipv4Hdr>())? {
+        return None;
+    }
+    if u16::from_be_bytes(ip.total_len) as usize != mem::size_of::<Ipv4Hdr>() + udp_len {
+        return None;
+    }
+
+    let udp_payload_len = udp_len.checked_sub(mem::size_of::<UdpHdr>())?;
+    let dst_port = u16::from_be_bytes(udp.dst);
+
+    if dst_port == TURN_PORT {
+        return relay_to_peer(ctx, eth, ip, udp, udp_payload_len);
+    }
+
+    relay_to_client(ctx, eth, ip, udp, udp_payload_len)
+}
+
+/// Unwraps channel data from a client and sends it to the peer.
+fn relay_to_peer(
+    ctx: &XdpContext,
+    eth: EthHdr,
+    ip: Ipv4Hdr,
+    udp: UdpHdr,
+    udp_payload_len: usize,
+) -> Option<u32> {
+    let header = *ptr_at::<[u8; CHANNEL_DATA_HEADER_LEN]>(ctx, HEADERS_LEN)?;
+    let channel = [header[0], header[1]];
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+    // Padding is optional over UDP, we only handle messages without it.
+    if length + CHANNEL_DATA_HEADER_LEN != udp_payload_len {
+        return None;
+    }
+
+    let key = ClientAndChannelV4 {
+        ipv4_address: ip.src,
+        port: udp.src,
+        channel,
+    };
+    // SAFETY: The value is copied out before anybody can modify the map.
+    let route = *unsafe { CHAN_TO_UDP_44.get(&key) }?;
+
+    // SAFETY: We only ever read from the packet through bounds-checked pointers.
+    if unsafe { bpf_xdp_adjust_head(ctx.ctx, CHANNEL_DATA_HEADER_LEN as i32) } != 0 {
+        return None;
+    }
+
+    let action = write_headers(
+        ctx,
+        eth,
+        ip,
+        ip.dst,
+        route.ipv4_address,
+        route.allocation_port,
+        route.peer_port,
+        length,
+    )?;
+    count_traffic(&key, length as u64, 0);
+
+    Some(action)
+}
+
+/// Wraps data from a peer in a channel data message and sends it to the client.
+fn relay_to_client(
+    ctx: &XdpContext,
+    eth: EthHdr,
+    ip: Ipv4Hdr,
+    udp: UdpHdr,
+    udp_payload_len: usize,
+) -> Option<u32> {
+    let key = PortAndPeerV4 {
+        ipv4_address: ip.src,
+        allocation_port: udp.dst,
+        peer_port: udp.src,
+    };
+    // SAFETY: The value is copied out before anybody can modify the map.
+    let route = *unsafe { UDP_TO_CHAN_44.get(&key) }?;
+
+    let length = u16::try_from(udp_payload_len).ok()?;
+
+    // SAFETY: We only ever read from the packet through bounds-checked pointers.
+    if unsafe { bpf_xdp_adjust_head(ctx.ctx, -(CHANNEL_DATA_HEADER_LEN as i32)) } != 0 {
+        return None;
+    }
+
+    let header = ptr_at::<[u8; CHANNEL_DATA_HEADER_LEN]>(ctx, HEADERS_LEN)?;
+    let length_bytes = length.to_be_bytes();
+    *header = [
+        route.channel[0],
+        route.channel[1],
+        length_bytes[0],
+        length_bytes[1],
+    ];
+
+    let action = write_headers(
+        ctx,
+        eth,
+        ip,
+        ip.dst,
+        route.ipv4_address,
+        TURN_PORT.to_be_bytes(),
+        route.port,
+        udp_payload_len + CHANNEL_DATA_HEADER_LEN,
+    )?;
+    count_traffic(&route, 0, udp_payload_len as u64);
+
+    Some(action)
+}
+
+/// Adds the relayed bytes to the counters of the channel.
+///
+/// The relay creates the counters before publishing a route and removes them only after withdrawing it, so they are always present unless we race with the removal.
+fn count_traffic(channel: &ClientAndChannelV4, to_peer: u64, from_peer: u64) {
+    let Some(traffic) = TRAFFIC_44.get_ptr_mut(channel) else {
+        return;
+    };
+
+    // SAFETY: The pointer is valid and aligned for as long as the entry exists and other CPUs only ever access the counters atomically as well.
+    unsafe {
+        AtomicU64::from_ptr(ptr::addr_of_mut!((*traffic).to_peer))
+            .fetch_add(to_peer, Ordering::Relaxed);
+        AtomicU64::from_ptr(ptr::addr_of_mut!((*traffic).from_peer))
+            .fetch_add(from_peer, Ordering::Relaxed);
+    }
+}
+
+/// Writes the headers of the outgoing packet to the (adjusted) start of the packet.
+///
+/// We send the packet back out of the interface it arrived on, thus the MAC addresses are swapped.
+#[allow(clippy::too_many_arguments)]
+fn write_headers(
+    ctx: &XdpContext,
+    eth: EthHdr,
+    ip: Ipv4Hdr,
+    src: [u8; 4],
+    dst: [u8; 4],
+    src_port: [u8; 2],
+    dst_port: [u8; 2],
+    udp_payload_len: usize,
+) -> Option<u32> {
+    let udp_len = u16::try_from(udp_payload_len + mem::size_of::<UdpHdr>()).ok()?;
+    let total_len = udp_len.checked_add(mem::size_of::<Ipv4Hdr>() as u16)?;
+
+    *ptr_at::<EthHdr>(ctx, 0)? = EthHdr {
+        dst: eth.src,
+        src: eth.dst,
+        ether_type: eth.ether_type,
+    };
+
+    let mut ip = Ipv4Hdr {
+        total_len: total_len.to_be_bytes(),
+        ttl: ip.ttl - 1,
+        check: [0, 0],
+        src,
+        dst,
+        ..ip
+    };
+    ip.check = checksum(&ip).to_be_bytes();
+    *ptr_at::<Ipv4Hdr>(ctx, mem::size_of::<EthHdr>())? = ip;
+
+    *ptr_at::<UdpHdr>(ctx, mem::size_of::<EthHdr>() + mem::size_of::<Ipv4Hdr>())? = UdpHdr {
+        src: src_port,
+        dst: dst_port,
+        len: udp_len.to_be_bytes(),
+        check: [0, 0], // The UDP checksum is optional for IPv4.
+    };
+
+    Some(xdp_action::XDP_TX)
+}
+
+fn checksum(ip: &Ipv4Hdr) -> u16 {
+    // SAFETY: `Ipv4Hdr` consists of exactly 20 bytes without padding.
+    let bytes = unsafe { &*(ip as *const Ipv4Hdr as *const [u8; 20]) };
+
+    let mut sum = 0u32;
+    for i in 0..10 {
+        sum += u16::from_be_bytes([bytes[2 * i], bytes[2 * i + 1]]) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Returns a pointer to a `T` at the given offset, if the packet is long enough.
+///
+/// The verifier insists on this check before every access.
+#[inline(always)]
+fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Option<&'static mut T> {
+    let start = ctx.data();
+    let end = ctx.data_end();
+
+    if start + offset + mem::size_of::<T>() > end {
+        return None;
+    }
+
+    // SAFETY: We just checked that the packet is long enough.
+    Some(unsafe { &mut *((start + offset) as *mut T) })
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    // SAFETY: The verifier rejects programs that could panic.
+    unsafe { core::hint::unreachable_unchecked() }
+}
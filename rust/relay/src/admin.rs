@@ -0,0 +1,233 @@
+//! An HTTP API for operators to inspect and manage a running relay.
+//!
+//! The [`Server`](crate::Server) is owned by the eventloop, thus all requests are forwarded to it via a channel.
+
+use crate::{AllocationId, AllocationInfo, ChannelInfo};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use futures::channel::{mpsc, oneshot};
+use futures::SinkExt;
+use std::time::SystemTime;
+
+/// A request from the admin API that needs to be answered by the eventloop.
+pub enum Request {
+    ListAllocations(oneshot::Sender<Vec<AllocationInfo>>),
+    /// Delete the given allocation, replying whether it existed.
+    DeleteAllocation(AllocationId, oneshot::Sender<bool>),
+    /// Stop accepting new allocations and shut down once all existing ones are gone.
+    StartDraining,
+}
+
+pub(crate) fn router(requests: mpsc::Sender<Request>) -> Router {
+    Router::new()
+        .route("/admin/allocations", get(list_allocations))
+        .route("/admin/allocations/:id", delete(delete_allocation))
+        .route("/admin/drain", post(start_draining))
+        .with_state(requests)
+}
+
+async fn list_allocations(
+    State(mut requests): State<mpsc::Sender<Request>>,
+) -> Result<Json<Vec<Allocation>>, StatusCode> {
+    let (sender, receiver) = oneshot::channel();
+
+    requests
+        .send(Request::ListAllocations(sender))
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    let allocations = receiver
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    Ok(Json(
+        allocations.into_iter().map(Allocation::from).collect(),
+    ))
+}
+
+async fn delete_allocation(
+    State(mut requests): State<mpsc::Sender<Request>>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    let Ok(id) = id.parse() else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    let (sender, receiver) = oneshot::channel();
+
+    if requests
+        .send(Request::DeleteAllocation(id, sender))
+        .await
+        .is_err()
+    {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    match receiver.await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+async fn start_draining(State(mut requests): State<mpsc::Sender<Request>>) -> StatusCode {
+    if requests.send(Request::StartDraining).await.is_err() {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    StatusCode::ACCEPTED
+}
+
+#[derive(serde::Serialize)]
+struct Allocation {
+    id: String,
+    client: String,
+    port: u16,
+    families: Vec<String>,
+    expires_at: u64,
+    bytes_to_peers: u64,
+    bytes_from_peers: u64,
+    channels: Vec<Channel>,
+}
+
+#[derive(serde::Serialize)]
+struct Channel {
+    number: u16,
+    peer: String,
+    bound: bool,
+    expires_at: u64,
+}
+
+impl From<AllocationInfo> for Allocation {
+    fn from(info: AllocationInfo) -> Self {
+        Self {
+            id: info.id.to_string(),
+            client: info.client.to_string(),
+            port: info.port,
+            families: info.families.iter().map(|f| f.to_string()).collect(),
+            expires_at: unix_timestamp(info.expires_at),
+            bytes_to_peers: info.bytes_to_peers,
+            bytes_from_peers: info.bytes_from_peers,
+            channels: info.channels.into_iter().map(Channel::from).collect(),
+        }
+    }
+}
+
+impl From<ChannelInfo> for Channel {
+    fn from(info: ChannelInfo) -> Self {
+        Self {
+            number: info.number,
+            peer: info.peer.to_string(),
+            bound: info.bound,
+            expires_at: unix_timestamp(info.expires_at),
+        }
+    }
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AddressFamily, ClientSocket, PeerSocket};
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn lists_allocations_as_json() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let eventloop = tokio::spawn(async move {
+            let Some(Request::ListAllocations(reply)) = receiver.next().await else {
+                panic!("expected request to list allocations")
+            };
+
+            reply.send(vec![allocation_info()]).unwrap();
+        });
+
+        let Json(allocations) = list_allocations(State(sender)).await.unwrap();
+        eventloop.await.unwrap();
+
+        assert_eq!(
+            serde_json::to_value(allocations).unwrap(),
+            serde_json::json!([{
+                "id": "AID-1",
+                "client": "198.51.100.1:40000",
+                "port": 49152,
+                "families": [AddressFamily::V4.to_string()],
+                "expires_at": 1_700_000_600,
+                "bytes_to_peers": 1000,
+                "bytes_from_peers": 2000,
+                "channels": [{
+                    "number": 0x4000,
+                    "peer": "203.0.113.1:50000",
+                    "bound": true,
+                    "expires_at": 1_700_000_300,
+                }],
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_request_to_start_draining() {
+        let (sender, mut receiver) = mpsc::channel(1);
+
+        let status = start_draining(State(sender)).await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(matches!(
+            receiver.next().await,
+            Some(Request::StartDraining)
+        ));
+    }
+
+    #[tokio::test]
+    async fn responds_with_not_found_for_unknown_allocation() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let eventloop = tokio::spawn(async move {
+            let Some(Request::DeleteAllocation(_, reply)) = receiver.next().await else {
+                panic!("expected request to delete allocation")
+            };
+
+            reply.send(false).unwrap();
+        });
+
+        let status = delete_allocation(State(sender), Path("AID-1".to_owned())).await;
+        eventloop.await.unwrap();
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_allocation_id() {
+        let (sender, _receiver) = mpsc::channel(1);
+
+        let status = delete_allocation(State(sender), Path("foo".to_owned())).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    fn allocation_info() -> AllocationInfo {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        AllocationInfo {
+            id: "AID-1".parse().unwrap(),
+            client: ClientSocket::new("198.51.100.1:40000".parse().unwrap()),
+            port: 49152,
+            families: vec![AddressFamily::V4],
+            expires_at: now + Duration::from_secs(600),
+            bytes_to_peers: 1000,
+            bytes_from_peers: 2000,
+            channels: vec![ChannelInfo {
+                number: 0x4000,
+                peer: PeerSocket::new("203.0.113.1:50000".parse().unwrap()),
+                bound: true,
+                expires_at: now + Duration::from_secs(300),
+            }],
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! One task per socket, used on platforms without `epoll` and `SO_REUSEPORT` where we cannot use the `SocketPool`.
+//!
+//! Every allocation has its own task and socket which forwards all packets to the eventloop via a channel.
+//! The main socket of each address family is driven by a task in the same way.
+//! The API mirrors the one of the `SocketPool` so the eventloop can use either.
+
+use crate::server::AllocationId;
+use crate::udp_socket::UdpSocket;
+use crate::{AddressFamily, ClientSocket, IpStack, PeerSocket};
+use anyhow::{anyhow, Context as _, Result};
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use opentelemetry::metrics::Counter;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::task::{ready, Context, Poll};
+use tokio::task;
+
+/// The maximum amount of items that can be buffered in the channel to a socket task.
+const MAX_BUFFERED_ITEMS: usize = 1000;
+
+/// Something that happened on one of the sockets.
+#[derive(Debug)]
+pub enum Event {
+    /// A peer sent data to an allocation.
+    Data(Vec<u8>, PeerSocket, AllocationId),
+    /// A client sent data to one of the main sockets.
+    ClientData(Vec<u8>, ClientSocket),
+    /// The socket of an allocation failed and has been removed.
+    SocketFailed(AllocationId, AddressFamily),
+}
+
+pub struct AllocationTasks {
+    allocations: HashMap<(AllocationId, AddressFamily), SocketTask>,
+    main_sockets: HashMap<AddressFamily, SocketTask>,
+    event_sender: mpsc::Sender<Event>,
+    event_receiver: mpsc::Receiver<Event>,
+
+    dropped_packets_counter: Counter<u64>,
+}
+
+impl AllocationTasks {
+    /// Binds a main socket on `port` for every address family of `public_address`.
+    pub fn new(public_address: IpStack, port: u16) -> Result<Self> {
+        let (event_sender, event_receiver) = mpsc::channel(MAX_BUFFERED_ITEMS);
+
+        let families = [
+            public_address.as_v4().map(|_| AddressFamily::V4),
+            public_address.as_v6().map(|_| AddressFamily::V6),
+        ];
+
+        let main_sockets = families
+            .into_iter()
+            .flatten()
+            .map(|family| {
+                let socket = UdpSocket::bind(family, port).with_context(|| {
+                    format!("Failed to bind main socket for {family} on port {port}")
+                })?;
+
+                let task = SocketTask::spawn(
+                    socket,
+                    event_sender.clone(),
+                    |data, sender| Event::ClientData(data, ClientSocket::new(sender)),
+                    None,
+                );
+
+                Ok((family, task))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        let dropped_packets_counter = opentelemetry_api::global::meter("relay")
+            .u64_counter("dropped_packets_total")
+            .with_description(
+                "The number of packets dropped because a socket task could not keep up",
+            )
+            .init();
+
+        Ok(Self {
+            allocations: Default::default(),
+            main_sockets,
+            event_sender,
+            event_receiver,
+            dropped_packets_counter,
+        })
+    }
+
+    /// Binds a new socket for the given allocation and spawns a task for it.
+    pub fn bind(&mut self, id: AllocationId, family: AddressFamily, port: u16) -> Result<()> {
+        let socket = UdpSocket::bind(family, port)?;
+
+        let task = SocketTask::spawn(
+            socket,
+            self.event_sender.clone(),
+            move |data, sender| Event::Data(data, PeerSocket::new(sender), id),
+            Some(Event::SocketFailed(id, family)),
+        );
+        self.allocations.insert((id, family), task);
+
+        tracing::debug!(allocation = %id, %family, %port, "Bound socket");
+
+        Ok(())
+    }
+
+    /// Closes the socket of the given allocation.
+    ///
+    /// Returns `false` if there is no such socket.
+    pub fn unbind(&mut self, id: AllocationId, family: AddressFamily) -> bool {
+        self.allocations.remove(&(id, family)).is_some()
+    }
+
+    /// Send data to a peer on the given allocation.
+    ///
+    /// In case the channel to the allocation's task is full, we drop the packet and record it in the `dropped_packets_total` metric.
+    /// An error is only returned if the task is gone, i.e. the allocation can no longer be served.
+    pub fn send(&mut self, id: AllocationId, data: Vec<u8>, recipient: PeerSocket) -> Result<()> {
+        let family = recipient.family();
+
+        let Some(task) = self.allocations.get_mut(&(id, family)) else {
+            tracing::debug!(allocation = %id, %family, "Unknown allocation");
+            return Ok(());
+        };
+
+        match task.sender.try_send((data, recipient.into_socket())) {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_full() => {
+                tracing::warn!(allocation = %id, "Send buffer for allocation is full, dropping packet");
+                self.dropped_packets_counter.add(1, &[]);
+
+                Ok(())
+            }
+            Err(_) => {
+                self.unbind(id, family);
+
+                Err(anyhow!("Task of allocation {id} is gone"))
+            }
+        }
+    }
+
+    /// Send data to a client from the main socket.
+    ///
+    /// Like [`AllocationTasks::send`], we drop the packet if the channel to the task is full.
+    pub fn send_to_client(&mut self, data: Vec<u8>, recipient: ClientSocket) -> Result<()> {
+        let family = recipient.family();
+
+        let main_socket = self
+            .main_sockets
+            .get_mut(&family)
+            .with_context(|| format!("No main socket for {family}"))?;
+
+        match main_socket.sender.try_send((data, recipient.into_socket())) {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_full() => {
+                tracing::warn!(%recipient, "Send buffer of main socket is full, dropping packet");
+                self.dropped_packets_counter.add(1, &[]);
+
+                Ok(())
+            }
+            Err(_) => Err(anyhow!("Task of main socket for {family} is gone")),
+        }
+    }
+
+    pub fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        let event = ready!(self.event_receiver.poll_next_unpin(cx));
+
+        if let Some(Event::SocketFailed(id, family)) = &event {
+            self.unbind(*id, *family);
+        }
+
+        Poll::Ready(event)
+    }
+}
+
+/// A task that reads from and writes to a single socket.
+///
+/// The task is aborted when this is dropped.
+struct SocketTask {
+    handle: task::JoinHandle<()>,
+    sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+}
+
+impl SocketTask {
+    /// Spawns a task for the given socket that turns every received packet into an [`Event`] via `make_event`.
+    ///
+    /// If the socket fails, the task emits `failed_event`, if any.
+    fn spawn(
+        socket: UdpSocket,
+        mut event_sender: mpsc::Sender<Event>,
+        make_event: impl Fn(Vec<u8>, SocketAddr) -> Event + Send + 'static,
+        failed_event: Option<Event>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(MAX_BUFFERED_ITEMS);
+
+        let handle = tokio::spawn(async move {
+            let Err(e) = drive_socket(socket, event_sender.clone(), receiver, make_event).await
+            else {
+                unreachable!()
+            };
+
+            tracing::warn!("Socket task failed: {e:#}");
+
+            if let Some(event) = failed_event {
+                let _ = event_sender.send(event).await;
+            }
+
+            // With the task stopping, the channel will be closed and any attempt to send data to it will fail.
+        });
+
+        Self { handle, sender }
+    }
+}
+
+impl Drop for SocketTask {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn drive_socket(
+    mut socket: UdpSocket,
+    mut event_sender: mpsc::Sender<Event>,
+    mut outbound_receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+    make_event: impl Fn(Vec<u8>, SocketAddr) -> Event,
+) -> Result<Infallible> {
+    loop {
+        tokio::select! {
+            result = socket.recv() => {
+                let (data, sender) = result?;
+                event_sender.send(make_event(data.to_vec(), sender)).await?;
+            }
+            maybe_item = outbound_receiver.next() => {
+                let (data, recipient) = maybe_item.context("Outbound data channel closed")?;
+                socket.send_to(&data, recipient).await?;
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use anyhow::{bail, Context as _};
 use base64::prelude::BASE64_STANDARD_NO_PAD;
 use base64::Engine;
 use once_cell::sync::Lazy;
@@ -7,6 +8,7 @@ use sha2::Sha256;
 use std::borrow::ToOwned;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::time::{Duration, SystemTime};
 use stun_codec::rfc5389::attributes::{MessageIntegrity, Realm, Username};
 use uuid::Uuid;
@@ -21,6 +23,9 @@ pub trait MessageIntegrityExt {
         username: &str,
         now: SystemTime,
     ) -> Result<(), Error>;
+
+    /// Verifies the message against a static password, see [`StaticCredentials`].
+    fn verify_static(&self, username: &str, password: &SecretString) -> Result<(), Error>;
 }
 
 impl MessageIntegrityExt for MessageIntegrity {
@@ -49,6 +54,80 @@ impl MessageIntegrityExt for MessageIntegrity {
 
         Ok(())
     }
+
+    fn verify_static(&self, username: &str, password: &SecretString) -> Result<(), Error> {
+        self.check_long_term_credential(
+            &Username::new(username.to_owned()).map_err(|_| Error::InvalidUsername)?,
+            &FIREZONE,
+            password.expose_secret(),
+        )
+        .map_err(|_| Error::InvalidPassword)?;
+
+        Ok(())
+    }
+}
+
+/// Static username / password pairs for RFC 5389 long-term credentials.
+///
+/// These are an alternative to the time-limited credentials derived from the relay's auth secret and allow running a relay without the portal.
+/// The textual format has one `username:password` pair per line.
+/// Empty lines and lines starting with `#` are ignored.
+#[derive(Default)]
+pub struct StaticCredentials {
+    inner: HashMap<String, SecretString>,
+}
+
+impl StaticCredentials {
+    pub fn password(&self, username: &str) -> Option<&SecretString> {
+        self.inner.get(username)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &SecretString)> {
+        self.inner
+            .iter()
+            .map(|(username, password)| (username.as_str(), password))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+impl FromStr for StaticCredentials {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut inner = HashMap::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (username, password) = line.split_once(':').with_context(|| {
+                format!("Line {} is not of the form `username:password`", index + 1)
+            })?;
+
+            if username.is_empty() || password.is_empty() {
+                bail!("Line {} has an empty username or password", index + 1);
+            }
+
+            if inner
+                .insert(username.to_owned(), SecretString::from(password.to_owned()))
+                .is_some()
+            {
+                bail!("Duplicate username `{username}` on line {}", index + 1);
+            }
+        }
+
+        Ok(Self { inner })
+    }
 }
 
 /// Tracks valid nonces for the TURN relay.
@@ -58,7 +137,7 @@ impl MessageIntegrityExt for MessageIntegrity {
 ///
 /// For simplicity reasons, we use a count-based strategy.
 /// Each nonce can be used for a certain number of requests before it is invalid.
-#[derive(Default)]
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Nonces {
     inner: HashMap<Uuid, u64>,
 }
@@ -241,6 +320,56 @@ mod tests {
         assert_eq!(result.unwrap_err(), Error::InvalidUsername)
     }
 
+    #[test]
+    fn static_credentials_are_valid() {
+        let credentials = "alice:secret1".parse::<StaticCredentials>().unwrap();
+        let message_integrity = static_message_integrity("alice", "secret1");
+
+        let result =
+            message_integrity.verify_static("alice", credentials.password("alice").unwrap());
+
+        result.expect("credentials to be valid");
+    }
+
+    #[test]
+    fn wrong_static_password_is_not_valid() {
+        let credentials = "alice:secret1".parse::<StaticCredentials>().unwrap();
+        let message_integrity = static_message_integrity("alice", "secret2");
+
+        let result =
+            message_integrity.verify_static("alice", credentials.password("alice").unwrap());
+
+        assert_eq!(result.unwrap_err(), Error::InvalidPassword)
+    }
+
+    #[test]
+    fn parses_static_credentials_ignoring_comments_and_empty_lines() {
+        let credentials = "# lab relays\n\nalice:secret1\n  bob:sec:ret2  \n"
+            .parse::<StaticCredentials>()
+            .unwrap();
+
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(
+            credentials.password("alice").unwrap().expose_secret(),
+            "secret1"
+        );
+        assert_eq!(
+            credentials.password("bob").unwrap().expose_secret(),
+            "sec:ret2"
+        );
+        assert!(credentials.password("carol").is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_static_credentials() {
+        assert!("alice".parse::<StaticCredentials>().is_err());
+        assert!(":secret1".parse::<StaticCredentials>().is_err());
+        assert!("alice:".parse::<StaticCredentials>().is_err());
+        assert!("alice:secret1\nalice:secret2"
+            .parse::<StaticCredentials>()
+            .is_err());
+    }
+
     #[test]
     fn nonces_are_valid_for_100_requests() {
         let mut nonces = Nonces::default();
@@ -290,6 +419,16 @@ mod tests {
         .unwrap()
     }
 
+    fn static_message_integrity(username: &str, password: &str) -> MessageIntegrity {
+        MessageIntegrity::new_long_term_credential(
+            &sample_message(),
+            &Username::new(username.to_owned()).unwrap(),
+            &FIREZONE,
+            password,
+        )
+        .unwrap()
+    }
+
     fn sample_message() -> Message<Attribute> {
         Message::new(
             MessageClass::Request,
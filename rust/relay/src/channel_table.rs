@@ -0,0 +1,324 @@
+//! Channel bindings shared between the [`Server`](crate::Server) and the workers of the [`SocketPool`](crate::SocketPool).
+//!
+//! Relaying channel data is by far the most common thing a relay does.
+//! To spread this across all cores, the [`Server`](crate::Server) publishes every bound channel here.
+//! Workers look up incoming channel data and relay it directly, without funneling it through the eventloop.
+//! Anything that isn't found here takes the slow path via the [`Server`](crate::Server).
+//!
+//! If an [`ebpf::Program`] is attached, it is kept in sync with the table so it can relay IPv4 channel data without involving userspace at all.
+//! The bytes it relays are periodically collected via [`ChannelTable::collect_ebpf_traffic`] and added to the same counters as the ones relayed in userspace.
+
+// Routes are only looked up by the `SocketPool` which is Linux-only.
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+
+#[cfg(all(target_os = "linux", feature = "ebpf"))]
+use crate::ebpf;
+use crate::server::AllocationId;
+use crate::{ClientSocket, PeerSocket};
+use opentelemetry::metrics::{Counter, Unit};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+
+#[derive(Clone)]
+pub struct ChannelTable {
+    routes: Arc<RwLock<Routes>>,
+
+    data_relayed_counter: Counter<u64>,
+    data_relayed: Arc<AtomicU64>, // Keep a separate counter because `Counter` doesn't expose the current value :(
+}
+
+#[derive(Default)]
+struct Routes {
+    to_peer: HashMap<(ClientSocket, u16), ToPeer>,
+    to_client: HashMap<(AllocationId, PeerSocket), ToClient>,
+
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    ebpf: Option<ebpf::Program>,
+}
+
+/// Where to relay channel data from a client to.
+#[derive(Clone)]
+pub(crate) struct ToPeer {
+    pub(crate) allocation: AllocationId,
+    pub(crate) peer: PeerSocket,
+    pub(crate) traffic: Arc<Traffic>,
+    /// The port of the allocation.
+    #[cfg_attr(not(all(target_os = "linux", feature = "ebpf")), allow(dead_code))]
+    port: u16,
+}
+
+/// Where to relay data from a peer to.
+#[derive(Clone)]
+pub(crate) struct ToClient {
+    pub(crate) client: ClientSocket,
+    pub(crate) channel: u16,
+    pub(crate) traffic: Arc<Traffic>,
+}
+
+/// The number of bytes relayed by an allocation, updated by whoever relays them.
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    to_peers: AtomicU64,
+    from_peers: AtomicU64,
+}
+
+impl Traffic {
+    pub(crate) fn new(to_peers: u64, from_peers: u64) -> Self {
+        Self {
+            to_peers: AtomicU64::new(to_peers),
+            from_peers: AtomicU64::new(from_peers),
+        }
+    }
+
+    pub(crate) fn bytes_to_peers(&self) -> u64 {
+        self.to_peers.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes_from_peers(&self) -> u64 {
+        self.from_peers.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for ChannelTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChannelTable {
+    pub fn new() -> Self {
+        let data_relayed_counter = opentelemetry_api::global::meter("relay")
+            .u64_counter("data_relayed_bytes")
+            .with_description("The number of bytes relayed")
+            .with_unit(Unit::new("b"))
+            .init();
+
+        Self {
+            routes: Default::default(),
+            data_relayed_counter,
+            data_relayed: Default::default(),
+        }
+    }
+
+    /// Hands the relaying of all published channels to the given [`ebpf::Program`], as far as it supports them.
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    pub fn attach_ebpf(&self, mut program: ebpf::Program) {
+        let mut routes = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+
+        for ((client, channel), to_peer) in &routes.to_peer {
+            if let Err(e) = program.add_channel(*client, *channel, to_peer.port, to_peer.peer) {
+                tracing::warn!(target: "relay", %client, %channel, "Failed to add channel to eBPF program: {e}");
+            }
+        }
+
+        routes.ebpf = Some(program);
+    }
+
+    /// Records the bytes the [`ebpf::Program`] relayed since we last called this.
+    ///
+    /// Must be called periodically for the traffic metrics and counters of each allocation to include the data relayed in the kernel.
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    pub fn collect_ebpf_traffic(&self) {
+        let mut guard = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+        let routes = &mut *guard;
+
+        let Some(program) = routes.ebpf.as_mut() else {
+            return;
+        };
+
+        for ((client, channel), to_peer) in &routes.to_peer {
+            match program.take_traffic(*client, *channel) {
+                Ok((to_peers, from_peers)) => {
+                    self.record_ebpf_traffic(to_peer, to_peers, from_peers)
+                }
+                Err(e) => {
+                    tracing::debug!(target: "relay", %client, %channel, "Failed to read traffic from eBPF program: {e}");
+                }
+            }
+        }
+    }
+
+    /// Publishes a bound channel.
+    pub(crate) fn insert(
+        &self,
+        client: ClientSocket,
+        channel: u16,
+        allocation: AllocationId,
+        port: u16,
+        peer: PeerSocket,
+        traffic: Arc<Traffic>,
+    ) {
+        let mut routes = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+
+        #[cfg(all(target_os = "linux", feature = "ebpf"))]
+        if let Some(program) = routes.ebpf.as_mut() {
+            if let Err(e) = program.add_channel(client, channel, port, peer) {
+                tracing::warn!(target: "relay", %client, %channel, "Failed to add channel to eBPF program: {e}");
+            }
+        }
+
+        routes.to_peer.insert(
+            (client, channel),
+            ToPeer {
+                allocation,
+                peer,
+                traffic: traffic.clone(),
+                port,
+            },
+        );
+        routes.to_client.insert(
+            (allocation, peer),
+            ToClient {
+                client,
+                channel,
+                traffic,
+            },
+        );
+    }
+
+    /// Withdraws a channel, i.e. relaying data through it again requires the [`Server`](crate::Server) to approve it.
+    pub(crate) fn remove(&self, client: ClientSocket, channel: u16) {
+        let mut routes = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+
+        let Some(to_peer) = routes.to_peer.remove(&(client, channel)) else {
+            return;
+        };
+        routes.to_client.remove(&(to_peer.allocation, to_peer.peer));
+
+        #[cfg(all(target_os = "linux", feature = "ebpf"))]
+        if let Some(program) = routes.ebpf.as_mut() {
+            self.remove_from_ebpf(program, client, channel, &to_peer);
+        }
+    }
+
+    /// Withdraws all channels of the given allocation.
+    pub(crate) fn remove_allocation(&self, allocation: AllocationId) {
+        let mut guard = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+        let routes = &mut *guard;
+
+        #[cfg(all(target_os = "linux", feature = "ebpf"))]
+        if let Some(program) = routes.ebpf.as_mut() {
+            for ((client, channel), to_peer) in &routes.to_peer {
+                if to_peer.allocation == allocation {
+                    self.remove_from_ebpf(program, *client, *channel, to_peer);
+                }
+            }
+        }
+
+        routes
+            .to_peer
+            .retain(|_, to_peer| to_peer.allocation != allocation);
+        routes.to_client.retain(|(id, _), _| *id != allocation);
+    }
+
+    pub(crate) fn route_to_peer(&self, client: ClientSocket, channel: u16) -> Option<ToPeer> {
+        self.routes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .to_peer
+            .get(&(client, channel))
+            .cloned()
+    }
+
+    pub(crate) fn route_to_client(
+        &self,
+        allocation: AllocationId,
+        peer: PeerSocket,
+    ) -> Option<ToClient> {
+        self.routes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .to_client
+            .get(&(allocation, peer))
+            .cloned()
+    }
+
+    pub(crate) fn record_to_peers(&self, traffic: &Traffic, num_bytes: usize) {
+        traffic
+            .to_peers
+            .fetch_add(num_bytes as u64, Ordering::Relaxed);
+        self.record(num_bytes);
+    }
+
+    pub(crate) fn record_from_peers(&self, traffic: &Traffic, num_bytes: usize) {
+        traffic
+            .from_peers
+            .fetch_add(num_bytes as u64, Ordering::Relaxed);
+        self.record(num_bytes);
+    }
+
+    /// The number of bytes relayed in either direction, across all allocations.
+    pub(crate) fn num_relayed_bytes(&self) -> u64 {
+        self.data_relayed.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, num_bytes: usize) {
+        self.data_relayed_counter.add(num_bytes as u64, &[]);
+        self.data_relayed
+            .fetch_add(num_bytes as u64, Ordering::Relaxed);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    fn remove_from_ebpf(
+        &self,
+        program: &mut ebpf::Program,
+        client: ClientSocket,
+        channel: u16,
+        to_peer: &ToPeer,
+    ) {
+        match program.remove_channel(client, channel, to_peer.port, to_peer.peer) {
+            Ok((to_peers, from_peers)) => self.record_ebpf_traffic(to_peer, to_peers, from_peers),
+            Err(e) => {
+                tracing::warn!(target: "relay", %client, %channel, "Failed to remove channel from eBPF program: {e}");
+            }
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    fn record_ebpf_traffic(&self, to_peer: &ToPeer, to_peers: u64, from_peers: u64) {
+        if to_peers > 0 {
+            self.record_to_peers(&to_peer.traffic, to_peers as usize);
+        }
+        if from_peers > 0 {
+            self.record_from_peers(&to_peer.traffic, from_peers as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn removing_allocation_withdraws_routes_in_both_directions() {
+        let table = ChannelTable::new();
+        let client = ClientSocket::new("10.0.0.1:5000".parse::<SocketAddr>().unwrap());
+        let peer = PeerSocket::new("10.0.0.2:6000".parse::<SocketAddr>().unwrap());
+        let allocation = "AID-1".parse::<AllocationId>().unwrap();
+
+        table.insert(client, 0x4000, allocation, 49152, peer, Default::default());
+
+        assert!(table.route_to_peer(client, 0x4000).is_some());
+        assert!(table.route_to_client(allocation, peer).is_some());
+
+        table.remove_allocation(allocation);
+
+        assert!(table.route_to_peer(client, 0x4000).is_none());
+        assert!(table.route_to_client(allocation, peer).is_none());
+    }
+
+    #[test]
+    fn relayed_bytes_are_recorded_for_allocation_and_total() {
+        let table = ChannelTable::new();
+        let traffic = Traffic::default();
+
+        table.record_to_peers(&traffic, 100);
+        table.record_from_peers(&traffic, 50);
+
+        assert_eq!(traffic.bytes_to_peers(), 100);
+        assert_eq!(traffic.bytes_from_peers(), 50);
+        assert_eq!(table.num_relayed_bytes(), 150);
+    }
+}
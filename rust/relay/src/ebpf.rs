@@ -0,0 +1,154 @@
+//! Loads the XDP program that relays channel data in the kernel, see `ebpf-turn-router`.
+//!
+//! The program only knows about the channels we put into its maps.
+//! The [`ChannelTable`](crate::ChannelTable) does that for every channel it publishes.
+//! It also collects the number of bytes the program relayed on these channels, see [`Program::take_traffic`].
+
+use crate::{ClientSocket, PeerSocket};
+use anyhow::{Context as _, Result};
+use aya::maps::{HashMap, MapData};
+use aya::programs::{Xdp, XdpFlags};
+use aya::Bpf;
+use ebpf_shared::{ClientAndChannelV4, PortAndPeerV4, TrafficV4};
+use std::collections::HashMap as StdHashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+
+const PROGRAM_NAME: &str = "handle_turn";
+
+pub struct Program {
+    chan_to_udp_44: HashMap<MapData, ClientAndChannelV4, PortAndPeerV4>,
+    udp_to_chan_44: HashMap<MapData, PortAndPeerV4, ClientAndChannelV4>,
+    traffic_44: HashMap<MapData, ClientAndChannelV4, TrafficV4>,
+
+    /// The traffic of each channel as of the last call to [`Program::take_traffic`].
+    reported_traffic: StdHashMap<ClientAndChannelV4, TrafficV4>,
+
+    /// Detaches the program from the interface when dropped.
+    _bpf: Bpf,
+}
+
+impl Program {
+    /// Loads the compiled program from the given file and attaches it to the network interface.
+    pub fn try_load(object: &Path, interface: &str) -> Result<Self> {
+        let mut bpf = Bpf::load_file(object)
+            .with_context(|| format!("Failed to load eBPF object from {}", object.display()))?;
+
+        let program: &mut Xdp = bpf
+            .program_mut(PROGRAM_NAME)
+            .with_context(|| format!("eBPF object does not contain `{PROGRAM_NAME}`"))?
+            .try_into()?;
+        program.load().context("Failed to load XDP program")?;
+        program
+            .attach(interface, XdpFlags::default())
+            .with_context(|| format!("Failed to attach XDP program to {interface}"))?;
+
+        let chan_to_udp_44 = HashMap::try_from(
+            bpf.take_map("CHAN_TO_UDP_44")
+                .context("Missing map `CHAN_TO_UDP_44`")?,
+        )?;
+        let udp_to_chan_44 = HashMap::try_from(
+            bpf.take_map("UDP_TO_CHAN_44")
+                .context("Missing map `UDP_TO_CHAN_44`")?,
+        )?;
+        let traffic_44 = HashMap::try_from(
+            bpf.take_map("TRAFFIC_44")
+                .context("Missing map `TRAFFIC_44`")?,
+        )?;
+
+        Ok(Self {
+            chan_to_udp_44,
+            udp_to_chan_44,
+            traffic_44,
+            reported_traffic: StdHashMap::new(),
+            _bpf: bpf,
+        })
+    }
+
+    /// Makes the program relay data on the given channel.
+    ///
+    /// The program only handles IPv4, channels between other address families are left to userspace.
+    pub(crate) fn add_channel(
+        &mut self,
+        client: ClientSocket,
+        channel: u16,
+        port: u16,
+        peer: PeerSocket,
+    ) -> Result<()> {
+        let Some((client_and_channel, port_and_peer)) = keys(client, channel, port, peer) else {
+            return Ok(());
+        };
+
+        // The program only counts traffic on existing entries, create it before the routes.
+        self.traffic_44
+            .insert(client_and_channel, TrafficV4::default(), 0)?;
+        self.reported_traffic
+            .insert(client_and_channel, TrafficV4::default());
+
+        self.chan_to_udp_44
+            .insert(client_and_channel, port_and_peer, 0)?;
+        self.udp_to_chan_44
+            .insert(port_and_peer, client_and_channel, 0)?;
+
+        Ok(())
+    }
+
+    /// Stops relaying data on the given channel, returning the traffic not yet taken via [`Program::take_traffic`].
+    pub(crate) fn remove_channel(
+        &mut self,
+        client: ClientSocket,
+        channel: u16,
+        port: u16,
+        peer: PeerSocket,
+    ) -> Result<(u64, u64)> {
+        let Some((client_and_channel, port_and_peer)) = keys(client, channel, port, peer) else {
+            return Ok((0, 0));
+        };
+
+        self.chan_to_udp_44.remove(&client_and_channel)?;
+        self.udp_to_chan_44.remove(&port_and_peer)?;
+
+        let traffic = self.take_traffic(client, channel)?;
+        self.reported_traffic.remove(&client_and_channel);
+        self.traffic_44.remove(&client_and_channel)?;
+
+        Ok(traffic)
+    }
+
+    /// Returns the number of bytes relayed to and from peers on the given channel since the last call.
+    pub(crate) fn take_traffic(
+        &mut self,
+        client: ClientSocket,
+        channel: u16,
+    ) -> Result<(u64, u64)> {
+        let SocketAddr::V4(client) = client.into_socket() else {
+            return Ok((0, 0));
+        };
+        let key = ClientAndChannelV4::new(client.ip().octets(), client.port(), channel);
+
+        let total = self.traffic_44.get(&key, 0)?;
+        let reported = self.reported_traffic.insert(key, total).unwrap_or_default();
+
+        Ok((
+            total.to_peer.saturating_sub(reported.to_peer),
+            total.from_peer.saturating_sub(reported.from_peer),
+        ))
+    }
+}
+
+fn keys(
+    client: ClientSocket,
+    channel: u16,
+    port: u16,
+    peer: PeerSocket,
+) -> Option<(ClientAndChannelV4, PortAndPeerV4)> {
+    let (SocketAddr::V4(client), SocketAddr::V4(peer)) = (client.into_socket(), peer.into_socket())
+    else {
+        return None;
+    };
+
+    Some((
+        ClientAndChannelV4::new(client.ip().octets(), client.port(), channel),
+        PortAndPeerV4::new(peer.ip().octets(), port, peer.port()),
+    ))
+}
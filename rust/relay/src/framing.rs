@@ -0,0 +1,158 @@
+//! Framing of STUN and channel data messages on stream-oriented transports like TCP and TLS.
+//!
+//! Over UDP, every datagram contains exactly one message.
+//! Over a stream, messages are sent back-to-back and need to be split using their length fields.
+//! Channel data messages are additionally padded to a multiple of 4 bytes.
+//!
+//! See <https://www.rfc-editor.org/rfc/rfc8656#section-12.5> for details.
+
+use bytes::{Buf, BytesMut};
+use std::io;
+
+const STUN_HEADER_LEN: usize = 20;
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+/// Splits a stream of bytes into individual STUN or channel data messages.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: BytesMut,
+}
+
+impl FrameDecoder {
+    /// Appends bytes read from the stream to the internal buffer.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete message, if any.
+    ///
+    /// An error means the stream is corrupted and the connection should be closed.
+    /// Any padding is stripped from channel data messages.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, io::Error> {
+        let Some((message_len, frame_len)) = frame_len(&self.buffer)? else {
+            return Ok(None);
+        };
+
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+
+        let message = self.buffer.split_to(message_len).to_vec();
+        self.buffer.advance(frame_len - message_len);
+
+        Ok(Some(message))
+    }
+
+    /// Returns the bytes that are not (yet) part of a complete message.
+    pub fn into_remaining(self) -> Vec<u8> {
+        self.buffer.to_vec()
+    }
+}
+
+/// Prepares a message for being written to a stream.
+///
+/// STUN messages are always a multiple of 4 bytes long.
+/// Channel data messages are padded to the next multiple of 4.
+pub fn encode_frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(message.len() + 3);
+    frame.extend_from_slice(message);
+
+    if is_channel_data(message) {
+        frame.resize(padded_len(message.len()), 0);
+    }
+
+    frame
+}
+
+/// Computes the length of the message at the start of `buffer` and the length of the frame including padding.
+fn frame_len(buffer: &[u8]) -> Result<Option<(usize, usize)>, io::Error> {
+    match buffer.first() {
+        Some(0..=3) => {
+            if buffer.len() < STUN_HEADER_LEN {
+                return Ok(None);
+            }
+
+            let attributes_len = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+            let message_len = STUN_HEADER_LEN + attributes_len;
+
+            Ok(Some((message_len, message_len)))
+        }
+        Some(64..=79) => {
+            if buffer.len() < CHANNEL_DATA_HEADER_LEN {
+                return Ok(None);
+            }
+
+            let payload_len = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+            let message_len = CHANNEL_DATA_HEADER_LEN + payload_len;
+
+            Ok(Some((message_len, padded_len(message_len))))
+        }
+        Some(other) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown message type {other}"),
+        )),
+        None => Ok(None),
+    }
+}
+
+fn is_channel_data(message: &[u8]) -> bool {
+    matches!(message.first(), Some(64..=79))
+}
+
+fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BINDING_REQUEST: [u8; 20] = hex_literal::hex!("000100002112a442000000000000000000000000");
+
+    #[test]
+    fn splits_back_to_back_stun_messages() {
+        let mut decoder = FrameDecoder::default();
+
+        decoder.push(&BINDING_REQUEST);
+        decoder.push(&BINDING_REQUEST);
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), BINDING_REQUEST);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), BINDING_REQUEST);
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn waits_for_complete_message() {
+        let mut decoder = FrameDecoder::default();
+
+        decoder.push(&BINDING_REQUEST[..10]);
+        assert!(decoder.next_frame().unwrap().is_none());
+
+        decoder.push(&BINDING_REQUEST[10..]);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), BINDING_REQUEST);
+    }
+
+    #[test]
+    fn strips_padding_of_channel_data() {
+        let mut decoder = FrameDecoder::default();
+        let message = [0x40, 0x00, 0x00, 0x03, 1, 2, 3];
+
+        let frame = encode_frame(&message);
+        assert_eq!(frame.len(), 8);
+
+        decoder.push(&frame);
+        decoder.push(&BINDING_REQUEST);
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), message);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), BINDING_REQUEST);
+    }
+
+    #[test]
+    fn rejects_unknown_message_type() {
+        let mut decoder = FrameDecoder::default();
+
+        decoder.push(&[0xFF, 0x00, 0x00, 0x00]);
+
+        assert!(decoder.next_frame().is_err());
+    }
+}
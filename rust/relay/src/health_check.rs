@@ -1,16 +1,114 @@
+use crate::admin;
 use anyhow::Result;
+use axum::extract::State;
+use axum::http::StatusCode;
 use axum::routing::get;
 use axum::Router;
+use futures::channel::mpsc;
+use prometheus::TextEncoder;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-pub async fn serve(addr: impl Into<SocketAddr>) -> Result<()> {
+/// The health of the relay, shared between the eventloop and the health-check endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    draining: Arc<AtomicBool>,
+}
+
+impl Status {
+    /// Signal load balancers that they should stop routing new clients to us.
+    pub fn set_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+/// Serves the health-check endpoint, our metrics and, if enabled, the [`admin`] API.
+///
+/// Whilst draining, the health-check responds with `503 Service Unavailable`.
+/// Metrics are served at `/metrics` in the Prometheus text format.
+pub async fn serve(
+    addr: impl Into<SocketAddr>,
+    status: Status,
+    metrics: prometheus::Registry,
+    admin_requests: Option<mpsc::Sender<admin::Request>>,
+) -> Result<()> {
     let addr = addr.into();
 
-    let service = Router::new()
-        .route("/healthz", get(|| async { "" }))
-        .into_make_service();
+    let mut router = Router::new()
+        .route("/healthz", get(healthz))
+        .with_state(status)
+        .merge(
+            Router::new()
+                .route("/metrics", get(metrics_handler))
+                .with_state(metrics),
+        );
+    if let Some(requests) = admin_requests {
+        router = router.merge(admin::router(requests));
+    }
 
-    axum::serve(tokio::net::TcpListener::bind(addr).await?, service).await?;
+    axum::serve(
+        tokio::net::TcpListener::bind(addr).await?,
+        router.into_make_service(),
+    )
+    .await?;
 
     Ok(())
 }
+
+async fn metrics_handler(
+    State(registry): State<prometheus::Registry>,
+) -> Result<String, StatusCode> {
+    TextEncoder::new()
+        .encode_to_string(&registry.gather())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn healthz(State(status): State<Status>) -> (StatusCode, &'static str) {
+    if status.is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "draining");
+    }
+
+    (StatusCode::OK, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn healthz_is_unavailable_whilst_draining() {
+        let status = Status::default();
+
+        assert_eq!(healthz(State(status.clone())).await.0, StatusCode::OK);
+
+        status.set_draining();
+
+        assert_eq!(
+            healthz(State(status)).await,
+            (StatusCode::SERVICE_UNAVAILABLE, "draining")
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_in_prometheus_text_format() {
+        let registry = prometheus::Registry::new();
+        let counter =
+            prometheus::IntCounter::new("allocations_total", "The number of allocations").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc_by(3);
+
+        let body = metrics_handler(State(registry)).await.unwrap();
+
+        assert_eq!(
+            body,
+            "# HELP allocations_total The number of allocations\n\
+             # TYPE allocations_total counter\n\
+             allocations_total 3\n"
+        );
+    }
+}
@@ -1,23 +1,42 @@
-mod allocation;
+#[cfg(not(target_os = "linux"))]
+mod allocation_tasks;
 mod auth;
+mod channel_table;
 mod net_ext;
 mod server;
 mod sleep;
+#[cfg(target_os = "linux")]
+mod socket_pool;
+mod tcp_allocation;
+mod tcp_listener;
 mod time_events;
 mod udp_socket;
 
+pub mod admin;
+#[cfg(all(target_os = "linux", feature = "ebpf"))]
+pub mod ebpf;
+pub mod framing;
 pub mod health_check;
 #[cfg(feature = "proptest")]
 pub mod proptest;
+pub mod selftest;
 
-pub use allocation::Allocation;
+#[cfg(not(target_os = "linux"))]
+pub use allocation_tasks::{AllocationTasks, Event as AllocationTasksEvent};
+pub use auth::StaticCredentials;
+pub use channel_table::ChannelTable;
 pub use net_ext::IpAddrExt;
 pub use server::{
-    Allocate, AllocationId, Attribute, Binding, ChannelBind, ChannelData, ClientMessage, Command,
-    CreatePermission, Refresh, Server,
+    Allocate, AllocationId, AllocationInfo, Attribute, Binding, ChannelBind, ChannelData,
+    ChannelInfo, ClientMessage, Command, Connect, ConnectionBind, ConnectionId, CreatePermission,
+    LoadThresholds, MobilityTicket, PeerPolicy, Quotas, Refresh, Server, Snapshot,
 };
 pub use sleep::Sleep;
+#[cfg(target_os = "linux")]
+pub use socket_pool::{Event as SocketPoolEvent, SocketPool};
 pub use stun_codec::rfc8656::attributes::AddressFamily;
+pub use tcp_allocation::{connect_to_peer, TcpAllocation};
+pub use tcp_listener::TcpListener;
 pub use udp_socket::UdpSocket;
 
 pub(crate) use time_events::TimeEvents;
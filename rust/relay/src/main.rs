@@ -1,25 +1,41 @@
 use anyhow::{anyhow, bail, Context, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
+#[cfg(all(target_os = "linux", feature = "ebpf"))]
+use firezone_relay::ebpf;
+use firezone_relay::framing::{self, FrameDecoder};
+use firezone_relay::selftest;
 use firezone_relay::{
-    AddressFamily, Allocation, AllocationId, ClientSocket, Command, IpStack, PeerSocket, Server,
-    Sleep, UdpSocket,
+    admin, connect_to_peer, health_check, AddressFamily, AllocationId, ClientSocket, Command,
+    ConnectionId, IpStack, LoadThresholds, PeerPolicy, PeerSocket, Quotas, Server, Sleep, Snapshot,
+    StaticCredentials, TcpAllocation, TcpListener,
 };
+#[cfg(target_os = "linux")]
+use firezone_relay::{SocketPool, SocketPoolEvent};
+// Without `epoll` and `SO_REUSEPORT`, we fall back to one task per allocation which offers the same API.
+#[cfg(not(target_os = "linux"))]
+use firezone_relay::{AllocationTasks as SocketPool, AllocationTasksEvent as SocketPoolEvent};
 use futures::channel::mpsc;
 use futures::{future, FutureExt, SinkExt, StreamExt};
+use ip_network::IpNetwork;
 use opentelemetry::{sdk, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
 use phoenix_channel::{Event, LoginUrl, PhoenixChannel};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use secrecy::{Secret, SecretString};
-use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::convert::Infallible;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::task::{ready, Poll};
+use std::sync::Arc;
+use std::task::Poll;
 use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tracing::{level_filters::LevelFilter, Instrument, Subscriber};
 use tracing_core::Dispatch;
 use tracing_stackdriver::CloudTraceConfiguration;
@@ -27,9 +43,25 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilte
 use url::Url;
 
 const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+const STATE_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+/// How often we collect the number of bytes relayed by the eBPF program.
+#[cfg(all(target_os = "linux", feature = "ebpf"))]
+const EBPF_TRAFFIC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The standard port for STUN & TURN, used for UDP and TCP.
+const TURN_PORT: u16 = 3478;
+
+/// The maximum number of messages we buffer for a single TCP / TLS client.
+const MAX_BUFFERED_STREAM_MESSAGES: usize = 100;
+
+/// How long we wait before accepting connections again after `accept` failed, e.g. because we ran out of file descriptors.
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Parser, Debug)]
 struct Args {
+    #[command(subcommand)]
+    action: Option<Action>,
+
     /// The public (i.e. internet-reachable) IPv4 address of the relay server.
     #[arg(long, env)]
     public_ip4_addr: Option<Ipv4Addr>,
@@ -39,8 +71,27 @@ struct Args {
     /// The address of the local interface where we should serve our health-check endpoint.
     ///
     /// The actual health-check endpoint will be at `http://<health_check_addr>/healthz`.
+    /// Metrics are served in the Prometheus text format at `http://<health_check_addr>/metrics`.
     #[arg(long, env, hide = true, default_value = "0.0.0.0:8080")]
     health_check_addr: SocketAddr,
+    /// Serve the admin API on `health_check_addr`.
+    ///
+    /// The admin API allows listing allocations at `/admin/allocations` and deleting them at `/admin/allocations/<id>`.
+    /// Only enable this if `health_check_addr` is not reachable from the internet.
+    #[arg(long, env, default_value_t = false)]
+    admin_api: bool,
+    /// For how many seconds to keep serving existing allocations once we start draining.
+    ///
+    /// Draining starts upon receiving SIGTERM or via the admin API.
+    /// A second SIGTERM shuts down the relay immediately.
+    #[arg(long, env, default_value = "300")]
+    drain_timeout_secs: u64,
+    /// Path to a file for persisting allocations across restarts.
+    ///
+    /// The state is written periodically and on shutdown and restored on startup.
+    /// It contains the auth secret and must thus be kept confidential.
+    #[arg(long, env)]
+    state_file: Option<PathBuf>,
     // See https://www.rfc-editor.org/rfc/rfc8656.html#name-allocations
     /// The lowest port used for TURN allocations.
     #[arg(long, env, hide = true, default_value = "49152")]
@@ -48,6 +99,70 @@ struct Args {
     /// The highest port used for TURN allocations.
     #[arg(long, env, hide = true, default_value = "65535")]
     highest_port: u16,
+    /// Accept TURN traffic over TCP on port 3478, in addition to UDP.
+    ///
+    /// Useful for clients behind firewalls that block outbound UDP.
+    #[arg(long, env, default_value_t = false)]
+    listen_tcp: bool,
+    /// Path to a PEM-encoded certificate chain for accepting TURN traffic over TLS.
+    ///
+    /// Must be set together with `tls_key_file`.
+    #[arg(long, env, requires = "tls_key_file")]
+    tls_cert_file: Option<PathBuf>,
+    /// Path to the PEM-encoded PKCS8 private key of the TLS certificate.
+    #[arg(long, env, requires = "tls_cert_file")]
+    tls_key_file: Option<PathBuf>,
+    /// The port on which we accept TURN traffic over TLS.
+    #[arg(long, env, default_value = "443")]
+    tls_port: u16,
+    /// The maximum number of concurrent allocations per username.
+    #[arg(long, env)]
+    max_allocations_per_user: Option<usize>,
+    /// The maximum number of concurrent channel bindings per username.
+    #[arg(long, env)]
+    max_channels_per_user: Option<usize>,
+    /// The maximum number of bytes per second relayed per username.
+    #[arg(long, env)]
+    max_bytes_per_sec_per_user: Option<u64>,
+    /// Sibling relays to redirect new allocations to when we are overloaded, e.g. `203.0.113.1:3478,[2001:db8::1]:3478`.
+    ///
+    /// Clients are redirected via `300 Try Alternate` once one of the `redirect_*` thresholds is exceeded or we run out of ports.
+    /// All alternate servers must accept the same credentials as this relay.
+    #[arg(long, env, value_delimiter = ',')]
+    alternate_servers: Vec<SocketAddr>,
+    /// Redirect new allocations once we have this many allocations.
+    #[arg(long, env)]
+    redirect_above_allocations: Option<usize>,
+    /// Redirect new allocations once we relay this many bytes per second, across all allocations.
+    #[arg(long, env)]
+    redirect_above_bytes_per_sec: Option<u64>,
+    /// Redirect new allocations once fewer than this many ports are available.
+    #[arg(long, env)]
+    redirect_below_free_ports: Option<usize>,
+    /// Networks clients may relay to even if they are denied, e.g. `10.0.0.0/8,fd00::/8`.
+    ///
+    /// By default, loopback, link-local, private, multicast and unspecified addresses are denied.
+    #[arg(long, env, value_delimiter = ',')]
+    allow_peers: Vec<IpNetwork>,
+    /// Networks clients may not relay to, in addition to the default ones.
+    #[arg(long, env, value_delimiter = ',')]
+    deny_peers: Vec<IpNetwork>,
+    /// The number of worker threads that drive the sockets of allocations.
+    ///
+    /// Defaults to the number of available CPU cores.
+    #[arg(long, env)]
+    num_workers: Option<usize>,
+    /// Path to the compiled `ebpf-turn-router` XDP program.
+    ///
+    /// If set, channel data between IPv4 clients and peers is relayed in the kernel, without going through userspace.
+    /// Must be set together with `ebpf_interface`.
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    #[arg(long, env, requires = "ebpf_interface")]
+    ebpf_program: Option<PathBuf>,
+    /// The network interface to attach the XDP program to.
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    #[arg(long, env, requires = "ebpf_program")]
+    ebpf_interface: Option<String>,
     #[arg(
         long,
         env = "FIREZONE_API_URL",
@@ -55,6 +170,15 @@ struct Args {
         default_value = "wss://api.firezone.dev"
     )]
     api_url: Url,
+    /// The secret from which the time-limited credentials of clients are derived.
+    ///
+    /// By default, a random secret is generated on startup and shared with the portal.
+    /// Set this to run standalone, i.e. without the portal, whilst still accepting credentials derived from a secret you control.
+    #[arg(long, env)]
+    auth_secret: Option<SecretString>,
+    /// Path to a file of static credentials to accept, one `username:password` pair per line.
+    #[arg(long, env)]
+    static_credentials_file: Option<PathBuf>,
     /// Token generated by the portal to authorize websocket connection.
     ///
     /// If omitted, we won't connect to the portal on startup.
@@ -88,6 +212,15 @@ struct Args {
     google_cloud_project_id: Option<String>,
 }
 
+#[derive(clap::Subcommand, Debug, Clone, Copy)]
+enum Action {
+    /// Check that the relay is reachable on its public addresses and exit.
+    ///
+    /// Runs a TURN client against each of the configured public addresses which allocates and relays data to itself.
+    /// The relay must already be running with the same `--auth-secret`.
+    Selftest,
+}
+
 #[derive(clap::ValueEnum, Debug, Clone, Copy)]
 enum LogFormat {
     Human,
@@ -101,6 +234,12 @@ async fn main() -> Result<()> {
 
     setup_tracing(&args).await?;
 
+    if let Some(Action::Selftest) = args.action {
+        return run_selftest(&args).await;
+    }
+
+    let metrics_registry = setup_metrics(&args)?;
+
     let public_addr = match (args.public_ip4_addr, args.public_ip6_addr) {
         (Some(ip4), Some(ip6)) => IpStack::Dual { ip4, ip6 },
         (Some(ip4), None) => IpStack::Ip4(ip4),
@@ -110,12 +249,70 @@ async fn main() -> Result<()> {
         }
     };
 
-    let server = Server::new(
+    let mut server = Server::new(
         public_addr,
         make_rng(args.rng_seed),
         args.lowest_port,
         args.highest_port,
     );
+    server.set_quotas(Quotas {
+        max_allocations: args.max_allocations_per_user,
+        max_channels: args.max_channels_per_user,
+        max_bytes_per_sec: args.max_bytes_per_sec_per_user,
+    });
+    server.set_alternate_servers(
+        args.alternate_servers.clone(),
+        LoadThresholds {
+            max_allocations: args.redirect_above_allocations,
+            max_bytes_per_sec: args.redirect_above_bytes_per_sec,
+            min_free_ports: args.redirect_below_free_ports,
+        },
+    );
+    server.set_peer_policy(PeerPolicy::new(
+        args.allow_peers.clone(),
+        args.deny_peers.clone(),
+    ));
+
+    if let Some(state_file) = args.state_file.as_deref() {
+        match read_snapshot(state_file) {
+            Ok(Some(snapshot)) => server.restore(snapshot, SystemTime::now()),
+            Ok(None) => {}
+            Err(e) => {
+                tracing::warn!(target: "relay", "Failed to restore state from {}: {e:#}", state_file.display())
+            }
+        }
+    }
+
+    // Explicitly configured credentials take precedence over restored ones.
+    if let Some(auth_secret) = args.auth_secret.clone() {
+        server.set_auth_secret(auth_secret);
+    }
+    if let Some(path) = args.static_credentials_file.as_deref() {
+        let static_credentials = read_static_credentials(path)?;
+
+        tracing::info!(target: "relay", "Loaded {} static credentials", static_credentials.len());
+
+        server.set_static_credentials(static_credentials);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "ebpf"))]
+    if let (Some(path), Some(interface)) = (&args.ebpf_program, &args.ebpf_interface) {
+        let program = ebpf::Program::try_load(path, interface)?;
+
+        tracing::info!(target: "relay", "Attached eBPF program to {interface}");
+
+        let channel_table = server.channel_table();
+        channel_table.attach_ebpf(program);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EBPF_TRAFFIC_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                channel_table.collect_ebpf_traffic();
+            }
+        });
+    }
 
     let channel = if let Some(token) = args.token.as_ref() {
         let base_url = args.api_url.clone();
@@ -129,18 +326,111 @@ async fn main() -> Result<()> {
     } else {
         tracing::warn!(target: "relay", "No portal token supplied, starting standalone mode");
 
+        if args.auth_secret.is_none() && args.static_credentials_file.is_none() {
+            tracing::warn!(target: "relay", "Neither `--auth-secret` nor `--static-credentials-file` are set, clients will not be able to authenticate");
+        }
+
         None
     };
 
-    let mut eventloop = Eventloop::new(server, channel, public_addr)?;
+    let mut stream_listeners = Vec::new();
+    if args.listen_tcp {
+        stream_listeners.push((TURN_PORT, StreamTransport::Tcp));
+    }
+    if let (Some(cert_file), Some(key_file)) = (&args.tls_cert_file, &args.tls_key_file) {
+        let acceptor = make_tls_acceptor(cert_file, key_file)?;
 
-    tokio::spawn(firezone_relay::health_check::serve(args.health_check_addr));
+        stream_listeners.push((args.tls_port, StreamTransport::Tls(acceptor)));
+    }
 
-    tracing::info!(target: "relay", "Listening for incoming traffic on UDP port 3478");
+    let (admin_request_sender, admin_request_receiver) = mpsc::channel(10);
+    let health_status = health_check::Status::default();
 
-    future::poll_fn(|cx| eventloop.poll(cx))
-        .await
-        .context("event loop failed")?;
+    #[cfg(target_os = "linux")]
+    let socket_pool = {
+        let num_workers = args.num_workers.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+        });
+
+        SocketPool::new(num_workers, public_addr, TURN_PORT, server.channel_table())?
+    };
+    #[cfg(not(target_os = "linux"))]
+    let socket_pool = {
+        if args.num_workers.is_some() {
+            tracing::warn!(target: "relay", "`--num-workers` is only supported on Linux");
+        }
+
+        SocketPool::new(public_addr, TURN_PORT)?
+    };
+
+    let mut eventloop = Eventloop::new(
+        server,
+        channel,
+        public_addr,
+        &stream_listeners,
+        socket_pool,
+        admin_request_receiver,
+        health_status.clone(),
+        Duration::from_secs(args.drain_timeout_secs),
+    )?
+    .with_state_file(args.state_file.clone());
+
+    tokio::spawn(health_check::serve(
+        args.health_check_addr,
+        health_status,
+        metrics_registry,
+        args.admin_api.then_some(admin_request_sender),
+    ));
+
+    tracing::info!(target: "relay", "Listening for incoming traffic on UDP port {TURN_PORT}");
+    for (port, transport) in &stream_listeners {
+        tracing::info!(target: "relay", "Listening for incoming traffic on {} port {port}", transport.name());
+    }
+
+    let result = future::poll_fn(|cx| eventloop.poll(cx)).await;
+
+    if let Some(state_writer) = eventloop.state_writer.take() {
+        let _ = state_writer.await;
+    }
+
+    if let Some(state_file) = args.state_file.as_deref() {
+        if let Err(e) = write_snapshot(state_file, &eventloop.server.snapshot()) {
+            tracing::warn!(target: "relay", "Failed to persist state to {}: {e:#}", state_file.display());
+        }
+    }
+
+    result.context("event loop failed")?;
+
+    Ok(())
+}
+
+/// Reads a [`Snapshot`] from the given file, returning `None` if the file does not exist.
+fn read_snapshot(path: &Path) -> Result<Option<Snapshot>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let snapshot = serde_json::from_reader(BufReader::new(file))?;
+
+    Ok(Some(snapshot))
+}
+
+/// Atomically writes a [`Snapshot`] to the given file, readable only by the current user.
+fn write_snapshot(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt as _;
+
+    let tmp_path = path.with_extension("tmp");
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    serde_json::to_writer(BufWriter::new(file), snapshot)?;
+    std::fs::rename(&tmp_path, path)?;
 
     Ok(())
 }
@@ -168,7 +458,7 @@ async fn setup_tracing(args: &Args) -> Result<()> {
 
             let exporter = opentelemetry_otlp::new_exporter()
                 .tonic()
-                .with_endpoint(grpc_endpoint.clone());
+                .with_endpoint(grpc_endpoint);
 
             let tracer =
                 opentelemetry_otlp::new_pipeline()
@@ -182,18 +472,6 @@ async fn setup_tracing(args: &Args) -> Result<()> {
 
             tracing::trace!(target: "relay", "Successfully initialized trace provider on tokio runtime");
 
-            let exporter = opentelemetry_otlp::new_exporter()
-                .tonic()
-                .with_endpoint(grpc_endpoint);
-
-            opentelemetry_otlp::new_pipeline()
-                .metrics(opentelemetry::runtime::Tokio)
-                .with_exporter(exporter)
-                .build()
-                .context("Failed to create OTLP metrics pipeline")?;
-
-            tracing::trace!(target: "relay", "Successfully initialized metric controller on tokio runtime");
-
             tracing_subscriber::registry()
                 .with(log_layer(args))
                 .with(
@@ -214,6 +492,44 @@ async fn setup_tracing(args: &Args) -> Result<()> {
     Ok(())
 }
 
+/// Sets up the global meter provider.
+///
+/// Metrics are always exposed in the Prometheus text format via the returned [`prometheus::Registry`].
+/// If the user has specified an OTLP collector via `Args.otlp_grpc_endpoint`, they are additionally pushed there.
+fn setup_metrics(args: &Args) -> Result<prometheus::Registry> {
+    let registry = prometheus::Registry::new();
+
+    let prometheus_exporter = opentelemetry_prometheus::exporter()
+        .with_registry(registry.clone())
+        .build()
+        .context("Failed to create Prometheus exporter")?;
+
+    let mut provider = sdk::metrics::MeterProvider::builder().with_reader(prometheus_exporter);
+
+    if let Some(endpoint) = args.otlp_grpc_endpoint {
+        let exporter = opentelemetry_otlp::MetricsExporterBuilder::from(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(format!("http://{endpoint}")),
+        )
+        .build_metrics_exporter(
+            Box::new(sdk::metrics::reader::DefaultTemporalitySelector::new()),
+            Box::new(sdk::metrics::reader::DefaultAggregationSelector::new()),
+        )
+        .context("Failed to create OTLP metrics exporter")?;
+
+        provider = provider.with_reader(
+            sdk::metrics::PeriodicReader::builder(exporter, opentelemetry::runtime::Tokio).build(),
+        );
+
+        tracing::trace!(target: "relay", "Successfully initialized OTLP metrics exporter on tokio runtime");
+    }
+
+    opentelemetry::global::set_meter_provider(provider.build());
+
+    Ok(registry)
+}
+
 /// Constructs the base log layer.
 ///
 /// The user has a choice between:
@@ -247,6 +563,75 @@ fn env_filter() -> EnvFilter {
         .from_env_lossy()
 }
 
+async fn run_selftest(args: &Args) -> Result<()> {
+    let credentials = selftest_credentials(args)?;
+
+    let servers = args
+        .public_ip4_addr
+        .map(IpAddr::V4)
+        .into_iter()
+        .chain(args.public_ip6_addr.map(IpAddr::V6))
+        .map(|ip| SocketAddr::new(ip, TURN_PORT))
+        .collect::<Vec<_>>();
+    if servers.is_empty() {
+        bail!("Must set at least one of `--public-ip4-addr` or `--public-ip6-addr`")
+    }
+
+    let mut passed = true;
+
+    for server in servers {
+        let report = selftest::run(server, &credentials).await;
+        println!("{report}");
+
+        passed &= report.passed();
+    }
+
+    if !passed {
+        bail!("Self-test failed")
+    }
+
+    Ok(())
+}
+
+/// Picks the credentials for the self-test from the same configuration the relay is running with.
+///
+/// Without `--auth-secret`, the relay shares a random secret with the portal which we can only read from its `--state-file`.
+fn selftest_credentials(args: &Args) -> Result<selftest::Credentials> {
+    if let Some(auth_secret) = args.auth_secret.clone() {
+        return Ok(selftest::Credentials::AuthSecret(auth_secret));
+    }
+
+    if let Some(path) = args.static_credentials_file.as_deref() {
+        let static_credentials = read_static_credentials(path)?;
+        let (username, password) = static_credentials
+            .iter()
+            .next()
+            .with_context(|| format!("{} does not contain any credentials", path.display()))?;
+
+        return Ok(selftest::Credentials::Static {
+            username: username.to_owned(),
+            password: password.clone(),
+        });
+    }
+
+    if let Some(path) = args.state_file.as_deref() {
+        let snapshot = read_snapshot(path)
+            .with_context(|| format!("Failed to read state from {}", path.display()))?
+            .with_context(|| format!("{} does not exist, is the relay running?", path.display()))?;
+
+        return Ok(selftest::Credentials::AuthSecret(snapshot.auth_secret()));
+    }
+
+    bail!("One of `--auth-secret`, `--static-credentials-file` or `--state-file` is required to authenticate against the relay")
+}
+
+fn read_static_credentials(path: &Path) -> Result<StaticCredentials> {
+    std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .parse::<StaticCredentials>()
+        .with_context(|| format!("Failed to parse static credentials in {}", path.display()))
+}
+
 async fn connect_to_portal(
     args: &Args,
     token: &SecretString,
@@ -287,6 +672,71 @@ struct JoinMessage {
     stamp_secret: String,
 }
 
+/// A stream-oriented transport on which clients can talk to us.
+#[derive(Clone)]
+enum StreamTransport {
+    Tcp,
+    Tls(tokio_rustls::TlsAcceptor),
+}
+
+impl StreamTransport {
+    fn name(&self) -> &'static str {
+        match self {
+            StreamTransport::Tcp => "TCP",
+            StreamTransport::Tls(_) => "TLS",
+        }
+    }
+}
+
+/// State changes of the stream connections of our clients.
+enum StreamEvent {
+    Connected(ClientSocket, mpsc::Sender<StreamCommand>),
+    Disconnected(ClientSocket),
+}
+
+/// Instructions for the task driving a stream connection of a client.
+enum StreamCommand {
+    /// Write the given message to the stream.
+    Send(Vec<u8>),
+    /// Turn the stream into a data connection that forwards all bytes to and from the given peer, see RFC 6062.
+    Splice(TcpStream),
+}
+
+/// A TCP connection to a peer of a TCP allocation.
+enum PeerConnection {
+    /// Waiting for the client to bind a data connection.
+    Pending(TcpStream),
+    /// Owned by the task of the given data connection.
+    Bound(ClientSocket),
+}
+
+fn make_tls_acceptor(cert_file: &Path, key_file: &Path) -> Result<tokio_rustls::TlsAcceptor> {
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+    use tokio_rustls::rustls::ServerConfig;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_file).with_context(|| format!("Failed to open {}", cert_file.display()))?,
+    ))
+    .context("Failed to parse TLS certificate chain")?
+    .into_iter()
+    .map(CertificateDer::from)
+    .collect::<Vec<_>>();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(
+        File::open(key_file).with_context(|| format!("Failed to open {}", key_file.display()))?,
+    ))
+    .context("Failed to parse TLS private key")?
+    .into_iter()
+    .next()
+    .context("No PKCS8 private key found")?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, PrivatePkcs8KeyDer::from(key).into())
+        .context("Invalid TLS certificate or private key")?;
+
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
 #[cfg(debug_assertions)]
 fn make_rng(seed: Option<u64>) -> StdRng {
     let Some(seed) = seed else {
@@ -309,13 +759,29 @@ fn make_rng(seed: Option<u64>) -> StdRng {
 
 struct Eventloop<R> {
     inbound_data_receiver: mpsc::Receiver<(Vec<u8>, ClientSocket)>,
-    outbound_ip4_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
-    outbound_ip6_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
     server: Server<R>,
     channel: Option<PhoenixChannel<JoinMessage, (), ()>>,
-    allocations: HashMap<(AllocationId, AddressFamily), Allocation>,
-    relay_data_sender: mpsc::Sender<(Vec<u8>, PeerSocket, AllocationId)>,
-    relay_data_receiver: mpsc::Receiver<(Vec<u8>, PeerSocket, AllocationId)>,
+    socket_pool: SocketPool,
+    stream_event_receiver: mpsc::Receiver<StreamEvent>,
+    /// Clients connected via TCP or TLS and the channels to their connection tasks.
+    stream_clients: HashMap<ClientSocket, mpsc::Sender<StreamCommand>>,
+    tcp_allocations: HashMap<(AllocationId, AddressFamily), TcpAllocation>,
+    peer_connection_sender: mpsc::Sender<(TcpStream, PeerSocket, AllocationId)>,
+    peer_connection_receiver: mpsc::Receiver<(TcpStream, PeerSocket, AllocationId)>,
+    connect_result_sender: mpsc::Sender<(ConnectionId, Result<TcpStream>)>,
+    connect_result_receiver: mpsc::Receiver<(ConnectionId, Result<TcpStream>)>,
+    peer_connections: HashMap<ConnectionId, PeerConnection>,
+    admin_request_receiver: mpsc::Receiver<admin::Request>,
+    health_status: health_check::Status,
+    drain_timeout: Duration,
+    sigterm: Signal,
+    /// Where to periodically persist the [`Server`]'s state.
+    state_file: Option<PathBuf>,
+    state_persist_interval: tokio::time::Interval,
+    /// The task writing the last periodic snapshot, if any.
+    ///
+    /// There is only ever one writer so snapshots never race on the temporary file.
+    state_writer: Option<tokio::task::JoinHandle<()>>,
     sleep: Sleep,
 
     stats_log_interval: tokio::time::Interval,
@@ -326,55 +792,86 @@ impl<R> Eventloop<R>
 where
     R: Rng,
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         server: Server<R>,
         channel: Option<PhoenixChannel<JoinMessage, (), ()>>,
         public_address: IpStack,
+        stream_listeners: &[(u16, StreamTransport)],
+        socket_pool: SocketPool,
+        admin_request_receiver: mpsc::Receiver<admin::Request>,
+        health_status: health_check::Status,
+        drain_timeout: Duration,
     ) -> Result<Self> {
-        let (relay_data_sender, relay_data_receiver) = mpsc::channel(1);
         let (inbound_data_sender, inbound_data_receiver) = mpsc::channel(1000);
-        let (outbound_ip4_data_sender, outbound_ip4_data_receiver) = mpsc::channel(1000);
-        let (outbound_ip6_data_sender, outbound_ip6_data_receiver) = mpsc::channel(1000);
-
-        if public_address.as_v4().is_some() {
-            tokio::spawn(main_udp_socket_task(
-                AddressFamily::V4,
-                inbound_data_sender.clone(),
-                outbound_ip4_data_receiver,
-            ));
-        }
-        if public_address.as_v6().is_some() {
-            tokio::spawn(main_udp_socket_task(
-                AddressFamily::V6,
-                inbound_data_sender,
-                outbound_ip6_data_receiver,
-            ));
+        let (stream_event_sender, stream_event_receiver) = mpsc::channel(1000);
+        let (peer_connection_sender, peer_connection_receiver) = mpsc::channel(10);
+        let (connect_result_sender, connect_result_receiver) = mpsc::channel(10);
+
+        for family in [AddressFamily::V4, AddressFamily::V6] {
+            let has_family = match family {
+                AddressFamily::V4 => public_address.as_v4().is_some(),
+                AddressFamily::V6 => public_address.as_v6().is_some(),
+            };
+
+            if !has_family {
+                continue;
+            }
+
+            for (port, transport) in stream_listeners {
+                let listener = TcpListener::bind(family, *port)?;
+
+                tokio::spawn(main_stream_listener_task(
+                    listener,
+                    transport.clone(),
+                    inbound_data_sender.clone(),
+                    stream_event_sender.clone(),
+                ));
+            }
         }
 
         Ok(Self {
             inbound_data_receiver,
-            outbound_ip4_data_sender,
-            outbound_ip6_data_sender,
             server,
             channel,
-            allocations: Default::default(),
-            relay_data_sender,
-            relay_data_receiver,
+            socket_pool,
+            stream_event_receiver,
+            stream_clients: Default::default(),
+            tcp_allocations: Default::default(),
+            peer_connection_sender,
+            peer_connection_receiver,
+            connect_result_sender,
+            connect_result_receiver,
+            peer_connections: Default::default(),
+            admin_request_receiver,
+            health_status,
+            drain_timeout,
+            sigterm: signal(SignalKind::terminate())?,
+            state_file: None,
+            state_persist_interval: tokio::time::interval(STATE_PERSIST_INTERVAL),
+            state_writer: None,
             sleep: Sleep::default(),
             stats_log_interval: tokio::time::interval(STATS_LOG_INTERVAL),
             last_num_bytes_relayed: 0,
         })
     }
 
+    fn with_state_file(mut self, state_file: Option<PathBuf>) -> Self {
+        self.state_file = state_file;
+
+        self
+    }
+
+    fn start_draining(&mut self, now: SystemTime) {
+        self.health_status.set_draining();
+        self.server.start_draining(now + self.drain_timeout);
+    }
+
     fn poll(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
         let span = tracing::error_span!("Eventloop::poll");
         let _guard = span.enter();
 
         loop {
-            // Don't fail these results. One of the senders might not be active because we might not be listening on IP4 / IP6.
-            let _ = ready!(self.outbound_ip4_data_sender.poll_ready_unpin(cx));
-            let _ = ready!(self.outbound_ip6_data_sender.poll_ready_unpin(cx));
-
             let now = SystemTime::now();
 
             // Priority 1: Execute the pending commands of the server.
@@ -384,22 +881,16 @@ where
                         let span = tracing::error_span!("Command::SendMessage");
                         let _guard = span.enter();
 
-                        let sender = match recipient.family() {
-                            AddressFamily::V4 => &mut self.outbound_ip4_data_sender,
-                            AddressFamily::V6 => &mut self.outbound_ip6_data_sender,
-                        };
-
-                        if let Err(e) = sender.try_send((payload, recipient)) {
-                            if e.is_disconnected() {
-                                return Poll::Ready(Err(anyhow!(
-                                    "Channel to primary UDP socket task has been closed"
-                                )));
+                        if let Some(stream) = self.stream_clients.get_mut(&recipient) {
+                            if stream.try_send(StreamCommand::Send(payload)).is_err() {
+                                tracing::debug!(target: "relay", %recipient, "Dropping message because stream connection is full or closed");
                             }
 
-                            // Should never happen because we poll for readiness above.
-                            if e.is_full() {
-                                tracing::warn!(target: "relay", %recipient, "Dropping message because channel to primary UDP socket task is full");
-                            }
+                            continue;
+                        }
+
+                        if let Err(e) = self.socket_pool.send_to_client(payload, recipient) {
+                            return Poll::Ready(Err(e.context("Failed to send message to client")));
                         }
                     }
                     Command::CreateAllocation { id, family, port } => {
@@ -407,16 +898,32 @@ where
                             tracing::error_span!("Command::CreateAllocation", %id, %family, %port);
                         let _guard = span.enter();
 
-                        self.allocations.insert(
+                        if let Err(e) = self.socket_pool.bind(id, family, port) {
+                            tracing::warn!(target: "relay", "Failed to bind socket for allocation: {e:#}");
+                            self.server.handle_allocation_failed(id);
+                        }
+                    }
+                    Command::CreateTcpAllocation { id, family, port } => {
+                        let span = tracing::error_span!("Command::CreateTcpAllocation", %id, %family, %port);
+                        let _guard = span.enter();
+
+                        self.tcp_allocations.insert(
                             (id, family),
-                            Allocation::new(self.relay_data_sender.clone(), id, family, port),
+                            TcpAllocation::new(
+                                self.peer_connection_sender.clone(),
+                                id,
+                                family,
+                                port,
+                            ),
                         );
                     }
                     Command::FreeAllocation { id, family } => {
                         let span = tracing::error_span!("Command::FreeAllocation", %id, %family);
                         let _guard = span.enter();
 
-                        if self.allocations.remove(&(id, family)).is_none() {
+                        if !self.socket_pool.unbind(id, family)
+                            && self.tcp_allocations.remove(&(id, family)).is_none()
+                        {
                             tracing::debug!(target: "relay", "Unknown allocation {id}");
                             continue;
                         };
@@ -447,17 +954,55 @@ where
                         let span = tracing::error_span!("Command::ForwardData", %id, %receiver);
                         let _guard = span.enter();
 
-                        let mut allocation = match self.allocations.entry((id, receiver.family())) {
-                            Entry::Occupied(entry) => entry,
-                            Entry::Vacant(_) => {
-                                tracing::debug!(target: "relay", allocation = %id, family = %receiver.family(), "Unknown allocation");
-                                continue;
-                            }
+                        if let Err(e) = self.socket_pool.send(id, data, receiver) {
+                            tracing::warn!(target: "relay", "Failed to relay data: {e:#}");
+                            self.server.handle_allocation_failed(id);
+                        }
+                    }
+                    Command::ConnectToPeer { id, port, peer } => {
+                        let span = tracing::error_span!("Command::ConnectToPeer", %id, %peer);
+                        let _guard = span.enter();
+
+                        let mut connect_result_sender = self.connect_result_sender.clone();
+
+                        tokio::spawn(async move {
+                            let result = connect_to_peer(port, peer).await;
+                            let _ = connect_result_sender.send((id, result)).await;
+                        });
+                    }
+                    Command::BindConnection { id, client } => {
+                        let span = tracing::error_span!("Command::BindConnection", %id, %client);
+                        let _guard = span.enter();
+
+                        let Some(PeerConnection::Pending(stream)) =
+                            self.peer_connections.remove(&id)
+                        else {
+                            tracing::debug!(target: "relay", "Unknown connection");
+                            continue;
+                        };
+                        let Some(sender) = self.stream_clients.get_mut(&client) else {
+                            tracing::debug!(target: "relay", "Data connection is already closed");
+                            continue;
                         };
 
-                        if allocation.get_mut().send(data, receiver).is_err() {
-                            self.server.handle_allocation_failed(id);
-                            allocation.remove();
+                        if sender.try_send(StreamCommand::Splice(stream)).is_err() {
+                            tracing::debug!(target: "relay", "Failed to hand connection to data connection task");
+                            continue;
+                        }
+
+                        self.peer_connections
+                            .insert(id, PeerConnection::Bound(client));
+                    }
+                    Command::CloseConnection { id } => {
+                        let span = tracing::error_span!("Command::CloseConnection", %id);
+                        let _guard = span.enter();
+
+                        match self.peer_connections.remove(&id) {
+                            Some(PeerConnection::Pending(_)) | None => {}
+                            Some(PeerConnection::Bound(client)) => {
+                                // Dropping the sender stops the task of the data connection.
+                                self.stream_clients.remove(&client);
+                            }
                         }
                     }
                 }
@@ -465,6 +1010,12 @@ where
                 continue; // Attempt to process more commands.
             }
 
+            if self.server.is_drained() {
+                tracing::info!(target: "relay", "All allocations are gone, shutting down");
+
+                return Poll::Ready(Ok(()));
+            }
+
             // Priority 2: Handle time-sensitive tasks:
             if self.sleep.poll_unpin(cx).is_ready() {
                 self.server.handle_deadline_reached(now);
@@ -472,14 +1023,71 @@ where
             }
 
             // Priority 3: Handle relayed data (we prioritize latency for existing allocations over making new ones)
-            if let Poll::Ready(Some((data, sender, allocation))) =
-                self.relay_data_receiver.poll_next_unpin(cx)
+            if let Poll::Ready(Some(event)) = self.socket_pool.poll_event(cx) {
+                match event {
+                    SocketPoolEvent::Data(data, sender, allocation) => {
+                        self.server
+                            .handle_peer_traffic(&data, sender, allocation, now);
+                    }
+                    SocketPoolEvent::ClientData(data, sender) => {
+                        self.server.handle_client_input(&data, sender, now);
+                    }
+                    SocketPoolEvent::SocketFailed(allocation, family) => {
+                        tracing::warn!(target: "relay", %allocation, %family, "Socket of allocation failed");
+                        self.server.handle_allocation_failed(allocation);
+                    }
+                }
+                continue; // Handle potentially new commands.
+            }
+
+            // Priority 4: Track stream connections of clients and TCP connections to peers
+            if let Poll::Ready(Some(event)) = self.stream_event_receiver.poll_next_unpin(cx) {
+                match event {
+                    StreamEvent::Connected(client, sender) => {
+                        self.stream_clients.insert(client, sender);
+                        self.server.handle_client_connected(client);
+                    }
+                    StreamEvent::Disconnected(client) => {
+                        self.stream_clients.remove(&client);
+                        self.server.handle_client_disconnected(client);
+                    }
+                }
+
+                continue; // Handle potentially new commands.
+            }
+            if let Poll::Ready(Some((stream, peer, allocation))) =
+                self.peer_connection_receiver.poll_next_unpin(cx)
+            {
+                if let Some(id) = self
+                    .server
+                    .handle_peer_connection_accepted(allocation, peer, now)
+                {
+                    self.peer_connections
+                        .insert(id, PeerConnection::Pending(stream));
+                }
+
+                continue; // Handle potentially new commands.
+            }
+            if let Poll::Ready(Some((id, result))) =
+                self.connect_result_receiver.poll_next_unpin(cx)
             {
-                self.server.handle_peer_traffic(&data, sender, allocation);
+                match result {
+                    Ok(stream) => {
+                        self.peer_connections
+                            .insert(id, PeerConnection::Pending(stream));
+                        self.server.handle_peer_connected(id, now);
+                    }
+                    Err(e) => {
+                        tracing::debug!(target: "relay", connection = %id, "{e:#}");
+
+                        self.server.handle_peer_connection_failed(id);
+                    }
+                }
+
                 continue; // Handle potentially new commands.
             }
 
-            // Priority 4: Accept new allocations / answer STUN requests etc
+            // Priority 5: Accept new allocations / answer STUN requests etc
             if let Poll::Ready(Some((buffer, sender))) =
                 self.inbound_data_receiver.poll_next_unpin(cx)
             {
@@ -487,7 +1095,7 @@ where
                 continue; // Handle potentially new commands.
             }
 
-            // Priority 5: Handle portal messages
+            // Priority 6: Handle portal messages
             match self.channel.as_mut().map(|c| c.poll(cx)) {
                 Some(Poll::Ready(Err(e))) => {
                     return Poll::Ready(Err(anyhow!("Portal connection failed: {e}")));
@@ -512,6 +1120,55 @@ where
                 | None => {}
             }
 
+            // Priority 7: Answer admin requests
+            if let Poll::Ready(Some(request)) = self.admin_request_receiver.poll_next_unpin(cx) {
+                match request {
+                    admin::Request::ListAllocations(reply) => {
+                        let _ = reply.send(self.server.allocations());
+                    }
+                    admin::Request::DeleteAllocation(id, reply) => {
+                        let _ = reply.send(self.server.handle_admin_delete_allocation(id));
+                    }
+                    admin::Request::StartDraining => {
+                        self.start_draining(now);
+                    }
+                }
+
+                continue; // Handle potentially new commands.
+            }
+
+            if self.sigterm.poll_recv(cx).is_ready() {
+                if self.server.is_draining() {
+                    tracing::info!(target: "relay", "Received second SIGTERM, shutting down immediately");
+
+                    return Poll::Ready(Ok(()));
+                }
+
+                self.start_draining(now);
+
+                continue; // Handle potentially new commands.
+            }
+
+            if self.state_persist_interval.poll_tick(cx).is_ready() {
+                if let Some(state_file) = self.state_file.clone() {
+                    if self
+                        .state_writer
+                        .as_ref()
+                        .is_some_and(|writer| !writer.is_finished())
+                    {
+                        tracing::debug!(target: "relay", "Previous snapshot is still being written, skipping");
+                    } else {
+                        let snapshot = self.server.snapshot();
+
+                        self.state_writer = Some(tokio::task::spawn_blocking(move || {
+                            if let Err(e) = write_snapshot(&state_file, &snapshot) {
+                                tracing::warn!(target: "relay", "Failed to persist state to {}: {e:#}", state_file.display());
+                            }
+                        }));
+                    }
+                }
+            }
+
             if self.stats_log_interval.poll_tick(cx).is_ready() {
                 let num_allocations = self.server.num_allocations();
                 let num_channels = self.server.num_channels();
@@ -544,25 +1201,150 @@ fn fmt_human_throughput(mut throughput: f64) -> String {
     format!("{throughput:.2} TB/s")
 }
 
-async fn main_udp_socket_task(
-    family: AddressFamily,
+/// Accepts TCP or TLS connections of clients for as long as the relay is running.
+///
+/// Failing to accept a connection is usually transient, e.g. because we ran out of file descriptors, so we keep going after [`ACCEPT_RETRY_INTERVAL`].
+async fn main_stream_listener_task(
+    mut listener: TcpListener,
+    transport: StreamTransport,
+    inbound_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
+    stream_event_sender: mpsc::Sender<StreamEvent>,
+) {
+    loop {
+        let (stream, sender) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!(target: "relay", "Failed to accept connection: {e:#}");
+
+                tokio::time::sleep(ACCEPT_RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+        let client = ClientSocket::new(sender);
+
+        let inbound_data_sender = inbound_data_sender.clone();
+        let stream_event_sender = stream_event_sender.clone();
+        let transport = transport.clone();
+
+        tokio::spawn(async move {
+            match transport {
+                StreamTransport::Tcp => {
+                    serve_stream_client(stream, client, inbound_data_sender, stream_event_sender)
+                        .await
+                }
+                StreamTransport::Tls(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        serve_stream_client(
+                            stream,
+                            client,
+                            inbound_data_sender,
+                            stream_event_sender,
+                        )
+                        .await
+                    }
+                    Err(e) => {
+                        tracing::debug!(target: "relay", %client, "TLS handshake failed: {e}");
+                    }
+                },
+            }
+        });
+    }
+}
+
+/// Announces a client to the eventloop once its connection is established and drives it until it closes.
+async fn serve_stream_client<S>(
+    stream: S,
+    client: ClientSocket,
+    inbound_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
+    mut stream_event_sender: mpsc::Sender<StreamEvent>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (outbound_command_sender, outbound_command_receiver) =
+        mpsc::channel(MAX_BUFFERED_STREAM_MESSAGES);
+
+    if stream_event_sender
+        .send(StreamEvent::Connected(client, outbound_command_sender))
+        .await
+        .is_err()
+    {
+        return; // The eventloop is gone.
+    }
+
+    if let Err(e) = stream_connection_task(
+        stream,
+        client,
+        inbound_data_sender,
+        outbound_command_receiver,
+    )
+    .await
+    {
+        tracing::debug!(target: "relay", %client, "Stream connection failed: {e:#}");
+    }
+
+    let _ = stream_event_sender
+        .send(StreamEvent::Disconnected(client))
+        .await;
+}
+
+/// Drives a single TCP or TLS connection of a client.
+///
+/// Returns once the client closed the connection.
+async fn stream_connection_task<S>(
+    stream: S,
+    client: ClientSocket,
     mut inbound_data_sender: mpsc::Sender<(Vec<u8>, ClientSocket)>,
-    mut outbound_data_receiver: mpsc::Receiver<(Vec<u8>, ClientSocket)>,
-) -> Result<Infallible> {
-    let mut socket = UdpSocket::bind(family, 3478)?;
+    mut outbound_command_receiver: mpsc::Receiver<StreamCommand>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut decoder = FrameDecoder::default();
+    let mut buffer = vec![0u8; u16::MAX as usize];
 
-    loop {
+    let mut peer = loop {
         tokio::select! {
-            result = socket.recv() => {
-                let (data, sender) = result?;
-                inbound_data_sender.send((data.to_vec(), ClientSocket::new(sender))).await?;
+            // Prefer commands so we don't read any data meant for the peer after a `ConnectionBind` response.
+            biased;
+
+            maybe_command = outbound_command_receiver.next() => {
+                match maybe_command {
+                    Some(StreamCommand::Send(data)) => {
+                        writer.write_all(&framing::encode_frame(&data)).await?;
+                    }
+                    Some(StreamCommand::Splice(peer)) => break peer,
+                    None => return Ok(()),
+                }
             }
-            maybe_item = outbound_data_receiver.next() => {
-                let (data, recipient) = maybe_item.context("Outbound data channel closed")?;
-                socket.send_to(data.as_ref(), recipient.into_socket()).await?;
+            result = reader.read(&mut buffer) => {
+                let num_read = result?;
+
+                if num_read == 0 {
+                    return Ok(());
+                }
+
+                decoder.push(&buffer[..num_read]);
+
+                while let Some(message) = decoder.next_frame()? {
+                    inbound_data_sender.send((message, client)).await?;
+                }
             }
         }
+    };
+
+    // From here on, this is a data connection as per RFC 6062 and we no longer look at the bytes.
+    let mut stream = reader.unsplit(writer);
+    peer.write_all(&decoder.into_remaining()).await?;
+
+    tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut stream, &mut peer) => {
+            result?;
+        }
+        None = outbound_command_receiver.next() => {}
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -0,0 +1,622 @@
+//! Checks whether a relay is reachable by running a TURN client against it.
+//!
+//! The client performs the same requests as a real client would: It discovers its own address via a STUN binding, allocates, creates a permission and binds a channel to itself.
+//! Finally, it sends data through the channel and expects it to come back via the allocation and vice versa.
+//! Each step depends on the previous one, thus we stop at the first failure.
+
+use crate::auth::{generate_password, FIREZONE};
+use crate::server::UDP_TRANSPORT;
+use crate::{Attribute, ChannelData};
+use anyhow::{anyhow, bail, Context as _, Result};
+use bytecodec::{DecodeExt as _, EncodeExt as _};
+use secrecy::{ExposeSecret as _, SecretString};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+use stun_codec::rfc5389::attributes::{
+    ErrorCode, MessageIntegrity, Nonce, Username, XorMappedAddress,
+};
+use stun_codec::rfc5389::errors::{StaleNonce, Unauthorized};
+use stun_codec::rfc5389::methods::BINDING;
+use stun_codec::rfc5766::attributes::{
+    ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress, XorRelayAddress,
+};
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
+use stun_codec::rfc8656::attributes::{AddressFamily, RequestedAddressFamily};
+use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, Method, TransactionId};
+use tokio::net::UdpSocket;
+
+/// How long we wait for a response before retransmitting a request.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_TRANSMITS: usize = 3;
+
+/// How long the credentials we derive from the auth secret are valid for.
+const CREDENTIALS_VALIDITY: Duration = Duration::from_secs(60 * 60);
+
+const CHANNEL: u16 = 0x4000;
+
+/// How we authenticate against the relay.
+pub enum Credentials {
+    /// Time-limited credentials derived from the relay's auth secret, just like the portal hands them out to clients.
+    AuthSecret(SecretString),
+    /// One of the static credentials the relay accepts.
+    Static {
+        username: String,
+        password: SecretString,
+    },
+}
+
+/// The outcome of running all steps against a single address of a relay.
+#[derive(Debug)]
+pub struct Report {
+    server: SocketAddr,
+    steps: Vec<(Step, Result<String>)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Binding,
+    Allocate,
+    CreatePermission,
+    ChannelBind,
+    ChannelData,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let family = match self.server {
+            SocketAddr::V4(_) => "IPv4",
+            SocketAddr::V6(_) => "IPv6",
+        };
+        let verdict = if self.passed() { "PASS" } else { "FAIL" };
+
+        writeln!(f, "{family} ({}): {verdict}", self.server)?;
+
+        for (step, outcome) in &self.steps {
+            match outcome {
+                Ok(details) => writeln!(f, "  {step:<18} ok ({details})")?,
+                Err(e) => writeln!(f, "  {step:<18} failed: {e:#}")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Step::Binding => "Binding",
+            Step::Allocate => "Allocate",
+            Step::CreatePermission => "CreatePermission",
+            Step::ChannelBind => "ChannelBind",
+            Step::ChannelData => "ChannelData",
+        };
+
+        f.pad(name)
+    }
+}
+
+/// Runs all steps against the relay listening on `server`, authenticating with the given [`Credentials`].
+pub async fn run(server: SocketAddr, credentials: &Credentials) -> Report {
+    let mut report = Report {
+        server,
+        steps: Vec::new(),
+    };
+
+    let mut client = match Client::bind(server, credentials).await {
+        Ok(client) => client,
+        Err(e) => {
+            report.steps.push((Step::Binding, Err(e)));
+            return report;
+        }
+    };
+
+    if run_steps(&mut client, &mut report).await.is_none() {
+        tracing::debug!(target: "relay", %server, "Self-test failed");
+    }
+
+    if client.allocated {
+        if let Err(e) = client.deallocate().await {
+            tracing::debug!(target: "relay", %server, "Failed to delete allocation: {e:#}");
+        }
+    }
+
+    report
+}
+
+async fn run_steps(client: &mut Client, report: &mut Report) -> Option<()> {
+    let mapped_address = report.record(Step::Binding, client.binding().await, |address| {
+        format!("mapped address {address}")
+    })?;
+    let relay_address = report.record(Step::Allocate, client.allocate().await, |address| {
+        format!("relay address {address}")
+    })?;
+    report.record(
+        Step::CreatePermission,
+        client.create_permission(mapped_address).await,
+        |_| format!("peer {mapped_address}"),
+    )?;
+    report.record(
+        Step::ChannelBind,
+        client.channel_bind(mapped_address).await,
+        |_| format!("channel {CHANNEL}"),
+    )?;
+    report.record(
+        Step::ChannelData,
+        client.channel_data(relay_address).await,
+        |rtt| format!("round-trip {rtt:?}"),
+    )?;
+
+    Some(())
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|(_, outcome)| outcome.is_ok())
+    }
+
+    fn record<T>(
+        &mut self,
+        step: Step,
+        outcome: Result<T>,
+        describe: impl FnOnce(&T) -> String,
+    ) -> Option<T> {
+        match outcome {
+            Ok(value) => {
+                self.steps.push((step, Ok(describe(&value))));
+                Some(value)
+            }
+            Err(e) => {
+                self.steps.push((step, Err(e)));
+                None
+            }
+        }
+    }
+}
+
+struct Client {
+    socket: UdpSocket,
+    server: SocketAddr,
+
+    username: Username,
+    password: String,
+    /// The nonce handed out by the relay, only present once it challenged us.
+    nonce: Option<Nonce>,
+    allocated: bool,
+
+    encoder: MessageEncoder<Attribute>,
+    decoder: MessageDecoder<Attribute>,
+}
+
+impl Client {
+    async fn bind(server: SocketAddr, credentials: &Credentials) -> Result<Self> {
+        let local = match server {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+        let socket = UdpSocket::bind(local)
+            .await
+            .context("Failed to bind local socket")?;
+
+        let (username, password) = match credentials {
+            Credentials::AuthSecret(auth_secret) => {
+                let expiry = SystemTime::now() + CREDENTIALS_VALIDITY;
+                let expiry_secs = expiry
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .expect("now to be later than UNIX_EPOCH")
+                    .as_secs();
+                let salt = "selftest";
+
+                (
+                    format!("{expiry_secs}:{salt}"),
+                    generate_password(auth_secret, expiry, salt),
+                )
+            }
+            Credentials::Static { username, password } => {
+                (username.clone(), password.expose_secret().clone())
+            }
+        };
+
+        Ok(Self {
+            socket,
+            server,
+            username: Username::new(username).map_err(|e| anyhow!("Invalid username: {e}"))?,
+            password,
+            nonce: None,
+            allocated: false,
+            encoder: Default::default(),
+            decoder: Default::default(),
+        })
+    }
+
+    async fn binding(&mut self) -> Result<SocketAddr> {
+        let response = self.request(BINDING, Vec::new(), false).await?;
+        let mapped_address = response
+            .get_attribute::<XorMappedAddress>()
+            .context("Response has no XOR-MAPPED-ADDRESS")?;
+
+        Ok(mapped_address.address())
+    }
+
+    async fn allocate(&mut self) -> Result<SocketAddr> {
+        let family = match self.server {
+            SocketAddr::V4(_) => AddressFamily::V4,
+            SocketAddr::V6(_) => AddressFamily::V6,
+        };
+
+        let response = self
+            .request(
+                ALLOCATE,
+                vec![
+                    RequestedTransport::new(UDP_TRANSPORT).into(),
+                    RequestedAddressFamily::new(family).into(),
+                ],
+                true,
+            )
+            .await?;
+        let relay_address = response
+            .get_attribute::<XorRelayAddress>()
+            .context("Response has no XOR-RELAYED-ADDRESS")?;
+        self.allocated = true;
+
+        Ok(relay_address.address())
+    }
+
+    async fn create_permission(&mut self, peer: SocketAddr) -> Result<()> {
+        self.request(
+            CREATE_PERMISSION,
+            vec![XorPeerAddress::new(peer).into()],
+            true,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn channel_bind(&mut self, peer: SocketAddr) -> Result<()> {
+        self.request(
+            CHANNEL_BIND,
+            vec![
+                ChannelNumber::new(CHANNEL)
+                    .expect("channel to be in range")
+                    .into(),
+                XorPeerAddress::new(peer).into(),
+            ],
+            true,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Relays data to ourselves in both directions and returns the time it took.
+    async fn channel_data(&self, relay_address: SocketAddr) -> Result<Duration> {
+        let start = Instant::now();
+
+        let to_peer = b"firezone-relay selftest: client to peer";
+        self.socket
+            .send_to(&ChannelData::new(CHANNEL, to_peer).to_bytes(), self.server)
+            .await?;
+        self.receive(|packet, from| (from == relay_address && packet == to_peer).then_some(()))
+            .await
+            .context("Data sent via the channel did not arrive from the allocation")?;
+
+        let to_client = b"firezone-relay selftest: peer to client";
+        self.socket.send_to(to_client, relay_address).await?;
+        self.receive(|packet, from| {
+            let channel_data = ChannelData::parse(packet).ok()?;
+
+            (from == self.server
+                && channel_data.channel() == CHANNEL
+                && channel_data.data() == to_client)
+                .then_some(())
+        })
+        .await
+        .context("Data sent to the allocation did not arrive via the channel")?;
+
+        Ok(start.elapsed())
+    }
+
+    async fn deallocate(&mut self) -> Result<()> {
+        self.request(
+            REFRESH,
+            vec![Lifetime::new(Duration::ZERO)
+                .expect("zero lifetime to be valid")
+                .into()],
+            true,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Sends a request to the relay and waits for the response.
+    ///
+    /// If the relay challenges us for (new) credentials, we retry once with the nonce it handed out.
+    async fn request(
+        &mut self,
+        method: Method,
+        attributes: Vec<Attribute>,
+        authenticate: bool,
+    ) -> Result<Message<Attribute>> {
+        let mut response = self.transmit(method, &attributes, authenticate).await?;
+
+        if authenticate && is_challenge(&response) {
+            self.nonce = Some(
+                response
+                    .get_attribute::<Nonce>()
+                    .context("Challenge has no NONCE")?
+                    .clone(),
+            );
+
+            response = self.transmit(method, &attributes, authenticate).await?;
+        }
+
+        match response.class() {
+            MessageClass::SuccessResponse => Ok(response),
+            _ => {
+                let error = response
+                    .get_attribute::<ErrorCode>()
+                    .context("Error response has no ERROR-CODE")?;
+
+                bail!("{} {}", error.code(), error.reason_phrase())
+            }
+        }
+    }
+
+    async fn transmit(
+        &mut self,
+        method: Method,
+        attributes: &[Attribute],
+        authenticate: bool,
+    ) -> Result<Message<Attribute>> {
+        let transaction_id = TransactionId::new(rand::random());
+
+        let mut message = Message::<Attribute>::new(MessageClass::Request, method, transaction_id);
+        for attribute in attributes {
+            message.add_attribute(attribute.clone());
+        }
+        if let (true, Some(nonce)) = (authenticate, self.nonce.clone()) {
+            message.add_attribute(self.username.clone());
+            message.add_attribute(FIREZONE.clone());
+            message.add_attribute(nonce);
+
+            let message_integrity = MessageIntegrity::new_long_term_credential(
+                &message,
+                &self.username,
+                &FIREZONE,
+                &self.password,
+            )
+            .map_err(|e| anyhow!("Failed to compute MESSAGE-INTEGRITY: {e}"))?;
+            message.add_attribute(message_integrity);
+        }
+
+        let bytes = self
+            .encoder
+            .encode_into_bytes(message)
+            .map_err(|e| anyhow!("Failed to encode request: {e}"))?;
+
+        for _ in 0..MAX_TRANSMITS {
+            self.socket.send_to(&bytes, self.server).await?;
+
+            let decoder = &mut self.decoder;
+            let server = self.server;
+            let response = tokio::time::timeout(
+                RETRANSMIT_TIMEOUT,
+                receive_from(&self.socket, |packet, from| {
+                    if from != server {
+                        return None;
+                    }
+
+                    let response = decoder.decode_from_bytes(packet).ok()?.ok()?;
+
+                    (response.transaction_id() == transaction_id).then_some(response)
+                }),
+            )
+            .await;
+
+            if let Ok(response) = response {
+                return response;
+            }
+        }
+
+        bail!("No response from {}", self.server)
+    }
+
+    /// Waits for a packet accepted by `accept`, for at most [`RETRANSMIT_TIMEOUT`].
+    async fn receive<T>(&self, accept: impl FnMut(&[u8], SocketAddr) -> Option<T>) -> Result<T> {
+        tokio::time::timeout(RETRANSMIT_TIMEOUT, receive_from(&self.socket, accept))
+            .await
+            .context("Timed out")?
+    }
+}
+
+async fn receive_from<T>(
+    socket: &UdpSocket,
+    mut accept: impl FnMut(&[u8], SocketAddr) -> Option<T>,
+) -> Result<T> {
+    let mut buffer = vec![0u8; 65536];
+
+    loop {
+        let (len, from) = socket.recv_from(&mut buffer).await?;
+
+        if let Some(value) = accept(&buffer[..len], from) {
+            return Ok(value);
+        }
+    }
+}
+
+fn is_challenge(response: &Message<Attribute>) -> bool {
+    response.get_attribute::<ErrorCode>().is_some_and(|error| {
+        error == &ErrorCode::from(Unauthorized) || error == &ErrorCode::from(StaleNonce)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientSocket, Command, PeerPolicy, PeerSocket, Server, StaticCredentials};
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn passes_against_relay() {
+        let auth_secret = SecretString::from("secret".to_owned());
+        let server = spawn_relay(auth_secret.clone(), StaticCredentials::default()).await;
+
+        let report = run(server, &Credentials::AuthSecret(auth_secret)).await;
+
+        assert!(report.passed(), "{report}");
+        assert_eq!(
+            report
+                .steps
+                .iter()
+                .map(|(step, _)| step.to_string())
+                .collect::<Vec<_>>(),
+            [
+                "Binding",
+                "Allocate",
+                "CreatePermission",
+                "ChannelBind",
+                "ChannelData"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_with_static_credentials() {
+        let server = spawn_relay(
+            SecretString::from("secret".to_owned()),
+            "selftest:password".parse().unwrap(),
+        )
+        .await;
+
+        let report = run(
+            server,
+            &Credentials::Static {
+                username: "selftest".to_owned(),
+                password: SecretString::from("password".to_owned()),
+            },
+        )
+        .await;
+
+        assert!(report.passed(), "{report}");
+    }
+
+    #[tokio::test]
+    async fn reports_failed_step_and_stops() {
+        let server = spawn_relay(
+            SecretString::from("secret".to_owned()),
+            StaticCredentials::default(),
+        )
+        .await;
+
+        let report = run(
+            server,
+            &Credentials::AuthSecret(SecretString::from("wrong-secret".to_owned())),
+        )
+        .await;
+
+        assert!(!report.passed());
+
+        let output = report.to_string();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{output}");
+        assert_eq!(lines[0], format!("IPv4 ({server}): FAIL"));
+        assert!(lines[1].starts_with("  Binding            ok (mapped address 127.0.0.1:"));
+        assert_eq!(lines[2], "  Allocate           failed: 401 Unauthorized");
+    }
+
+    /// Runs a [`Server`] on loopback, with one task per allocation socket.
+    async fn spawn_relay(
+        auth_secret: SecretString,
+        static_credentials: StaticCredentials,
+    ) -> SocketAddr {
+        let main_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = main_socket.local_addr().unwrap();
+
+        let mut server = Server::new(Ipv4Addr::LOCALHOST, StdRng::from_entropy(), 49152, 65535);
+        server.set_auth_secret(auth_secret);
+        server.set_static_credentials(static_credentials);
+        server.set_peer_policy(PeerPolicy::new(
+            vec!["127.0.0.0/8".parse().unwrap()],
+            Vec::new(),
+        ));
+
+        tokio::spawn(async move {
+            let (peer_sender, mut peer_receiver) = mpsc::unbounded();
+            let mut allocations = HashMap::new();
+            let mut buffer = vec![0u8; 65536];
+
+            loop {
+                while let Some(command) = server.next_command() {
+                    match command {
+                        Command::SendMessage { payload, recipient } => {
+                            main_socket
+                                .send_to(&payload, recipient.into_socket())
+                                .await
+                                .unwrap();
+                        }
+                        Command::CreateAllocation { id, port, .. } => {
+                            let Ok(socket) = UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).await
+                            else {
+                                server.handle_allocation_failed(id);
+                                continue;
+                            };
+                            let socket = Arc::new(socket);
+                            let peer_sender = peer_sender.clone();
+
+                            let task = tokio::spawn({
+                                let socket = socket.clone();
+
+                                async move {
+                                    let mut buffer = vec![0u8; 65536];
+
+                                    while let Ok((len, from)) = socket.recv_from(&mut buffer).await
+                                    {
+                                        let packet = (buffer[..len].to_vec(), from, id);
+
+                                        if peer_sender.unbounded_send(packet).is_err() {
+                                            break;
+                                        }
+                                    }
+                                }
+                            });
+                            allocations.insert(id, (socket, task));
+                        }
+                        Command::FreeAllocation { id, .. } => {
+                            if let Some((_, task)) = allocations.remove(&id) {
+                                task.abort();
+                            }
+                        }
+                        Command::ForwardData { id, data, receiver } => {
+                            if let Some((socket, _)) = allocations.get(&id) {
+                                socket.send_to(&data, receiver.into_socket()).await.unwrap();
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+
+                tokio::select! {
+                    result = main_socket.recv_from(&mut buffer) => {
+                        let (len, from) = result.unwrap();
+
+                        server.handle_client_input(&buffer[..len], ClientSocket::new(from), SystemTime::now());
+                    }
+                    Some((data, from, id)) = peer_receiver.next() => {
+                        server.handle_peer_traffic(&data, PeerSocket::new(from), id, SystemTime::now());
+                    }
+                }
+            }
+        });
+
+        addr
+    }
+}
@@ -1,34 +1,59 @@
 mod channel_data;
 mod client_message;
+mod load;
+mod peer_policy;
+mod quota;
+mod rfc6062;
+mod rfc8016;
+mod snapshot;
 
 pub use crate::server::channel_data::ChannelData;
 pub use crate::server::client_message::{
-    Allocate, Binding, ChannelBind, ClientMessage, CreatePermission, Refresh,
+    Allocate, Binding, ChannelBind, ClientMessage, Connect, ConnectionBind, CreatePermission,
+    Refresh,
 };
-
-use crate::auth::{MessageIntegrityExt, Nonces, FIREZONE};
+pub use crate::server::load::LoadThresholds;
+pub use crate::server::peer_policy::PeerPolicy;
+pub use crate::server::quota::Quotas;
+pub use crate::server::rfc6062::ConnectionId;
+pub use crate::server::rfc8016::MobilityTicket;
+pub use crate::server::snapshot::Snapshot;
+
+use crate::auth::{MessageIntegrityExt, Nonces, StaticCredentials, FIREZONE};
+use crate::channel_table::{ChannelTable, Traffic};
 use crate::net_ext::IpAddrExt;
+use crate::server::load::Throughput;
+use crate::server::quota::Usage;
+use crate::server::rfc6062::{
+    ConnectionAlreadyExists, ConnectionTimeoutOrFailure, CONNECT, CONNECTION_ATTEMPT,
+    CONNECTION_BIND, TCP_TRANSPORT,
+};
 use crate::{ClientSocket, IpStack, PeerSocket, TimeEvents};
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 use bytecodec::EncodeExt;
 use core::fmt;
-use opentelemetry::metrics::{Counter, Unit, UpDownCounter};
+use opentelemetry::metrics::{Counter, Histogram, Unit, UpDownCounter};
 use opentelemetry::KeyValue;
 use rand::Rng;
 use secrecy::SecretString;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::hash::Hash;
+use std::iter;
 use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use stun_codec::rfc5389::attributes::{
-    ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
+    AlternateServer, ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
 };
-use stun_codec::rfc5389::errors::{BadRequest, StaleNonce, Unauthorized};
+use stun_codec::rfc5389::errors::{BadRequest, StaleNonce, TryAlternate, Unauthorized};
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{
     ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress, XorRelayAddress,
 };
-use stun_codec::rfc5766::errors::{AllocationMismatch, InsufficientCapacity};
+use stun_codec::rfc5766::errors::{
+    AllocationMismatch, AllocationQuotaReached, Forbidden, InsufficientCapacity, WrongCredentials,
+};
 use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
 use stun_codec::rfc8656::attributes::{
     AdditionalAddressFamily, AddressFamily, RequestedAddressFamily,
@@ -41,10 +66,16 @@ use uuid::Uuid;
 
 /// A sans-IO STUN & TURN server.
 ///
-/// A [`Server`] is bound to an IPv4 address and assumes to only operate on UDP.
+/// A [`Server`] is bound to an IPv4 address and relays data to peers via UDP (or TCP, see below).
 /// Thus, 3 out of the 5 components of a "5-tuple" are unique to an instance of [`Server`] and
 /// we can index data simply by the sender's [`SocketAddr`].
 ///
+/// Clients may talk to the [`Server`] over UDP, TCP or TLS.
+/// For stream-oriented transports, it is the caller's responsibility to split the stream into individual messages (see [`crate::framing`])
+/// and to inform the [`Server`] via [`Server::handle_client_connected`] and [`Server::handle_client_disconnected`] when a connection is opened and closed.
+/// Such clients may also request allocations that relay to peers via TCP as per [RFC 6062](https://www.rfc-editor.org/rfc/rfc6062).
+/// The bytes of those TCP connections never pass through the [`Server`], it only instructs the caller via [`Command::ConnectToPeer`] and [`Command::BindConnection`].
+///
 /// Additionally, we assume to have complete ownership over the port range `lowest_port` - `highest_port`.
 pub struct Server<R> {
     decoder: client_message::Decoder,
@@ -65,21 +96,50 @@ pub struct Server<R> {
     /// Channel numbers are unique between clients and peers, thus indexed by both.
     channel_numbers_by_client_and_peer: HashMap<(ClientSocket, PeerSocket), u16>,
 
+    /// Clients connected via a stream-oriented transport, i.e. TCP or TLS.
+    ///
+    /// Only these can create TCP allocations and bind data connections, see <https://www.rfc-editor.org/rfc/rfc6062>.
+    stream_clients: HashSet<ClientSocket>,
+    /// The TCP connections to peers of all TCP allocations.
+    connections: HashMap<ConnectionId, Connection>,
+    /// Data connections of clients, indexed by the client's socket address of that connection.
+    connections_by_data_connection: HashMap<ClientSocket, ConnectionId>,
+    next_connection_id: ConnectionId,
+
     pending_commands: VecDeque<Command>,
     next_allocation_id: AllocationId,
 
     rng: R,
 
     auth_secret: SecretString,
+    static_credentials: StaticCredentials,
 
     nonces: Nonces,
 
     time_events: TimeEvents<TimedAction>,
 
+    /// If set, we are draining and will delete all remaining allocations at this deadline.
+    drain_deadline: Option<SystemTime>,
+
+    quotas: Quotas,
+    /// The resources used per username, only present whilst a username has allocations or channels.
+    usage_by_username: HashMap<String, Usage>,
+
+    /// Sibling relays we redirect new allocations to if we are overloaded.
+    alternate_servers: Vec<SocketAddr>,
+    next_alternate_server: usize,
+    load_thresholds: LoadThresholds,
+    throughput: Throughput,
+
+    peer_policy: PeerPolicy,
+
     allocations_up_down_counter: UpDownCounter<i64>,
-    data_relayed_counter: Counter<u64>,
-    data_relayed: u64, // Keep a separate counter because `Counter` doesn't expose the current value :(
+    channels_up_down_counter: UpDownCounter<i64>,
+    allocation_lifetime_histogram: Histogram<u64>,
     responses_counter: Counter<u64>,
+
+    /// Bound channels, published for relaying channel data without going through us.
+    channel_table: ChannelTable,
 }
 
 /// The commands returned from a [`Server`].
@@ -101,6 +161,15 @@ pub enum Command {
         family: AddressFamily,
         port: u16,
     },
+    /// Listen for TCP connections from peers on the provided port and [AddressFamily].
+    ///
+    /// Like [`Command::CreateAllocation`] but for allocations that relay via TCP, see <https://www.rfc-editor.org/rfc/rfc6062>.
+    /// Incoming connections should be handed to the [`Server`] via [`Server::handle_peer_connection_accepted`].
+    CreateTcpAllocation {
+        id: AllocationId,
+        family: AddressFamily,
+        port: u16,
+    },
     /// Free the allocation associated with the given [`AllocationId`] and [AddressFamily]
     FreeAllocation {
         id: AllocationId,
@@ -114,6 +183,23 @@ pub enum Command {
     },
     /// At the latest, the [`Server`] needs to be woken at the specified deadline to execute time-based actions correctly.
     Wake { deadline: SystemTime },
+    /// Open a TCP connection to the peer from the given port of a TCP allocation.
+    ///
+    /// The outcome should be reported via [`Server::handle_peer_connected`] or [`Server::handle_peer_connection_failed`].
+    ConnectToPeer {
+        id: ConnectionId,
+        port: u16,
+        peer: PeerSocket,
+    },
+    /// Stop reading messages from the client's stream connection and instead forward all bytes between it and the TCP connection to the peer.
+    ///
+    /// This is always preceded by a [`Command::SendMessage`] to the same client which needs to be written to the stream first.
+    BindConnection {
+        id: ConnectionId,
+        client: ClientSocket,
+    },
+    /// Close the TCP connection to the peer and, if bound, the client's data connection.
+    CloseConnection { id: ConnectionId },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
@@ -135,14 +221,61 @@ impl fmt::Display for AllocationId {
     }
 }
 
+impl FromStr for AllocationId {
+    type Err = anyhow::Error;
+
+    /// Parses an [`AllocationId`] from its [`Display`](fmt::Display) representation, i.e. `AID-<number>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = s
+            .strip_prefix("AID-")
+            .context("Allocation ID must start with `AID-`")?
+            .parse()?;
+
+        Ok(AllocationId(number))
+    }
+}
+
+/// A snapshot of an allocation's state, for introspection by operators.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllocationInfo {
+    pub id: AllocationId,
+    pub client: ClientSocket,
+    pub port: u16,
+    /// The address families this allocation relays for.
+    pub families: Vec<AddressFamily>,
+    pub expires_at: SystemTime,
+    /// The number of bytes the client sent to its peers.
+    pub bytes_to_peers: u64,
+    /// The number of bytes the peers sent to the client.
+    pub bytes_from_peers: u64,
+    pub channels: Vec<ChannelInfo>,
+}
+
+/// A snapshot of a channel binding's state, for introspection by operators.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelInfo {
+    pub number: u16,
+    pub peer: PeerSocket,
+    pub bound: bool,
+    pub expires_at: SystemTime,
+}
+
 /// See <https://www.rfc-editor.org/rfc/rfc8656#name-requested-transport>.
-const UDP_TRANSPORT: u8 = 17;
+pub(crate) const UDP_TRANSPORT: u8 = 17;
+
+/// How long we wait for a TCP connection to a peer to be established and then bound by the client.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.2>.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// The duration of a channel binding.
 ///
 /// See <https://www.rfc-editor.org/rfc/rfc8656#name-channels-2>.
 const CHANNEL_BINDING_DURATION: Duration = Duration::from_secs(600);
 
+/// How long an unbound channel is kept around before it is deleted, see [`Channel::bound`].
+const UNBOUND_CHANNEL_GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
 impl<R> Server<R>
 where
     R: Rng,
@@ -169,15 +302,19 @@ where
             .i64_up_down_counter("allocations_total")
             .with_description("The number of active allocations")
             .init();
+        let channels_up_down_counter = meter
+            .i64_up_down_counter("channels_total")
+            .with_description("The number of active channel bindings")
+            .init();
+        let allocation_lifetime_histogram = meter
+            .u64_histogram("allocation_lifetime_seconds")
+            .with_description("The lifetime granted to allocations upon allocate and refresh")
+            .with_unit(Unit::new("s"))
+            .init();
         let responses_counter = meter
             .u64_counter("responses_total")
             .with_description("The number of responses")
             .init();
-        let data_relayed_counter = meter
-            .u64_counter("data_relayed_bytes")
-            .with_description("The number of bytes relayed")
-            .with_unit(Unit::new("b"))
-            .init();
 
         Self {
             decoder: Default::default(),
@@ -190,16 +327,30 @@ where
             highest_port,
             channels_by_client_and_number: Default::default(),
             channel_numbers_by_client_and_peer: Default::default(),
+            stream_clients: Default::default(),
+            connections: Default::default(),
+            connections_by_data_connection: Default::default(),
+            next_connection_id: ConnectionId::new(1),
             pending_commands: Default::default(),
             next_allocation_id: AllocationId(1),
             auth_secret: SecretString::from(hex::encode(rng.gen::<[u8; 32]>())),
+            static_credentials: StaticCredentials::default(),
             rng,
             time_events: TimeEvents::default(),
             nonces: Default::default(),
+            drain_deadline: None,
+            quotas: Quotas::default(),
+            usage_by_username: Default::default(),
+            alternate_servers: Default::default(),
+            next_alternate_server: 0,
+            load_thresholds: LoadThresholds::default(),
+            throughput: Throughput::default(),
+            peer_policy: PeerPolicy::default(),
             allocations_up_down_counter,
+            channels_up_down_counter,
+            allocation_lifetime_histogram,
             responses_counter,
-            data_relayed_counter,
-            data_relayed: 0,
+            channel_table: ChannelTable::new(),
         }
     }
 
@@ -207,6 +358,18 @@ where
         &self.auth_secret
     }
 
+    /// Replaces the randomly generated auth secret.
+    ///
+    /// Useful when running without the portal, in which case whoever hands out credentials needs to know the secret.
+    pub fn set_auth_secret(&mut self, auth_secret: SecretString) {
+        self.auth_secret = auth_secret;
+    }
+
+    /// Accept the given static credentials in addition to the ones derived from the auth secret.
+    pub fn set_static_credentials(&mut self, static_credentials: StaticCredentials) {
+        self.static_credentials = static_credentials;
+    }
+
     /// Registers a new, valid nonce.
     ///
     /// Each nonce is valid for 10 requests.
@@ -214,8 +377,74 @@ where
         self.nonces.add_new(nonce);
     }
 
+    /// Limit the resources each username can use.
+    ///
+    /// By default, there are no limits.
+    /// Usernames that are already in use keep their current bandwidth limit until all their allocations and channels are gone.
+    pub fn set_quotas(&mut self, quotas: Quotas) {
+        self.quotas = quotas;
+    }
+
+    /// Redirect new allocations to the given relays whenever we exceed one of the [`LoadThresholds`].
+    ///
+    /// Clients are answered with `300 Try Alternate`, picking the alternate servers of the client's address family in turn.
+    /// Without alternate servers, the thresholds have no effect.
+    pub fn set_alternate_servers(
+        &mut self,
+        alternate_servers: Vec<SocketAddr>,
+        load_thresholds: LoadThresholds,
+    ) {
+        self.alternate_servers = alternate_servers;
+        self.load_thresholds = load_thresholds;
+    }
+
+    /// Restrict which peers clients may relay to.
+    ///
+    /// By default, loopback, link-local, private, multicast and unspecified addresses are denied.
+    /// Requests naming a denied peer are answered with `403 Forbidden`.
+    /// Existing channel bindings are not affected.
+    pub fn set_peer_policy(&mut self, peer_policy: PeerPolicy) {
+        self.peer_policy = peer_policy;
+    }
+
+    /// Stop accepting new allocations in preparation for shutting down.
+    ///
+    /// Existing allocations continue to work until they expire or `deadline` is reached, whatever comes first.
+    /// Once [`Server::is_drained`] returns `true`, the relay can be shut down without disrupting any clients.
+    #[tracing::instrument(skip(self), level = "error")]
+    pub fn start_draining(&mut self, deadline: SystemTime) {
+        if self.drain_deadline.is_some() {
+            return;
+        }
+
+        tracing::info!(target: "relay", num_allocations = %self.allocations.len(), "Draining");
+
+        self.drain_deadline = Some(deadline);
+
+        let wake_deadline = self.time_events.add(deadline, TimedAction::DrainDeadline);
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.drain_deadline.is_some()
+    }
+
+    /// Whether we are draining and all allocations are gone.
+    pub fn is_drained(&self) -> bool {
+        self.is_draining() && self.allocations.is_empty()
+    }
+
     pub fn num_relayed_bytes(&self) -> u64 {
-        self.data_relayed
+        self.channel_table.num_relayed_bytes()
+    }
+
+    /// A handle to the bound channels of this [`Server`], for relaying channel data on other threads.
+    ///
+    /// Channels are only published if there is no bandwidth quota because only the [`Server`] can enforce it.
+    pub fn channel_table(&self) -> ChannelTable {
+        self.channel_table.clone()
     }
 
     pub fn num_allocations(&self) -> usize {
@@ -226,6 +455,41 @@ where
         self.channels_by_client_and_number.len()
     }
 
+    /// Returns a snapshot of all current allocations and their channel bindings.
+    pub fn allocations(&self) -> Vec<AllocationInfo> {
+        let mut channels_by_allocation = HashMap::<AllocationId, Vec<ChannelInfo>>::new();
+
+        for ((_, number), channel) in &self.channels_by_client_and_number {
+            channels_by_allocation
+                .entry(channel.allocation)
+                .or_default()
+                .push(ChannelInfo {
+                    number: *number,
+                    peer: channel.peer_address,
+                    bound: channel.bound,
+                    expires_at: channel.expiry,
+                });
+        }
+
+        self.allocations
+            .iter()
+            .map(|(client, allocation)| AllocationInfo {
+                id: allocation.id,
+                client: *client,
+                port: allocation.port,
+                families: std::iter::once(allocation.first_relay_addr.family())
+                    .chain(allocation.second_relay_addr.map(|a| a.family()))
+                    .collect(),
+                expires_at: allocation.expires_at,
+                bytes_to_peers: allocation.traffic.bytes_to_peers(),
+                bytes_from_peers: allocation.traffic.bytes_from_peers(),
+                channels: channels_by_allocation
+                    .remove(&allocation.id)
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+
     /// Process the bytes received from a client.
     ///
     /// After calling this method, you should call [`Server::next_command`] until it returns `None`.
@@ -279,6 +543,10 @@ where
             ClientMessage::CreatePermission(request) => {
                 self.handle_create_permission_request(request, sender, now)
             }
+            ClientMessage::Connect(request) => self.handle_connect_request(request, sender, now),
+            ClientMessage::ConnectionBind(request) => {
+                self.handle_connection_bind_request(request, sender, now)
+            }
             ClientMessage::Binding(request) => {
                 self.handle_binding_request(request, sender);
                 return;
@@ -330,6 +598,7 @@ where
         bytes: &[u8],
         sender: PeerSocket,
         allocation: AllocationId,
+        now: SystemTime,
     ) {
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(bytes);
@@ -371,10 +640,22 @@ where
             return;
         }
 
+        if !has_bandwidth(
+            &mut self.usage_by_username,
+            &channel.username,
+            bytes.len(),
+            now,
+        ) {
+            tracing::debug!(target: "relay", username = %channel.username, "Bandwidth quota exceeded, refusing to relay {} bytes", bytes.len());
+            return;
+        }
+
         tracing::debug!(target: "relay", "Relaying {} bytes", bytes.len());
 
-        self.data_relayed_counter.add(bytes.len() as u64, &[]);
-        self.data_relayed += bytes.len() as u64;
+        if let Some(allocation) = self.allocations.get(&client) {
+            self.channel_table
+                .record_from_peers(&allocation.traffic, bytes.len());
+        }
 
         let data = ChannelData::new(*channel_number, bytes).to_bytes();
 
@@ -416,9 +697,10 @@ where
                         tracing::info!(target: "relay", channel = %chan, %client, peer = %channel.peer_address, allocation = %channel.allocation, "Channel is now expired");
 
                         channel.bound = false;
+                        self.channel_table.remove(client, chan);
 
                         let wake_deadline = self.time_events.add(
-                            now + Duration::from_secs(5 * 60),
+                            now + UNBOUND_CHANNEL_GRACE_PERIOD,
                             TimedAction::DeleteChannel((client, chan)),
                         );
                         self.pending_commands.push_back(Command::Wake {
@@ -429,6 +711,34 @@ where
                 TimedAction::DeleteChannel((client, chan)) => {
                     self.delete_channel_binding(client, chan);
                 }
+                TimedAction::ExpireConnection(id) => {
+                    let Some(connection) = self.connections.get(&id) else {
+                        continue;
+                    };
+
+                    if matches!(connection.state, ConnectionState::Bound(_))
+                        || connection.deadline > now
+                    {
+                        continue;
+                    }
+
+                    tracing::info!(target: "relay", connection = %id, peer = %connection.peer, "Connection to peer timed out");
+
+                    self.close_connection(id);
+                }
+                TimedAction::DrainDeadline => {
+                    let remaining_allocations = self
+                        .clients_by_allocation
+                        .keys()
+                        .copied()
+                        .collect::<Vec<_>>();
+
+                    tracing::info!(target: "relay", num_allocations = %remaining_allocations.len(), "Drain deadline reached, deleting remaining allocations");
+
+                    for id in remaining_allocations {
+                        self.delete_allocation(id);
+                    }
+                }
             }
         }
     }
@@ -439,6 +749,160 @@ where
         self.delete_allocation(allocation)
     }
 
+    /// An operator requested to delete an allocation.
+    ///
+    /// Returns `false` if there is no such allocation.
+    #[tracing::instrument(skip(self), fields(%allocation), level = "error")]
+    pub fn handle_admin_delete_allocation(&mut self, allocation: AllocationId) -> bool {
+        if !self.clients_by_allocation.contains_key(&allocation) {
+            return false;
+        }
+
+        tracing::info!(target: "relay", "Deleting allocation on request of operator");
+
+        self.delete_allocation(allocation);
+
+        true
+    }
+
+    /// A client connected via a stream-oriented transport, i.e. TCP or TLS.
+    pub fn handle_client_connected(&mut self, client: ClientSocket) {
+        self.stream_clients.insert(client);
+    }
+
+    /// The stream connection (TCP or TLS) of a client has been closed.
+    ///
+    /// As per <https://www.rfc-editor.org/rfc/rfc6062#section-5.1>, closing the control connection deletes the allocation.
+    /// Closing a data connection closes the TCP connection to the peer it is bound to.
+    #[tracing::instrument(skip(self), fields(%client, allocation), level = "error")]
+    pub fn handle_client_disconnected(&mut self, client: ClientSocket) {
+        self.stream_clients.remove(&client);
+
+        if let Some(id) = self.connections_by_data_connection.get(&client).copied() {
+            tracing::info!(target: "relay", connection = %id, "Client closed its data connection");
+
+            self.close_connection(id);
+        }
+
+        let Some(allocation) = self.allocations.get(&client) else {
+            return;
+        };
+
+        let id = allocation.id;
+        Span::current().record("allocation", display(&id));
+
+        tracing::info!(target: "relay", "Client closed its connection");
+
+        self.delete_allocation(id)
+    }
+
+    /// A peer connected to the relayed address of a TCP allocation.
+    ///
+    /// Returns the [`ConnectionId`] the connection is known as from now on.
+    /// If `None` is returned, the connection is not wanted and should be closed.
+    #[tracing::instrument(skip(self, now), fields(%allocation, %peer), level = "error")]
+    pub fn handle_peer_connection_accepted(
+        &mut self,
+        allocation: AllocationId,
+        peer: PeerSocket,
+        now: SystemTime,
+    ) -> Option<ConnectionId> {
+        let client = self.clients_by_allocation.get(&allocation).copied()?;
+        let username = self
+            .allocations
+            .get(&client)
+            .filter(|a| a.transport == PeerTransport::Tcp)?
+            .username
+            .clone();
+
+        let id = self.next_connection_id.next();
+        let deadline = now + CONNECTION_TIMEOUT;
+
+        self.connections.insert(
+            id,
+            Connection {
+                allocation,
+                peer,
+                username,
+                state: ConnectionState::Pending,
+                deadline,
+            },
+        );
+
+        let wake_deadline = self
+            .time_events
+            .add(deadline, TimedAction::ExpireConnection(id));
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
+
+        let mut message = Message::new(
+            MessageClass::Indication,
+            *CONNECTION_ATTEMPT,
+            TransactionId::new(self.rng.gen()),
+        );
+        message.add_attribute(id);
+        message.add_attribute(XorPeerAddress::new(peer.0));
+
+        self.send_message(message, client);
+
+        tracing::info!(target: "relay", connection = %id, "Peer connected to TCP allocation");
+
+        Some(id)
+    }
+
+    /// A TCP connection to a peer requested via [`Command::ConnectToPeer`] has been established.
+    #[tracing::instrument(skip(self, now), fields(%id), level = "error")]
+    pub fn handle_peer_connected(&mut self, id: ConnectionId, now: SystemTime) {
+        let Some(connection) = self.connections.get_mut(&id) else {
+            tracing::debug!(target: "relay", "Connection is no longer needed");
+
+            self.pending_commands
+                .push_back(Command::CloseConnection { id });
+            return;
+        };
+
+        let ConnectionState::Connecting(transaction_id) = connection.state else {
+            debug_assert!(false, "Connection should be connecting");
+            return;
+        };
+
+        connection.state = ConnectionState::Pending;
+        connection.deadline = now + CONNECTION_TIMEOUT;
+
+        let deadline = connection.deadline;
+        let Some(client) = self
+            .clients_by_allocation
+            .get(&connection.allocation)
+            .copied()
+        else {
+            debug_assert!(false, "Connections should be closed with their allocation");
+            return;
+        };
+
+        let wake_deadline = self
+            .time_events
+            .add(deadline, TimedAction::ExpireConnection(id));
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
+
+        let mut message = Message::new(MessageClass::SuccessResponse, *CONNECT, transaction_id);
+        message.add_attribute(id);
+
+        self.send_message(message, client);
+
+        tracing::info!(target: "relay", "Connected to peer");
+    }
+
+    /// A TCP connection to a peer requested via [`Command::ConnectToPeer`] could not be established.
+    #[tracing::instrument(skip(self), fields(%id), level = "error")]
+    pub fn handle_peer_connection_failed(&mut self, id: ConnectionId) {
+        tracing::info!(target: "relay", "Failed to connect to peer");
+
+        self.close_connection(id);
+    }
+
     /// Return the next command to be executed.
     pub fn next_command(&mut self) -> Option<Command> {
         let num_commands = self.pending_commands.len();
@@ -470,7 +934,13 @@ where
         sender: ClientSocket,
         now: SystemTime,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request, now)?;
+        if self.is_draining() {
+            tracing::warn!(target: "relay", "Refusing new allocation because we are draining");
+
+            return Err(error_response(InsufficientCapacity, &request));
+        }
+
+        let username = self.verify_auth(&request, now)?;
 
         if let Some(allocation) = self.allocations.get(&sender) {
             Span::current().record("allocation", display(&allocation.id));
@@ -479,6 +949,30 @@ where
             return Err(error_response(AllocationMismatch, &request));
         }
 
+        if let Some(max_allocations) = self.quotas.max_allocations {
+            let num_allocations = self
+                .usage_by_username
+                .get(&username)
+                .map_or(0, |usage| usage.allocations);
+
+            if num_allocations >= max_allocations {
+                tracing::warn!(target: "relay", %username, %max_allocations, "Allocation quota reached");
+
+                return Err(error_response(AllocationQuotaReached, &request));
+            }
+        }
+
+        if let Some(reason) = self.overload_reason(now) {
+            if let Some(alternate_server) = self.next_alternate_server(sender) {
+                tracing::info!(target: "relay", %alternate_server, "Redirecting client because {reason}");
+
+                let mut message = error_response(TryAlternate, &request);
+                message.add_attribute(AlternateServer::new(alternate_server));
+
+                return Err(message);
+            }
+        }
+
         let max_available_ports = self.max_available_ports() as usize;
         if self.allocations_by_port.len() == max_available_ports {
             tracing::warn!(target: "relay", %max_available_ports, "No more ports available");
@@ -487,11 +981,20 @@ where
         }
 
         let requested_protocol = request.requested_transport().protocol();
-        if requested_protocol != UDP_TRANSPORT {
-            tracing::warn!(target: "relay", %requested_protocol, "Unsupported protocol");
+        let transport = match requested_protocol {
+            UDP_TRANSPORT => PeerTransport::Udp,
+            TCP_TRANSPORT if self.stream_clients.contains(&sender) => PeerTransport::Tcp,
+            TCP_TRANSPORT => {
+                tracing::warn!(target: "relay", "TCP allocations can only be requested over TCP or TLS");
 
-            return Err(error_response(BadRequest, &request));
-        }
+                return Err(error_response(BadRequest, &request));
+            }
+            _ => {
+                tracing::warn!(target: "relay", %requested_protocol, "Unsupported protocol");
+
+                return Err(error_response(BadRequest, &request));
+            }
+        };
 
         let (first_relay_address, maybe_second_relay_addr) = derive_relay_addresses(
             self.public_address,
@@ -504,11 +1007,13 @@ where
         // TODO: Do we need to handle EVEN/ODD-PORT?
         let effective_lifetime = request.effective_lifetime();
 
-        let allocation = self.create_new_allocation(
+        let mut allocation = self.create_new_allocation(
             now,
             &effective_lifetime,
             first_relay_address,
             maybe_second_relay_addr,
+            transport,
+            username,
         );
 
         let mut message = Message::new(
@@ -533,6 +1038,14 @@ where
         message.add_attribute(XorMappedAddress::new(sender.0));
         message.add_attribute(effective_lifetime.clone());
 
+        // TCP allocations cannot move because they are tied to the client's control connection.
+        if request.mobility_ticket().is_some() && transport == PeerTransport::Udp {
+            let ticket = MobilityTicket::random(&mut self.rng);
+
+            message.add_attribute(ticket.clone());
+            allocation.mobility_ticket = Some(ticket);
+        }
+
         let wake_deadline = self.time_events.add(
             allocation.expires_at,
             TimedAction::ExpireAllocation(allocation.id),
@@ -540,16 +1053,15 @@ where
         self.pending_commands.push_back(Command::Wake {
             deadline: wake_deadline,
         });
-        self.pending_commands.push_back(Command::CreateAllocation {
-            id: allocation.id,
-            family: first_relay_address.family(),
-            port,
-        });
-        if let Some(second_relay_addr) = maybe_second_relay_addr {
-            self.pending_commands.push_back(Command::CreateAllocation {
-                id: allocation.id,
-                family: second_relay_addr.family(),
-                port,
+        for family in iter::once(first_relay_address)
+            .chain(maybe_second_relay_addr)
+            .map(|addr| addr.family())
+        {
+            let id = allocation.id;
+
+            self.pending_commands.push_back(match transport {
+                PeerTransport::Udp => Command::CreateAllocation { id, family, port },
+                PeerTransport::Tcp => Command::CreateTcpAllocation { id, family, port },
             });
         }
         self.send_message(message, sender);
@@ -573,9 +1085,12 @@ where
             )
         }
 
+        self.usage_mut(&allocation.username, now).allocations += 1;
         self.clients_by_allocation.insert(allocation.id, sender);
         self.allocations.insert(sender, allocation);
         self.allocations_up_down_counter.add(1, &[]);
+        self.allocation_lifetime_histogram
+            .record(effective_lifetime.lifetime().as_secs(), &[]);
 
         Ok(())
     }
@@ -589,7 +1104,12 @@ where
         sender: ClientSocket,
         now: SystemTime,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request, now)?;
+        let username = self.verify_auth(&request, now)?;
+
+        // A refresh from an unknown address may be a client that moved, see <https://www.rfc-editor.org/rfc/rfc8016#section-3.3>.
+        if !self.allocations.contains_key(&sender) && request.mobility_ticket().is_some() {
+            self.migrate_allocation(&request, &username, sender, now)?;
+        }
 
         // TODO: Verify that this is the correct error code.
         let allocation = self
@@ -614,6 +1134,17 @@ where
         }
 
         allocation.expires_at = now + effective_lifetime.lifetime();
+        self.allocation_lifetime_histogram
+            .record(effective_lifetime.lifetime().as_secs(), &[]);
+
+        let mut response = refresh_success_response(effective_lifetime, request.transaction_id());
+        if let (Some(_), Some(ticket)) =
+            (request.mobility_ticket(), &mut allocation.mobility_ticket)
+        {
+            // Rotate the ticket so a leaked one is only good until the client's next refresh, see <https://www.rfc-editor.org/rfc/rfc8016#section-3.3>.
+            *ticket = MobilityTicket::random(&mut self.rng);
+            response.add_attribute(ticket.clone());
+        }
 
         tracing::info!(
             target: "relay",
@@ -628,10 +1159,7 @@ where
         self.pending_commands.push_back(Command::Wake {
             deadline: wake_deadline,
         });
-        self.send_message(
-            refresh_success_response(effective_lifetime, request.transaction_id()),
-            sender,
-        );
+        self.send_message(response, sender);
 
         Ok(())
     }
@@ -645,7 +1173,7 @@ where
         sender: ClientSocket,
         now: SystemTime,
     ) -> Result<(), Message<Attribute>> {
-        self.verify_auth(&request, now)?;
+        let username = self.verify_auth(&request, now)?;
 
         let allocation = self
             .allocations
@@ -667,6 +1195,19 @@ where
             return Err(error_response(PeerAddressFamilyMismatch, &request));
         }
 
+        if !self.peer_policy.permits(peer_address.0.ip()) {
+            tracing::warn!(target: "relay", "Peer address is not allowed");
+
+            return Err(error_response(Forbidden, &request));
+        }
+
+        // Channels are not supported for TCP allocations, see <https://www.rfc-editor.org/rfc/rfc6062#section-5.1>.
+        if allocation.transport == PeerTransport::Tcp {
+            tracing::warn!(target: "relay", "Cannot bind channel on TCP allocation");
+
+            return Err(error_response(BadRequest, &request));
+        }
+
         // Ensure the same address isn't already bound to a different channel.
         if let Some(number) = self
             .channel_numbers_by_client_and_peer
@@ -714,10 +1255,29 @@ where
         // Channel binding does not exist yet, create it.
 
         // TODO: Any additional validations would go here.
-        // TODO: Capacity checking would go here.
+
+        if let Some(max_channels) = self.quotas.max_channels {
+            let num_channels = self
+                .usage_by_username
+                .get(&username)
+                .map_or(0, |usage| usage.channels);
+
+            if num_channels >= max_channels {
+                tracing::warn!(target: "relay", %username, %max_channels, "Channel quota reached");
+
+                return Err(error_response(InsufficientCapacity, &request));
+            }
+        }
 
         let allocation_id = allocation.id;
-        self.create_channel_binding(sender, requested_channel, peer_address, allocation_id, now);
+        self.create_channel_binding(
+            sender,
+            requested_channel,
+            peer_address,
+            allocation_id,
+            username,
+            now,
+        );
         self.send_message(
             channel_bind_success_response(request.transaction_id()),
             sender,
@@ -733,7 +1293,7 @@ where
     /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-createpermissio> for details.
     ///
     /// This TURN server implementation does not support relaying data other than through channels.
-    /// Thus, creating a permission is a no-op that always succeeds.
+    /// Thus, creating a permission is a no-op that succeeds unless one of the peers is not allowed by our [`PeerPolicy`].
     #[tracing::instrument(skip(self, message, now), fields(%sender), level = "error")]
     fn handle_create_permission_request(
         &mut self,
@@ -743,6 +1303,17 @@ where
     ) -> Result<(), Message<Attribute>> {
         self.verify_auth(&message, now)?;
 
+        if let Some(peer) = message
+            .xor_peer_addresses()
+            .iter()
+            .map(|address| address.address())
+            .find(|address| !self.peer_policy.permits(address.ip()))
+        {
+            tracing::warn!(target: "relay", %peer, "Peer address is not allowed");
+
+            return Err(error_response(Forbidden, &message));
+        }
+
         self.send_message(
             create_permission_success_response(message.transaction_id()),
             sender,
@@ -751,11 +1322,148 @@ where
         Ok(())
     }
 
+    /// Handle a TURN connect request.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.2> for details.
+    #[tracing::instrument(skip(self, request, now), fields(%sender, allocation, peer), level = "error")]
+    fn handle_connect_request(
+        &mut self,
+        request: Connect,
+        sender: ClientSocket,
+        now: SystemTime,
+    ) -> Result<(), Message<Attribute>> {
+        let username = self.verify_auth(&request, now)?;
+
+        let allocation = self
+            .allocations
+            .get(&sender)
+            .ok_or(error_response(AllocationMismatch, &request))?;
+
+        let peer = PeerSocket(request.xor_peer_address().address());
+
+        Span::current().record("allocation", display(&allocation.id));
+        Span::current().record("peer", display(&peer));
+
+        if allocation.transport != PeerTransport::Tcp {
+            tracing::warn!(target: "relay", "Cannot connect to peer on UDP allocation");
+
+            return Err(error_response(BadRequest, &request));
+        }
+
+        if !allocation.can_relay_to(peer) {
+            tracing::warn!(target: "relay", "Allocation cannot relay to peer");
+
+            return Err(error_response(PeerAddressFamilyMismatch, &request));
+        }
+
+        if !self.peer_policy.permits(peer.0.ip()) {
+            tracing::warn!(target: "relay", "Peer address is not allowed");
+
+            return Err(error_response(Forbidden, &request));
+        }
+
+        let allocation_id = allocation.id;
+        let port = allocation.port;
+
+        if self
+            .connections
+            .values()
+            .any(|c| c.allocation == allocation_id && c.peer == peer)
+        {
+            tracing::warn!(target: "relay", "Connection to peer already exists");
+
+            return Err(error_response(ConnectionAlreadyExists, &request));
+        }
+
+        let id = self.next_connection_id.next();
+        let deadline = now + CONNECTION_TIMEOUT;
+
+        self.connections.insert(
+            id,
+            Connection {
+                allocation: allocation_id,
+                peer,
+                username,
+                state: ConnectionState::Connecting(request.transaction_id()),
+                deadline,
+            },
+        );
+
+        let wake_deadline = self
+            .time_events
+            .add(deadline, TimedAction::ExpireConnection(id));
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
+        self.pending_commands
+            .push_back(Command::ConnectToPeer { id, port, peer });
+
+        tracing::info!(target: "relay", connection = %id, "Connecting to peer");
+
+        Ok(())
+    }
+
+    /// Handle a TURN connection bind request.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.4> for details.
+    #[tracing::instrument(skip(self, request, now), fields(%sender, connection), level = "error")]
+    fn handle_connection_bind_request(
+        &mut self,
+        request: ConnectionBind,
+        sender: ClientSocket,
+        now: SystemTime,
+    ) -> Result<(), Message<Attribute>> {
+        let username = self.verify_auth(&request, now)?;
+
+        let id = request.connection_id();
+        Span::current().record("connection", display(&id));
+
+        if !self.stream_clients.contains(&sender) {
+            tracing::warn!(target: "relay", "Data connections must use TCP or TLS");
+
+            return Err(error_response(BadRequest, &request));
+        }
+
+        let Some(connection) = self
+            .connections
+            .get_mut(&id)
+            .filter(|c| c.state == ConnectionState::Pending)
+        else {
+            tracing::warn!(target: "relay", "No pending connection to bind");
+
+            return Err(error_response(BadRequest, &request));
+        };
+
+        if connection.username != username {
+            tracing::warn!(target: "relay", "Connection belongs to a different username");
+
+            return Err(error_response(WrongCredentials, &request));
+        }
+
+        connection.state = ConnectionState::Bound(sender);
+        self.connections_by_data_connection.insert(sender, id);
+
+        self.send_message(
+            Message::new(
+                MessageClass::SuccessResponse,
+                *CONNECTION_BIND,
+                request.transaction_id(),
+            ),
+            sender,
+        );
+        self.pending_commands
+            .push_back(Command::BindConnection { id, client: sender });
+
+        tracing::info!(target: "relay", "Bound data connection");
+
+        Ok(())
+    }
+
     fn handle_channel_data_message(
         &mut self,
         message: ChannelData,
         sender: ClientSocket,
-        _: SystemTime,
+        now: SystemTime,
     ) {
         let channel_number = message.channel();
         let data = message.data();
@@ -780,10 +1488,22 @@ where
         Span::current().record("recipient", field::display(&channel.peer_address));
         Span::current().record("channel", field::display(&channel_number));
 
+        if !has_bandwidth(
+            &mut self.usage_by_username,
+            &channel.username,
+            data.len(),
+            now,
+        ) {
+            tracing::debug!(target: "relay", username = %channel.username, "Bandwidth quota exceeded, refusing to forward {} bytes", data.len());
+            return;
+        }
+
         tracing::debug!(target: "relay", "Relaying {} bytes", data.len());
 
-        self.data_relayed_counter.add(data.len() as u64, &[]);
-        self.data_relayed += data.len() as u64;
+        if let Some(allocation) = self.allocations.get(&sender) {
+            self.channel_table
+                .record_to_peers(&allocation.traffic, data.len());
+        }
 
         if tracing::enabled!(target: "wire", tracing::Level::TRACE) {
             let hex_bytes = hex::encode(data);
@@ -797,11 +1517,12 @@ where
         });
     }
 
+    /// Verifies the long-term credentials of a request, returning the authenticated username.
     fn verify_auth(
         &mut self,
         request: &(impl StunRequest + ProtectedRequest),
         now: SystemTime,
-    ) -> Result<(), Message<Attribute>> {
+    ) -> Result<String, Message<Attribute>> {
         let message_integrity = request
             .message_integrity()
             .map_err(|e| error_response(e, request))?;
@@ -821,11 +1542,13 @@ where
             .handle_nonce_used(nonce)
             .map_err(|_| error_response(StaleNonce, request))?;
 
-        message_integrity
-            .verify(&self.auth_secret, username.name(), now)
-            .map_err(|_| error_response(Unauthorized, request))?;
+        let result = match self.static_credentials.password(username.name()) {
+            Some(password) => message_integrity.verify_static(username.name(), password),
+            None => message_integrity.verify(&self.auth_secret, username.name(), now),
+        };
+        result.map_err(|_| error_response(Unauthorized, request))?;
 
-        Ok(())
+        Ok(username.name().to_owned())
     }
 
     fn create_new_allocation(
@@ -834,6 +1557,8 @@ where
         lifetime: &Lifetime,
         first_relay_addr: IpAddr,
         second_relay_addr: Option<IpAddr>,
+        transport: PeerTransport,
+        username: String,
     ) -> Allocation {
         // First, find an unused port.
 
@@ -861,6 +1586,10 @@ where
             expires_at: now + lifetime.lifetime(),
             first_relay_addr,
             second_relay_addr,
+            transport,
+            username,
+            traffic: Default::default(),
+            mobility_ticket: None,
         }
     }
 
@@ -868,16 +1597,69 @@ where
         self.highest_port - self.lowest_port
     }
 
+    /// Checks whether we are beyond any of our [`LoadThresholds`], returning a description of why.
+    fn overload_reason(&mut self, now: SystemTime) -> Option<String> {
+        let free_ports =
+            (self.max_available_ports() as usize).saturating_sub(self.allocations_by_port.len());
+        if free_ports == 0 {
+            return Some("no more ports are available".to_owned());
+        }
+        if let Some(min_free_ports) = self
+            .load_thresholds
+            .min_free_ports
+            .filter(|min| free_ports < *min)
+        {
+            return Some(format!(
+                "only {free_ports} ports are available (minimum: {min_free_ports})"
+            ));
+        }
+        if let Some(max_allocations) = self
+            .load_thresholds
+            .max_allocations
+            .filter(|max| self.allocations.len() >= *max)
+        {
+            return Some(format!("we have {max_allocations} allocations"));
+        }
+        if let Some(max_bytes_per_sec) = self.load_thresholds.max_bytes_per_sec {
+            let bytes_per_sec = self.throughput.sample(self.num_relayed_bytes(), now);
+
+            if bytes_per_sec >= max_bytes_per_sec {
+                return Some(format!(
+                    "we relay {bytes_per_sec} bytes/s (maximum: {max_bytes_per_sec})"
+                ));
+            }
+        }
+
+        None
+    }
+
+    /// Picks the next alternate server that the client can reach, i.e. of the same address family.
+    fn next_alternate_server(&mut self, client: ClientSocket) -> Option<SocketAddr> {
+        let candidates = self
+            .alternate_servers
+            .iter()
+            .filter(|server| server.is_ipv4() == client.0.is_ipv4())
+            .collect::<Vec<_>>();
+
+        let server = **candidates.get(self.next_alternate_server % candidates.len().max(1))?;
+        self.next_alternate_server = self.next_alternate_server.wrapping_add(1);
+
+        Some(server)
+    }
+
     fn create_channel_binding(
         &mut self,
         client: ClientSocket,
         requested_channel: u16,
         peer: PeerSocket,
         id: AllocationId,
+        username: String,
         now: SystemTime,
     ) {
         let expiry = now + CHANNEL_BINDING_DURATION;
 
+        self.usage_mut(&username, now).channels += 1;
+
         let existing = self.channels_by_client_and_number.insert(
             (client, requested_channel),
             Channel {
@@ -885,6 +1667,7 @@ where
                 peer_address: peer,
                 allocation: id,
                 bound: true,
+                username,
             },
         );
         debug_assert!(existing.is_none());
@@ -895,6 +1678,9 @@ where
 
         debug_assert!(existing.is_none());
 
+        self.publish_channel(client, requested_channel, id, peer);
+        self.channels_up_down_counter.add(1, &[]);
+
         let wake_deadline = self.time_events.add(
             expiry,
             TimedAction::UnbindChannel((client, requested_channel)),
@@ -904,9 +1690,38 @@ where
         });
     }
 
+    /// Publishes a bound channel to the [`ChannelTable`].
+    ///
+    /// Bandwidth quotas can only be enforced by us, thus we don't publish anything if they are set.
+    /// Clients connected via TCP or TLS are served by the eventloop, so their channels are never published either.
+    fn publish_channel(
+        &self,
+        client: ClientSocket,
+        number: u16,
+        allocation: AllocationId,
+        peer: PeerSocket,
+    ) {
+        if self.quotas.max_bytes_per_sec.is_some() || self.stream_clients.contains(&client) {
+            return;
+        }
+
+        let Some((port, traffic)) = self
+            .allocations
+            .get(&client)
+            .filter(|a| a.id == allocation)
+            .map(|a| (a.port, a.traffic.clone()))
+        else {
+            return;
+        };
+
+        self.channel_table
+            .insert(client, number, allocation, port, peer, traffic);
+    }
+
     fn send_message(&mut self, message: Message<Attribute>, recipient: ClientSocket) {
         let method = message.method();
         let class = message.class();
+        let error_code = message.get_attribute::<ErrorCode>().map(|e| e.code());
         tracing::trace!(target: "relay",  method = %message.method(), class = %message.class(), "Sending message");
 
         let Ok(bytes) = self.encoder.encode_into_bytes(message) else {
@@ -938,13 +1753,15 @@ where
             CREATE_PERMISSION => "createpermission",
             _ => return,
         };
-        self.responses_counter.add(
-            1,
-            &[
-                KeyValue::new("response_class", response_class),
-                KeyValue::new("message_type", message_type),
-            ],
-        );
+        let mut attributes = vec![
+            KeyValue::new("response_class", response_class),
+            KeyValue::new("message_type", message_type),
+        ];
+        if let Some(error_code) = error_code {
+            attributes.push(KeyValue::new("error_code", i64::from(error_code)));
+        }
+
+        self.responses_counter.add(1, &attributes);
     }
 
     fn get_allocation(&self, id: &AllocationId) -> Option<&Allocation> {
@@ -966,7 +1783,19 @@ where
 
         let port = allocation.port;
 
+        let connections = self
+            .connections
+            .iter()
+            .filter(|(_, c)| c.allocation == id)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for connection in connections {
+            self.close_connection(connection);
+        }
+
         self.allocations_by_port.remove(&port);
+        self.channel_table.remove_allocation(id);
+        self.release_usage(&allocation.username, |usage| usage.allocations -= 1);
 
         self.allocations_up_down_counter.add(-1, &[]);
         self.pending_commands.push_back(Command::FreeAllocation {
@@ -1000,10 +1829,86 @@ where
             "Channel state should be consistent"
         );
 
-        self.channels_by_client_and_number.remove(&(client, chan));
+        self.channel_table.remove(client, chan);
+
+        if let Some(channel) = self.channels_by_client_and_number.remove(&(client, chan)) {
+            self.release_usage(&channel.username, |usage| usage.channels -= 1);
+            self.channels_up_down_counter.add(-1, &[]);
+        }
 
         tracing::info!(target: "relay", channel = %chan, %client, %peer, %allocation, "Channel binding is now deleted (and can be rebound)");
     }
+
+    /// Forgets about a TCP connection to a peer and instructs the caller to close it.
+    ///
+    /// If we were still connecting on behalf of the client, the client is informed that this failed.
+    fn close_connection(&mut self, id: ConnectionId) {
+        let Some(connection) = self.connections.remove(&id) else {
+            return;
+        };
+
+        match connection.state {
+            ConnectionState::Connecting(transaction_id) => {
+                if let Some(client) = self
+                    .clients_by_allocation
+                    .get(&connection.allocation)
+                    .copied()
+                {
+                    let mut message =
+                        Message::new(MessageClass::ErrorResponse, *CONNECT, transaction_id);
+                    message.add_attribute(ErrorCode::from(ConnectionTimeoutOrFailure));
+
+                    self.queue_error_response(client, message);
+                }
+            }
+            ConnectionState::Pending => {}
+            ConnectionState::Bound(data_connection) => {
+                self.connections_by_data_connection.remove(&data_connection);
+            }
+        }
+
+        self.pending_commands
+            .push_back(Command::CloseConnection { id });
+
+        tracing::info!(target: "relay", connection = %id, peer = %connection.peer, allocation = %connection.allocation, "Closed connection to peer");
+    }
+
+    fn usage_mut(&mut self, username: &str, now: SystemTime) -> &mut Usage {
+        self.usage_by_username
+            .entry(username.to_owned())
+            .or_insert_with(|| Usage::new(&self.quotas, now))
+    }
+
+    /// Releases resources of the given username and forgets about it once it no longer uses any.
+    fn release_usage(&mut self, username: &str, release: impl FnOnce(&mut Usage)) {
+        let Some(usage) = self.usage_by_username.get_mut(username) else {
+            debug_assert!(
+                false,
+                "Usage should be tracked for every allocation and channel"
+            );
+            return;
+        };
+
+        release(usage);
+
+        if usage.is_unused() {
+            self.usage_by_username.remove(username);
+        }
+    }
+}
+
+/// Checks whether the given username still has bandwidth left for relaying `num_bytes`.
+///
+/// This is a free function to allow for split borrows of the [`Server`]'s fields.
+fn has_bandwidth(
+    usage_by_username: &mut HashMap<String, Usage>,
+    username: &str,
+    num_bytes: usize,
+    now: SystemTime,
+) -> bool {
+    usage_by_username
+        .get_mut(username)
+        .map_or(true, |usage| usage.try_consume_bandwidth(num_bytes, now))
 }
 
 fn refresh_success_response(
@@ -1036,6 +1941,50 @@ struct Allocation {
 
     first_relay_addr: IpAddr,
     second_relay_addr: Option<IpAddr>,
+
+    /// Whether we relay to peers via UDP or TCP.
+    transport: PeerTransport,
+
+    /// The username this allocation was created with.
+    username: String,
+
+    /// Shared with the [`ChannelTable`] so data relayed without going through us is accounted for too.
+    traffic: Arc<Traffic>,
+
+    /// Allows the client to move this allocation to a different address, only present if the client asked for it.
+    mobility_ticket: Option<MobilityTicket>,
+}
+
+/// The transport protocol an allocation uses to talk to peers.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc8656#name-requested-transport> and <https://www.rfc-editor.org/rfc/rfc6062>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeerTransport {
+    Udp,
+    Tcp,
+}
+
+/// A TCP connection between us and a peer of a TCP allocation.
+struct Connection {
+    allocation: AllocationId,
+    peer: PeerSocket,
+
+    /// The username of the allocation, a data connection must be bound with the same one.
+    username: String,
+
+    state: ConnectionState,
+    /// Until when the connection needs to be established and bound.
+    deadline: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConnectionState {
+    /// We are connecting to the peer on behalf of the client's connect request with the given transaction ID.
+    Connecting(TransactionId),
+    /// The connection is established and waits for the client to bind a data connection.
+    Pending,
+    /// All data is forwarded between the peer and the client's given data connection.
+    Bound(ClientSocket),
 }
 
 struct Channel {
@@ -1058,6 +2007,9 @@ struct Channel {
     ///
     /// With the data structure still existing while the channel is unbound, our existing validations cover the above requirement.
     bound: bool,
+
+    /// The username this channel was bound with.
+    username: String,
 }
 
 impl Channel {
@@ -1097,6 +2049,8 @@ enum TimedAction {
     ExpireAllocation(AllocationId),
     UnbindChannel((ClientSocket, u16)),
     DeleteChannel((ClientSocket, u16)),
+    ExpireConnection(ConnectionId),
+    DrainDeadline,
 }
 
 fn error_response(
@@ -1212,6 +2166,8 @@ impl_stun_request_for!(Allocate, ALLOCATE);
 impl_stun_request_for!(ChannelBind, CHANNEL_BIND);
 impl_stun_request_for!(CreatePermission, CREATE_PERMISSION);
 impl_stun_request_for!(Refresh, REFRESH);
+impl_stun_request_for!(Connect, *CONNECT);
+impl_stun_request_for!(ConnectionBind, *CONNECTION_BIND);
 
 /// Private helper trait to make [`Server::verify_auth`] more ergonomic to use.
 trait ProtectedRequest {
@@ -1242,6 +2198,8 @@ impl_protected_request_for!(Allocate);
 impl_protected_request_for!(ChannelBind);
 impl_protected_request_for!(CreatePermission);
 impl_protected_request_for!(Refresh);
+impl_protected_request_for!(Connect);
+impl_protected_request_for!(ConnectionBind);
 
 // Define an enum of all attributes that we care about for our server.
 stun_codec::define_attribute_enums!(
@@ -1261,7 +2219,10 @@ stun_codec::define_attribute_enums!(
         Realm,
         Username,
         RequestedAddressFamily,
-        AdditionalAddressFamily
+        AdditionalAddressFamily,
+        ConnectionId,
+        AlternateServer,
+        MobilityTicket
     ]
 );
 
@@ -1325,4 +2286,14 @@ mod tests {
 
         assert_eq!(error_code.code(), BadRequest::CODEPOINT)
     }
+
+    #[test]
+    fn allocation_id_roundtrips_through_display() {
+        let id = AllocationId(42);
+
+        let parsed = id.to_string().parse::<AllocationId>().unwrap();
+
+        assert_eq!(parsed, id);
+        assert!("42".parse::<AllocationId>().is_err());
+    }
 }
@@ -1,5 +1,7 @@
 use crate::auth::{generate_password, split_username, systemtime_from_unix, FIREZONE};
 use crate::server::channel_data::ChannelData;
+use crate::server::rfc6062::{ConnectionId, CONNECT, CONNECTION_BIND, TCP_TRANSPORT};
+use crate::server::rfc8016::MobilityTicket;
 use crate::server::UDP_TRANSPORT;
 use crate::Attribute;
 use bytecodec::DecodeExt;
@@ -68,6 +70,12 @@ impl Decoder {
                     (CREATE_PERMISSION, Request) => Ok(Ok(ClientMessage::CreatePermission(
                         CreatePermission::parse(&message),
                     ))),
+                    (method, Request) if method == *CONNECT => {
+                        Ok(Connect::parse(&message).map(ClientMessage::Connect))
+                    }
+                    (method, Request) if method == *CONNECTION_BIND => {
+                        Ok(ConnectionBind::parse(&message).map(ClientMessage::ConnectionBind))
+                    }
                     (_, Request) => Ok(Err(bad_request(&message))),
                     (method, class) => {
                         Err(Error::DecodeStun(bytecodec::Error::from(io::Error::new(
@@ -95,6 +103,8 @@ pub enum ClientMessage<'a> {
     Refresh(Refresh),
     ChannelBind(ChannelBind),
     CreatePermission(CreatePermission),
+    Connect(Connect),
+    ConnectionBind(ConnectionBind),
 }
 
 impl<'a> ClientMessage<'a> {
@@ -105,6 +115,8 @@ impl<'a> ClientMessage<'a> {
             ClientMessage::Refresh(request) => Some(request.transaction_id),
             ClientMessage::ChannelBind(request) => Some(request.transaction_id),
             ClientMessage::CreatePermission(request) => Some(request.transaction_id),
+            ClientMessage::Connect(request) => Some(request.transaction_id),
+            ClientMessage::ConnectionBind(request) => Some(request.transaction_id),
             ClientMessage::ChannelData(_) => None,
         }
     }
@@ -140,6 +152,7 @@ pub struct Allocate {
     nonce: Option<Nonce>,
     requested_address_family: Option<RequestedAddressFamily>,
     additional_address_family: Option<AdditionalAddressFamily>,
+    mobility_ticket: Option<MobilityTicket>,
 }
 
 impl Allocate {
@@ -156,6 +169,106 @@ impl Allocate {
             &username,
             relay_secret,
             nonce,
+            RequestedTransport::new(UDP_TRANSPORT),
+            None,
+            None,
+        );
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            requested_transport,
+            lifetime,
+            username: Some(username),
+            nonce: Some(nonce),
+            requested_address_family: None, // IPv4 is the default.
+            additional_address_family: None,
+            mobility_ticket: None,
+        }
+    }
+
+    /// Like [`Allocate::new_authenticated_udp_implicit_ip4`] but authenticates with a static password, see [`StaticCredentials`](crate::StaticCredentials).
+    pub fn new_authenticated_udp_implicit_ip4_with_password(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        password: &str,
+        nonce: Uuid,
+    ) -> Self {
+        let (requested_transport, nonce, message_integrity) = Self::make_attributes_with_password(
+            transaction_id,
+            &lifetime,
+            &username,
+            password,
+            nonce,
+            RequestedTransport::new(UDP_TRANSPORT),
+            None,
+            None,
+        );
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            requested_transport,
+            lifetime,
+            username: Some(username),
+            nonce: Some(nonce),
+            requested_address_family: None, // IPv4 is the default.
+            additional_address_family: None,
+            mobility_ticket: None,
+        }
+    }
+
+    /// Like [`Allocate::new_authenticated_udp_implicit_ip4`] but signals support for mobility, see <https://www.rfc-editor.org/rfc/rfc8016#section-3.1>.
+    pub fn new_authenticated_udp_implicit_ip4_with_mobility(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let mobility_ticket = MobilityTicket::new(Vec::new());
+
+        let (requested_transport, nonce, message_integrity) = Self::make_attributes(
+            transaction_id,
+            &lifetime,
+            &username,
+            relay_secret,
+            nonce,
+            RequestedTransport::new(UDP_TRANSPORT),
+            None,
+            Some(mobility_ticket.clone()),
+        );
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            requested_transport,
+            lifetime,
+            username: Some(username),
+            nonce: Some(nonce),
+            requested_address_family: None, // IPv4 is the default.
+            additional_address_family: None,
+            mobility_ticket: Some(mobility_ticket),
+        }
+    }
+
+    /// Requests an allocation that relays to peers via TCP, see <https://www.rfc-editor.org/rfc/rfc6062#section-4.1>.
+    pub fn new_authenticated_tcp_implicit_ip4(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let (requested_transport, nonce, message_integrity) = Self::make_attributes(
+            transaction_id,
+            &lifetime,
+            &username,
+            relay_secret,
+            nonce,
+            RequestedTransport::new(TCP_TRANSPORT),
+            None,
             None,
         );
 
@@ -168,6 +281,7 @@ impl Allocate {
             nonce: Some(nonce),
             requested_address_family: None, // IPv4 is the default.
             additional_address_family: None,
+            mobility_ticket: None,
         }
     }
 
@@ -186,7 +300,9 @@ impl Allocate {
             &username,
             relay_secret,
             nonce,
+            RequestedTransport::new(UDP_TRANSPORT),
             Some(requested_address_family.clone()),
+            None,
         );
 
         Self {
@@ -198,6 +314,7 @@ impl Allocate {
             nonce: Some(nonce),
             requested_address_family: Some(requested_address_family),
             additional_address_family: None,
+            mobility_ticket: None,
         }
     }
 
@@ -224,18 +341,49 @@ impl Allocate {
             nonce: None,
             requested_address_family: None,
             additional_address_family: None,
+            mobility_ticket: None,
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn make_attributes(
         transaction_id: TransactionId,
         lifetime: &Option<Lifetime>,
         username: &Username,
         relay_secret: &SecretString,
         nonce: Uuid,
+        requested_transport: RequestedTransport,
         requested_address_family: Option<RequestedAddressFamily>,
+        mobility_ticket: Option<MobilityTicket>,
+    ) -> (RequestedTransport, Nonce, MessageIntegrity) {
+        let (expiry, salt) = split_username(username.name()).expect("a valid username");
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        Self::make_attributes_with_password(
+            transaction_id,
+            lifetime,
+            username,
+            &password,
+            nonce,
+            requested_transport,
+            requested_address_family,
+            mobility_ticket,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn make_attributes_with_password(
+        transaction_id: TransactionId,
+        lifetime: &Option<Lifetime>,
+        username: &Username,
+        password: &str,
+        nonce: Uuid,
+        requested_transport: RequestedTransport,
+        requested_address_family: Option<RequestedAddressFamily>,
+        mobility_ticket: Option<MobilityTicket>,
     ) -> (RequestedTransport, Nonce, MessageIntegrity) {
-        let requested_transport = RequestedTransport::new(UDP_TRANSPORT);
         let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
 
         let mut message =
@@ -252,13 +400,12 @@ impl Allocate {
             message.add_attribute(lifetime.clone());
         }
 
-        let (expiry, salt) = split_username(username.name()).expect("a valid username");
-        let expiry_systemtime = systemtime_from_unix(expiry);
-
-        let password = generate_password(relay_secret, expiry_systemtime, salt);
+        if let Some(mobility_ticket) = mobility_ticket {
+            message.add_attribute(mobility_ticket);
+        }
 
         let message_integrity =
-            MessageIntegrity::new_long_term_credential(&message, username, &FIREZONE, &password)
+            MessageIntegrity::new_long_term_credential(&message, username, &FIREZONE, password)
                 .unwrap();
         (requested_transport, nonce, message_integrity)
     }
@@ -275,6 +422,7 @@ impl Allocate {
         let username = message.get_attribute::<Username>().cloned();
         let requested_address_family = message.get_attribute::<RequestedAddressFamily>().cloned();
         let additional_address_family = message.get_attribute::<AdditionalAddressFamily>().cloned();
+        let mobility_ticket = message.get_attribute::<MobilityTicket>().cloned();
 
         Ok(Allocate {
             transaction_id,
@@ -285,6 +433,7 @@ impl Allocate {
             nonce,
             requested_address_family,
             additional_address_family,
+            mobility_ticket,
         })
     }
 
@@ -319,6 +468,10 @@ impl Allocate {
     pub fn additional_address_family(&self) -> Option<&AdditionalAddressFamily> {
         self.additional_address_family.as_ref()
     }
+
+    pub fn mobility_ticket(&self) -> Option<&MobilityTicket> {
+        self.mobility_ticket.as_ref()
+    }
 }
 
 pub struct Refresh {
@@ -327,6 +480,7 @@ pub struct Refresh {
     lifetime: Option<Lifetime>,
     username: Option<Username>,
     nonce: Option<Nonce>,
+    mobility_ticket: Option<MobilityTicket>,
 }
 
 impl Refresh {
@@ -336,6 +490,25 @@ impl Refresh {
         username: Username,
         relay_secret: &SecretString,
         nonce: Uuid,
+    ) -> Self {
+        Self::new_with_mobility_ticket(
+            transaction_id,
+            lifetime,
+            None,
+            username,
+            relay_secret,
+            nonce,
+        )
+    }
+
+    /// Like [`Refresh::new`] but optionally presents a [`MobilityTicket`], e.g. to move the allocation to our new address.
+    pub fn new_with_mobility_ticket(
+        transaction_id: TransactionId,
+        lifetime: Option<Lifetime>,
+        mobility_ticket: Option<MobilityTicket>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
     ) -> Self {
         let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
 
@@ -347,6 +520,10 @@ impl Refresh {
             message.add_attribute(lifetime.clone());
         }
 
+        if let Some(mobility_ticket) = &mobility_ticket {
+            message.add_attribute(mobility_ticket.clone());
+        }
+
         let (expiry, salt) = split_username(username.name()).expect("a valid username");
         let expiry_systemtime = systemtime_from_unix(expiry);
 
@@ -362,6 +539,7 @@ impl Refresh {
             lifetime,
             username: Some(username),
             nonce: Some(nonce),
+            mobility_ticket,
         }
     }
 
@@ -371,6 +549,7 @@ impl Refresh {
         let nonce = message.get_attribute::<Nonce>().cloned();
         let lifetime = message.get_attribute::<Lifetime>().cloned();
         let username = message.get_attribute::<Username>().cloned();
+        let mobility_ticket = message.get_attribute::<MobilityTicket>().cloned();
 
         Refresh {
             transaction_id,
@@ -378,6 +557,7 @@ impl Refresh {
             lifetime,
             username,
             nonce,
+            mobility_ticket,
         }
     }
 
@@ -400,6 +580,10 @@ impl Refresh {
     pub fn nonce(&self) -> Option<&Nonce> {
         self.nonce.as_ref()
     }
+
+    pub fn mobility_ticket(&self) -> Option<&MobilityTicket> {
+        self.mobility_ticket.as_ref()
+    }
 }
 
 pub struct ChannelBind {
@@ -502,6 +686,7 @@ pub struct CreatePermission {
     message_integrity: Option<MessageIntegrity>,
     username: Option<Username>,
     nonce: Option<Nonce>,
+    xor_peer_addresses: Vec<XorPeerAddress>,
 }
 
 impl CreatePermission {
@@ -510,12 +695,20 @@ impl CreatePermission {
         let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
         let username = message.get_attribute::<Username>().cloned();
         let nonce = message.get_attribute::<Nonce>().cloned();
+        let xor_peer_addresses = message
+            .attributes()
+            .filter_map(|attribute| match attribute {
+                Attribute::XorPeerAddress(address) => Some(address.clone()),
+                _ => None,
+            })
+            .collect();
 
         CreatePermission {
             transaction_id,
             message_integrity,
             username,
             nonce,
+            xor_peer_addresses,
         }
     }
 
@@ -534,6 +727,176 @@ impl CreatePermission {
     pub fn nonce(&self) -> Option<&Nonce> {
         self.nonce.as_ref()
     }
+
+    pub fn xor_peer_addresses(&self) -> &[XorPeerAddress] {
+        &self.xor_peer_addresses
+    }
+}
+
+/// A request to open a TCP connection to a peer, see <https://www.rfc-editor.org/rfc/rfc6062#section-5.2>.
+pub struct Connect {
+    transaction_id: TransactionId,
+    message_integrity: Option<MessageIntegrity>,
+    nonce: Option<Nonce>,
+    xor_peer_address: XorPeerAddress,
+    username: Option<Username>,
+}
+
+impl Connect {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_address: XorPeerAddress,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
+
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, *CONNECT, transaction_id);
+        message.add_attribute(username.clone());
+        message.add_attribute(xor_peer_address.clone());
+        message.add_attribute(nonce.clone());
+
+        let (expiry, salt) = split_username(username.name()).expect("a valid username");
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        let message_integrity =
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)
+                .unwrap();
+
+        Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            xor_peer_address,
+            username: Some(username),
+            nonce: Some(nonce),
+        }
+    }
+
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Message<Attribute>> {
+        let transaction_id = message.transaction_id();
+        let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
+        let nonce = message.get_attribute::<Nonce>().cloned();
+        let username = message.get_attribute::<Username>().cloned();
+        let xor_peer_address = message
+            .get_attribute::<XorPeerAddress>()
+            .ok_or(bad_request(message))?
+            .clone();
+
+        Ok(Connect {
+            transaction_id,
+            message_integrity,
+            nonce,
+            xor_peer_address,
+            username,
+        })
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    pub fn message_integrity(&self) -> Option<&MessageIntegrity> {
+        self.message_integrity.as_ref()
+    }
+
+    pub fn xor_peer_address(&self) -> &XorPeerAddress {
+        &self.xor_peer_address
+    }
+
+    pub fn username(&self) -> Option<&Username> {
+        self.username.as_ref()
+    }
+
+    pub fn nonce(&self) -> Option<&Nonce> {
+        self.nonce.as_ref()
+    }
+}
+
+/// A request to associate a new data connection with a TCP connection to a peer, see <https://www.rfc-editor.org/rfc/rfc6062#section-5.4>.
+pub struct ConnectionBind {
+    transaction_id: TransactionId,
+    connection_id: ConnectionId,
+    message_integrity: Option<MessageIntegrity>,
+    nonce: Option<Nonce>,
+    username: Option<Username>,
+}
+
+impl ConnectionBind {
+    pub fn new(
+        transaction_id: TransactionId,
+        connection_id: ConnectionId,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Self {
+        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).expect("len(uuid) < 128");
+
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, *CONNECTION_BIND, transaction_id);
+        message.add_attribute(username.clone());
+        message.add_attribute(connection_id);
+        message.add_attribute(nonce.clone());
+
+        let (expiry, salt) = split_username(username.name()).expect("a valid username");
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        let message_integrity =
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)
+                .unwrap();
+
+        Self {
+            transaction_id,
+            connection_id,
+            message_integrity: Some(message_integrity),
+            username: Some(username),
+            nonce: Some(nonce),
+        }
+    }
+
+    pub fn parse(message: &Message<Attribute>) -> Result<Self, Message<Attribute>> {
+        let transaction_id = message.transaction_id();
+        let connection_id = message
+            .get_attribute::<ConnectionId>()
+            .copied()
+            .ok_or(bad_request(message))?;
+        let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
+        let nonce = message.get_attribute::<Nonce>().cloned();
+        let username = message.get_attribute::<Username>().cloned();
+
+        Ok(ConnectionBind {
+            transaction_id,
+            connection_id,
+            message_integrity,
+            nonce,
+            username,
+        })
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    pub fn message_integrity(&self) -> Option<&MessageIntegrity> {
+        self.message_integrity.as_ref()
+    }
+
+    pub fn username(&self) -> Option<&Username> {
+        self.username.as_ref()
+    }
+
+    pub fn nonce(&self) -> Option<&Nonce> {
+        self.nonce.as_ref()
+    }
 }
 
 /// Computes the effective lifetime of an allocation.
@@ -0,0 +1,62 @@
+use std::time::{Duration, SystemTime};
+
+/// Thresholds beyond which we consider ourselves overloaded and redirect new allocations to one of our alternate servers.
+///
+/// `None` means the particular resource is not considered.
+/// Running out of ports always counts as overloaded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LoadThresholds {
+    /// The maximum number of concurrent allocations.
+    pub max_allocations: Option<usize>,
+    /// The maximum number of bytes per second relayed in either direction, across all allocations.
+    pub max_bytes_per_sec: Option<u64>,
+    /// The minimum number of ports that must remain available for new allocations.
+    pub min_free_ports: Option<usize>,
+}
+
+/// How often we re-compute our throughput at most.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Estimates our throughput from the total number of relayed bytes.
+///
+/// We only sample when we need to know, i.e. the throughput is averaged since the previous sample but over at least [`SAMPLE_INTERVAL`].
+#[derive(Debug, Default)]
+pub(crate) struct Throughput {
+    last_sample: Option<(SystemTime, u64)>,
+    bytes_per_sec: u64,
+}
+
+impl Throughput {
+    pub(crate) fn sample(&mut self, num_relayed_bytes: u64, now: SystemTime) -> u64 {
+        let Some((sampled_at, sampled_bytes)) = self.last_sample else {
+            self.last_sample = Some((now, num_relayed_bytes));
+            return self.bytes_per_sec;
+        };
+
+        let elapsed = now.duration_since(sampled_at).unwrap_or_default();
+        if elapsed < SAMPLE_INTERVAL {
+            return self.bytes_per_sec;
+        }
+
+        self.bytes_per_sec =
+            (num_relayed_bytes.saturating_sub(sampled_bytes) as f64 / elapsed.as_secs_f64()) as u64;
+        self.last_sample = Some((now, num_relayed_bytes));
+
+        self.bytes_per_sec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throughput_is_averaged_over_sample_interval() {
+        let mut throughput = Throughput::default();
+        let now = SystemTime::UNIX_EPOCH;
+
+        assert_eq!(throughput.sample(0, now), 0);
+        assert_eq!(throughput.sample(1000, now + Duration::from_millis(500)), 0);
+        assert_eq!(throughput.sample(4000, now + Duration::from_secs(2)), 2000);
+    }
+}
@@ -0,0 +1,151 @@
+use ip_network::IpNetwork;
+use std::net::IpAddr;
+
+/// Networks we never relay to unless explicitly allowed.
+///
+/// Without these, clients could use the relay to reach services on the relay's host or within its private network, like cloud metadata endpoints (`169.254.169.254`).
+/// This includes the shared address space (`100.64.0.0/10`) which Firezone uses for tunnel IPs and the NAT64 prefix (`64:ff9b::/96`) which would otherwise let clients reach denied IPv4 addresses through a NAT64 gateway.
+/// For the same reason, we deny the 6to4 (`2002::/16`) and Teredo (`2001::/32`) prefixes which embed an IPv4 address.
+const DEFAULT_DENIED: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "255.255.255.255/32",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "2001::/32",
+    "2002::/16",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Which peer addresses clients may relay to.
+///
+/// An address is denied if it is within one of the denied networks, unless it is also within one of the allowed networks.
+/// IPv4-mapped IPv6 addresses are treated like the IPv4 address they map to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerPolicy {
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+}
+
+impl Default for PeerPolicy {
+    /// Denies loopback, link-local, private, shared (CGNAT), NAT64, 6to4, Teredo, multicast and unspecified addresses.
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: default_denied().collect(),
+        }
+    }
+}
+
+impl PeerPolicy {
+    /// Denies the given networks in addition to the default ones, except for the ones in `allow`.
+    pub fn new(allow: Vec<IpNetwork>, deny: Vec<IpNetwork>) -> Self {
+        Self {
+            allow,
+            deny: default_denied().chain(deny).collect(),
+        }
+    }
+
+    pub(crate) fn permits(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(ip6) => ip6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+
+        if self.allow.iter().any(|network| network.contains(ip)) {
+            return true;
+        }
+
+        !self.deny.iter().any(|network| network.contains(ip))
+    }
+}
+
+fn default_denied() -> impl Iterator<Item = IpNetwork> {
+    DEFAULT_DENIED
+        .iter()
+        .map(|network| network.parse().expect("default networks to be valid"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn denies_internal_addresses_by_default() {
+        let policy = PeerPolicy::default();
+
+        assert!(!policy.permits(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert!(!policy.permits(IpAddr::V4(Ipv4Addr::new(169, 254, 169, 254))));
+        assert!(!policy.permits(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))));
+        assert!(!policy.permits(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert!(!policy.permits(IpAddr::V6(Ipv4Addr::new(192, 168, 0, 1).to_ipv6_mapped())));
+
+        assert!(policy.permits(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1))));
+        assert!(policy.permits(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))));
+    }
+
+    #[test]
+    fn denies_shared_address_space_by_default() {
+        let policy = PeerPolicy::default();
+
+        assert!(!policy.permits(IpAddr::V4(Ipv4Addr::new(100, 64, 0, 1))));
+        assert!(!policy.permits(IpAddr::V4(Ipv4Addr::new(100, 127, 255, 254))));
+
+        assert!(policy.permits(IpAddr::V4(Ipv4Addr::new(100, 128, 0, 1))));
+    }
+
+    #[test]
+    fn denies_nat64_addresses_by_default() {
+        let policy = PeerPolicy::default();
+
+        // `64:ff9b::a9fe:a9fe` translates to `169.254.169.254`.
+        assert!(!policy.permits(IpAddr::V6(Ipv6Addr::new(
+            0x64, 0xff9b, 0, 0, 0, 0, 0xa9fe, 0xa9fe
+        ))));
+        // Public IPv4 addresses are denied too, clients can relay to them directly.
+        assert!(!policy.permits(IpAddr::V6(Ipv6Addr::new(
+            0x64, 0xff9b, 0, 0, 0, 0, 0xcb00, 0x7101
+        ))));
+    }
+
+    #[test]
+    fn denies_6to4_and_teredo_addresses_by_default() {
+        let policy = PeerPolicy::default();
+
+        // `2002:a9fe:a9fe::1` routes to `169.254.169.254` via a 6to4 relay.
+        assert!(!policy.permits(IpAddr::V6(Ipv6Addr::new(
+            0x2002, 0xa9fe, 0xa9fe, 0, 0, 0, 0, 1
+        ))));
+        // A Teredo address whose server is `65.54.227.120`.
+        assert!(!policy.permits(IpAddr::V6(Ipv6Addr::new(
+            0x2001, 0, 0x4136, 0xe378, 0x8000, 0x63bf, 0x3fff, 0xfdd2
+        ))));
+
+        // Other networks within `2001::/16` are not affected.
+        assert!(policy.permits(IpAddr::V6(Ipv6Addr::new(
+            0x2001, 0x4860, 0, 0, 0, 0, 0, 0x8888
+        ))));
+    }
+
+    #[test]
+    fn allowed_networks_take_precedence() {
+        let policy = PeerPolicy::new(
+            vec!["10.0.0.0/24".parse().unwrap()],
+            vec!["203.0.113.0/24".parse().unwrap()],
+        );
+
+        assert!(policy.permits(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert!(!policy.permits(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1))));
+        assert!(!policy.permits(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1))));
+    }
+}
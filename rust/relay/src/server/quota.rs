@@ -0,0 +1,142 @@
+use std::time::SystemTime;
+
+/// Limits that apply to all allocations and channels created with the same username.
+///
+/// The username is the `expiry:salt` string that we verify as part of the long-term credentials or a static username.
+/// `None` means the particular resource is not limited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Quotas {
+    /// The maximum number of concurrent allocations.
+    pub max_allocations: Option<usize>,
+    /// The maximum number of concurrent channel bindings, across all allocations.
+    pub max_channels: Option<usize>,
+    /// The maximum number of bytes per second relayed in either direction, across all allocations.
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+/// The resources currently used by a single username.
+#[derive(Debug)]
+pub(crate) struct Usage {
+    pub(crate) allocations: usize,
+    pub(crate) channels: usize,
+    bandwidth: Option<TokenBucket>,
+}
+
+impl Usage {
+    pub(crate) fn new(quotas: &Quotas, now: SystemTime) -> Self {
+        Self {
+            allocations: 0,
+            channels: 0,
+            bandwidth: quotas
+                .max_bytes_per_sec
+                .map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    pub(crate) fn is_unused(&self) -> bool {
+        self.allocations == 0 && self.channels == 0
+    }
+
+    /// Attempts to spend `num_bytes` of this username's bandwidth.
+    ///
+    /// Returns `false` if the username exceeded its bandwidth, in which case the data should be dropped.
+    pub(crate) fn try_consume_bandwidth(&mut self, num_bytes: usize, now: SystemTime) -> bool {
+        let Some(bucket) = self.bandwidth.as_mut() else {
+            return true;
+        };
+
+        bucket.try_consume(num_bytes as u64, now)
+    }
+}
+
+/// A token bucket that refills at a constant rate and allows bursts of up to one second worth of tokens.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: u64,
+    last_refill: SystemTime,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: SystemTime) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last_refill: now,
+        }
+    }
+
+    fn try_consume(&mut self, amount: u64, now: SystemTime) -> bool {
+        self.refill(now);
+
+        if self.tokens < amount {
+            return false;
+        }
+
+        self.tokens -= amount;
+
+        true
+    }
+
+    fn refill(&mut self, now: SystemTime) {
+        let Ok(elapsed) = now.duration_since(self.last_refill) else {
+            return; // Time went backwards, don't refill.
+        };
+
+        let new_tokens = (elapsed.as_nanos() * self.rate as u128 / 1_000_000_000) as u64;
+
+        if new_tokens == 0 {
+            return; // Don't update `last_refill` to avoid losing fractional tokens.
+        }
+
+        self.tokens = self.tokens.saturating_add(new_tokens).min(self.rate);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_starts_full() {
+        let now = SystemTime::UNIX_EPOCH;
+        let mut bucket = TokenBucket::new(1000, now);
+
+        assert!(bucket.try_consume(1000, now));
+        assert!(!bucket.try_consume(1, now));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let now = SystemTime::UNIX_EPOCH;
+        let mut bucket = TokenBucket::new(1000, now);
+
+        assert!(bucket.try_consume(1000, now));
+
+        let now = now + Duration::from_millis(500);
+
+        assert!(bucket.try_consume(500, now));
+        assert!(!bucket.try_consume(1, now));
+    }
+
+    #[test]
+    fn bucket_does_not_exceed_capacity() {
+        let now = SystemTime::UNIX_EPOCH;
+        let mut bucket = TokenBucket::new(1000, now);
+
+        let now = now + Duration::from_secs(10);
+
+        assert!(!bucket.try_consume(1001, now));
+        assert!(bucket.try_consume(1000, now));
+    }
+
+    #[test]
+    fn unlimited_usage_always_has_bandwidth() {
+        let now = SystemTime::UNIX_EPOCH;
+        let mut usage = Usage::new(&Quotas::default(), now);
+
+        assert!(usage.try_consume_bandwidth(usize::MAX, now));
+    }
+}
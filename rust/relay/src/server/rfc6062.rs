@@ -0,0 +1,156 @@
+//! Methods, attributes and error codes of TURN extensions for TCP allocations.
+//!
+//! `stun_codec` doesn't implement these (yet), see <https://www.rfc-editor.org/rfc/rfc6062#section-6>.
+
+use bytecodec::fixnum::{U32beDecoder, U32beEncoder};
+use bytecodec::{ByteCount, Decode, Encode, Eos, SizedEncode, TryTaggedDecode};
+use core::fmt;
+use once_cell::sync::Lazy;
+use stun_codec::rfc5389::attributes::ErrorCode;
+use stun_codec::{Attribute, AttributeType, Method};
+
+// TODO: Upstream these to `stun-codec` which would allow us to use `const`s.
+pub static CONNECT: Lazy<Method> = Lazy::new(|| Method::new(0x000A).expect("valid method"));
+pub static CONNECTION_BIND: Lazy<Method> = Lazy::new(|| Method::new(0x000B).expect("valid method"));
+pub static CONNECTION_ATTEMPT: Lazy<Method> =
+    Lazy::new(|| Method::new(0x000C).expect("valid method"));
+
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.2.1>.
+pub const TCP_TRANSPORT: u8 = 6;
+
+/// Uniquely identifies a TCP connection between the relay and a peer.
+///
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.2.1>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ConnectionId(u32);
+
+impl ConnectionId {
+    pub const CODEPOINT: u16 = 0x002A;
+
+    pub fn new(value: u32) -> Self {
+        Self(value)
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+
+    pub(crate) fn next(&mut self) -> Self {
+        let id = self.0;
+
+        self.0 = self.0.wrapping_add(1);
+
+        ConnectionId(id)
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CID-{}", self.0)
+    }
+}
+
+impl Attribute for ConnectionId {
+    type Decoder = ConnectionIdDecoder;
+    type Encoder = ConnectionIdEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionIdDecoder(U32beDecoder);
+
+impl Decode for ConnectionIdDecoder {
+    type Item = ConnectionId;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.decode(buf, eos)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        self.0.finish_decoding().map(ConnectionId)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl TryTaggedDecode for ConnectionIdDecoder {
+    type Tag = AttributeType;
+
+    fn try_start_decoding(&mut self, attribute_type: Self::Tag) -> bytecodec::Result<bool> {
+        Ok(attribute_type.as_u16() == ConnectionId::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionIdEncoder(U32beEncoder);
+
+impl Encode for ConnectionIdEncoder {
+    type Item = ConnectionId;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.encode(buf, eos)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        self.0.start_encoding(item.0)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl SizedEncode for ConnectionIdEncoder {
+    fn exact_requiring_bytes(&self) -> u64 {
+        self.0.exact_requiring_bytes()
+    }
+}
+
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.3>.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionAlreadyExists;
+
+impl ConnectionAlreadyExists {
+    pub const CODEPOINT: u16 = 446;
+}
+
+impl From<ConnectionAlreadyExists> for ErrorCode {
+    fn from(_: ConnectionAlreadyExists) -> Self {
+        ErrorCode::new(
+            ConnectionAlreadyExists::CODEPOINT,
+            "Connection Already Exists".to_owned(),
+        )
+        .expect("never fails")
+    }
+}
+
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-6.3>.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionTimeoutOrFailure;
+
+impl ConnectionTimeoutOrFailure {
+    pub const CODEPOINT: u16 = 447;
+}
+
+impl From<ConnectionTimeoutOrFailure> for ErrorCode {
+    fn from(_: ConnectionTimeoutOrFailure) -> Self {
+        ErrorCode::new(
+            ConnectionTimeoutOrFailure::CODEPOINT,
+            "Connection Timeout or Failure".to_owned(),
+        )
+        .expect("never fails")
+    }
+}
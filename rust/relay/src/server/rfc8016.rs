@@ -0,0 +1,243 @@
+//! Mobility for allocations, i.e. allowing clients to keep their allocation when their IP address changes.
+//!
+//! `stun_codec` doesn't implement these (yet), see <https://www.rfc-editor.org/rfc/rfc8016>.
+
+use crate::server::{
+    error_response, Channel, Command, PeerTransport, Refresh, Server, TimedAction,
+    UNBOUND_CHANNEL_GRACE_PERIOD,
+};
+use crate::ClientSocket;
+use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+use bytecodec::{ByteCount, Decode, Encode, Eos, SizedEncode, TryTaggedDecode};
+use rand::Rng;
+use std::time::SystemTime;
+use stun_codec::rfc5389::attributes::ErrorCode;
+use stun_codec::rfc5766::errors::{AllocationMismatch, WrongCredentials};
+use stun_codec::{Attribute, AttributeType, Message};
+
+/// The number of random bytes in the tickets we hand out.
+const TICKET_LEN: usize = 16;
+
+/// An opaque ticket that allows a client to move its allocation to a different address.
+///
+/// Clients signal that they support mobility by sending an empty ticket in their allocate request.
+/// See <https://www.rfc-editor.org/rfc/rfc8016#section-3.1>.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MobilityTicket(Vec<u8>);
+
+impl MobilityTicket {
+    pub const CODEPOINT: u16 = 0x8030;
+
+    pub fn new(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub(crate) fn random(rng: &mut impl Rng) -> Self {
+        Self(rng.gen::<[u8; TICKET_LEN]>().to_vec())
+    }
+}
+
+impl Attribute for MobilityTicket {
+    type Decoder = MobilityTicketDecoder;
+    type Encoder = MobilityTicketEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MobilityTicketDecoder(RemainingBytesDecoder);
+
+impl Decode for MobilityTicketDecoder {
+    type Item = MobilityTicket;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.decode(buf, eos)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        self.0.finish_decoding().map(MobilityTicket)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl TryTaggedDecode for MobilityTicketDecoder {
+    type Tag = AttributeType;
+
+    fn try_start_decoding(&mut self, attribute_type: Self::Tag) -> bytecodec::Result<bool> {
+        Ok(attribute_type.as_u16() == MobilityTicket::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MobilityTicketEncoder(BytesEncoder<Vec<u8>>);
+
+impl Encode for MobilityTicketEncoder {
+    type Item = MobilityTicket;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        self.0.encode(buf, eos)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        self.0.start_encoding(item.0)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl SizedEncode for MobilityTicketEncoder {
+    fn exact_requiring_bytes(&self) -> u64 {
+        self.0.exact_requiring_bytes()
+    }
+}
+
+/// See <https://www.rfc-editor.org/rfc/rfc8016#section-3.4>.
+#[derive(Debug, Clone, Copy)]
+pub struct MobilityForbidden;
+
+impl MobilityForbidden {
+    pub const CODEPOINT: u16 = 405;
+}
+
+impl From<MobilityForbidden> for ErrorCode {
+    fn from(_: MobilityForbidden) -> Self {
+        ErrorCode::new(
+            MobilityForbidden::CODEPOINT,
+            "Mobility Forbidden".to_owned(),
+        )
+        .expect("never fails")
+    }
+}
+
+impl<R> Server<R>
+where
+    R: Rng,
+{
+    /// Moves the allocation identified by the request's [`MobilityTicket`] and all its channels to `client`.
+    ///
+    /// See <https://www.rfc-editor.org/rfc/rfc8016#section-3.3>.
+    pub(crate) fn migrate_allocation(
+        &mut self,
+        request: &Refresh,
+        username: &str,
+        client: ClientSocket,
+        now: SystemTime,
+    ) -> Result<(), Message<crate::Attribute>> {
+        let Some(ticket) = request.mobility_ticket().filter(|t| !t.value().is_empty()) else {
+            return Err(error_response(AllocationMismatch, request));
+        };
+
+        let Some((&previous_client, allocation)) = self
+            .allocations
+            .iter()
+            .find(|(_, a)| a.mobility_ticket.as_ref() == Some(ticket))
+        else {
+            tracing::warn!(target: "relay", "Unknown mobility ticket");
+
+            return Err(error_response(MobilityForbidden, request));
+        };
+
+        if allocation.username != username {
+            tracing::warn!(target: "relay", %previous_client, "Mobility ticket belongs to a different username");
+
+            return Err(error_response(WrongCredentials, request));
+        }
+
+        // RFC 6062 allocations are tied to the client's control connection.
+        if allocation.transport != PeerTransport::Udp || self.stream_clients.contains(&client) {
+            return Err(error_response(MobilityForbidden, request));
+        }
+
+        let id = allocation.id;
+
+        // Channels from a previous allocation at the new address may still linger until they are deleted.
+        for number in self.channel_numbers_of(client) {
+            self.delete_channel_binding(client, number);
+        }
+
+        let allocation = self
+            .allocations
+            .remove(&previous_client)
+            .expect("allocation to exist");
+        self.allocations.insert(client, allocation);
+        self.clients_by_allocation.insert(id, client);
+        self.channel_table.remove_allocation(id);
+
+        for number in self.channel_numbers_of(previous_client) {
+            let channel = self
+                .channels_by_client_and_number
+                .remove(&(previous_client, number))
+                .expect("channel to exist");
+            self.channel_numbers_by_client_and_peer
+                .remove(&(previous_client, channel.peer_address));
+
+            self.move_channel(client, number, channel, now);
+        }
+
+        tracing::info!(target: "relay", allocation = %id, %previous_client, "Migrated allocation to new client address");
+
+        Ok(())
+    }
+
+    fn channel_numbers_of(&self, client: ClientSocket) -> Vec<u16> {
+        self.channels_by_client_and_number
+            .keys()
+            .filter(|(c, _)| *c == client)
+            .map(|(_, number)| *number)
+            .collect()
+    }
+
+    /// Re-inserts a channel under the new client address, including its timers as these are keyed by client.
+    fn move_channel(
+        &mut self,
+        client: ClientSocket,
+        number: u16,
+        channel: Channel,
+        now: SystemTime,
+    ) {
+        let peer = channel.peer_address;
+        let allocation = channel.allocation;
+        let bound = channel.bound && channel.expiry > now;
+
+        let (trigger, action) = if bound {
+            (channel.expiry, TimedAction::UnbindChannel((client, number)))
+        } else {
+            (
+                channel.expiry + UNBOUND_CHANNEL_GRACE_PERIOD,
+                TimedAction::DeleteChannel((client, number)),
+            )
+        };
+        let wake_deadline = self.time_events.add(trigger, action);
+        self.pending_commands.push_back(Command::Wake {
+            deadline: wake_deadline,
+        });
+
+        self.channel_numbers_by_client_and_peer
+            .insert((client, peer), number);
+        self.channels_by_client_and_number
+            .insert((client, number), Channel { bound, ..channel });
+
+        if bound {
+            self.publish_channel(client, number, allocation, peer);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use crate::auth::Nonces;
+use crate::channel_table::Traffic;
+use crate::net_ext::IpAddrExt;
+use crate::server::{
+    Allocation, AllocationId, Channel, Command, MobilityTicket, PeerTransport, Server, TimedAction,
+    UNBOUND_CHANNEL_GRACE_PERIOD,
+};
+use crate::{ClientSocket, PeerSocket};
+use rand::Rng;
+use secrecy::{ExposeSecret, SecretString};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The state of a [`Server`] that needs to survive a restart for clients to not notice it.
+///
+/// This includes the auth secret because all credentials handed out so far are derived from it.
+/// Treat a serialized [`Snapshot`] as confidential!
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    auth_secret: String,
+    next_allocation_id: u64,
+    nonces: Nonces,
+    allocations: Vec<AllocationSnapshot>,
+    channels: Vec<ChannelSnapshot>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct AllocationSnapshot {
+    id: u64,
+    client: SocketAddr,
+    port: u16,
+    expires_at: SystemTime,
+    first_relay_addr: IpAddr,
+    second_relay_addr: Option<IpAddr>,
+    username: String,
+    bytes_to_peers: u64,
+    bytes_from_peers: u64,
+    #[serde(default)]
+    mobility_ticket: Option<Vec<u8>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ChannelSnapshot {
+    client: SocketAddr,
+    number: u16,
+    peer: SocketAddr,
+    allocation: u64,
+    expiry: SystemTime,
+    bound: bool,
+    username: String,
+}
+
+impl Snapshot {
+    /// The auth secret of the [`Server`] at the time of the snapshot.
+    pub fn auth_secret(&self) -> SecretString {
+        SecretString::from(self.auth_secret.clone())
+    }
+}
+
+impl<R> Server<R>
+where
+    R: Rng,
+{
+    /// Captures the current state for restoring it later via [`Server::restore`].
+    ///
+    /// TCP allocations and allocations of clients connected via TCP or TLS are not included because they don't outlive the client's connection anyway.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            auth_secret: self.auth_secret.expose_secret().clone(),
+            next_allocation_id: self.next_allocation_id.0,
+            nonces: self.nonces.clone(),
+            allocations: self
+                .allocations
+                .iter()
+                .filter(|(client, allocation)| {
+                    allocation.transport == PeerTransport::Udp
+                        && !self.stream_clients.contains(client)
+                })
+                .map(|(client, allocation)| AllocationSnapshot {
+                    id: allocation.id.0,
+                    client: client.into_socket(),
+                    port: allocation.port,
+                    expires_at: allocation.expires_at,
+                    first_relay_addr: allocation.first_relay_addr,
+                    second_relay_addr: allocation.second_relay_addr,
+                    username: allocation.username.clone(),
+                    bytes_to_peers: allocation.traffic.bytes_to_peers(),
+                    bytes_from_peers: allocation.traffic.bytes_from_peers(),
+                    mobility_ticket: allocation
+                        .mobility_ticket
+                        .as_ref()
+                        .map(|ticket| ticket.value().to_vec()),
+                })
+                .collect(),
+            channels: self
+                .channels_by_client_and_number
+                .iter()
+                .filter(|((client, _), _)| !self.stream_clients.contains(client))
+                .map(|((client, number), channel)| ChannelSnapshot {
+                    client: client.into_socket(),
+                    number: *number,
+                    peer: channel.peer_address.into_socket(),
+                    allocation: channel.allocation.0,
+                    expiry: channel.expiry,
+                    bound: channel.bound,
+                    username: channel.username.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Restores the state captured by [`Server::snapshot`].
+    ///
+    /// This must be called on a freshly constructed [`Server`], before any traffic is handled.
+    /// For each restored allocation, a [`Command::CreateAllocation`] is emitted with the same port as before.
+    /// Allocations that expired in the meantime or whose port is no longer within our port range are dropped.
+    #[tracing::instrument(skip_all, level = "error")]
+    pub fn restore(&mut self, snapshot: Snapshot, now: SystemTime) {
+        debug_assert!(
+            self.allocations.is_empty(),
+            "Can only restore a fresh server"
+        );
+
+        self.auth_secret = SecretString::from(snapshot.auth_secret);
+        self.next_allocation_id = AllocationId(snapshot.next_allocation_id);
+        self.nonces = snapshot.nonces;
+
+        for allocation in snapshot.allocations {
+            let id = AllocationId(allocation.id);
+            let client = ClientSocket::new(allocation.client);
+
+            if allocation.expires_at <= now {
+                tracing::debug!(target: "relay", allocation = %id, "Not restoring expired allocation");
+                continue;
+            }
+
+            if !(self.lowest_port..=self.highest_port).contains(&allocation.port) {
+                tracing::warn!(target: "relay", allocation = %id, port = %allocation.port, "Not restoring allocation outside of port range");
+                continue;
+            }
+
+            if self.allocations_by_port.contains_key(&allocation.port)
+                || self.allocations.contains_key(&client)
+            {
+                tracing::warn!(target: "relay", allocation = %id, "Not restoring conflicting allocation");
+                continue;
+            }
+
+            let wake_deadline = self
+                .time_events
+                .add(allocation.expires_at, TimedAction::ExpireAllocation(id));
+            self.pending_commands.push_back(Command::Wake {
+                deadline: wake_deadline,
+            });
+            self.pending_commands.push_back(Command::CreateAllocation {
+                id,
+                family: allocation.first_relay_addr.family(),
+                port: allocation.port,
+            });
+            if let Some(second_relay_addr) = allocation.second_relay_addr {
+                self.pending_commands.push_back(Command::CreateAllocation {
+                    id,
+                    family: second_relay_addr.family(),
+                    port: allocation.port,
+                });
+            }
+
+            self.usage_mut(&allocation.username, now).allocations += 1;
+            self.allocations_by_port.insert(allocation.port, id);
+            self.clients_by_allocation.insert(id, client);
+            self.allocations.insert(
+                client,
+                Allocation {
+                    id,
+                    port: allocation.port,
+                    expires_at: allocation.expires_at,
+                    first_relay_addr: allocation.first_relay_addr,
+                    second_relay_addr: allocation.second_relay_addr,
+                    transport: PeerTransport::Udp,
+                    username: allocation.username,
+                    traffic: Arc::new(Traffic::new(
+                        allocation.bytes_to_peers,
+                        allocation.bytes_from_peers,
+                    )),
+                    mobility_ticket: allocation.mobility_ticket.map(MobilityTicket::new),
+                },
+            );
+            self.allocations_up_down_counter.add(1, &[]);
+        }
+
+        for channel in snapshot.channels {
+            let client = ClientSocket::new(channel.client);
+            let peer = PeerSocket::new(channel.peer);
+            let allocation = AllocationId(channel.allocation);
+
+            if !self.clients_by_allocation.contains_key(&allocation) {
+                continue;
+            }
+
+            // The policy may have been tightened since the channel was bound.
+            if !self.peer_policy.permits(peer.0.ip()) {
+                tracing::warn!(target: "relay", %peer, "Not restoring channel to peer that is not allowed");
+                continue;
+            }
+
+            let delete_at = channel.expiry + UNBOUND_CHANNEL_GRACE_PERIOD;
+
+            if delete_at <= now {
+                continue;
+            }
+
+            let bound = channel.bound && channel.expiry > now;
+            let (trigger, action) = if bound {
+                (
+                    channel.expiry,
+                    TimedAction::UnbindChannel((client, channel.number)),
+                )
+            } else {
+                (
+                    delete_at,
+                    TimedAction::DeleteChannel((client, channel.number)),
+                )
+            };
+
+            let wake_deadline = self.time_events.add(trigger, action);
+            self.pending_commands.push_back(Command::Wake {
+                deadline: wake_deadline,
+            });
+
+            self.usage_mut(&channel.username, now).channels += 1;
+            self.channel_numbers_by_client_and_peer
+                .insert((client, peer), channel.number);
+            self.channels_by_client_and_number.insert(
+                (client, channel.number),
+                Channel {
+                    expiry: channel.expiry,
+                    peer_address: peer,
+                    allocation,
+                    bound,
+                    username: channel.username,
+                },
+            );
+            if bound {
+                self.publish_channel(client, channel.number, allocation, peer);
+            }
+            self.channels_up_down_counter.add(1, &[]);
+        }
+
+        tracing::info!(target: "relay", num_allocations = %self.allocations.len(), num_channels = %self.channels_by_client_and_number.len(), "Restored state");
+    }
+}
@@ -0,0 +1,1038 @@
+//! A fixed number of worker threads that drive the UDP sockets of all allocations.
+//!
+//! Spawning a task with its own channel per allocation doesn't scale well to thousands of allocations.
+//! Instead, each socket is assigned to one of a fixed number of workers.
+//! A worker is a dedicated OS thread that waits for all of its sockets via `epoll` and reads and writes packets in batches via `recvmmsg` and `sendmmsg`.
+//!
+//! In addition, every worker binds its own socket on the main TURN port with `SO_REUSEPORT`.
+//! The kernel spreads the packets of our clients across these sockets based on a hash of the client's address.
+//! Channel data for channels published in the [`ChannelTable`] is relayed directly by the worker that received it.
+//! Everything else is handed to the eventloop.
+
+use crate::channel_table::ChannelTable;
+use crate::server::{AllocationId, ChannelData};
+use crate::udp_socket::{make_reuseport_socket, make_wildcard_socket, MAX_UDP_SIZE};
+use crate::{AddressFamily, ClientSocket, IpStack, PeerSocket};
+use anyhow::{anyhow, Context as _, Result};
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use opentelemetry::metrics::{Counter, Histogram};
+use socket2::SockAddr;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::ops::ControlFlow;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::task::{Context, Poll};
+use std::thread;
+
+/// The maximum number of packets read or written with a single syscall.
+const BATCH_SIZE: usize = 32;
+
+/// The maximum number of packets that can be queued for sending on a single worker.
+const MAX_QUEUED_PACKETS: usize = 1000;
+
+/// The maximum number of events that can be buffered in the channel to the eventloop.
+const MAX_BUFFERED_EVENTS: usize = 1000;
+
+/// The maximum number of readiness events we process per call to `epoll_wait`.
+const MAX_EPOLL_EVENTS: usize = 64;
+
+/// The `epoll` token of a worker's [`Waker`].
+const WAKER_TOKEN: u64 = u64::MAX;
+
+/// The `epoll` token of a worker's main socket for IPv4.
+const MAIN_SOCKET_V4_TOKEN: u64 = u64::MAX - 1;
+
+/// The `epoll` token of a worker's main socket for IPv6.
+const MAIN_SOCKET_V6_TOKEN: u64 = u64::MAX - 2;
+
+/// The sockets of all allocations, shared by all workers so they can relay channel data from their main socket to any peer.
+type AllocationSockets = Arc<RwLock<HashMap<(AllocationId, AddressFamily), Arc<UdpSocket>>>>;
+
+/// Something that happened on one of the sockets of the pool.
+#[derive(Debug)]
+pub enum Event {
+    /// A peer sent data to an allocation.
+    Data(Vec<u8>, PeerSocket, AllocationId),
+    /// A client sent data to one of the main sockets.
+    ClientData(Vec<u8>, ClientSocket),
+    /// The socket of an allocation failed and has been removed from the pool.
+    SocketFailed(AllocationId, AddressFamily),
+}
+
+pub struct SocketPool {
+    workers: Vec<WorkerHandle>,
+    /// The worker that each socket is assigned to.
+    sockets: HashMap<(AllocationId, AddressFamily), usize>,
+    allocation_sockets: AllocationSockets,
+    event_receiver: mpsc::Receiver<Event>,
+
+    metrics: Metrics,
+}
+
+impl SocketPool {
+    /// Spawns `num_workers` threads to drive the sockets of this pool.
+    ///
+    /// Each worker binds a main socket on `port` for every address family of `public_address`.
+    pub fn new(
+        num_workers: usize,
+        public_address: IpStack,
+        port: u16,
+        channel_table: ChannelTable,
+    ) -> Result<Self> {
+        anyhow::ensure!(num_workers > 0, "Need at least one worker");
+
+        let (event_sender, event_receiver) = mpsc::channel(MAX_BUFFERED_EVENTS);
+        let metrics = Metrics::new();
+        let allocation_sockets = AllocationSockets::default();
+
+        let families = [
+            public_address.as_v4().map(|_| AddressFamily::V4),
+            public_address.as_v6().map(|_| AddressFamily::V6),
+        ];
+
+        let workers = (0..num_workers)
+            .map(|index| {
+                let main_sockets = families
+                    .iter()
+                    .flatten()
+                    .map(|family| {
+                        let socket = make_reuseport_socket(*family, port).with_context(|| {
+                            format!("Failed to bind main socket for {family} on port {port}")
+                        })?;
+
+                        Ok((*family, socket))
+                    })
+                    .collect::<Result<HashMap<_, _>>>()?;
+
+                WorkerHandle::spawn(
+                    index,
+                    main_sockets,
+                    event_sender.clone(),
+                    channel_table.clone(),
+                    allocation_sockets.clone(),
+                    metrics.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            workers,
+            sockets: Default::default(),
+            allocation_sockets,
+            event_receiver,
+            metrics,
+        })
+    }
+
+    /// Binds a new socket for the given allocation and assigns it to the least busy worker.
+    pub fn bind(&mut self, id: AllocationId, family: AddressFamily, port: u16) -> Result<()> {
+        let socket = Arc::new(make_wildcard_socket(family, port)?);
+
+        let (index, worker) = self
+            .workers
+            .iter_mut()
+            .enumerate()
+            .min_by_key(|(_, worker)| worker.num_sockets)
+            .expect("at least one worker");
+
+        worker.send_control(Control::Add {
+            id,
+            family,
+            socket: socket.clone(),
+        })?;
+        self.sockets.insert((id, family), index);
+        self.allocation_sockets
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert((id, family), socket);
+
+        tracing::debug!(allocation = %id, %family, %port, worker = %index, "Bound socket");
+
+        Ok(())
+    }
+
+    /// Closes the socket of the given allocation.
+    ///
+    /// Returns `false` if there is no such socket.
+    pub fn unbind(&mut self, id: AllocationId, family: AddressFamily) -> bool {
+        let Some(index) = self.sockets.remove(&(id, family)) else {
+            return false;
+        };
+        self.allocation_sockets
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&(id, family));
+
+        // If the worker is gone, so is the socket.
+        let _ = self.workers[index].send_control(Control::Remove { id, family });
+
+        true
+    }
+
+    /// Send data to a peer on the given allocation.
+    ///
+    /// In case the queue of the responsible worker is full, we drop the packet and record it in the `dropped_packets_total` metric.
+    /// All our data is relayed over UDP which by design is an unreliable protocol.
+    /// Thus, any application running on top of this relay must already account for potential packet loss.
+    ///
+    /// An error is only returned if the worker is gone, i.e. the allocation can no longer be served.
+    pub fn send(&mut self, id: AllocationId, data: Vec<u8>, recipient: PeerSocket) -> Result<()> {
+        let family = recipient.family();
+
+        let Some(index) = self.sockets.get(&(id, family)).copied() else {
+            tracing::debug!(allocation = %id, %family, "Unknown allocation");
+            return Ok(());
+        };
+        let worker = &mut self.workers[index];
+
+        match worker.outbound_sender.try_send(Outbound::ToPeer {
+            id,
+            family,
+            data,
+            recipient,
+        }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!(allocation = %id, worker = %index, "Send queue of worker is full, dropping packet");
+                self.metrics.dropped_packets.add(1, &[]);
+
+                return Ok(());
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.unbind(id, family);
+
+                return Err(anyhow!("Worker {index} is gone"));
+            }
+        }
+
+        worker.waker.wake();
+
+        Ok(())
+    }
+
+    /// Send data to a client from the main socket.
+    ///
+    /// All packets for the same client are sent by the same worker so they don't get reordered.
+    /// Like [`SocketPool::send`], we drop the packet if the queue of that worker is full.
+    pub fn send_to_client(&mut self, data: Vec<u8>, recipient: ClientSocket) -> Result<()> {
+        let mut hasher = DefaultHasher::new();
+        recipient.hash(&mut hasher);
+        let index = hasher.finish() as usize % self.workers.len();
+        let worker = &mut self.workers[index];
+
+        match worker
+            .outbound_sender
+            .try_send(Outbound::ToClient { data, recipient })
+        {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!(%recipient, worker = %index, "Send queue of worker is full, dropping packet");
+                self.metrics.dropped_packets.add(1, &[]);
+
+                return Ok(());
+            }
+            Err(TrySendError::Disconnected(_)) => return Err(anyhow!("Worker {index} is gone")),
+        }
+
+        worker.waker.wake();
+
+        Ok(())
+    }
+
+    pub fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        let event = std::task::ready!(self.event_receiver.poll_next_unpin(cx));
+
+        if let Some(Event::SocketFailed(id, family)) = &event {
+            self.unbind(*id, *family);
+        }
+
+        Poll::Ready(event)
+    }
+}
+
+impl Drop for SocketPool {
+    fn drop(&mut self) {
+        for worker in self.workers.drain(..) {
+            let waker = worker.waker.clone();
+
+            // Waking a worker after its control channel is closed stops it.
+            drop(worker);
+            waker.wake();
+        }
+    }
+}
+
+struct WorkerHandle {
+    control_sender: Sender<Control>,
+    outbound_sender: SyncSender<Outbound>,
+    waker: Arc<Waker>,
+    num_sockets: usize,
+}
+
+impl WorkerHandle {
+    fn spawn(
+        index: usize,
+        main_sockets: HashMap<AddressFamily, UdpSocket>,
+        event_sender: mpsc::Sender<Event>,
+        channel_table: ChannelTable,
+        allocation_sockets: AllocationSockets,
+        metrics: Metrics,
+    ) -> Result<Self> {
+        let (control_sender, control_receiver) = std::sync::mpsc::channel();
+        let (outbound_sender, outbound_receiver) = sync_channel(MAX_QUEUED_PACKETS);
+        let waker = Arc::new(Waker::new().context("Failed to create eventfd")?);
+        let epoll = Epoll::new().context("Failed to create epoll instance")?;
+        epoll.add(waker.as_raw_fd(), WAKER_TOKEN)?;
+
+        for (family, socket) in &main_sockets {
+            epoll.add(socket.as_raw_fd(), main_socket_token(*family))?;
+        }
+
+        let worker = Worker {
+            index,
+            epoll,
+            waker: waker.clone(),
+            control_receiver,
+            outbound_receiver,
+            event_sender,
+            main_sockets,
+            sockets: Default::default(),
+            tokens: Default::default(),
+            next_token: 0,
+            channel_table,
+            allocation_sockets,
+            recv_buffer: RecvBuffer::new(),
+            send_queues: Default::default(),
+            metrics,
+        };
+
+        thread::Builder::new()
+            .name(format!("relay-worker-{index}"))
+            .spawn(move || worker.run())
+            .context("Failed to spawn worker thread")?;
+
+        Ok(Self {
+            control_sender,
+            outbound_sender,
+            waker,
+            num_sockets: 0,
+        })
+    }
+
+    fn send_control(&mut self, control: Control) -> Result<()> {
+        match &control {
+            Control::Add { .. } => self.num_sockets += 1,
+            Control::Remove { .. } => self.num_sockets = self.num_sockets.saturating_sub(1),
+        }
+
+        self.control_sender
+            .send(control)
+            .map_err(|_| anyhow!("Worker is gone"))?;
+        self.waker.wake();
+
+        Ok(())
+    }
+}
+
+enum Control {
+    Add {
+        id: AllocationId,
+        family: AddressFamily,
+        socket: Arc<UdpSocket>,
+    },
+    Remove {
+        id: AllocationId,
+        family: AddressFamily,
+    },
+}
+
+enum Outbound {
+    ToPeer {
+        id: AllocationId,
+        family: AddressFamily,
+        data: Vec<u8>,
+        recipient: PeerSocket,
+    },
+    ToClient {
+        data: Vec<u8>,
+        recipient: ClientSocket,
+    },
+}
+
+#[derive(Clone)]
+struct Metrics {
+    recv_batch_size: Histogram<u64>,
+    send_batch_size: Histogram<u64>,
+    stalls: Counter<u64>,
+    dropped_packets: Counter<u64>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let meter = opentelemetry_api::global::meter("relay");
+
+        Self {
+            recv_batch_size: meter
+                .u64_histogram("worker_recv_batch_size")
+                .with_description("The number of packets read from a socket with a single syscall")
+                .init(),
+            send_batch_size: meter
+                .u64_histogram("worker_send_batch_size")
+                .with_description("The number of packets written to a socket with a single syscall")
+                .init(),
+            stalls: meter
+                .u64_counter("worker_stalls_total")
+                .with_description(
+                    "The number of times a worker had to wait for the eventloop to catch up",
+                )
+                .init(),
+            dropped_packets: meter
+                .u64_counter("dropped_packets_total")
+                .with_description(
+                    "The number of packets dropped because a worker could not keep up",
+                )
+                .init(),
+        }
+    }
+}
+
+struct Worker {
+    index: usize,
+    epoll: Epoll,
+    waker: Arc<Waker>,
+    control_receiver: Receiver<Control>,
+    outbound_receiver: Receiver<Outbound>,
+    event_sender: mpsc::Sender<Event>,
+
+    /// This worker's sockets on the main TURN port.
+    main_sockets: HashMap<AddressFamily, UdpSocket>,
+    sockets: HashMap<u64, AllocationSocket>,
+    tokens: HashMap<(AllocationId, AddressFamily), u64>,
+    next_token: u64,
+
+    channel_table: ChannelTable,
+    allocation_sockets: AllocationSockets,
+
+    recv_buffer: RecvBuffer,
+    send_queues: HashMap<u64, Vec<(Vec<u8>, SocketAddr)>>,
+
+    metrics: Metrics,
+}
+
+struct AllocationSocket {
+    id: AllocationId,
+    family: AddressFamily,
+    socket: Arc<UdpSocket>,
+}
+
+impl Worker {
+    fn run(mut self) {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EPOLL_EVENTS];
+
+        loop {
+            let num_events = match self.epoll.wait(&mut events) {
+                Ok(num_events) => num_events,
+                Err(e) => {
+                    tracing::error!(worker = %self.index, "Failed to wait for sockets: {e}");
+                    return;
+                }
+            };
+
+            for event in &events[..num_events] {
+                let token = event.u64;
+
+                let flow = match token {
+                    WAKER_TOKEN => self.handle_wake(),
+                    MAIN_SOCKET_V4_TOKEN => self.handle_client_readable(AddressFamily::V4),
+                    MAIN_SOCKET_V6_TOKEN => self.handle_client_readable(AddressFamily::V6),
+                    token => self.handle_readable(token),
+                };
+
+                if flow.is_break() {
+                    tracing::debug!(worker = %self.index, "Stopping worker");
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle_wake(&mut self) -> ControlFlow<()> {
+        // Reset before draining the channels so we don't miss a wake-up for items that are queued in the meantime.
+        self.waker.reset();
+
+        loop {
+            match self.control_receiver.try_recv() {
+                Ok(Control::Add { id, family, socket }) => self.add_socket(id, family, socket)?,
+                Ok(Control::Remove { id, family }) => self.remove_socket(id, family),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return ControlFlow::Break(()),
+            }
+        }
+
+        self.flush_outbound()
+    }
+
+    fn add_socket(
+        &mut self,
+        id: AllocationId,
+        family: AddressFamily,
+        socket: Arc<UdpSocket>,
+    ) -> ControlFlow<()> {
+        let token = self.next_token;
+        self.next_token += 1;
+
+        if let Err(e) = self.epoll.add(socket.as_raw_fd(), token) {
+            tracing::warn!(allocation = %id, %family, "Failed to register socket: {e}");
+
+            return self.emit(Event::SocketFailed(id, family));
+        }
+
+        self.tokens.insert((id, family), token);
+        self.sockets
+            .insert(token, AllocationSocket { id, family, socket });
+
+        ControlFlow::Continue(())
+    }
+
+    fn remove_socket(&mut self, id: AllocationId, family: AddressFamily) {
+        let Some(token) = self.tokens.remove(&(id, family)) else {
+            return;
+        };
+        let Some(socket) = self.sockets.remove(&token) else {
+            return;
+        };
+        self.send_queues.remove(&token);
+        self.allocation_sockets
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&(id, family));
+
+        let _ = self.epoll.delete(socket.socket.as_raw_fd());
+    }
+
+    /// Reads a single batch of packets from one of our main sockets.
+    ///
+    /// Channel data on a published channel is relayed right away, everything else goes to the eventloop.
+    fn handle_client_readable(&mut self, family: AddressFamily) -> ControlFlow<()> {
+        let Some(socket) = self.main_sockets.get(&family) else {
+            return ControlFlow::Continue(());
+        };
+
+        let num_packets = match self.recv_buffer.recv_from(socket.as_raw_fd()) {
+            Ok(num_packets) => num_packets,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return ControlFlow::Continue(()),
+            Err(e) => {
+                tracing::warn!(%family, "Failed to receive from main socket: {e}");
+                return ControlFlow::Continue(());
+            }
+        };
+
+        self.metrics.recv_batch_size.record(num_packets as u64, &[]);
+
+        for index in 0..num_packets {
+            let Some((data, sender)) = self.recv_buffer.packet(index) else {
+                continue;
+            };
+            let client = ClientSocket::new(sender);
+
+            if self.try_relay_to_peer(data, client) {
+                continue;
+            }
+
+            let event = Event::ClientData(data.to_vec(), client);
+
+            self.emit(event)?;
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Relays channel data from a client to a peer, if the channel is published in the [`ChannelTable`].
+    ///
+    /// The socket of the allocation may belong to a different worker.
+    /// That is fine because sending on a UDP socket doesn't need any coordination.
+    fn try_relay_to_peer(&self, packet: &[u8], client: ClientSocket) -> bool {
+        let Ok(channel_data) = ChannelData::parse(packet) else {
+            return false;
+        };
+        let Some(route) = self
+            .channel_table
+            .route_to_peer(client, channel_data.channel())
+        else {
+            return false;
+        };
+        let Some(socket) = self
+            .allocation_sockets
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&(route.allocation, route.peer.family()))
+            .cloned()
+        else {
+            return false;
+        };
+
+        match socket.send_to(channel_data.data(), route.peer.into_socket()) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.metrics.dropped_packets.add(1, &[]);
+                return true;
+            }
+            Err(_) => return false, // Let the eventloop deal with the failure.
+        }
+
+        self.channel_table
+            .record_to_peers(&route.traffic, channel_data.data().len());
+
+        true
+    }
+
+    /// Reads a single batch of packets from the given socket.
+    ///
+    /// If more packets are pending, `epoll` reports the socket as readable again which gives the other sockets a fair chance.
+    fn handle_readable(&mut self, token: u64) -> ControlFlow<()> {
+        let Some(socket) = self.sockets.get(&token) else {
+            return ControlFlow::Continue(()); // Removed in the meantime.
+        };
+        let id = socket.id;
+        let family = socket.family;
+        let fd = socket.socket.as_raw_fd();
+
+        let num_packets = match self.recv_buffer.recv_from(fd) {
+            Ok(num_packets) => num_packets,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return ControlFlow::Continue(()),
+            Err(e) => {
+                tracing::warn!(allocation = %id, %family, "Failed to receive from socket: {e}");
+                self.remove_socket(id, family);
+
+                return self.emit(Event::SocketFailed(id, family));
+            }
+        };
+
+        self.metrics.recv_batch_size.record(num_packets as u64, &[]);
+
+        for index in 0..num_packets {
+            let Some((data, sender)) = self.recv_buffer.packet(index) else {
+                continue;
+            };
+            let peer = PeerSocket::new(sender);
+
+            // Relay to the client from our own main socket, the kernel doesn't care which of the sockets in the `SO_REUSEPORT` group we use.
+            if let Some(route) = self.channel_table.route_to_client(id, peer) {
+                if self.main_sockets.contains_key(&route.client.family()) {
+                    let message = ChannelData::new(route.channel, data).to_bytes();
+
+                    self.channel_table
+                        .record_from_peers(&route.traffic, data.len());
+                    self.send_queues
+                        .entry(main_socket_token(route.client.family()))
+                        .or_default()
+                        .push((message, route.client.into_socket()));
+
+                    continue;
+                }
+            }
+
+            let event = Event::Data(data.to_vec(), peer, id);
+
+            self.emit(event)?;
+        }
+
+        self.flush_send_queues()
+    }
+
+    fn flush_outbound(&mut self) -> ControlFlow<()> {
+        loop {
+            match self.outbound_receiver.try_recv() {
+                Ok(Outbound::ToPeer {
+                    id,
+                    family,
+                    data,
+                    recipient,
+                }) => {
+                    let Some(token) = self.tokens.get(&(id, family)) else {
+                        continue; // Removed in the meantime.
+                    };
+
+                    self.send_queues
+                        .entry(*token)
+                        .or_default()
+                        .push((data, recipient.into_socket()));
+                }
+                Ok(Outbound::ToClient { data, recipient }) => {
+                    self.send_queues
+                        .entry(main_socket_token(recipient.family()))
+                        .or_default()
+                        .push((data, recipient.into_socket()));
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return ControlFlow::Break(()),
+            }
+        }
+
+        self.flush_send_queues()
+    }
+
+    fn flush_send_queues(&mut self) -> ControlFlow<()> {
+        let mut failed_sockets = Vec::new();
+
+        for (token, packets) in self.send_queues.iter_mut() {
+            if packets.is_empty() {
+                continue;
+            }
+
+            if let Some((family, socket)) = self
+                .main_sockets
+                .iter()
+                .find(|(family, _)| main_socket_token(**family) == *token)
+            {
+                match send_batch(socket.as_raw_fd(), packets, &self.metrics) {
+                    Ok(0) => {}
+                    Ok(num_dropped) => {
+                        tracing::debug!(%family, "Main socket is not ready, dropped {num_dropped} packets");
+                        self.metrics.dropped_packets.add(num_dropped as u64, &[]);
+                    }
+                    Err(e) => {
+                        tracing::warn!(%family, "Failed to send on main socket: {e}");
+                    }
+                }
+
+                packets.clear();
+                continue;
+            }
+
+            let Some(socket) = self.sockets.get(token) else {
+                packets.clear();
+                continue;
+            };
+
+            match send_batch(socket.socket.as_raw_fd(), packets, &self.metrics) {
+                Ok(0) => {}
+                Ok(num_dropped) => {
+                    tracing::debug!(allocation = %socket.id, "Socket is not ready, dropped {num_dropped} packets");
+                    self.metrics.dropped_packets.add(num_dropped as u64, &[]);
+                }
+                Err(e) => {
+                    tracing::warn!(allocation = %socket.id, family = %socket.family, "Failed to send on socket: {e}");
+                    failed_sockets.push((socket.id, socket.family));
+                }
+            }
+
+            packets.clear();
+        }
+
+        for (id, family) in failed_sockets {
+            self.remove_socket(id, family);
+            self.emit(Event::SocketFailed(id, family))?;
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Hands an event to the eventloop.
+    ///
+    /// If the eventloop cannot keep up, we block the worker until it does.
+    /// In the meantime, packets queue up in the socket's receive buffer and are eventually dropped by the kernel.
+    /// That is our backpressure mechanism.
+    fn emit(&mut self, event: Event) -> ControlFlow<()> {
+        let event = match self.event_sender.try_send(event) {
+            Ok(()) => return ControlFlow::Continue(()),
+            Err(e) if e.is_disconnected() => return ControlFlow::Break(()),
+            Err(e) => e.into_inner(),
+        };
+
+        self.metrics.stalls.add(1, &[]);
+
+        match futures::executor::block_on(self.event_sender.send(event)) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(_) => ControlFlow::Break(()),
+        }
+    }
+}
+
+fn main_socket_token(family: AddressFamily) -> u64 {
+    match family {
+        AddressFamily::V4 => MAIN_SOCKET_V4_TOKEN,
+        AddressFamily::V6 => MAIN_SOCKET_V6_TOKEN,
+    }
+}
+
+/// Writes all given packets to the socket, using as few syscalls as possible.
+///
+/// Returns the number of packets that were dropped because the socket's send buffer is full.
+fn send_batch(
+    fd: RawFd,
+    packets: &[(Vec<u8>, SocketAddr)],
+    metrics: &Metrics,
+) -> io::Result<usize> {
+    let mut num_dropped = 0;
+
+    for chunk in packets.chunks(BATCH_SIZE) {
+        let addresses = chunk
+            .iter()
+            .map(|(_, address)| SockAddr::from(*address))
+            .collect::<Vec<_>>();
+
+        // SAFETY: All-zero is a valid bit-pattern for these C structs.
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for (iovec, (data, _)) in iovecs.iter_mut().zip(chunk) {
+            iovec.iov_base = data.as_ptr() as *mut libc::c_void;
+            iovec.iov_len = data.len();
+        }
+
+        for ((header, iovec), address) in headers.iter_mut().zip(iovecs.iter_mut()).zip(&addresses)
+        {
+            header.msg_hdr.msg_name = address.as_ptr() as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = address.len();
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+        }
+
+        let mut offset = 0;
+
+        while offset < chunk.len() {
+            // SAFETY: The headers point to buffers and addresses that outlive this call.
+            let result = unsafe {
+                libc::sendmmsg(
+                    fd,
+                    headers[offset..].as_mut_ptr(),
+                    (chunk.len() - offset) as _,
+                    0,
+                )
+            };
+
+            if result == -1 {
+                let error = io::Error::last_os_error();
+
+                if error.kind() == io::ErrorKind::WouldBlock {
+                    num_dropped += chunk.len() - offset;
+                    break;
+                }
+
+                return Err(error);
+            }
+
+            metrics.send_batch_size.record(result as u64, &[]);
+            offset += result as usize;
+        }
+    }
+
+    Ok(num_dropped)
+}
+
+/// Pre-allocated buffers for receiving a batch of packets via `recvmmsg`.
+struct RecvBuffer {
+    data: Box<[u8]>,
+    addresses: Box<[libc::sockaddr_storage]>,
+    address_lengths: [libc::socklen_t; BATCH_SIZE],
+    packet_lengths: [usize; BATCH_SIZE],
+}
+
+impl RecvBuffer {
+    fn new() -> Self {
+        Self {
+            data: vec![0u8; BATCH_SIZE * MAX_UDP_SIZE].into_boxed_slice(),
+            // SAFETY: All-zero is a valid bit-pattern for `sockaddr_storage`.
+            addresses: vec![unsafe { mem::zeroed::<libc::sockaddr_storage>() }; BATCH_SIZE]
+                .into_boxed_slice(),
+            address_lengths: [0; BATCH_SIZE],
+            packet_lengths: [0; BATCH_SIZE],
+        }
+    }
+
+    /// Reads up to [`BATCH_SIZE`] packets from the given socket without blocking.
+    fn recv_from(&mut self, fd: RawFd) -> io::Result<usize> {
+        // SAFETY: All-zero is a valid bit-pattern for these C structs.
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for (iovec, buffer) in iovecs.iter_mut().zip(self.data.chunks_mut(MAX_UDP_SIZE)) {
+            iovec.iov_base = buffer.as_mut_ptr() as *mut libc::c_void;
+            iovec.iov_len = buffer.len();
+        }
+
+        for ((header, iovec), address) in headers
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .zip(self.addresses.iter_mut())
+        {
+            header.msg_hdr.msg_name = address as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_hdr.msg_namelen =
+                mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: The headers point to buffers and addresses that outlive this call.
+        let result = unsafe {
+            libc::recvmmsg(
+                fd,
+                headers.as_mut_ptr(),
+                BATCH_SIZE as _,
+                libc::MSG_DONTWAIT as _,
+                std::ptr::null_mut(),
+            )
+        };
+
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let num_packets = result as usize;
+
+        for (index, header) in headers.iter().enumerate().take(num_packets) {
+            self.packet_lengths[index] = header.msg_len as usize;
+            self.address_lengths[index] = header.msg_hdr.msg_namelen;
+        }
+
+        Ok(num_packets)
+    }
+
+    /// Returns the packet at the given index of the last batch.
+    fn packet(&self, index: usize) -> Option<(&[u8], SocketAddr)> {
+        let start = index * MAX_UDP_SIZE;
+        let data = &self.data[start..start + self.packet_lengths[index]];
+
+        // SAFETY: The address was initialized by `recvmmsg` with the given length.
+        let sender = unsafe { SockAddr::new(self.addresses[index], self.address_lengths[index]) };
+
+        Some((data, sender.as_socket()?))
+    }
+}
+
+/// Safe wrapper around an `epoll` instance.
+struct Epoll(OwnedFd);
+
+impl Epoll {
+    fn new() -> io::Result<Self> {
+        // SAFETY: No pointers involved.
+        let fd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+
+        // SAFETY: We just created this fd and nobody else owns it.
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    fn add(&self, fd: RawFd, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token,
+        };
+
+        // SAFETY: `event` is valid for the duration of the call.
+        cvt(unsafe { libc::epoll_ctl(self.0.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut event) })?;
+
+        Ok(())
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        // SAFETY: A null event is allowed for `EPOLL_CTL_DEL`.
+        cvt(unsafe {
+            libc::epoll_ctl(
+                self.0.as_raw_fd(),
+                libc::EPOLL_CTL_DEL,
+                fd,
+                std::ptr::null_mut(),
+            )
+        })?;
+
+        Ok(())
+    }
+
+    /// Blocks until at least one of the registered file descriptors is ready.
+    fn wait(&self, events: &mut [libc::epoll_event]) -> io::Result<usize> {
+        loop {
+            // SAFETY: `events` is valid for writes of `events.len()` elements.
+            let result = cvt(unsafe {
+                libc::epoll_wait(
+                    self.0.as_raw_fd(),
+                    events.as_mut_ptr(),
+                    events.len() as libc::c_int,
+                    -1,
+                )
+            });
+
+            match result {
+                Ok(num_events) => return Ok(num_events as usize),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Wakes a worker blocked in `epoll_wait`, backed by an `eventfd`.
+struct Waker {
+    fd: OwnedFd,
+    /// Whether a wake-up is already pending, saves us from writing to the `eventfd` for every packet.
+    pending: AtomicBool,
+}
+
+impl Waker {
+    fn new() -> io::Result<Self> {
+        // SAFETY: No pointers involved.
+        let fd = cvt(unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })?;
+
+        Ok(Self {
+            // SAFETY: We just created this fd and nobody else owns it.
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            pending: AtomicBool::new(false),
+        })
+    }
+
+    fn wake(&self) {
+        if self.pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let value = 1u64;
+
+        // SAFETY: `value` is valid for reads of 8 bytes.
+        // The only possible error is `EAGAIN` if the counter overflows in which case the worker is awake anyway.
+        let _ = unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                &value as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+    }
+
+    fn reset(&self) {
+        let mut value = 0u64;
+
+        // SAFETY: `value` is valid for writes of 8 bytes.
+        // The only possible error is `EAGAIN` if there was no pending wake-up.
+        let _ = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                &mut value as *mut u64 as *mut libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+
+        self.pending.store(false, Ordering::SeqCst);
+    }
+}
+
+impl AsRawFd for Waker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(result)
+}
@@ -0,0 +1,100 @@
+use crate::server::AllocationId;
+use crate::{AddressFamily, PeerSocket};
+use anyhow::{Context as _, Result};
+use futures::channel::mpsc;
+use futures::SinkExt;
+use std::convert::Infallible;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::task;
+
+/// The maximum number of pending connections in the accept queue.
+const BACKLOG: u32 = 1024;
+
+/// The listening side of an allocation that relays to peers via TCP.
+///
+/// Connections from peers are handed to the eventloop which asks the [`Server`](crate::Server) whether they are wanted.
+/// See <https://www.rfc-editor.org/rfc/rfc6062#section-5.3>.
+pub struct TcpAllocation {
+    /// The handle to the task that is accepting connections.
+    ///
+    /// Stored here to make resource-cleanup easy.
+    handle: task::JoinHandle<()>,
+}
+
+impl TcpAllocation {
+    pub fn new(
+        peer_connection_sender: mpsc::Sender<(TcpStream, PeerSocket, AllocationId)>,
+        id: AllocationId,
+        family: AddressFamily,
+        port: u16,
+    ) -> Self {
+        let task = tokio::spawn(async move {
+            let Err(e) = accept_peer_connections(peer_connection_sender, id, family, port).await
+            else {
+                unreachable!()
+            };
+
+            tracing::warn!(allocation = %id, %family, "TCP allocation task failed: {e:#}");
+        });
+
+        Self { handle: task }
+    }
+}
+
+impl Drop for TcpAllocation {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Opens a TCP connection to a peer from the given port of a TCP allocation.
+///
+/// As per <https://www.rfc-editor.org/rfc/rfc6062#section-5.2>, the connection originates from the relayed address so the peer sees the same address regardless of who initiated the connection.
+pub async fn connect_to_peer(port: u16, peer: PeerSocket) -> Result<TcpStream> {
+    let socket = make_socket(peer.family(), port)?;
+    let stream = socket
+        .connect(peer.into_socket())
+        .await
+        .with_context(|| format!("Failed to connect to {peer}"))?;
+    stream.set_nodelay(true)?;
+
+    Ok(stream)
+}
+
+async fn accept_peer_connections(
+    mut peer_connection_sender: mpsc::Sender<(TcpStream, PeerSocket, AllocationId)>,
+    id: AllocationId,
+    family: AddressFamily,
+    port: u16,
+) -> Result<Infallible> {
+    let listener = make_socket(family, port)
+        .and_then(|socket| socket.listen(BACKLOG))
+        .with_context(|| format!("Failed to listen on port {port}"))?;
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+
+        peer_connection_sender
+            .send((stream, PeerSocket::new(peer), id))
+            .await?;
+    }
+}
+
+/// Creates a TCP socket that is bound to the wildcard address of the given [`AddressFamily`].
+///
+/// We set `SO_REUSEPORT` because we need to accept connections and initiate connections on the same port.
+fn make_socket(family: AddressFamily, port: u16) -> io::Result<TcpSocket> {
+    let (socket, address) = match family {
+        AddressFamily::V4 => (TcpSocket::new_v4()?, IpAddr::from(Ipv4Addr::UNSPECIFIED)),
+        AddressFamily::V6 => (TcpSocket::new_v6()?, IpAddr::from(Ipv6Addr::UNSPECIFIED)),
+    };
+
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(SocketAddr::new(address, port))?;
+
+    Ok(socket)
+}
@@ -0,0 +1,58 @@
+use crate::AddressFamily;
+use anyhow::{Context as _, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::TcpStream;
+
+/// The maximum number of pending connections in the accept queue.
+const BACKLOG: i32 = 1024;
+
+/// A thin wrapper around [`tokio::net::TcpListener`] that binds to the wildcard address of the given [`AddressFamily`].
+pub struct TcpListener {
+    inner: tokio::net::TcpListener,
+}
+
+impl TcpListener {
+    pub fn bind(family: AddressFamily, port: u16) -> Result<Self> {
+        let std_listener = make_wildcard_listener(family, port)
+            .with_context(|| format!("Failed to bind TCP listener for {family} on port {port}"))?;
+
+        Ok(Self {
+            inner: tokio::net::TcpListener::from_std(std_listener)?,
+        })
+    }
+
+    pub async fn accept(&mut self) -> Result<(TcpStream, SocketAddr)> {
+        let (stream, sender) = self.inner.accept().await?;
+        stream.set_nodelay(true)?;
+
+        Ok((stream, sender))
+    }
+}
+
+/// Creates an [std::net::TcpListener] via the [socket2] library that is configured for our needs.
+///
+/// Like for our UDP sockets, this sets the `IPV6_V6ONLY` flag so we can listen on IP4 and IP6 addresses on the same port.
+fn make_wildcard_listener(family: AddressFamily, port: u16) -> Result<std::net::TcpListener> {
+    use socket2::*;
+
+    let domain = match family {
+        AddressFamily::V4 => Domain::IPV4,
+        AddressFamily::V6 => Domain::IPV6,
+    };
+    let address = match family {
+        AddressFamily::V4 => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        AddressFamily::V6 => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    if family == AddressFamily::V6 {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::from(SocketAddr::new(address, port)))?;
+    socket.listen(BACKLOG)?;
+
+    Ok(socket.into())
+}
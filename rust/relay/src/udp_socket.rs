@@ -4,7 +4,7 @@ use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::task::{ready, Context, Poll};
 use tokio::io::ReadBuf;
 
-const MAX_UDP_SIZE: usize = 65536;
+pub(crate) const MAX_UDP_SIZE: usize = 65536;
 
 /// A thin wrapper around [`tokio::net::UdpSocket`] that provides a slightly more convenient API.
 pub struct UdpSocket {
@@ -60,17 +60,36 @@ impl UdpSocket {
 /// Creates an [std::net::UdpSocket] via the [socket2] library that is configured for our needs.
 ///
 /// Most importantly, this sets the `IPV6_V6ONLY` flag to ensure we disallow IP4-mapped IPv6 addresses and can bind to IP4 and IP6 addresses on the same port.
-fn make_wildcard_socket(family: AddressFamily, port: u16) -> Result<std::net::UdpSocket> {
+pub(crate) fn make_wildcard_socket(
+    family: AddressFamily,
+    port: u16,
+) -> Result<std::net::UdpSocket> {
+    let socket = make_socket(family)?;
+
+    bind(socket, family, port)
+}
+
+/// Like [`make_wildcard_socket`] but sets `SO_REUSEPORT`.
+///
+/// This allows several sockets to be bound to the same port, with the kernel distributing incoming packets between them based on a hash of the sender's address.
+#[cfg(target_os = "linux")]
+pub(crate) fn make_reuseport_socket(
+    family: AddressFamily,
+    port: u16,
+) -> Result<std::net::UdpSocket> {
+    let socket = make_socket(family)?;
+    socket.set_reuse_port(true)?;
+
+    bind(socket, family, port)
+}
+
+fn make_socket(family: AddressFamily) -> Result<socket2::Socket> {
     use socket2::*;
 
     let domain = match family {
         AddressFamily::V4 => Domain::IPV4,
         AddressFamily::V6 => Domain::IPV6,
     };
-    let address = match family {
-        AddressFamily::V4 => IpAddr::from(Ipv4Addr::UNSPECIFIED),
-        AddressFamily::V6 => IpAddr::from(Ipv6Addr::UNSPECIFIED),
-    };
 
     let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
     if family == AddressFamily::V6 {
@@ -78,7 +97,17 @@ fn make_wildcard_socket(family: AddressFamily, port: u16) -> Result<std::net::Ud
     }
 
     socket.set_nonblocking(true)?;
-    socket.bind(&SockAddr::from(SocketAddr::new(address, port)))?;
+
+    Ok(socket)
+}
+
+fn bind(socket: socket2::Socket, family: AddressFamily, port: u16) -> Result<std::net::UdpSocket> {
+    let address = match family {
+        AddressFamily::V4 => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        AddressFamily::V6 => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+
+    socket.bind(&socket2::SockAddr::from(SocketAddr::new(address, port)))?;
 
     Ok(socket.into())
 }
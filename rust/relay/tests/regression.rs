@@ -1,23 +1,30 @@
 use bytecodec::{DecodeExt, EncodeExt};
 use firezone_relay::{
-    AddressFamily, Allocate, AllocationId, Attribute, Binding, ChannelBind, ChannelData,
-    ClientMessage, ClientSocket, Command, IpStack, PeerSocket, Refresh, Server,
+    AddressFamily, Allocate, AllocationId, AllocationInfo, Attribute, Binding, ChannelBind,
+    ChannelData, ChannelInfo, ClientMessage, ClientSocket, Command, Connect, ConnectionBind,
+    ConnectionId, IpStack, LoadThresholds, MobilityTicket, PeerPolicy, PeerSocket, Quotas, Refresh,
+    Server, Snapshot,
 };
 use rand::rngs::mock::StepRng;
-use secrecy::SecretString;
+use secrecy::{ExposeSecret, SecretString};
 use std::collections::HashMap;
 use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::{Duration, SystemTime};
-use stun_codec::rfc5389::attributes::{ErrorCode, Nonce, Realm, Username, XorMappedAddress};
-use stun_codec::rfc5389::errors::Unauthorized;
+use stun_codec::rfc5389::attributes::{
+    AlternateServer, ErrorCode, Nonce, Realm, Username, XorMappedAddress,
+};
+use stun_codec::rfc5389::errors::{BadRequest, TryAlternate, Unauthorized};
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{ChannelNumber, Lifetime, XorPeerAddress, XorRelayAddress};
+use stun_codec::rfc5766::errors::{AllocationQuotaReached, Forbidden, InsufficientCapacity};
 use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, REFRESH};
-use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId};
+use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, Method, TransactionId};
 use test_strategy::proptest;
 use uuid::Uuid;
-use Output::{CreateAllocation, FreeAllocation, Wake};
+use Output::{
+    BindConnection, ConnectToPeer, CreateAllocation, CreateTcpAllocation, FreeAllocation, Wake,
+};
 
 #[proptest]
 fn can_answer_stun_request_from_ip4_address(
@@ -130,6 +137,81 @@ fn unauthenticated_allocate_triggers_authentication(
     );
 }
 
+#[proptest]
+fn allocates_with_static_credentials(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_static_credentials("alice:secret1");
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4_with_password(
+                transaction_id,
+                Some(lifetime.clone()),
+                Username::new("alice".to_owned()).unwrap(),
+                "secret1",
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+}
+
+#[proptest]
+fn rejects_wrong_static_password(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    // Nonces are generated randomly and we control the randomness in the test, thus this is deterministic.
+    let next_nonce = Uuid::from_u128(0x0);
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_static_credentials("alice:secret1");
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4_with_password(
+                transaction_id,
+                Some(lifetime),
+                Username::new("alice".to_owned()).unwrap(),
+                "secret2",
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            unauthorized_allocate_response(transaction_id, next_nonce),
+        )],
+    );
+}
+
 #[proptest]
 fn when_refreshed_in_time_allocation_does_not_expire(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
@@ -368,7 +450,7 @@ fn ping_pong_relay(
     );
 
     server.assert_commands(
-        from_peer(peer, peer_to_client_ping.as_ref(), 49152),
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
         [send_channel_data(
             source,
             ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
@@ -376,6 +458,107 @@ fn ping_pong_relay(
     );
 }
 
+#[proptest]
+fn lists_allocations_with_their_channels_and_traffic(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
+    let allocated_at = now;
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(60 * 10)),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(source, ChannelData::new(channel.value(), &[1; 10]), now),
+        [forward(peer, &[1; 10], 49152)],
+    );
+    server.assert_commands(
+        from_peer(peer, &[2; 20], 49152, now),
+        [send_channel_data(
+            source,
+            ChannelData::new(channel.value(), &[2; 20]),
+        )],
+    );
+
+    assert_eq!(
+        server.server.allocations(),
+        vec![AllocationInfo {
+            id: server.id_to_port[&49152],
+            client: ClientSocket::new(source.into()),
+            port: 49152,
+            families: vec![AddressFamily::V4],
+            expires_at: allocated_at + lifetime.lifetime(),
+            bytes_to_peers: 10,
+            bytes_from_peers: 20,
+            channels: vec![ChannelInfo {
+                number: channel.value(),
+                peer: PeerSocket::new(peer.into()),
+                bound: true,
+                expires_at: now + Duration::from_secs(60 * 10),
+            }],
+        }]
+    );
+}
+
 #[proptest]
 fn allows_rebind_channel_after_expiry(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
@@ -566,7 +749,7 @@ fn ping_pong_ip6_relay(
     );
 
     server.assert_commands(
-        from_peer(peer, peer_to_client_ping.as_ref(), 49152),
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
         [send_channel_data(
             source,
             ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
@@ -574,72 +757,1222 @@ fn ping_pong_ip6_relay(
     );
 }
 
-struct TestServer {
-    server: Server<StepRng>,
-    id_to_port: HashMap<u16, AllocationId>,
-}
-
-impl TestServer {
-    fn new(relay_public_addr: impl Into<IpStack>) -> Self {
-        Self {
-            server: Server::new(relay_public_addr, StepRng::new(0, 0), 49152, 65535),
-            id_to_port: Default::default(),
-        }
-    }
+#[proptest]
+fn rejects_allocation_beyond_quota_of_username(
+    #[strategy(firezone_relay::proptest::transaction_id())] first_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] second_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
 
-    fn with_nonce(mut self, nonce: Uuid) -> Self {
-        self.server.add_nonce(nonce);
+    let second_source = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1));
 
-        self
-    }
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_quotas(Quotas {
+            max_allocations: Some(1),
+            ..Default::default()
+        });
+    let secret = server.auth_secret().to_owned();
 
-    fn auth_secret(&self) -> &SecretString {
-        self.server.auth_secret()
-    }
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                first_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    first_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
 
-    fn assert_commands<const N: usize>(&mut self, input: Input, output: [Output; N]) {
-        match input {
-            Input::Client(sender, message, now) => {
-                self.server.handle_client_message(message, sender, now);
-            }
-            Input::Time(now) => {
-                self.server.handle_deadline_reached(now);
-            }
-            Input::Peer(peer, data, port) => {
-                self.server
-                    .handle_peer_traffic(&data, peer, self.id_to_port[&port]);
-            }
-        }
+    server.assert_commands(
+        from_client(
+            second_source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                second_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            second_source,
+            allocation_quota_reached_response(second_transaction_id),
+        )],
+    );
+}
 
-        for expected_output in output {
-            let Some(actual_output) = self.server.next_command() else {
-                let msg = match expected_output {
-                    Output::SendMessage((recipient, msg)) => {
-                        format!("to send message {:?} to {recipient}", msg)
-                    }
-                    Wake(time) => format!("to be woken at {time:?}"),
-                    CreateAllocation(port, family) => {
-                        format!("to create allocation on port {port} for address family {family}")
-                    }
-                    FreeAllocation(port, family) => {
-                        format!("to free allocation on port {port} for address family {family}")
-                    }
-                    Output::SendChannelData((peer, _)) => {
-                        format!("to send channel data from {peer} to client")
-                    }
-                    Output::Forward((peer, _, _)) => format!("to forward data to peer {peer}"),
-                };
+#[proptest]
+fn rejects_channel_bind_beyond_quota_of_username(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    first_channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    second_channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
 
-                panic!("No commands produced but expected {msg}");
-            };
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_quotas(Quotas {
+            max_channels: Some(1),
+            ..Default::default()
+        });
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
+    let second_channel = ChannelNumber::new(channel.value() ^ 1).unwrap();
+    let second_peer = SocketAddrV4::new(*peer.ip(), peer.port().wrapping_add(1));
 
-            match (expected_output, actual_output) {
-                (
-                    Output::SendMessage((to, message)),
-                    Command::SendMessage { payload, recipient },
-                ) => {
-                    let expected_bytes = MessageEncoder::new()
-                        .encode_into_bytes(message.clone())
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                first_channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(60 * 10)),
+            send_message(
+                source,
+                channel_bind_response(first_channel_bind_transaction_id),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                second_channel_bind_transaction_id,
+                second_channel,
+                XorPeerAddress::new(second_peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            insufficient_capacity_channel_bind_response(second_channel_bind_transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn drops_data_beyond_bandwidth_quota_of_username(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    client_to_peer_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_quotas(Quotas {
+            max_bytes_per_sec: Some(64), // Two pings per second, in either direction.
+            ..Default::default()
+        });
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(60 * 10)),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_channel_data(
+            source,
+            ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
+        )],
+    );
+
+    // The quota is used up, further data is dropped in both directions.
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [],
+    );
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [],
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+}
+
+#[proptest]
+fn redirects_allocation_to_alternate_server_when_overloaded(
+    #[strategy(firezone_relay::proptest::transaction_id())] first_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] second_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    alternate_server: SocketAddrV4,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let second_source = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1));
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_alternate_servers(
+            vec![alternate_server.into()],
+            LoadThresholds {
+                max_allocations: Some(1),
+                ..Default::default()
+            },
+        );
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                first_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    first_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            second_source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                second_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            second_source,
+            try_alternate_response(second_transaction_id, alternate_server.into()),
+        )],
+    );
+}
+
+#[proptest]
+fn rejects_channel_bind_to_denied_peer(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let metadata_endpoint = SocketAddrV4::new(Ipv4Addr::new(169, 254, 169, 254), 80);
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_nonce(nonce)
+        .with_peer_policy(PeerPolicy::default());
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(metadata_endpoint.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            forbidden_channel_bind_response(channel_bind_transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn draining_refuses_new_allocations_and_deletes_remaining_at_deadline(
+    #[strategy(firezone_relay::proptest::transaction_id())] first_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] second_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let second_source = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1));
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap(); // Lifetime longer than drain deadline.
+    let drain_deadline = now + Duration::from_secs(60);
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                first_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    first_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(start_draining(drain_deadline), [Wake(drain_deadline)]);
+
+    server.assert_commands(
+        from_client(
+            second_source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                second_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            second_source,
+            insufficient_capacity_response(second_transaction_id),
+        )],
+    );
+    assert!(!server.server.is_drained());
+
+    server.assert_commands(
+        forward_time_to(drain_deadline + Duration::from_secs(1)),
+        [FreeAllocation(49152, AddressFamily::V4)],
+    );
+    assert!(server.server.is_drained());
+}
+
+#[proptest]
+fn restored_server_keeps_relaying_on_existing_channels(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    client_to_peer_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap(); // Lifetime longer than channel expiry
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(60 * 10)),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    let snapshot = serde_json::to_string(&server.server.snapshot()).unwrap();
+    let snapshot = serde_json::from_str(&snapshot).unwrap();
+
+    let mut restored = TestServer::new(public_relay_addr);
+    let restart = now + Duration::from_secs(1);
+
+    restored.assert_commands(
+        restore(snapshot, restart),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            Wake(now + Duration::from_secs(60 * 10)),
+        ],
+    );
+    assert_eq!(
+        restored.auth_secret().expose_secret(),
+        secret.expose_secret()
+    );
+
+    restored.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            restart,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+}
+
+#[proptest]
+fn does_not_restore_channels_to_denied_peers(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    client_to_peer_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let metadata_endpoint = SocketAddrV4::new(Ipv4Addr::new(169, 254, 169, 254), 80);
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(metadata_endpoint.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(60 * 10)),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    let snapshot = server.server.snapshot();
+
+    // The relay restarts with the default policy which denies the peer.
+    let mut restored = TestServer::new(public_relay_addr).with_peer_policy(PeerPolicy::default());
+    let restart = now + Duration::from_secs(1);
+
+    restored.assert_commands(
+        restore(snapshot, restart),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+        ],
+    );
+    restored.assert_commands(
+        from_client(
+            source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            restart,
+        ),
+        [],
+    );
+}
+
+#[proptest]
+fn does_not_restore_allocations_of_stream_clients(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(client_connected(source), []);
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    let snapshot = server.server.snapshot();
+
+    // The client's connection is gone after a restart, so is its allocation.
+    let mut restored = TestServer::new(public_relay_addr);
+    restored.assert_commands(restore(snapshot, now + Duration::from_secs(1)), []);
+    assert_eq!(restored.server.num_allocations(), 0);
+}
+
+#[proptest]
+fn refresh_with_mobility_ticket_moves_allocation_to_new_address(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    peer_to_client_ping: [u8; 32],
+    client_to_peer_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap(); // Lifetime longer than channel expiry
+    let ticket = MobilityTicket::new(vec![0; 16]); // `StepRng` always yields 0.
+    let new_source = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1));
+    let channel_expiry = now + Duration::from_secs(60 * 10);
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4_with_mobility(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response_with_mobility_ticket(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                    ticket.clone(),
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(channel_expiry),
+            send_message(source, channel_bind_response(channel_bind_transaction_id)),
+        ],
+    );
+
+    let now = now + Duration::from_secs(1);
+
+    server.assert_commands(
+        from_client(
+            new_source,
+            Refresh::new_with_mobility_ticket(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                Some(ticket.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(channel_expiry), // Re-added timer of the moved channel.
+            Wake(channel_expiry), // Still the earliest deadline.
+            send_message(
+                new_source,
+                refresh_response_with_mobility_ticket(
+                    refresh_transaction_id,
+                    lifetime.clone(),
+                    ticket,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            new_source,
+            ChannelData::new(channel.value(), client_to_peer_ping.as_ref()),
+            now,
+        ),
+        [forward(peer, &client_to_peer_ping, 49152)],
+    );
+    server.assert_commands(
+        from_peer(peer, peer_to_client_ping.as_ref(), 49152, now),
+        [send_channel_data(
+            new_source,
+            ChannelData::new(channel.value(), peer_to_client_ping.as_ref()),
+        )],
+    );
+}
+
+#[proptest]
+fn mobility_ticket_is_rotated_on_refresh(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    second_refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server =
+        TestServer::new_with_rng(public_relay_addr, StepRng::new(0, 1)).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap();
+    let new_source = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1));
+    let attacker = SocketAddrV4::new(*source.ip(), source.port().wrapping_add(2));
+
+    let first_ticket = server
+        .mobility_ticket_in_response(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4_with_mobility(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        )
+        .unwrap();
+
+    let now = now + Duration::from_secs(1);
+
+    let second_ticket = server
+        .mobility_ticket_in_response(
+            new_source,
+            Refresh::new_with_mobility_ticket(
+                refresh_transaction_id,
+                Some(lifetime.clone()),
+                Some(first_ticket.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        )
+        .unwrap();
+    assert_ne!(first_ticket, second_ticket);
+
+    // The ticket used for the migration can no longer be used to take over the allocation.
+    server.assert_commands(
+        from_client(
+            attacker,
+            Refresh::new_with_mobility_ticket(
+                second_refresh_transaction_id,
+                Some(lifetime),
+                Some(first_ticket),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            attacker,
+            mobility_forbidden_refresh_response(second_refresh_transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn tcp_allocation_connects_to_peer_and_binds_data_connection(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] connect_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    connection_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    data_connection: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+    let lifetime = Lifetime::new(Duration::from_secs(60 * 60)).unwrap(); // Lifetime longer than connection timeout
+    let connection_id = ConnectionId::new(1);
+
+    server.assert_commands(client_connected(source), []);
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + lifetime.lifetime()),
+            CreateTcpAllocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+    server.assert_commands(
+        from_client(
+            source,
+            Connect::new(
+                connect_transaction_id,
+                XorPeerAddress::new(peer.into()),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            Wake(now + Duration::from_secs(30)),
+            ConnectToPeer(49152, PeerSocket::new(peer.into())),
+        ],
+    );
+    server.assert_commands(
+        peer_connected(connection_id, now),
+        [
+            Wake(now + Duration::from_secs(30)),
+            send_message(
+                source,
+                connect_response(connect_transaction_id, connection_id),
+            ),
+        ],
+    );
+    server.assert_commands(client_connected(data_connection), []);
+    server.assert_commands(
+        from_client(
+            data_connection,
+            ConnectionBind::new(
+                connection_bind_transaction_id,
+                connection_id,
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [
+            send_message(
+                data_connection,
+                connection_bind_response(connection_bind_transaction_id),
+            ),
+            BindConnection(connection_id, ClientSocket::new(data_connection.into())),
+        ],
+    );
+}
+
+#[proptest]
+fn tcp_allocation_requires_stream_connection(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::now())] now: SystemTime,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_tcp_implicit_ip4(
+                transaction_id,
+                Some(lifetime),
+                valid_username(now, &username_salt),
+                &secret,
+                nonce,
+            ),
+            now,
+        ),
+        [send_message(
+            source,
+            bad_request_allocate_response(transaction_id),
+        )],
+    );
+}
+
+struct TestServer {
+    server: Server<StepRng>,
+    id_to_port: HashMap<u16, AllocationId>,
+}
+
+impl TestServer {
+    fn new(relay_public_addr: impl Into<IpStack>) -> Self {
+        Self::new_with_rng(relay_public_addr, StepRng::new(0, 0))
+    }
+
+    /// Like [`TestServer::new`] but with a custom RNG, e.g. for the server to hand out distinct random values.
+    ///
+    /// Small values for the RNG still yield the lowest port of the range.
+    fn new_with_rng(relay_public_addr: impl Into<IpStack>, rng: StepRng) -> Self {
+        let mut server = Server::new(relay_public_addr, rng, 49152, 65535);
+
+        // Most tests relay to arbitrary peers, including private ones.
+        server.set_peer_policy(PeerPolicy::new(
+            vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()],
+            vec![],
+        ));
+
+        Self {
+            server,
+            id_to_port: Default::default(),
+        }
+    }
+
+    fn with_nonce(mut self, nonce: Uuid) -> Self {
+        self.server.add_nonce(nonce);
+
+        self
+    }
+
+    fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.server.set_quotas(quotas);
+
+        self
+    }
+
+    fn with_static_credentials(mut self, credentials: &str) -> Self {
+        self.server
+            .set_static_credentials(credentials.parse().unwrap());
+
+        self
+    }
+
+    fn with_alternate_servers(
+        mut self,
+        alternate_servers: Vec<SocketAddr>,
+        load_thresholds: LoadThresholds,
+    ) -> Self {
+        self.server
+            .set_alternate_servers(alternate_servers, load_thresholds);
+
+        self
+    }
+
+    fn with_peer_policy(mut self, peer_policy: PeerPolicy) -> Self {
+        self.server.set_peer_policy(peer_policy);
+
+        self
+    }
+
+    /// Handles a message from a client and returns the [`MobilityTicket`] the server responded with, if any.
+    ///
+    /// Unlike [`TestServer::assert_commands`], this ignores all other commands.
+    fn mobility_ticket_in_response<'a>(
+        &mut self,
+        source: impl Into<SocketAddr>,
+        message: impl Into<ClientMessage<'a>>,
+        now: SystemTime,
+    ) -> Option<MobilityTicket> {
+        self.server
+            .handle_client_message(message.into(), ClientSocket::new(source.into()), now);
+
+        iter::from_fn(|| self.server.next_command()).find_map(|command| match command {
+            Command::SendMessage { payload, .. } => parse_message(&payload)
+                .get_attribute::<MobilityTicket>()
+                .cloned(),
+            _ => None,
+        })
+    }
+
+    fn auth_secret(&self) -> &SecretString {
+        self.server.auth_secret()
+    }
+
+    fn assert_commands<const N: usize>(&mut self, input: Input, output: [Output; N]) {
+        match input {
+            Input::Client(sender, message, now) => {
+                self.server.handle_client_message(message, sender, now);
+            }
+            Input::Time(now) => {
+                self.server.handle_deadline_reached(now);
+            }
+            Input::Drain(deadline) => {
+                self.server.start_draining(deadline);
+            }
+            Input::Restore(snapshot, now) => {
+                self.server.restore(snapshot, now);
+            }
+            Input::Peer(peer, data, port, now) => {
+                self.server
+                    .handle_peer_traffic(&data, peer, self.id_to_port[&port], now);
+            }
+            Input::ClientConnected(client) => {
+                self.server.handle_client_connected(client);
+            }
+            Input::PeerConnected(id, now) => {
+                self.server.handle_peer_connected(id, now);
+            }
+        }
+
+        for expected_output in output {
+            let Some(actual_output) = self.server.next_command() else {
+                let msg = match expected_output {
+                    Output::SendMessage((recipient, msg)) => {
+                        format!("to send message {:?} to {recipient}", msg)
+                    }
+                    Wake(time) => format!("to be woken at {time:?}"),
+                    CreateAllocation(port, family) => {
+                        format!("to create allocation on port {port} for address family {family}")
+                    }
+                    FreeAllocation(port, family) => {
+                        format!("to free allocation on port {port} for address family {family}")
+                    }
+                    CreateTcpAllocation(port, family) => {
+                        format!(
+                            "to create TCP allocation on port {port} for address family {family}"
+                        )
+                    }
+                    ConnectToPeer(port, peer) => {
+                        format!("to connect to peer {peer} from port {port}")
+                    }
+                    BindConnection(id, client) => {
+                        format!("to bind connection {id} to {client}")
+                    }
+                    Output::SendChannelData((peer, _)) => {
+                        format!("to send channel data from {peer} to client")
+                    }
+                    Output::Forward((peer, _, _)) => format!("to forward data to peer {peer}"),
+                };
+
+                panic!("No commands produced but expected {msg}");
+            };
+
+            match (expected_output, actual_output) {
+                (
+                    Output::SendMessage((to, message)),
+                    Command::SendMessage { payload, recipient },
+                ) => {
+                    let expected_bytes = MessageEncoder::new()
+                        .encode_into_bytes(message.clone())
                         .unwrap();
 
                     if expected_bytes != payload {
@@ -666,6 +1999,32 @@ impl TestServer {
                     assert_eq!(expected_port, actual_port);
                     assert_eq!(expected_family, actual_family);
                 }
+                (
+                    CreateTcpAllocation(expected_port, expected_family),
+                    Command::CreateTcpAllocation {
+                        id,
+                        family: actual_family,
+                        port: actual_port,
+                    },
+                ) => {
+                    self.id_to_port.insert(actual_port, id);
+                    assert_eq!(expected_port, actual_port);
+                    assert_eq!(expected_family, actual_family);
+                }
+                (
+                    ConnectToPeer(expected_port, expected_peer),
+                    Command::ConnectToPeer { port, peer, .. },
+                ) => {
+                    assert_eq!(expected_port, port);
+                    assert_eq!(expected_peer, peer);
+                }
+                (
+                    BindConnection(expected_id, expected_client),
+                    Command::BindConnection { id, client },
+                ) => {
+                    assert_eq!(expected_id, id);
+                    assert_eq!(expected_client, client);
+                }
                 (
                     FreeAllocation(port, family),
                     Command::FreeAllocation {
@@ -768,6 +2127,20 @@ fn allocate_response(
     message
 }
 
+fn allocate_response_with_mobility_ticket(
+    transaction_id: TransactionId,
+    public_relay_addr: impl Into<IpAddr>,
+    port: u16,
+    source: impl Into<SocketAddr>,
+    lifetime: &Lifetime,
+    ticket: MobilityTicket,
+) -> Message<Attribute> {
+    let mut message = allocate_response(transaction_id, public_relay_addr, port, source, lifetime);
+    message.add_attribute(ticket);
+
+    message
+}
+
 fn unauthorized_allocate_response(
     transaction_id: TransactionId,
     nonce: Uuid,
@@ -781,6 +2154,44 @@ fn unauthorized_allocate_response(
     message
 }
 
+fn allocation_quota_reached_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(AllocationQuotaReached));
+
+    message
+}
+
+fn try_alternate_response(
+    transaction_id: TransactionId,
+    alternate_server: SocketAddr,
+) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(TryAlternate));
+    message.add_attribute(AlternateServer::new(alternate_server));
+
+    message
+}
+
+fn insufficient_capacity_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(InsufficientCapacity));
+
+    message
+}
+
+fn insufficient_capacity_channel_bind_response(
+    transaction_id: TransactionId,
+) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, CHANNEL_BIND, transaction_id);
+    message.add_attribute(ErrorCode::from(InsufficientCapacity));
+
+    message
+}
+
 fn refresh_response(transaction_id: TransactionId, lifetime: Lifetime) -> Message<Attribute> {
     let mut message =
         Message::<Attribute>::new(MessageClass::SuccessResponse, REFRESH, transaction_id);
@@ -789,10 +2200,67 @@ fn refresh_response(transaction_id: TransactionId, lifetime: Lifetime) -> Messag
     message
 }
 
+fn refresh_response_with_mobility_ticket(
+    transaction_id: TransactionId,
+    lifetime: Lifetime,
+    ticket: MobilityTicket,
+) -> Message<Attribute> {
+    let mut message = refresh_response(transaction_id, lifetime);
+    message.add_attribute(ticket);
+
+    message
+}
+
+fn mobility_forbidden_refresh_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, REFRESH, transaction_id);
+    message.add_attribute(ErrorCode::new(405, "Mobility Forbidden".to_owned()).unwrap());
+
+    message
+}
+
 fn channel_bind_response(transaction_id: TransactionId) -> Message<Attribute> {
     Message::<Attribute>::new(MessageClass::SuccessResponse, CHANNEL_BIND, transaction_id)
 }
 
+fn forbidden_channel_bind_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, CHANNEL_BIND, transaction_id);
+    message.add_attribute(ErrorCode::from(Forbidden));
+
+    message
+}
+
+fn bad_request_allocate_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, ALLOCATE, transaction_id);
+    message.add_attribute(ErrorCode::from(BadRequest));
+
+    message
+}
+
+fn connect_response(
+    transaction_id: TransactionId,
+    connection_id: ConnectionId,
+) -> Message<Attribute> {
+    let mut message = Message::<Attribute>::new(
+        MessageClass::SuccessResponse,
+        Method::new(0x000A).unwrap(), // CONNECT
+        transaction_id,
+    );
+    message.add_attribute(connection_id);
+
+    message
+}
+
+fn connection_bind_response(transaction_id: TransactionId) -> Message<Attribute> {
+    Message::<Attribute>::new(
+        MessageClass::SuccessResponse,
+        Method::new(0x000B).unwrap(), // CONNECTION-BIND
+        transaction_id,
+    )
+}
+
 fn parse_message(message: &[u8]) -> Message<Attribute> {
     MessageDecoder::new()
         .decode_from_bytes(message)
@@ -802,8 +2270,12 @@ fn parse_message(message: &[u8]) -> Message<Attribute> {
 
 enum Input<'a> {
     Client(ClientSocket, ClientMessage<'a>, SystemTime),
-    Peer(PeerSocket, Vec<u8>, u16),
+    Peer(PeerSocket, Vec<u8>, u16, SystemTime),
     Time(SystemTime),
+    Drain(SystemTime),
+    Restore(Snapshot, SystemTime),
+    ClientConnected(ClientSocket),
+    PeerConnected(ConnectionId, SystemTime),
 }
 
 fn from_client<'a>(
@@ -814,14 +2286,35 @@ fn from_client<'a>(
     Input::Client(ClientSocket::new(from.into()), message.into(), now)
 }
 
-fn from_peer<'a>(from: impl Into<SocketAddr>, data: &[u8], port: u16) -> Input<'a> {
-    Input::Peer(PeerSocket::new(from.into()), data.to_vec(), port)
+fn from_peer<'a>(
+    from: impl Into<SocketAddr>,
+    data: &[u8],
+    port: u16,
+    now: SystemTime,
+) -> Input<'a> {
+    Input::Peer(PeerSocket::new(from.into()), data.to_vec(), port, now)
 }
 
 fn forward_time_to<'a>(when: SystemTime) -> Input<'a> {
     Input::Time(when)
 }
 
+fn start_draining<'a>(deadline: SystemTime) -> Input<'a> {
+    Input::Drain(deadline)
+}
+
+fn restore<'a>(snapshot: Snapshot, now: SystemTime) -> Input<'a> {
+    Input::Restore(snapshot, now)
+}
+
+fn client_connected<'a>(client: impl Into<SocketAddr>) -> Input<'a> {
+    Input::ClientConnected(ClientSocket::new(client.into()))
+}
+
+fn peer_connected<'a>(id: ConnectionId, now: SystemTime) -> Input<'a> {
+    Input::PeerConnected(id, now)
+}
+
 #[derive(Debug)]
 enum Output<'a> {
     SendMessage((ClientSocket, Message<Attribute>)),
@@ -830,6 +2323,9 @@ enum Output<'a> {
     Wake(SystemTime),
     CreateAllocation(u16, AddressFamily),
     FreeAllocation(u16, AddressFamily),
+    CreateTcpAllocation(u16, AddressFamily),
+    ConnectToPeer(u16, PeerSocket),
+    BindConnection(ConnectionId, ClientSocket),
 }
 
 fn send_message<'a>(source: impl Into<SocketAddr>, message: Message<Attribute>) -> Output<'a> {
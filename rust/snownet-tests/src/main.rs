@@ -113,6 +113,7 @@ async fn main() -> Result<()> {
                         username: answer.username,
                         password: answer.password,
                     },
+                    key_exchange: None,
                 },
                 Instant::now(),
             );
@@ -177,6 +178,7 @@ async fn main() -> Result<()> {
                         username: offer.username,
                         password: offer.password,
                     },
+                    key_exchange: None,
                 },
                 offer.public_key.into(),
                 stun_server.into_iter().collect(),
@@ -396,6 +398,21 @@ impl<T> Eventloop<T> {
             Some(snownet::Event::ConnectionFailed(conn)) => {
                 return Poll::Ready(Ok(Event::ConnectionFailed { conn }))
             }
+            Some(snownet::Event::NatTypeDetected(nat_type)) => {
+                tracing::info!(?nat_type, "Detected NAT type");
+            }
+            Some(snownet::Event::PresharedKeyRotated(conn)) => {
+                tracing::info!(%conn, "Rotated preshared key");
+            }
+            Some(snownet::Event::PathMtuChanged { connection, mtu }) => {
+                tracing::info!(%connection, %mtu, "Path MTU changed");
+            }
+            Some(snownet::Event::RelayTransportChanged { relay, transport }) => {
+                tracing::info!(%relay, ?transport, "Relay transport changed");
+            }
+            Some(snownet::Event::SignalIceRestart { connection, .. }) => {
+                tracing::warn!(%connection, "Signalling ICE restarts is not supported");
+            }
             None => {}
         }
 